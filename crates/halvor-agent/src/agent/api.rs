@@ -1,14 +1,52 @@
+use crate::agent::auth::{self, PeerCredentials};
+use crate::agent::exec_stream::{CancelHandle, ExecFrame, ExitStatus, OutputStream};
+use crate::agent::file_ops::{self, DirEntry, FileOp, FileOpOutput, FileStat, WriteOptions};
+use crate::agent::handshake::{Hello, capability};
+use crate::agent::mesh;
+use crate::agent::mesh_config::ConfigEntry;
+use crate::agent::mesh_protocol::{MeshMessage, MessagePayload};
 use crate::agent::server::{AgentRequest, AgentResponse, HostInfo};
-use halvor_core::utils::{format_address, read_json, write_json};
+use crate::agent::tls::{self, ClientStream, IssuedCertificate, ServerTrust};
+use halvor_core::utils::{DEFAULT_MAX_FRAME_SIZE, FrameBuffer, format_address, read_json, write_json};
 use anyhow::{Context, Result};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{OnceLock, mpsc};
 use std::time::Duration;
 
+/// Default timeout for connecting to an agent
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a streamed exec checks for input and cancellation while waiting for output
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Client for communicating with halvor agents
+///
+/// Connects over TLS once this host trusts a mesh CA (see [`crate::agent::tls`]),
+/// presenting its node certificate if it has one. Agents that don't have a
+/// certificate yet are still reached in plaintext.
 pub struct AgentClient {
     host: String,
     port: u16,
-    token: Option<String>,
+    credentials: Option<PeerCredentials>,
+    max_frame_size: usize,
+    server_trust: ServerTrust,
+    connect_timeout: Duration,
+    read_timeout: Option<Duration>,
+    /// The agent's handshake, once asked for
+    hello: OnceLock<Hello>,
+}
+
+/// Result of a successful `JoinRequest`
+#[derive(Debug)]
+pub struct JoinAccepted {
+    pub shared_secret: String,
+    pub mesh_peers: Vec<String>,
+    /// Certificate for the joining node, if the issuer runs a mesh CA
+    pub certificate: Option<IssuedCertificate>,
+    /// CA certificates the issuer trusts
+    pub ca_certificates: Vec<String>,
 }
 
 impl AgentClient {
@@ -16,19 +54,155 @@ impl AgentClient {
         Self {
             host: host.to_string(),
             port,
-            token: None,
+            credentials: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            server_trust: ServerTrust::Mesh,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: None,
+            hello: OnceLock::new(),
         }
     }
 
-    pub fn with_token(mut self, token: &str) -> Self {
-        self.token = Some(token.to_string());
+    /// Encrypt and sign requests with the given credentials
+    pub fn with_credentials(mut self, credentials: PeerCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Encrypt and sign requests with the shared secret this host holds for `peer_hostname`
+    ///
+    /// Required for requests that carry secrets or run commands (`execute_command`,
+    /// `sync_database`); agents reject those in plaintext.
+    pub fn with_peer(self, peer_hostname: &str) -> Result<Self> {
+        Ok(self.with_credentials(PeerCredentials::for_peer(peer_hostname)?))
+    }
+
+    /// Set the maximum accepted response frame size in bytes
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Set how the agent's TLS certificate is checked
+    pub fn with_server_trust(mut self, server_trust: ServerTrust) -> Self {
+        self.server_trust = server_trust;
+        self
+    }
+
+    /// Set the timeout for connecting to the agent
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Set a timeout for waiting on the agent's response (none by default)
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
         self
     }
 
     /// Ping the agent
+    ///
+    /// An agent that only accepts TLS, reached by a host without a mesh CA to
+    /// connect with, still counts as up.
     pub fn ping(&self) -> Result<bool> {
-        let response = self.send_request(AgentRequest::Ping)?;
-        Ok(matches!(response, AgentResponse::Pong))
+        match self.send_request(AgentRequest::Ping) {
+            Ok(response) => Ok(matches!(response, AgentResponse::Pong)),
+            Err(e) if e.downcast_ref::<TlsRequired>().is_some() => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The agent's protocol version, halvor version and capabilities
+    ///
+    /// Asked for once per client. Agents from before the handshake are
+    /// reported as [`Hello::legacy`].
+    pub fn hello(&self) -> Result<&Hello> {
+        if let Some(hello) = self.hello.get() {
+            return Ok(hello);
+        }
+
+        let agent = self
+            .credentials
+            .as_ref()
+            .map_or(self.host.as_str(), |c| c.peer_hostname.as_str());
+        // Sent unencrypted: it's allowed before joining, and carries nothing secret
+        let hello = match self.send_raw(&AgentRequest::Hello(Hello::local(&mesh::local_mesh_hostname()))) {
+            Ok(AgentResponse::Hello(hello)) => hello,
+            Ok(AgentResponse::Error { .. }) => Hello::legacy(agent),
+            Ok(_) => anyhow::bail!("Unexpected response to Hello from {}", self.host),
+            Err(e) if e.downcast_ref::<TlsRequired>().is_some() => return Err(e),
+            // Agents that can't parse the request hang up; tell that apart from
+            // one that's down
+            Err(e) => match self.ping() {
+                Ok(true) => Hello::legacy(agent),
+                _ => return Err(e),
+            },
+        };
+        Ok(self.hello.get_or_init(|| hello))
+    }
+
+    /// Fail with an explanation unless the agent supports `capability`
+    pub fn require(&self, capability: &str) -> Result<()> {
+        self.hello()?.require(capability)
+    }
+
+    /// Ask to join the agent's mesh
+    pub fn join(
+        &self,
+        join_token: &str,
+        joiner_hostname: &str,
+        joiner_public_key: &str,
+    ) -> Result<JoinAccepted> {
+        let response = self.send_request(AgentRequest::JoinRequest {
+            join_token: join_token.to_string(),
+            joiner_hostname: joiner_hostname.to_string(),
+            joiner_public_key: joiner_public_key.to_string(),
+        })?;
+
+        match response {
+            AgentResponse::JoinAccepted {
+                shared_secret,
+                mesh_peers,
+                certificate,
+                ca_certificates,
+            } => Ok(JoinAccepted {
+                shared_secret,
+                mesh_peers,
+                certificate,
+                ca_certificates,
+            }),
+            AgentResponse::Error { message } => anyhow::bail!("Join failed: {}", message),
+            _ => anyhow::bail!("Unexpected response from agent"),
+        }
+    }
+
+    /// Ask the agent's mesh CA for a certificate for `public_key`
+    ///
+    /// Issued for this host's name, or `hostname` when it is about to be
+    /// renamed. Requires peer credentials; returns the certificate and the
+    /// CAs the agent trusts.
+    pub fn renew_certificate(
+        &self,
+        public_key: &str,
+        hostname: Option<&str>,
+    ) -> Result<(IssuedCertificate, Vec<String>)> {
+        if self.credentials.is_none() {
+            anyhow::bail!("Renewing a certificate requires mesh peer credentials");
+        }
+        let response = self.send_request(AgentRequest::RenewCertificate {
+            public_key: public_key.to_string(),
+            hostname: hostname.map(str::to_string),
+        })?;
+
+        match response {
+            AgentResponse::CertificateIssued {
+                certificate,
+                ca_certificates,
+            } => Ok((certificate, ca_certificates)),
+            AgentResponse::Error { message } => anyhow::bail!("Certificate renewal failed: {}", message),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
     }
 
     /// Get host information
@@ -42,14 +216,24 @@ impl AgentClient {
     }
 
     /// Execute a command remotely
+    ///
+    /// Requires peer credentials (see [`AgentClient::with_peer`]); the remote agent
+    /// must also have a policy rule allowing this host to run the command.
     pub fn execute_command(&self, command: &str, args: &[&str]) -> Result<String> {
-        let token = self.token.as_deref().unwrap_or("default");
+        let credentials = self.credentials.as_ref().with_context(|| {
+            format!(
+                "Executing commands on {} requires mesh peer credentials",
+                self.host
+            )
+        })?;
         let args_vec: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let payload = auth::exec_payload(command, &args_vec)?;
+        let auth = credentials.sign("ExecuteCommand", &payload)?;
 
         let response = self.send_request(AgentRequest::ExecuteCommand {
             command: command.to_string(),
             args: args_vec,
-            token: token.to_string(),
+            auth,
         })?;
 
         match response {
@@ -59,6 +243,266 @@ impl AgentClient {
         }
     }
 
+    /// Execute a command remotely, streaming its output as it's produced
+    ///
+    /// `on_output` gets each chunk the program writes to stdout or stderr as it
+    /// arrives. `stdin`, if given, is forwarded to the program until it ends;
+    /// otherwise the program's input is closed straight away. Cancelling
+    /// `cancel` kills the program. Returns how the program exited.
+    ///
+    /// Needs the same credentials and policy rule as [`AgentClient::execute_command`].
+    pub fn execute_streaming<F>(
+        &self,
+        command: &str,
+        args: &[&str],
+        stdin: Option<Box<dyn Read + Send>>,
+        cancel: &CancelHandle,
+        mut on_output: F,
+    ) -> Result<ExitStatus>
+    where
+        F: FnMut(OutputStream, &[u8]),
+    {
+        let credentials = self.credentials.as_ref().with_context(|| {
+            format!(
+                "Executing commands on {} requires mesh peer credentials",
+                self.host
+            )
+        })?;
+        self.require(capability::EXEC_STREAM)?;
+
+        let args_vec: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        let payload = auth::exec_payload(command, &args_vec)?;
+        let auth = credentials.sign("ExecuteCommand", &payload)?;
+        let request = AgentRequest::ExecuteCommand {
+            command: command.to_string(),
+            args: args_vec,
+            auth,
+        };
+        let opening = MeshMessage::seal(
+            credentials.local_hostname.clone(),
+            credentials.peer_hostname.clone(),
+            &serde_json::to_vec(&request)?,
+            &credentials.shared_secret,
+            None,
+        )?;
+        let request_id = opening.message_id.clone();
+
+        let (mut stream, addr) = self.open_stream()?;
+        write_json(&mut stream, &AgentRequest::ExecuteStream(opening))?;
+        // Wake up regularly to forward input and cancellation
+        stream.set_read_timeout(Some(EXEC_POLL_INTERVAL))?;
+
+        let send = |stream: &mut ClientStream, frame: &ExecFrame| -> Result<()> {
+            let sealed = MeshMessage::seal(
+                credentials.local_hostname.clone(),
+                credentials.peer_hostname.clone(),
+                &serde_json::to_vec(frame)?,
+                &credentials.shared_secret,
+                Some(request_id.clone()),
+            )?;
+            write_json(stream, &AgentRequest::Secure(sealed))
+        };
+
+        let mut input = stdin.map(read_in_background);
+        if input.is_none() {
+            send(&mut stream, &ExecFrame::StdinEof)?;
+        }
+        let mut cancel_sent = false;
+        let mut frames = FrameBuffer::new(self.max_frame_size);
+        let mut seen = HashSet::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            if cancel.is_cancelled() && !cancel_sent {
+                send(&mut stream, &ExecFrame::Cancel)?;
+                cancel_sent = true;
+            }
+            while let Some(chunks) = &input {
+                match chunks.try_recv() {
+                    Ok(data) => send(&mut stream, &ExecFrame::stdin(&data))?,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        send(&mut stream, &ExecFrame::StdinEof)?;
+                        input = None;
+                    }
+                }
+            }
+
+            match stream.read(&mut buf) {
+                Ok(0) => anyhow::bail!(
+                    "Agent at {} closed the connection before '{}' finished",
+                    addr,
+                    command
+                ),
+                Ok(n) => frames.extend(&buf[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Lost connection to agent at {}", addr));
+                }
+            }
+
+            while let Some(payload) = frames.next_frame()? {
+                let response: AgentResponse =
+                    serde_json::from_slice(&payload).context("Invalid exec stream response")?;
+                let message = match response {
+                    AgentResponse::Secure(message) => message,
+                    AgentResponse::Error { message } => anyhow::bail!("Command failed: {}", message),
+                    AgentResponse::TlsRequired => return Err(TlsRequired { addr }.into()),
+                    _ => anyhow::bail!("Unexpected response type"),
+                };
+                // Every frame is bound to our request, and each may only arrive once
+                if message.in_reply_to() != Some(request_id.as_str())
+                    || !seen.insert(message.message_id.clone())
+                {
+                    anyhow::bail!("Exec stream frame from {} does not match the request", self.host);
+                }
+                let frame: ExecFrame = serde_json::from_slice(&message.open(&credentials.shared_secret)?)
+                    .context("Invalid exec stream frame")?;
+                match frame {
+                    ExecFrame::Stdout { data } => on_output(OutputStream::Stdout, &ExecFrame::decode(&data)?),
+                    ExecFrame::Stderr { data } => on_output(OutputStream::Stderr, &ExecFrame::decode(&data)?),
+                    ExecFrame::Exit(status) => return Ok(status),
+                    ExecFrame::Error { message } => anyhow::bail!("Command failed: {}", message),
+                    frame => anyhow::bail!("Unexpected exec stream frame: {:?}", frame),
+                }
+            }
+        }
+    }
+
+    /// Metadata of a file on the agent's host, `None` if nothing is there
+    ///
+    /// Like the other file operations, needs peer credentials and a policy rule
+    /// on the agent for the operation (`file:stat`, `file:read`, …).
+    pub fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        match self.file_operation(FileOp::Stat { path: path.to_string() })? {
+            FileOpOutput::Stat(stat) => Ok(stat),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
+    /// Read a whole file from the agent's host, in ranges of at most
+    /// [`file_ops::MAX_READ_SIZE`]
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        loop {
+            let (data, eof) = self.read_range(path, content.len() as u64, file_ops::MAX_READ_SIZE)?;
+            content.extend_from_slice(&data);
+            if eof || data.is_empty() {
+                return Ok(content);
+            }
+        }
+    }
+
+    /// Read up to `length` bytes from `offset`, and whether they reach the end of the file
+    pub fn read_range(&self, path: &str, offset: u64, length: u64) -> Result<(Vec<u8>, bool)> {
+        let op = FileOp::Read {
+            path: path.to_string(),
+            offset,
+            length,
+        };
+        match self.file_operation(op)? {
+            FileOpOutput::Data { data, eof } => Ok((FileOpOutput::decode(&data)?, eof)),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
+    /// Replace a file on the agent's host; readers never see it half written
+    ///
+    /// Limited to [`file_ops::MAX_WRITE_SIZE`]; push larger files with
+    /// `halvor agent push`.
+    pub fn write_file(&self, path: &str, content: &[u8], options: WriteOptions) -> Result<()> {
+        if content.len() > file_ops::MAX_WRITE_SIZE {
+            anyhow::bail!(
+                "{} is {} bytes, more than a single write sends ({} bytes); use `halvor agent push`",
+                path,
+                content.len(),
+                file_ops::MAX_WRITE_SIZE
+            );
+        }
+        self.file_operation(FileOp::write(path, content, options))?;
+        Ok(())
+    }
+
+    /// Create a directory and any missing parents on the agent's host
+    pub fn mkdir(&self, path: &str, mode: Option<u32>) -> Result<()> {
+        self.file_operation(FileOp::Mkdir {
+            path: path.to_string(),
+            mode,
+        })?;
+        Ok(())
+    }
+
+    /// List a directory on the agent's host, sorted by name
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        match self.file_operation(FileOp::ReadDir { path: path.to_string() })? {
+            FileOpOutput::Entries(entries) => Ok(entries),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
+    fn file_operation(&self, op: FileOp) -> Result<FileOpOutput> {
+        let credentials = self.credentials.as_ref().with_context(|| {
+            format!("File operations on {} require mesh peer credentials", self.host)
+        })?;
+        self.require(capability::FILE_OPS)?;
+        let auth = credentials.sign("FileOperation", &op.payload()?)?;
+
+        match self.send_request(AgentRequest::FileOperation { op, auth })? {
+            AgentResponse::File(output) => Ok(output),
+            AgentResponse::Error { message } => anyhow::bail!("{}", message),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
+    /// Send a mesh protocol message to the agent's message router
+    ///
+    /// Requires peer credentials; returns the handler's reply, if any. Messages
+    /// the agent has no handler for are answered with an `Error` message.
+    pub fn send_mesh(&self, message: MeshMessage) -> Result<Option<MeshMessage>> {
+        if self.credentials.is_none() {
+            anyhow::bail!("Sending mesh messages to {} requires mesh peer credentials", self.host);
+        }
+
+        match self.send_request(AgentRequest::Mesh(message))? {
+            AgentResponse::Mesh { reply } => Ok(reply),
+            AgentResponse::Error { message } => anyhow::bail!("Agent error: {}", message),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
+    /// Send `payload` from this host to the peer the credentials are for
+    pub fn send_to_peer(&self, payload: MessagePayload) -> Result<Option<MeshMessage>> {
+        let credentials = self.credentials.as_ref().with_context(|| {
+            format!("Sending mesh messages to {} requires mesh peer credentials", self.host)
+        })?;
+        self.send_mesh(MeshMessage::new(
+            credentials.local_hostname.clone(),
+            credentials.peer_hostname.clone(),
+            payload,
+        ))
+    }
+
+    /// Exchange replicated config with the agent
+    ///
+    /// Sends all of `entries` and returns the agent's entries after it merged
+    /// them. Requires peer credentials.
+    pub fn sync_config(&self, entries: &[ConfigEntry]) -> Result<Vec<ConfigEntry>> {
+        if self.credentials.is_none() {
+            anyhow::bail!("Syncing config with {} requires mesh peer credentials", self.host);
+        }
+        self.require(capability::MESH_CONFIG)?;
+
+        let response = self.send_request(AgentRequest::SyncConfig {
+            data: serde_json::to_vec(entries)?,
+        })?;
+        match response {
+            AgentResponse::Success { output } => {
+                serde_json::from_str(&output).context("Invalid config sync response")
+            }
+            AgentResponse::Error { message } => anyhow::bail!("Config sync failed: {}", message),
+            _ => anyhow::bail!("Unexpected response type"),
+        }
+    }
+
     /// Sync database with remote agent
     pub fn sync_database(&self, from_hostname: &str, last_sync: Option<i64>) -> Result<String> {
         let response = self.send_request(AgentRequest::SyncDatabase {
@@ -73,19 +517,140 @@ impl AgentClient {
         }
     }
 
+    /// Send a request, encrypted for the peer when credentials are set
     fn send_request(&self, request: AgentRequest) -> Result<AgentResponse> {
+        let Some(credentials) = &self.credentials else {
+            return self.send_raw(&request);
+        };
+
+        let sealed = MeshMessage::seal(
+            credentials.local_hostname.clone(),
+            credentials.peer_hostname.clone(),
+            &serde_json::to_vec(&request)?,
+            &credentials.shared_secret,
+            None,
+        )?;
+        let request_id = sealed.message_id.clone();
+
+        match self.send_raw(&AgentRequest::Secure(sealed))? {
+            AgentResponse::Secure(response) => {
+                // Binding to our request ID stops an old response being replayed to us
+                if response.in_reply_to() != Some(request_id.as_str()) {
+                    anyhow::bail!("Encrypted response from {} does not match the request", self.host);
+                }
+                let plaintext = response.open(&credentials.shared_secret)?;
+                serde_json::from_slice(&plaintext).context("Invalid encrypted response")
+            }
+            // The agent could not decrypt the request (e.g. it doesn't know us)
+            AgentResponse::Error { message } => Ok(AgentResponse::Error { message }),
+            _ => anyhow::bail!(
+                "Agent at {} answered an encrypted request in plaintext",
+                self.host
+            ),
+        }
+    }
+
+    /// Exchange one request and response
+    fn send_raw(&self, request: &AgentRequest) -> Result<AgentResponse> {
+        let (stream, addr) = self.open_stream()?;
+        match self.exchange(stream, request, &addr)? {
+            AgentResponse::TlsRequired => Err(TlsRequired { addr }.into()),
+            response => Ok(response),
+        }
+    }
+
+    /// Connect to the agent, over TLS when this host has a mesh CA
+    fn open_stream(&self) -> Result<(ClientStream, String)> {
         let addr = format_address(&self.host, self.port);
-        
+
         // Resolve address and connect with timeout
         let socket_addr = addr
             .to_socket_addrs()?
             .next()
             .ok_or_else(|| anyhow::anyhow!("Failed to resolve address: {}", addr))?;
-        
-        let mut stream = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(2))
-            .with_context(|| format!("Failed to connect to agent at {} (timeout: 2s)", addr))?;
 
-        write_json(&mut stream, &request)?;
-        read_json(&mut stream, 8192)
+        let expected_name = self.credentials.as_ref().map(|c| c.peer_hostname.as_str());
+        let Some(config) = tls::client_config(&self.server_trust, expected_name)? else {
+            let stream = self.connect(&socket_addr, &addr)?;
+            return Ok((ClientStream::Plain(stream), addr));
+        };
+
+        let stream = self.connect(&socket_addr, &addr)?;
+        match ClientStream::connect_tls(stream, config, &self.host) {
+            Ok(stream) => Ok((stream, addr)),
+            // Agents without a certificate yet only speak plaintext
+            Err(e) if matches!(self.server_trust, ServerTrust::Mesh) && tls::is_plaintext_peer(&e) => {
+                let stream = self.connect(&socket_addr, &addr)?;
+                Ok((ClientStream::Plain(stream), addr))
+            }
+            Err(e) => Err(e).with_context(|| format!("TLS handshake with agent at {} failed", addr)),
+        }
+    }
+
+    fn connect(&self, socket_addr: &SocketAddr, addr: &str) -> Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(socket_addr, self.connect_timeout).with_context(|| {
+            format!(
+                "Failed to connect to agent at {} (timeout: {}s)",
+                addr,
+                self.connect_timeout.as_secs()
+            )
+        })?;
+        stream.set_read_timeout(self.read_timeout)?;
+        Ok(stream)
+    }
+
+    fn exchange(&self, mut stream: ClientStream, request: &AgentRequest, addr: &str) -> Result<AgentResponse> {
+        write_json(&mut stream, request)?;
+        read_json(&mut stream, self.max_frame_size).map_err(|e| {
+            if e.downcast_ref::<serde_json::Error>().is_some() {
+                // Most likely a response added in a release this host doesn't have
+                e.context(format!(
+                    "Agent at {} sent a response halvor {} can't read; it may run a different version (compare with `halvor agent verify`)",
+                    addr,
+                    env!("CARGO_PKG_VERSION")
+                ))
+            } else {
+                e.context(format!("Failed to read response from agent at {}", addr))
+            }
+        })
     }
 }
+
+/// Read `input` on a thread of its own, so waiting on it doesn't hold up output
+///
+/// The channel disconnects once `input` ends.
+fn read_in_background(mut input: Box<dyn Read + Send>) -> mpsc::Receiver<Vec<u8>> {
+    let (chunks, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if chunks.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// The agent only accepts TLS, and this host has no mesh CA to connect with
+#[derive(Debug)]
+pub struct TlsRequired {
+    addr: String,
+}
+
+impl std::fmt::Display for TlsRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Agent at {} only accepts TLS connections and this host has no mesh certificate yet (join the mesh first: halvor agent join)",
+            self.addr
+        )
+    }
+}
+
+impl std::error::Error for TlsRequired {}
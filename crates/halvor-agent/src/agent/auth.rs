@@ -0,0 +1,254 @@
+//! Peer authentication for privileged agent requests
+//!
+//! After joining, both ends of a peer relationship hold the same shared secret in
+//! `peer_keys`. Privileged requests carry a [`PeerAuth`] whose signature is an
+//! HMAC-SHA256 over the operation, the sender, a timestamp, a nonce and the
+//! request payload. The receiver looks up the secret for `from_hostname`,
+//! verifies the signature and rejects stale or replayed requests.
+//!
+//! Traffic itself is encrypted separately, see `MeshMessage::seal`.
+
+use crate::agent::mesh;
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Maximum allowed difference between the sender's and receiver's clocks
+pub const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// Authentication attached to a privileged request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerAuth {
+    /// Mesh hostname of the sending agent (key into the receiver's `peer_keys`)
+    pub from_hostname: String,
+    /// Unix timestamp (seconds) when the request was signed
+    pub timestamp: i64,
+    /// Random value, unique per request
+    pub nonce: String,
+    /// Base64 HMAC-SHA256 signature
+    pub signature: String,
+}
+
+/// Local identity and shared secret used to sign and encrypt requests to one peer
+#[derive(Debug, Clone)]
+pub struct PeerCredentials {
+    pub local_hostname: String,
+    pub peer_hostname: String,
+    pub shared_secret: String,
+}
+
+impl PeerCredentials {
+    pub fn new(local_hostname: &str, peer_hostname: &str, shared_secret: &str) -> Self {
+        Self {
+            local_hostname: local_hostname.to_string(),
+            peer_hostname: peer_hostname.to_string(),
+            shared_secret: shared_secret.to_string(),
+        }
+    }
+
+    /// Load credentials for talking to `peer_hostname` from the local mesh database
+    pub fn for_peer(peer_hostname: &str) -> Result<Self> {
+        let stored = mesh::find_peer(peer_hostname)?.with_context(|| {
+            format!(
+                "'{}' is not a mesh peer of this host (join the mesh first: halvor agent join)",
+                peer_hostname
+            )
+        })?;
+        let shared_secret = mesh::get_peer_shared_secret(&stored)?
+            .with_context(|| format!("No shared secret stored for peer '{}'", stored))?;
+
+        Ok(Self::new(&mesh::local_mesh_hostname(), &stored, &shared_secret))
+    }
+
+    /// Sign `payload` for `operation`
+    pub fn sign(&self, operation: &str, payload: &[u8]) -> Result<PeerAuth> {
+        let timestamp = chrono::Utc::now().timestamp();
+        let nonce = uuid::Uuid::new_v4().to_string();
+        let mac = compute_mac(
+            &self.shared_secret,
+            operation,
+            &self.local_hostname,
+            timestamp,
+            &nonce,
+            payload,
+        )?;
+
+        Ok(PeerAuth {
+            from_hostname: self.local_hostname.clone(),
+            timestamp,
+            nonce,
+            signature: general_purpose::STANDARD.encode(mac.finalize().into_bytes()),
+        })
+    }
+}
+
+/// Verify the signature and freshness of `auth` against `shared_secret`
+pub fn verify(auth: &PeerAuth, shared_secret: &str, operation: &str, payload: &[u8]) -> Result<()> {
+    check_timestamp(auth.timestamp)?;
+
+    let signature = general_purpose::STANDARD
+        .decode(&auth.signature)
+        .context("signature is not valid base64")?;
+    let mac = compute_mac(
+        shared_secret,
+        operation,
+        &auth.from_hostname,
+        auth.timestamp,
+        &auth.nonce,
+        payload,
+    )?;
+    // verify_slice compares in constant time
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow::anyhow!("invalid signature"))
+}
+
+/// Reject timestamps further than [`MAX_CLOCK_SKEW_SECS`] from the local clock
+pub fn check_timestamp(timestamp: i64) -> Result<()> {
+    let skew = (chrono::Utc::now().timestamp() - timestamp).abs();
+    if skew > MAX_CLOCK_SKEW_SECS {
+        anyhow::bail!(
+            "request timestamp is {}s away from local clock (max {}s)",
+            skew,
+            MAX_CLOCK_SKEW_SECS
+        );
+    }
+    Ok(())
+}
+
+/// Signing payload for an `ExecuteCommand` request
+pub fn exec_payload(command: &str, args: &[String]) -> Result<Vec<u8>> {
+    serde_json::to_vec(&(command, args)).context("Failed to serialize command for signing")
+}
+
+fn compute_mac(
+    shared_secret: &str,
+    operation: &str,
+    from_hostname: &str,
+    timestamp: i64,
+    nonce: &str,
+    payload: &[u8],
+) -> Result<HmacSha256> {
+    let mut mac = HmacSha256::new_from_slice(shared_secret.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Invalid shared secret: {}", e))?;
+    // Newline-separated header fields, payload last
+    mac.update(operation.as_bytes());
+    mac.update(b"\n");
+    mac.update(from_hostname.as_bytes());
+    mac.update(b"\n");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\n");
+    mac.update(payload);
+    Ok(mac)
+}
+
+/// Remembers recently used nonces so a captured request can't be replayed
+///
+/// Used for [`PeerAuth`] nonces and sealed `MeshMessage` IDs. Requests outside the
+/// clock skew window are already rejected by [`check_timestamp`], so nonces only
+/// need to be kept for that long.
+pub struct ReplayGuard {
+    window_secs: i64,
+    seen: Mutex<HashMap<(String, String), i64>>,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(MAX_CLOCK_SKEW_SECS)
+    }
+}
+
+impl ReplayGuard {
+    pub fn new(window_secs: i64) -> Self {
+        Self {
+            window_secs,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a nonce from `from_hostname`, failing if it was already used
+    pub fn check(&self, from_hostname: &str, nonce: &str, timestamp: i64) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut seen = self
+            .seen
+            .lock()
+            .map_err(|_| anyhow::anyhow!("replay cache lock poisoned"))?;
+
+        // Entries outside both sides of the skew window can never be accepted again
+        let window = self.window_secs;
+        seen.retain(|_, timestamp| (now - *timestamp).abs() <= window);
+
+        let key = (from_hostname.to_string(), nonce.to_string());
+        if seen.contains_key(&key) {
+            anyhow::bail!("replayed request (nonce already used)");
+        }
+        seen.insert(key, timestamp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> PeerCredentials {
+        PeerCredentials::new("frigg", "baulder", "c2VjcmV0LWtleS1mb3ItdGVzdHM=")
+    }
+
+    #[test]
+    fn test_sign_verify_roundtrip() {
+        let payload = exec_payload("uptime", &[]).unwrap();
+        let auth = credentials().sign("ExecuteCommand", &payload).unwrap();
+
+        assert_eq!(auth.from_hostname, "frigg");
+        verify(&auth, &credentials().shared_secret, "ExecuteCommand", &payload).unwrap();
+    }
+
+    #[test]
+    fn test_verify_rejects_tampering() {
+        let payload = exec_payload("ls", &["/tmp".to_string()]).unwrap();
+        let auth = credentials().sign("ExecuteCommand", &payload).unwrap();
+        let secret = credentials().shared_secret;
+
+        let tampered = exec_payload("rm", &["-rf".to_string(), "/tmp".to_string()]).unwrap();
+        assert!(verify(&auth, &secret, "ExecuteCommand", &tampered).is_err());
+        assert!(verify(&auth, "other-secret", "ExecuteCommand", &payload).is_err());
+        assert!(verify(&auth, &secret, "SyncConfig", &payload).is_err());
+
+        let mut spoofed = auth.clone();
+        spoofed.from_hostname = "baulder".to_string();
+        assert!(verify(&spoofed, &secret, "ExecuteCommand", &payload).is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_stale_timestamp() {
+        let payload = exec_payload("uptime", &[]).unwrap();
+        let mut auth = credentials().sign("ExecuteCommand", &payload).unwrap();
+        auth.timestamp -= MAX_CLOCK_SKEW_SECS + 60;
+
+        let err = verify(&auth, &credentials().shared_secret, "ExecuteCommand", &payload)
+            .unwrap_err();
+        assert!(err.to_string().contains("timestamp"));
+    }
+
+    #[test]
+    fn test_replay_guard_rejects_reused_nonce() {
+        let guard = ReplayGuard::default();
+        let auth = credentials().sign("ExecuteCommand", b"{}").unwrap();
+
+        guard.check(&auth.from_hostname, &auth.nonce, auth.timestamp).unwrap();
+        assert!(guard.check(&auth.from_hostname, &auth.nonce, auth.timestamp).is_err());
+        // Same nonce from a different peer is a different request
+        guard.check("baulder", &auth.nonce, auth.timestamp).unwrap();
+
+        let fresh = credentials().sign("ExecuteCommand", b"{}").unwrap();
+        guard.check(&fresh.from_hostname, &fresh.nonce, fresh.timestamp).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use crate::agent::discovery::DiscoveredHost;
+use crate::agent::mesh::{self, MeshPeer};
 use crate::agent::server::HostInfo;
 use crate::agent::{api::AgentClient, discovery::HostDiscovery};
 use anyhow::Result;
@@ -64,13 +65,26 @@ impl HalvorClient {
         command: String,
         args: Vec<String>,
     ) -> Result<String, String> {
-        let client = AgentClient::new(&host, port);
+        // Commands are signed with the secret shared with that peer, so find out who it is
+        let peer_hostname = AgentClient::new(&host, port)
+            .get_host_info()
+            .map_err(|e| e.to_string())?
+            .hostname;
+        let client = AgentClient::new(&host, port)
+            .with_peer(&peer_hostname)
+            .map_err(|e| e.to_string())?;
         let args_refs: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
         client
             .execute_command(&command, &args_refs)
             .map_err(|e| e.to_string())
     }
 
+    /// List this node's mesh peers with their heartbeat status
+    #[halvor_ffi_macro::multi_platform_export]
+    pub fn list_mesh_peers(&self) -> Result<Vec<MeshPeer>, String> {
+        mesh::list_peers().map_err(|e| e.to_string())
+    }
+
     /// Get the version of the Halvor client
     /// This is a test function to verify macro generation works correctly
     #[halvor_ffi_macro::multi_platform_export]
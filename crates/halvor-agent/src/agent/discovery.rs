@@ -1,8 +1,14 @@
+use crate::agent::api::AgentClient;
+use crate::agent::mdns;
 use crate::apps::tailscale;
-use halvor_core::utils::{format_address, networking, write_json};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,20 +19,190 @@ pub struct DiscoveredHost {
     pub tailscale_hostname: Option<String>,
     pub agent_port: u16,
     pub reachable: bool,
+    /// halvor version the agent reported, if it was asked
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// Default number of addresses probed at once during a subnet scan
+pub const DEFAULT_SCAN_CONCURRENCY: usize = 64;
+
+/// Default connect/read timeout for each probe
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Interface networks wider than this are narrowed to the /24 around our
+/// address, so a /16 LAN doesn't mean probing 65k hosts
+const MIN_INTERFACE_PREFIX: u8 = 22;
+
+/// Configured subnets wider than this are refused
+const MIN_SCAN_PREFIX: u8 = 16;
+
+/// Comma-separated CIDRs to scan instead of the local interface networks
+pub const SUBNETS_ENV: &str = "HALVOR_DISCOVERY_SUBNETS";
+
+/// Comma-separated CIDRs or addresses never to probe
+pub const EXCLUDE_ENV: &str = "HALVOR_DISCOVERY_EXCLUDE";
+
+/// An IPv4 network, e.g. `192.168.1.0/24` (a bare address is a /32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Cidr {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Cidr {
+    /// The network containing `ip`, with host bits cleared
+    pub fn new(ip: Ipv4Addr, prefix: u8) -> Result<Self> {
+        anyhow::ensure!(prefix <= 32, "Invalid prefix length /{}", prefix);
+        Ok(Self {
+            network: Ipv4Addr::from(u32::from(ip) & Self::mask(prefix)),
+            prefix,
+        })
+    }
+
+    /// The network of an interface address with the given netmask
+    pub fn from_netmask(ip: Ipv4Addr, netmask: Ipv4Addr) -> Result<Self> {
+        let mask = u32::from(netmask);
+        anyhow::ensure!(
+            mask.leading_ones() == mask.count_ones(),
+            "Invalid netmask {}",
+            netmask
+        );
+        Self::new(ip, mask.count_ones() as u8)
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::mask(self.prefix) == u32::from(self.network)
+    }
+
+    /// Usable host addresses (all but the network and broadcast addresses,
+    /// except in /31 and /32 networks, which have none)
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> + use<> {
+        let first = u32::from(self.network);
+        let last = first | !Self::mask(self.prefix);
+        let (first, last) = if self.prefix >= 31 {
+            (first, last)
+        } else {
+            (first + 1, last - 1)
+        };
+        (first..=last).map(Ipv4Addr::from)
+    }
+
+    fn mask(prefix: u8) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, prefix) = match s.trim().split_once('/') {
+            Some((ip, prefix)) => (
+                ip,
+                prefix
+                    .parse()
+                    .with_context(|| format!("Invalid prefix length in '{}'", s))?,
+            ),
+            None => (s.trim(), 32),
+        };
+        let ip: Ipv4Addr = ip
+            .parse()
+            .with_context(|| format!("Invalid IPv4 address in '{}'", s))?;
+        Self::new(ip, prefix)
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Parse a comma-separated list of CIDRs
+pub fn parse_cidrs(list: &str) -> Result<Vec<Ipv4Cidr>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(str::parse)
+        .collect()
 }
 
 /// Discover halvor agents on the network
 pub struct HostDiscovery {
     agent_port: u16,
+    /// Networks to scan; the local interface networks if empty
+    subnets: Vec<Ipv4Cidr>,
+    exclude: Vec<Ipv4Cidr>,
+    concurrency: usize,
+    probe_timeout: Duration,
+    /// How long to listen for mDNS advertisements (zero to skip mDNS)
+    mdns_timeout: Duration,
+    /// Browse mDNS on loopback only
+    mdns_loopback: bool,
 }
 
 impl HostDiscovery {
+    /// Discovery on `agent_port`, scanning the subnets in `HALVOR_DISCOVERY_SUBNETS`
+    /// (or the local networks) minus those in `HALVOR_DISCOVERY_EXCLUDE`
     pub fn new(agent_port: u16) -> Self {
-        Self { agent_port }
+        Self {
+            agent_port,
+            subnets: cidrs_from_env(SUBNETS_ENV),
+            exclude: cidrs_from_env(EXCLUDE_ENV),
+            concurrency: DEFAULT_SCAN_CONCURRENCY,
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
+            mdns_timeout: mdns::DEFAULT_BROWSE_TIMEOUT,
+            mdns_loopback: false,
+        }
     }
 
     pub fn default() -> Self {
-        Self { agent_port: 13500 }
+        Self::new(13500)
+    }
+
+    /// Scan these networks instead of the local interface networks
+    pub fn with_subnets(mut self, subnets: Vec<Ipv4Cidr>) -> Self {
+        self.subnets = subnets;
+        self
+    }
+
+    /// Never probe addresses in these networks
+    pub fn with_exclusions(mut self, exclude: Vec<Ipv4Cidr>) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Probe at most `concurrency` addresses at once
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Give each probe this long to connect and answer
+    pub fn with_probe_timeout(mut self, probe_timeout: Duration) -> Self {
+        self.probe_timeout = probe_timeout;
+        self
+    }
+
+    /// Listen for mDNS advertisements this long (zero skips mDNS)
+    pub fn with_mdns_timeout(mut self, mdns_timeout: Duration) -> Self {
+        self.mdns_timeout = mdns_timeout;
+        self
+    }
+
+    /// Browse mDNS on loopback only, for agents on this machine
+    pub fn with_mdns_loopback(mut self, mdns_loopback: bool) -> Self {
+        self.mdns_loopback = mdns_loopback;
+        self
     }
 
     /// Discover hosts via Tailscale
@@ -46,6 +222,7 @@ impl HostDiscovery {
                             tailscale_hostname: Some(device.name.clone()),
                             agent_port: self.agent_port,
                             reachable: true,
+                            version: None,
                         });
                     }
                 }
@@ -55,62 +232,156 @@ impl HostDiscovery {
         Ok(hosts)
     }
 
-    /// Discover hosts on local network
-    pub fn discover_via_local_network(&self) -> Result<Vec<DiscoveredHost>> {
+    /// Discover hosts advertising themselves over mDNS
+    ///
+    /// Each advertised address is pinged; agents that don't answer are still
+    /// returned, marked unreachable.
+    pub fn discover_via_mdns(&self) -> Result<Vec<DiscoveredHost>> {
+        if self.mdns_timeout.is_zero() {
+            return Ok(Vec::new());
+        }
+
         let mut hosts = Vec::new();
+        for agent in mdns::browse(self.mdns_timeout, self.mdns_loopback)? {
+            let port = agent.advertisement.port;
+            let address = agent
+                .addresses
+                .iter()
+                .map(|ip| ip.to_string())
+                .find(|ip| self.check_agent_reachable_on(ip, port));
+            let reachable = address.is_some();
+            hosts.push(DiscoveredHost {
+                hostname: agent.advertisement.hostname,
+                local_ip: address.or_else(|| agent.addresses.first().map(|ip| ip.to_string())),
+                tailscale_ip: None,
+                tailscale_hostname: None,
+                agent_port: port,
+                reachable,
+                version: Some(agent.advertisement.version),
+            });
+        }
+        Ok(hosts)
+    }
 
-        // Get local network IPs
-        if let Ok(local_ips) = networking::get_local_ips() {
-            for ip in local_ips {
-                // Skip loopback
-                if ip == "127.0.0.1" || ip == "::1" {
-                    continue;
-                }
+    /// Discover hosts on local network
+    ///
+    /// Probes every address in the scanned subnets in parallel and names each
+    /// agent found by the hostname it reports.
+    pub fn discover_via_local_network(&self) -> Result<Vec<DiscoveredHost>> {
+        let local_ips = local_ipv4_interfaces()
+            .into_iter()
+            .map(|(ip, _)| ip)
+            .collect::<Vec<_>>();
+        let targets: Vec<Ipv4Addr> = self
+            .scan_subnets()
+            .iter()
+            .flat_map(|subnet| subnet.hosts())
+            .filter(|ip| !local_ips.contains(ip))
+            .filter(|ip| !self.exclude.iter().any(|cidr| cidr.contains(*ip)))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
 
-                // Extract network prefix (assume /24 for IPv4)
-                if let Some(prefix) = ip.rsplit('.').nth(1) {
-                    // Scan network for halvor agents
-                    // For now, just check common IPs
-                    for i in 1..255 {
-                        let test_ip = format!("{}.{}", prefix, i);
-                        if self.check_agent_reachable(&test_ip) {
-                            hosts.push(DiscoveredHost {
-                                hostname: format!("host-{}", i),
-                                local_ip: Some(test_ip),
-                                tailscale_ip: None,
-                                tailscale_hostname: None,
-                                agent_port: self.agent_port,
-                                reachable: true,
-                            });
+        let next = AtomicUsize::new(0);
+        let hosts = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..self.concurrency.min(targets.len()) {
+                scope.spawn(|| {
+                    while let Some(ip) = targets.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if let Some(host) = self.probe(&ip.to_string()) {
+                            hosts.lock().unwrap_or_else(|e| e.into_inner()).push(host);
                         }
                     }
-                }
+                });
             }
-        }
+        });
 
+        let mut hosts = hosts.into_inner().unwrap_or_else(|e| e.into_inner());
+        hosts.sort_by_key(|host| host.local_ip.as_ref().and_then(|ip| ip.parse::<Ipv4Addr>().ok()));
         Ok(hosts)
     }
 
-    /// Check if agent is reachable at given IP
-    fn check_agent_reachable(&self, ip: &str) -> bool {
-        let addr = format_address(ip, self.agent_port);
-        if let Ok(mut addr_iter) = addr.to_socket_addrs() {
-            if let Some(addr) = addr_iter.next() {
-                if let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
-                    // Try to send ping
-                    let ping = serde_json::json!({
-                        "Ping": {}
-                    });
-                    if write_json(&mut stream, &ping).is_ok() {
-                        return true;
+    /// Networks to scan: the configured subnets, or those of the local interfaces
+    fn scan_subnets(&self) -> Vec<Ipv4Cidr> {
+        if !self.subnets.is_empty() {
+            return self
+                .subnets
+                .iter()
+                .filter(|subnet| {
+                    let allowed = subnet.prefix() >= MIN_SCAN_PREFIX;
+                    if !allowed {
+                        eprintln!(
+                            "Warning: Not scanning {}: subnets wider than /{} are too large",
+                            subnet, MIN_SCAN_PREFIX
+                        );
                     }
+                    allowed
+                })
+                .copied()
+                .collect();
+        }
+
+        let mut subnets: Vec<Ipv4Cidr> = local_ipv4_interfaces()
+            .into_iter()
+            .filter_map(|(ip, netmask)| {
+                let subnet = Ipv4Cidr::from_netmask(ip, netmask).ok()?;
+                if subnet.prefix() < MIN_INTERFACE_PREFIX {
+                    Ipv4Cidr::new(ip, 24).ok()
+                } else {
+                    Some(subnet)
                 }
-            }
+            })
+            .collect();
+        subnets.sort();
+        subnets.dedup();
+        subnets
+    }
+
+    /// Probe one address, returning the agent there (if any)
+    fn probe(&self, ip: &str) -> Option<DiscoveredHost> {
+        if !self.check_agent_reachable(ip) {
+            return None;
         }
-        false
+        // An agent that only talks to mesh members won't say who it is;
+        // fall back to its address
+        let (hostname, version) = match AgentClient::new(ip, self.agent_port)
+            .with_connect_timeout(self.probe_timeout)
+            .with_read_timeout(self.probe_timeout.max(Duration::from_secs(5)))
+            .get_host_info()
+        {
+            Ok(info) => (info.hostname, info.version),
+            Err(_) => (ip.to_string(), None),
+        };
+        Some(DiscoveredHost {
+            hostname,
+            version,
+            local_ip: Some(ip.to_string()),
+            tailscale_ip: None,
+            tailscale_hostname: None,
+            agent_port: self.agent_port,
+            reachable: true,
+        })
+    }
+
+    /// Check if agent is reachable at given IP
+    ///
+    /// Pings over TLS when this host has a mesh CA, and requires a Pong so
+    /// non-agent listeners aren't reported.
+    fn check_agent_reachable(&self, ip: &str) -> bool {
+        self.check_agent_reachable_on(ip, self.agent_port)
     }
 
-    /// Discover all available hosts (Tailscale + local network)
+    fn check_agent_reachable_on(&self, ip: &str, port: u16) -> bool {
+        AgentClient::new(ip, port)
+            .with_connect_timeout(self.probe_timeout)
+            .with_read_timeout(self.probe_timeout)
+            // Pong is tiny - cap the frame so a stray service can't make us allocate
+            .with_max_frame_size(1024)
+            .ping()
+            .unwrap_or(false)
+    }
+
+    /// Discover all available hosts (Tailscale + mDNS + local network)
     pub fn discover_all(&self) -> Result<Vec<DiscoveredHost>> {
         let mut hosts = Vec::new();
 
@@ -119,6 +390,11 @@ impl HostDiscovery {
             hosts.append(&mut tailscale_hosts);
         }
 
+        // Discover via mDNS
+        if let Ok(mut mdns_hosts) = self.discover_via_mdns() {
+            hosts.append(&mut mdns_hosts);
+        }
+
         // Discover via local network
         if let Ok(mut local_hosts) = self.discover_via_local_network() {
             hosts.append(&mut local_hosts);
@@ -139,3 +415,67 @@ impl HostDiscovery {
         Ok(hosts)
     }
 }
+
+/// IPv4 addresses and netmasks of this host's interfaces, leaving out loopback
+/// and Tailscale (100.64.0.0/10), which is discovered separately
+fn local_ipv4_interfaces() -> Vec<(Ipv4Addr, Ipv4Addr)> {
+    let tailscale = Ipv4Cidr {
+        network: Ipv4Addr::new(100, 64, 0, 0),
+        prefix: 10,
+    };
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(addr) if !addr.is_loopback() && !tailscale.contains(addr.ip) => {
+                Some((addr.ip, addr.netmask))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn cidrs_from_env(var: &str) -> Vec<Ipv4Cidr> {
+    let Ok(list) = std::env::var(var) else {
+        return Vec::new();
+    };
+    parse_cidrs(&list).unwrap_or_else(|e| {
+        eprintln!("Warning: Ignoring {}: {}", var, e);
+        Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Ipv4Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parsing_clears_host_bits() {
+        assert_eq!(cidr("192.168.1.77/24").to_string(), "192.168.1.0/24");
+        assert_eq!(cidr("10.0.0.5").to_string(), "10.0.0.5/32");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert!("10.0.0.0/33".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0/24".parse::<Ipv4Cidr>().is_err());
+
+        let from_mask =
+            Ipv4Cidr::from_netmask(Ipv4Addr::new(172, 16, 5, 9), Ipv4Addr::new(255, 255, 252, 0));
+        assert_eq!(from_mask.unwrap(), cidr("172.16.4.0/22"));
+        assert!(Ipv4Cidr::from_netmask(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(255, 0, 255, 0)).is_err());
+    }
+
+    #[test]
+    fn test_cidr_hosts_skip_network_and_broadcast() {
+        let hosts: Vec<_> = cidr("192.168.1.0/30").hosts().collect();
+        assert_eq!(hosts, vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 2)]);
+        assert_eq!(cidr("10.1.0.0/22").hosts().count(), 1022);
+        assert_eq!(cidr("10.0.0.7/32").hosts().collect::<Vec<_>>(), vec![Ipv4Addr::new(10, 0, 0, 7)]);
+
+        assert!(cidr("10.1.0.0/22").contains(Ipv4Addr::new(10, 1, 3, 255)));
+        assert!(!cidr("10.1.0.0/22").contains(Ipv4Addr::new(10, 1, 4, 0)));
+        assert_eq!(parse_cidrs(" 10.0.0.0/24, 10.0.1.1 ,").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,183 @@
+//! Streaming remote command execution
+//!
+//! `ExecuteCommand` waits for a program to finish and answers with all of its
+//! output at once, without the exit code. A streamed exec keeps the connection
+//! open instead:
+//!
+//! 1. The client sends `AgentRequest::ExecuteStream`, a sealed, signed
+//!    `ExecuteCommand`. The agent checks it like any other command.
+//! 2. The agent sends [`ExecFrame`]s as the program writes to stdout and
+//!    stderr, each sealed for the client and bound to the opening request, and
+//!    finishes with [`ExecFrame::Exit`].
+//! 3. Meanwhile the client may send sealed `Stdin`, `StdinEof` and `Cancel`
+//!    frames. Hanging up kills the program too.
+//!
+//! Refusals before the program starts come back as a plain `Error` response,
+//! like other sealed requests that fail to open.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Which of the program's outputs a chunk came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A message within a streamed exec, in either direction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExecFrame {
+    /// Output the program wrote to stdout (base64)
+    Stdout { data: String },
+    /// Output the program wrote to stderr (base64)
+    Stderr { data: String },
+    /// Input for the program (base64)
+    Stdin { data: String },
+    /// The client has no more input; the program's stdin is closed
+    StdinEof,
+    /// Kill the program; the agent still finishes with `Exit`
+    Cancel,
+    /// The program ended; always the last frame from the agent
+    Exit(ExitStatus),
+    /// The stream broke down after the program started
+    Error { message: String },
+}
+
+impl ExecFrame {
+    /// A chunk of the program's output
+    pub fn output(stream: OutputStream, bytes: &[u8]) -> Self {
+        let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+        match stream {
+            OutputStream::Stdout => ExecFrame::Stdout { data },
+            OutputStream::Stderr => ExecFrame::Stderr { data },
+        }
+    }
+
+    /// A chunk of input for the program
+    pub fn stdin(bytes: &[u8]) -> Self {
+        ExecFrame::Stdin {
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+
+    /// Decode the bytes carried by a `Stdout`, `Stderr` or `Stdin` frame
+    pub fn decode(data: &str) -> Result<Vec<u8>> {
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .context("Invalid data in exec stream frame")
+    }
+}
+
+/// How a remote program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitStatus {
+    /// Exit code, if the program exited normally
+    pub code: Option<i32>,
+    /// Signal that killed the program, if any
+    pub signal: Option<i32>,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Exit code to end a local process with, following the shell's 128+signal convention
+    pub fn exit_code(&self) -> i32 {
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+
+    /// The equivalent local `std::process::ExitStatus`
+    #[cfg(unix)]
+    pub fn to_std(self) -> std::process::ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        match (self.code, self.signal) {
+            (Some(code), _) => std::process::ExitStatus::from_raw((code & 0xff) << 8),
+            (None, Some(signal)) => std::process::ExitStatus::from_raw(signal & 0x7f),
+            (None, None) => std::process::ExitStatus::from_raw(1 << 8),
+        }
+    }
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        let signal = {
+            use std::os::unix::process::ExitStatusExt;
+            status.signal()
+        };
+        #[cfg(not(unix))]
+        let signal = None;
+        Self {
+            code: status.code(),
+            signal,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "exit code {}", code),
+            (None, Some(signal)) => write!(f, "killed by signal {}", signal),
+            (None, None) => write!(f, "unknown exit status"),
+        }
+    }
+}
+
+/// Cancels a streamed exec from another thread (e.g. a Ctrl-C handler)
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the agent to kill the program
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_frames_carry_binary_data() {
+        let bytes = [0u8, 159, 146, 150, b'\n'];
+        let frame = ExecFrame::output(OutputStream::Stderr, &bytes);
+        let json = serde_json::to_string(&frame).unwrap();
+        let ExecFrame::Stderr { data } = serde_json::from_str(&json).unwrap() else {
+            panic!("expected a stderr frame: {}", json);
+        };
+        assert_eq!(ExecFrame::decode(&data).unwrap(), bytes);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_status_round_trips_to_std() {
+        let exited = ExitStatus { code: Some(3), signal: None };
+        assert_eq!(ExitStatus::from(exited.to_std()), exited);
+        assert_eq!(exited.exit_code(), 3);
+
+        let killed = ExitStatus { code: None, signal: Some(9) };
+        assert_eq!(ExitStatus::from(killed.to_std()), killed);
+        assert_eq!(killed.exit_code(), 137);
+        assert!(!killed.success());
+    }
+}
@@ -0,0 +1,271 @@
+//! Running commands on a host through its halvor agent
+//!
+//! [`AgentExecutor`] implements `CommandExecutor` over [`AgentClient`], so
+//! anything that takes an executor can run through the agent mesh instead of
+//! SSH. [`register`] plugs it into `Executor::new`, which picks the agent or
+//! SSH per host (`HOST_<name>_TRANSPORT=agent|ssh|auto`, SSH if unset).
+//!
+//! Commands still need a policy rule on the agent allowing this host to run
+//! them (`halvor agent policy allow`). File access uses the agent's native
+//! file operations (`file:read`, `file:write`, … in policy rules), or shell
+//! commands on agents too old to have them.
+
+use crate::agent::api::AgentClient;
+use crate::agent::exec_stream::{CancelHandle, OutputStream};
+use crate::agent::file_ops::{FileKind, WriteOptions};
+use crate::agent::handshake::capability;
+use crate::agent::mesh::DEFAULT_AGENT_PORT;
+use anyhow::{Context, Result};
+use halvor_core::config::EnvConfig;
+use halvor_core::utils::exec::{self, CommandExecutor};
+use halvor_core::utils::format_address;
+use std::io::{self, Write};
+use std::process::Output;
+
+/// Let `Executor::new` reach hosts through their agents (`Executor::Agent`)
+///
+/// Called once at startup.
+pub fn register() {
+    exec::set_agent_connector(|hostname, config| Ok(Box::new(AgentExecutor::connect(hostname, config)?)));
+}
+
+/// Agent-based executor that uses the halvor agent API instead of SSH
+/// This avoids password prompts by using encrypted agent communication
+pub struct AgentExecutor {
+    client: AgentClient,
+    hostname: String,
+}
+
+impl AgentExecutor {
+    /// Connect to the agent on `hostname`, at the addresses its config gives
+    ///
+    /// Fails unless an agent answers and this host is one of its mesh peers.
+    pub fn connect(hostname: &str, config: &EnvConfig) -> Result<Self> {
+        let actual_hostname = halvor_core::utils::hostname::find_hostname_in_config(hostname, config)
+            .with_context(|| format!("Host '{}' not found in config", hostname))?;
+        let host_config = config
+            .hosts
+            .get(&actual_hostname)
+            .with_context(|| format!("Host '{}' not found in config", actual_hostname))?;
+
+        // Prefer the Tailscale hostname, like SSH connections do
+        let mut addresses = Vec::new();
+        if let Some(hostname_val) = &host_config.hostname {
+            addresses.push(hostname_val.trim_end_matches('.').to_string());
+        }
+        if let Some(ip) = &host_config.ip
+            && !addresses.contains(ip)
+        {
+            addresses.push(ip.clone());
+        }
+
+        let mut last_error = None;
+        for address in &addresses {
+            match Self::connect_to(address, DEFAULT_AGENT_PORT, hostname) {
+                Ok(executor) => return Ok(executor),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("No IP or Tailscale hostname configured for {}", hostname)))
+    }
+
+    /// Connect to the agent listening on `address`
+    pub fn connect_to(address: &str, port: u16, hostname: &str) -> Result<Self> {
+        let probe = AgentClient::new(address, port);
+        let hello = probe
+            .hello()
+            .with_context(|| format!("No halvor agent at {}", format_address(address, port)))?;
+        // Requests are encrypted for the peer the agent says it is
+        let peer_hostname = if hello.protocol_version == 0 {
+            probe.get_host_info()?.hostname
+        } else {
+            hello.hostname.clone()
+        };
+        let client = AgentClient::new(address, port).with_peer(&peer_hostname)?;
+
+        Ok(Self {
+            client,
+            hostname: hostname.to_string(),
+        })
+    }
+
+    /// The host commands run on
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Whether the agent has native file operations, rather than shell commands
+    fn native_files(&self) -> Result<bool> {
+        Ok(self.client.hello()?.supports(capability::FILE_OPS))
+    }
+
+    fn kind_of(&self, path: &str) -> Result<Option<FileKind>> {
+        Ok(self.client.stat(path)?.map(|stat| stat.kind))
+    }
+
+    /// Run a program through the agent, collecting its output and exit status
+    ///
+    /// With `echo`, output is also printed as it arrives, so long installs show
+    /// progress. Agents without streamed exec only report output once the
+    /// program is done, and fail instead of returning a non-zero status.
+    fn run(&self, program: &str, args: &[&str], echo: bool) -> Result<Output> {
+        #[cfg(unix)]
+        use std::os::unix::process::ExitStatusExt;
+
+        if !self.client.hello()?.supports(capability::EXEC_STREAM) {
+            let stdout = self.client.execute_command(program, args)?;
+            if echo {
+                print!("{}", stdout);
+            }
+            return Ok(Output {
+                status: std::process::ExitStatus::from_raw(0),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            });
+        }
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = self.client.execute_streaming(program, args, None, &CancelHandle::new(), |stream, data| {
+            match stream {
+                OutputStream::Stdout => {
+                    if echo {
+                        let _ = io::stdout().write_all(data);
+                        let _ = io::stdout().flush();
+                    }
+                    stdout.extend_from_slice(data);
+                }
+                OutputStream::Stderr => {
+                    if echo {
+                        let _ = io::stderr().write_all(data);
+                    }
+                    stderr.extend_from_slice(data);
+                }
+            }
+        })?;
+        Ok(Output {
+            status: status.to_std(),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl CommandExecutor for AgentExecutor {
+    fn execute_shell(&self, command: &str) -> Result<Output> {
+        self.run("sh", &["-c", command], false)
+    }
+
+    fn execute_interactive(&self, program: &str, args: &[&str]) -> Result<()> {
+        let output = self.run(program, args, true)?;
+        if !output.status.success() {
+            anyhow::bail!("Command failed: {} {:?}", program, args);
+        }
+        Ok(())
+    }
+
+    fn execute_shell_interactive(&self, command: &str) -> Result<()> {
+        let output = self.run("sh", &["-c", command], true)?;
+        if !output.status.success() {
+            anyhow::bail!("Shell command failed");
+        }
+        Ok(())
+    }
+
+    fn get_username(&self) -> Result<String> {
+        let output = self.client.execute_command("whoami", &[])?;
+        Ok(output.trim().to_string())
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<String>> {
+        if self.native_files()? {
+            // Like local listings: nothing to list is not an error
+            if self.kind_of(path)? != Some(FileKind::Directory) {
+                return Ok(Vec::new());
+            }
+            let entries = self.client.read_dir(path)?;
+            return Ok(entries.into_iter().map(|entry| entry.name).collect());
+        }
+        let output = self.client.execute_command("ls", &["-1", path])?;
+        Ok(output.lines().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+    }
+
+    fn read_file(&self, path: &str) -> Result<String> {
+        if self.native_files()? {
+            let content = self.client.read_file(path)?;
+            return String::from_utf8(content).with_context(|| format!("{} is not valid UTF-8", path));
+        }
+        self.client.execute_command("cat", &[path])
+    }
+
+    fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+        if self.native_files()? {
+            return self.client.write_file(path, content, WriteOptions::default());
+        }
+        use base64::Engine as _;
+        let base64_content = base64::engine::general_purpose::STANDARD.encode(content);
+        let cmd = format!(
+            "echo {} | base64 -d > {}",
+            halvor_core::utils::ssh::shell_escape(&base64_content),
+            halvor_core::utils::ssh::shell_escape(path)
+        );
+        self.client.execute_command("sh", &["-c", &cmd])?;
+        Ok(())
+    }
+
+    fn check_command_exists(&self, command: &str) -> Result<bool> {
+        let output = self.client.execute_command("which", &[command]).ok();
+        Ok(output.map(|s| !s.trim().is_empty()).unwrap_or(false))
+    }
+
+    fn is_linux(&self) -> Result<bool> {
+        let output = self.client.execute_command("uname", &["-s"])?;
+        Ok(output.trim() == "Linux")
+    }
+
+    fn mkdir_p(&self, path: &str) -> Result<()> {
+        if self.native_files()? {
+            return self.client.mkdir(path, None);
+        }
+        self.client.execute_command("mkdir", &["-p", path])?;
+        Ok(())
+    }
+
+    fn file_exists(&self, path: &str) -> Result<bool> {
+        if self.native_files()? {
+            return Ok(self.kind_of(path)? == Some(FileKind::File));
+        }
+        let output = self.client.execute_command("test", &["-f", path]).ok();
+        Ok(output.is_some())
+    }
+
+    fn is_directory(&self, path: &str) -> Result<bool> {
+        if self.native_files()? {
+            return Ok(self.kind_of(path)? == Some(FileKind::Directory));
+        }
+        let output = self.client.execute_command("test", &["-d", path]).ok();
+        Ok(output.is_some())
+    }
+
+    #[cfg(unix)]
+    fn get_uid(&self) -> Result<u32> {
+        let output = self.client.execute_command("id", &["-u"])?;
+        output.trim().parse().map_err(|e| anyhow::anyhow!("Failed to parse UID: {}", e))
+    }
+
+    #[cfg(unix)]
+    fn get_gid(&self) -> Result<u32> {
+        let output = self.client.execute_command("id", &["-g"])?;
+        output.trim().parse().map_err(|e| anyhow::anyhow!("Failed to parse GID: {}", e))
+    }
+
+    fn get_home_dir(&self) -> Result<String> {
+        let output = self.client.execute_command("echo", &["$HOME"])?;
+        Ok(output.trim().to_string())
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
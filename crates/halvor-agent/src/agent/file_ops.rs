@@ -0,0 +1,501 @@
+//! Native file operations for mesh peers
+//!
+//! `CommandExecutor` reads, writes and lists files on remote hosts. Shelling
+//! out through `ExecuteCommand` for that (`cat`, `echo … | base64 -d`) loses
+//! binary data and needs a policy rule for a shell. `AgentRequest::FileOperation`
+//! carries a signed [`FileOp`] instead, answered with a [`FileOpOutput`].
+//!
+//! File operations go through the exec policy like commands do, named
+//! `file:stat`, `file:read`, `file:write`, `file:mkdir` and `file:readdir`
+//! with the path as their argument (a `*` rule allows them too). The path is
+//! resolved first ([`FileOp::resolved`]), so a symlink can't lead a request
+//! out of the directories a rule allows. Writes that change a file's owner
+//! count as sudo and need the rule's `allow_sudo`.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Most bytes returned by one `Read`
+///
+/// Data is base64 encoded and then encrypted (base64 again), so it has to be
+/// well below the 16MB frame limit.
+pub const MAX_READ_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Most bytes accepted by one `Write`; larger files go through `halvor agent push`
+pub const MAX_WRITE_SIZE: usize = 4 * 1024 * 1024;
+
+/// A file operation requested by a mesh peer
+///
+/// Paths must be absolute; the agent's working directory means nothing to the peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileOp {
+    /// Metadata of a path, following symlinks
+    Stat { path: String },
+    /// Up to `length` bytes (at most [`MAX_READ_SIZE`]) from `offset`
+    Read { path: String, offset: u64, length: u64 },
+    /// Replace a file's contents atomically (base64 `data`)
+    Write {
+        path: String,
+        data: String,
+        #[serde(default)]
+        options: WriteOptions,
+    },
+    /// Create a directory and any missing parents
+    Mkdir { path: String, mode: Option<u32> },
+    /// List a directory
+    ReadDir { path: String },
+}
+
+/// Permissions for a written file
+///
+/// Unset fields keep the existing file's values (or the defaults for new files).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteOptions {
+    pub mode: Option<u32>,
+    /// Owner uid
+    pub owner: Option<u32>,
+    /// Group gid
+    pub group: Option<u32>,
+}
+
+/// Result of a [`FileOp`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileOpOutput {
+    /// `None` if nothing exists at the path
+    Stat(Option<FileStat>),
+    /// Bytes read (base64), and whether they reach the end of the file
+    Data { data: String, eof: bool },
+    Entries(Vec<DirEntry>),
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub kind: FileKind,
+    pub size: u64,
+    /// Permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Last modification, as a Unix timestamp
+    pub modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileKind,
+}
+
+impl FileOp {
+    /// A write of `content` to `path`
+    pub fn write(path: &str, content: &[u8], options: WriteOptions) -> Self {
+        FileOp::Write {
+            path: path.to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(content),
+            options,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            FileOp::Stat { path }
+            | FileOp::Read { path, .. }
+            | FileOp::Write { path, .. }
+            | FileOp::Mkdir { path, .. }
+            | FileOp::ReadDir { path } => path,
+        }
+    }
+
+    /// The same operation on the path with symlinks resolved (see [`resolve_path`])
+    pub fn resolved(&self) -> Result<FileOp> {
+        let resolved = resolve_path(self.path())?;
+        let resolved = resolved
+            .to_str()
+            .with_context(|| format!("'{}' is not valid UTF-8", resolved.display()))?
+            .to_string();
+        let mut op = self.clone();
+        match &mut op {
+            FileOp::Stat { path }
+            | FileOp::Read { path, .. }
+            | FileOp::Write { path, .. }
+            | FileOp::Mkdir { path, .. }
+            | FileOp::ReadDir { path } => *path = resolved,
+        }
+        Ok(op)
+    }
+
+    /// Name of the operation in policy rules
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileOp::Stat { .. } => "file:stat",
+            FileOp::Read { .. } => "file:read",
+            FileOp::Write { .. } => "file:write",
+            FileOp::Mkdir { .. } => "file:mkdir",
+            FileOp::ReadDir { .. } => "file:readdir",
+        }
+    }
+
+    /// The command and arguments the exec policy checks this operation as
+    pub fn policy_command(&self) -> (String, Vec<String>) {
+        let changes_owner = matches!(
+            self,
+            FileOp::Write { options, .. } if options.owner.is_some() || options.group.is_some()
+        );
+        if changes_owner {
+            ("sudo".to_string(), vec![self.name().to_string(), self.path().to_string()])
+        } else {
+            (self.name().to_string(), vec![self.path().to_string()])
+        }
+    }
+
+    /// Signing payload for a `FileOperation` request
+    pub fn payload(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self).context("Failed to serialize file operation for signing")
+    }
+
+    /// Carry out the operation on this host
+    pub fn apply(&self) -> Result<FileOpOutput> {
+        let path = Path::new(self.path());
+        if !path.is_absolute() {
+            anyhow::bail!("'{}' is not an absolute path", self.path());
+        }
+
+        match self {
+            FileOp::Stat { .. } => stat(path).map(FileOpOutput::Stat),
+            FileOp::Read { offset, length, .. } => read_range(path, *offset, *length),
+            FileOp::Write { data, options, .. } => {
+                let content = base64::engine::general_purpose::STANDARD
+                    .decode(data)
+                    .context("Invalid data in file write")?;
+                if content.len() > MAX_WRITE_SIZE {
+                    anyhow::bail!(
+                        "{} bytes is more than a single write accepts ({} bytes)",
+                        content.len(),
+                        MAX_WRITE_SIZE
+                    );
+                }
+                write_atomic(path, &content, options)?;
+                Ok(FileOpOutput::Done)
+            }
+            FileOp::Mkdir { mode, .. } => {
+                mkdir_all(path, *mode)?;
+                Ok(FileOpOutput::Done)
+            }
+            FileOp::ReadDir { .. } => read_dir(path).map(FileOpOutput::Entries),
+        }
+    }
+}
+
+impl FileOpOutput {
+    /// Decode the bytes of a `Data` output
+    pub fn decode(data: &str) -> Result<Vec<u8>> {
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .context("Invalid data in file read")
+    }
+}
+
+impl FileKind {
+    fn of(file_type: fs::FileType) -> Self {
+        if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_dir() {
+            FileKind::Directory
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        }
+    }
+}
+
+fn stat(path: &Path) -> Result<Option<FileStat>> {
+    // A dangling symlink still exists, as a symlink
+    let meta = match fs::metadata(path).or_else(|_| fs::symlink_metadata(path)) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {}", path.display())),
+    };
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|age| age.as_secs() as i64);
+
+    #[cfg(unix)]
+    let (mode, uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (meta.mode() & 0o7777, meta.uid(), meta.gid())
+    };
+    #[cfg(not(unix))]
+    let (mode, uid, gid) = (if meta.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0);
+
+    Ok(Some(FileStat {
+        kind: FileKind::of(meta.file_type()),
+        size: meta.len(),
+        mode,
+        uid,
+        gid,
+        modified,
+    }))
+}
+
+fn read_range(path: &Path, offset: u64, length: u64) -> Result<FileOpOutput> {
+    let mut file = File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::new();
+    file.take(length.min(MAX_READ_SIZE))
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(FileOpOutput::Data {
+        eof: offset + data.len() as u64 >= size,
+        data: base64::engine::general_purpose::STANDARD.encode(&data),
+    })
+}
+
+/// Write to a temporary file next to `path` and rename it into place, so
+/// readers never see a partly written file
+fn write_atomic(path: &Path, content: &[u8], options: &WriteOptions) -> Result<()> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        anyhow::bail!("'{}' does not name a file", path.display());
+    };
+    let temp = parent.join(format!(
+        ".{}.halvor-tmp-{}",
+        name.to_string_lossy(),
+        uuid::Uuid::new_v4()
+    ));
+
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .with_context(|| format!("Failed to create {}", temp.display()))?;
+        file.write_all(content)?;
+        set_permissions(&temp, path, options)?;
+        file.sync_all()?;
+        fs::rename(&temp, path).with_context(|| format!("Failed to move file into place at {}", path.display()))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Apply `options` to `temp`, falling back to the mode and owner of the file it replaces
+#[cfg(unix)]
+fn set_permissions(temp: &Path, existing: &Path, options: &WriteOptions) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let existing = fs::metadata(existing).ok();
+    if let Some(mode) = options.mode.or(existing.as_ref().map(|meta| meta.mode() & 0o7777)) {
+        fs::set_permissions(temp, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode {:o}", mode))?;
+    }
+    if options.owner.is_some() || options.group.is_some() {
+        std::os::unix::fs::chown(temp, options.owner, options.group)
+            .context("Failed to change owner (the agent must run as root)")?;
+    } else if let Some(meta) = existing {
+        // Best effort: only root can give a file to someone else
+        let _ = std::os::unix::fs::chown(temp, Some(meta.uid()), Some(meta.gid()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_permissions(_temp: &Path, _existing: &Path, options: &WriteOptions) -> Result<()> {
+    if options != &WriteOptions::default() {
+        anyhow::bail!("File modes and owners are only supported on Unix");
+    }
+    Ok(())
+}
+
+fn mkdir_all(path: &Path, mode: Option<u32>) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode);
+    }
+    #[cfg(not(unix))]
+    if mode.is_some() {
+        anyhow::bail!("Directory modes are only supported on Unix");
+    }
+    builder
+        .create(path)
+        .with_context(|| format!("Failed to create directory: {}", path.display()))
+}
+
+fn read_dir(path: &Path) -> Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path).with_context(|| format!("Failed to read directory: {}", path.display()))? {
+        let entry = entry?;
+        entries.push(DirEntry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            kind: FileKind::of(entry.file_type()?),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// `path` with symlinks resolved, so a rule sees where an operation really lands
+///
+/// Like `transfer::resolve_path`: `..` is refused, and the deepest part of
+/// the path that exists is canonicalized with the rest appended to it.
+pub fn resolve_path(path: &str) -> Result<PathBuf> {
+    let requested = Path::new(path);
+    if !requested.is_absolute() {
+        anyhow::bail!("'{}' is not an absolute path", path);
+    }
+    if requested
+        .components()
+        .any(|component| matches!(component, Component::ParentDir))
+    {
+        anyhow::bail!("'{}' must not contain '..'", path);
+    }
+
+    let mut existing = requested;
+    let mut missing = Vec::new();
+    while existing.symlink_metadata().is_err() {
+        let Some(parent) = existing.parent() else {
+            break;
+        };
+        missing.extend(existing.file_name());
+        existing = parent;
+    }
+    let mut resolved = existing
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", existing.display()))?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("halvor-file-ops-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn path(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_write_read_binary_ranges() {
+        let dir = scratch_dir("rw");
+        let file = path(&dir, "sub/data.bin");
+        let content: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        FileOp::Mkdir { path: path(&dir, "sub"), mode: None }.apply().unwrap();
+        let write = FileOp::write(&file, &content, WriteOptions::default());
+        assert_eq!(write.apply().unwrap(), FileOpOutput::Done);
+
+        let read = FileOp::Read { path: file.clone(), offset: 990, length: 100 };
+        let FileOpOutput::Data { data, eof } = read.apply().unwrap() else {
+            panic!("expected data");
+        };
+        assert!(eof);
+        assert_eq!(FileOpOutput::decode(&data).unwrap(), content[990..]);
+
+        let read = FileOp::Read { path: file.clone(), offset: 0, length: 10 };
+        let FileOpOutput::Data { data, eof } = read.apply().unwrap() else {
+            panic!("expected data");
+        };
+        assert!(!eof);
+        assert_eq!(FileOpOutput::decode(&data).unwrap(), content[..10]);
+
+        // No temporary files are left behind
+        let FileOpOutput::Entries(entries) = FileOp::ReadDir { path: path(&dir, "sub") }.apply().unwrap() else {
+            panic!("expected entries");
+        };
+        assert_eq!(
+            entries,
+            vec![DirEntry { name: "data.bin".to_string(), kind: FileKind::File }]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_keeps_or_sets_mode() {
+        let dir = scratch_dir("mode");
+        let file = path(&dir, "script.sh");
+        let stat = |file: &str| {
+            let op = FileOp::Stat { path: file.to_string() };
+            match op.apply().unwrap() {
+                FileOpOutput::Stat(stat) => stat,
+                other => panic!("expected stat, got {:?}", other),
+            }
+        };
+
+        assert_eq!(stat(&file), None);
+        let options = WriteOptions { mode: Some(0o750), ..Default::default() };
+        FileOp::write(&file, b"#!/bin/sh\n", options).apply().unwrap();
+        assert_eq!(stat(&file).unwrap().mode, 0o750);
+
+        FileOp::write(&file, b"#!/bin/sh\nexit 0\n", WriteOptions::default()).apply().unwrap();
+        let stat = stat(&file).unwrap();
+        assert_eq!((stat.kind, stat.mode, stat.size), (FileKind::File, 0o750, 17));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relative_paths_and_owner_changes() {
+        assert!(FileOp::Stat { path: "etc/passwd".to_string() }.apply().is_err());
+
+        let plain = FileOp::write("/etc/motd", b"hi", WriteOptions::default());
+        assert_eq!(plain.policy_command(), ("file:write".to_string(), vec!["/etc/motd".to_string()]));
+        let chown = FileOp::write("/etc/motd", b"hi", WriteOptions { owner: Some(0), ..Default::default() });
+        assert_eq!(
+            chown.policy_command(),
+            ("sudo".to_string(), vec!["file:write".to_string(), "/etc/motd".to_string()])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_paths_are_resolved_through_symlinks() {
+        let dir = scratch_dir("resolve");
+        let outside = dir.join("outside");
+        fs::create_dir_all(dir.join("allowed")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("allowed").join("link")).unwrap();
+
+        let op = FileOp::write(&path(&dir, "allowed/link/new/app.toml"), b"", WriteOptions::default());
+        let resolved = op.resolved().unwrap();
+        assert_eq!(
+            Path::new(resolved.path()),
+            outside.canonicalize().unwrap().join("new").join("app.toml")
+        );
+        assert_eq!(resolved.name(), "file:write");
+
+        assert!(resolve_path(&path(&dir, "allowed/../outside")).is_err());
+        assert!(resolve_path("etc/passwd").is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,496 @@
+//! Gossip broadcast for mesh messages
+//!
+//! A message addressed to [`BROADCAST`] is handed to a few of this node's
+//! direct peers. Every node that sees it for the first time handles it and
+//! relays it to its own peers with the TTL decremented, so events reach the
+//! whole mesh without any node needing every other node's address. Recently
+//! seen message IDs are kept in a bounded cache; a broadcast arriving again
+//! over another path is dropped.
+//!
+//! Each hop is encrypted with the secret the two peers share. `from` stays the
+//! node that originated the broadcast, vouched for by the relaying peer only,
+//! so events that change another node's entry carry that node's signature.
+
+use crate::agent::api::AgentClient;
+use crate::agent::mesh;
+use crate::agent::mesh_protocol::{BROADCAST, MeshMessage, MessagePayload, MessageRouter};
+use crate::agent::revocation;
+use crate::agent::tls::{self, NodeSignature};
+use anyhow::{Context, Result};
+use halvor_core::utils::hostname::normalize_hostname;
+use rand::seq::SliceRandom;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Relay hops a new broadcast is allowed
+pub const DEFAULT_TTL: u8 = 6;
+
+/// How many peers each node hands a broadcast to
+pub const DEFAULT_FANOUT: usize = 6;
+
+/// Message IDs remembered for deduplication
+pub const SEEN_CACHE_CAPACITY: usize = 4096;
+
+/// Connect and read timeout when handing a broadcast to a peer
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long after signing a membership event is accepted, so an old one
+/// can't be replayed once the seen cache has forgotten it
+const EVENT_MAX_AGE_SECS: i64 = 3600;
+
+/// Bounded set of recently seen message IDs; the oldest are forgotten first
+pub struct SeenCache {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Remember `message_id`, returning false if it was already seen
+    pub fn insert(&mut self, message_id: &str) -> bool {
+        if self.ids.contains(message_id) {
+            return false;
+        }
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        self.order.push_back(message_id.to_string());
+        self.ids.insert(message_id.to_string());
+        true
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.ids.contains(message_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Originates, deduplicates and relays broadcasts
+pub struct Gossip {
+    seen: Mutex<SeenCache>,
+    fanout: usize,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self::new(DEFAULT_FANOUT)
+    }
+}
+
+impl Gossip {
+    pub fn new(fanout: usize) -> Self {
+        Self {
+            seen: Mutex::new(SeenCache::new(SEEN_CACHE_CAPACITY)),
+            fanout: fanout.max(1),
+        }
+    }
+
+    /// Broadcast `payload` from this node, returning how many peers took it
+    pub fn broadcast(&self, payload: MessagePayload) -> usize {
+        let local_hostname = mesh::local_mesh_hostname();
+        let mut message = MeshMessage::new(local_hostname.clone(), BROADCAST.to_string(), payload);
+        message.ttl = DEFAULT_TTL;
+        self.mark_seen(&message.message_id);
+
+        forward(&message, &[local_hostname.as_str()], self.fanout)
+    }
+
+    /// Accept a broadcast relayed by `relayed_by`
+    ///
+    /// Returns false if it was seen before (or originated here) and must not be
+    /// handled again. New broadcasts with TTL left are relayed in the background.
+    pub fn accept(&self, message: &MeshMessage, relayed_by: &str) -> bool {
+        if !self.mark_seen(&message.message_id) {
+            return false;
+        }
+        let local_hostname = mesh::local_mesh_hostname();
+        if normalize_hostname(&message.from) == normalize_hostname(&local_hostname) {
+            return false;
+        }
+
+        if message.ttl > 0 {
+            let mut relayed = message.clone();
+            relayed.ttl -= 1;
+            let relayed_by = relayed_by.to_string();
+            let fanout = self.fanout;
+            std::thread::spawn(move || {
+                let origin = relayed.from.clone();
+                forward(
+                    &relayed,
+                    &[origin.as_str(), relayed_by.as_str(), local_hostname.as_str()],
+                    fanout,
+                );
+            });
+        }
+        true
+    }
+
+    fn mark_seen(&self, message_id: &str) -> bool {
+        match self.seen.lock() {
+            Ok(mut seen) => seen.insert(message_id),
+            // A poisoned cache only risks handling a broadcast twice
+            Err(poisoned) => poisoned.into_inner().insert(message_id),
+        }
+    }
+}
+
+/// Hand `message` to up to `fanout` peers that aren't in `exclude`, in parallel
+fn forward(message: &MeshMessage, exclude: &[&str], fanout: usize) -> usize {
+    let mut peers = match relay_targets(exclude) {
+        Ok(peers) => peers,
+        Err(e) => {
+            eprintln!("[MESH] Failed to load peers for broadcast: {}", e);
+            return 0;
+        }
+    };
+    peers.shuffle(&mut rand::rng());
+    peers.truncate(fanout);
+
+    std::thread::scope(|scope| {
+        let sends: Vec<_> = peers
+            .iter()
+            .map(|peer| scope.spawn(move || (peer, send_to(peer, message))))
+            .collect();
+        sends
+            .into_iter()
+            .filter_map(|send| send.join().ok())
+            .filter(|(peer, result)| match result {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("[MESH] Failed to relay {} to {}: {}", message.message_id, peer, e);
+                    false
+                }
+            })
+            .count()
+    })
+}
+
+/// Active peers this node shares a secret with, minus `exclude`
+fn relay_targets(exclude: &[&str]) -> Result<Vec<String>> {
+    let excluded: HashSet<String> = exclude.iter().map(|h| normalize_hostname(h)).collect();
+    let mut targets = Vec::new();
+    for peer in mesh::get_active_peers()? {
+        if excluded.contains(&normalize_hostname(&peer)) {
+            continue;
+        }
+        if mesh::get_peer_shared_secret(&peer)?.is_some() {
+            targets.push(peer);
+        }
+    }
+    Ok(targets)
+}
+
+/// Hand `message` to `peer`; a peer that refused a broadcast straight from
+/// its origin says so in an error reply
+fn send_to(peer: &str, message: &MeshMessage) -> Result<()> {
+    let reply = AgentClient::new(&mesh::peer_address(peer)?, mesh::DEFAULT_AGENT_PORT)
+        .with_connect_timeout(RELAY_TIMEOUT)
+        .with_read_timeout(RELAY_TIMEOUT)
+        .with_peer(peer)?
+        .send_mesh(message.clone())?;
+    if let Some(MeshMessage {
+        payload: MessagePayload::Error { message, .. },
+        ..
+    }) = reply
+    {
+        anyhow::bail!("refused: {}", message);
+    }
+    Ok(())
+}
+
+/// Register handlers that apply membership broadcasts to the local peer list
+pub fn register_membership_handlers(router: &mut MessageRouter) {
+    router.register("peer_joined".to_string(), |message: &MeshMessage| {
+        if let MessagePayload::PeerJoined {
+            hostname,
+            tailscale_ip,
+            tailscale_hostname,
+            public_key,
+        } = &message.payload
+            && !is_local(hostname)
+            && !revocation::is_revoked(hostname, Some(public_key))?
+            && mesh::record_peer(hostname, tailscale_ip.clone(), tailscale_hostname.clone(), public_key)?
+        {
+            println!("[MESH] {} joined the mesh (announced by {})", hostname, message.from);
+        }
+        Ok(None)
+    });
+
+    // Only a node can announce its own leave; removing anyone else takes a
+    // signed revocation (`peer_revoked`)
+    router.register("peer_left".to_string(), |message: &MeshMessage| {
+        let MessagePayload::PeerLeft { hostname, .. } = &message.payload else {
+            return Ok(None);
+        };
+        if is_local(hostname) {
+            return Ok(None);
+        }
+        if let Err(e) = verify_event(&message.payload) {
+            println!("[MESH] Ignoring leave of {} relayed from {}: {:#}", hostname, message.from, e);
+            return Ok(None);
+        }
+        if let Some(peer) = mesh::find_peer(hostname)? {
+            mesh::remove_peer(&peer)?;
+            println!("[MESH] {} left the mesh", peer);
+        }
+        Ok(None)
+    });
+
+    router.register("peer_revoked".to_string(), |message: &MeshMessage| {
+        let MessagePayload::PeerRevoked { revocation, wipe } = &message.payload else {
+            return Ok(None);
+        };
+        if !is_local(&revocation.hostname) {
+            if revocation::apply(revocation)? {
+                println!(
+                    "[MESH] {} was removed from the mesh by {}",
+                    revocation.hostname, revocation.revoked_by
+                );
+            }
+            return Ok(None);
+        }
+
+        // Only the revoking node, telling this node directly, can have it wipe itself
+        if !*wipe
+            || message.to == BROADCAST
+            || normalize_hostname(&message.from) != normalize_hostname(&revocation.revoked_by)
+        {
+            return Ok(None);
+        }
+        revocation.verify()?;
+        revocation::wipe_mesh_state()?;
+        println!("[MESH] Removed from the mesh by {}; mesh state wiped", revocation.revoked_by);
+        Ok(Some(MeshMessage::ack(
+            message.reply_from(),
+            message.from.clone(),
+            message.message_id.clone(),
+        )))
+    });
+
+    // Only a node can rename itself, and not to a name another node holds.
+    // Refusals are errors, so the renaming node hears about them.
+    router.register("peer_renamed".to_string(), |message: &MeshMessage| {
+        let MessagePayload::PeerRenamed {
+            old_hostname,
+            new_hostname,
+            ..
+        } = &message.payload
+        else {
+            return Ok(None);
+        };
+        verify_event(&message.payload)
+            .with_context(|| format!("Refusing rename of {} to {}", old_hostname, new_hostname))?;
+        if is_local(new_hostname) || mesh::find_peer(new_hostname)?.is_some() {
+            anyhow::bail!("Refusing rename of {} to {}: the name is taken", old_hostname, new_hostname);
+        }
+        if mesh::rename_peer(old_hostname, new_hostname)? {
+            println!("[MESH] {} is now called {}", old_hostname, new_hostname);
+        }
+        Ok(None)
+    });
+}
+
+/// `PeerLeft` for this node, signed with its node key
+pub fn peer_left() -> Result<MessagePayload> {
+    let hostname = mesh::local_mesh_hostname();
+    let signed_at = chrono::Utc::now().timestamp();
+    let signature = tls::sign_with_node_key(&peer_left_bytes(&hostname, signed_at))?
+        .context("Can't announce leaving without a mesh certificate to sign it")?;
+    Ok(MessagePayload::PeerLeft {
+        hostname,
+        signed_at,
+        signature: Some(signature),
+    })
+}
+
+fn peer_left_bytes(hostname: &str, signed_at: i64) -> Vec<u8> {
+    format!("halvor peer left\n{}\n{}", normalize_hostname(hostname), signed_at).into_bytes()
+}
+
+/// `PeerRenamed` from `old_hostname` to `new_hostname`, signed with this
+/// node's key while its certificate is still the one for `old_hostname`
+pub fn peer_renamed(old_hostname: &str, new_hostname: &str) -> Result<MessagePayload> {
+    let signed_at = chrono::Utc::now().timestamp();
+    let signature = tls::sign_with_node_key(&peer_renamed_bytes(old_hostname, new_hostname, signed_at))?
+        .context("Can't announce a rename without a mesh certificate to sign it")?;
+    Ok(MessagePayload::PeerRenamed {
+        old_hostname: old_hostname.to_string(),
+        new_hostname: new_hostname.to_string(),
+        signed_at,
+        signature: Some(signature),
+    })
+}
+
+fn peer_renamed_bytes(old_hostname: &str, new_hostname: &str, signed_at: i64) -> Vec<u8> {
+    format!(
+        "halvor peer renamed\n{}\n{}\n{}",
+        normalize_hostname(old_hostname),
+        normalize_hostname(new_hostname),
+        signed_at
+    )
+    .into_bytes()
+}
+
+/// The node that must have signed a membership event, what it signed, when,
+/// and its signature
+fn signed_event(payload: &MessagePayload) -> Option<(&str, Vec<u8>, i64, Option<&NodeSignature>)> {
+    match payload {
+        MessagePayload::PeerLeft {
+            hostname,
+            signed_at,
+            signature,
+        } => Some((hostname, peer_left_bytes(hostname, *signed_at), *signed_at, signature.as_ref())),
+        MessagePayload::PeerRenamed {
+            old_hostname,
+            new_hostname,
+            signed_at,
+            signature,
+        } => Some((
+            old_hostname,
+            peer_renamed_bytes(old_hostname, new_hostname, *signed_at),
+            *signed_at,
+            signature.as_ref(),
+        )),
+        _ => None,
+    }
+}
+
+/// Check that a membership event is recent and signed by the node it is about
+fn verify_event(payload: &MessagePayload) -> Result<()> {
+    let (signer, message, signed_at, signature) =
+        signed_event(payload).context("not a signed membership event")?;
+    let signature = check_event(signed_at, chrono::Utc::now().timestamp(), signature)?;
+    tls::verify_node_signature(signer, &message, signature)
+}
+
+fn check_event(signed_at: i64, now: i64, signature: Option<&NodeSignature>) -> Result<&NodeSignature> {
+    let signature = signature.context("not signed")?;
+    if (now - signed_at).abs() > EVENT_MAX_AGE_SECS {
+        anyhow::bail!("signed too long ago");
+    }
+    Ok(signature)
+}
+
+fn is_local(hostname: &str) -> bool {
+    normalize_hostname(hostname) == normalize_hostname(&mesh::local_mesh_hostname())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_cache_deduplicates() {
+        let mut cache = SeenCache::new(8);
+        assert!(cache.insert("a"));
+        assert!(!cache.insert("a"));
+        assert!(cache.insert("b"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_seen_cache_is_bounded() {
+        let mut cache = SeenCache::new(3);
+        for id in ["a", "b", "c", "d"] {
+            assert!(cache.insert(id));
+        }
+        assert_eq!(cache.len(), 3);
+        // The oldest ID was forgotten, the rest are still deduplicated
+        assert!(!cache.contains("a"));
+        assert!(!cache.insert("d"));
+        assert!(cache.insert("a"));
+        assert!(!cache.contains("b"));
+    }
+
+    #[test]
+    fn test_ttl_survives_serialization() {
+        let mut message = MeshMessage::new(
+            "frigg".to_string(),
+            BROADCAST.to_string(),
+            MessagePayload::PeerLeft {
+                hostname: "baulder".to_string(),
+                signed_at: 0,
+                signature: None,
+            },
+        );
+        message.ttl = DEFAULT_TTL;
+        let received = MeshMessage::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        assert_eq!(received.ttl, DEFAULT_TTL);
+
+        // Messages from agents without gossip support are never relayed
+        let mut legacy: serde_json::Value = serde_json::from_slice(&message.to_bytes().unwrap()).unwrap();
+        legacy.as_object_mut().unwrap().remove("ttl");
+        let legacy: MeshMessage = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.ttl, 0);
+    }
+
+    #[test]
+    fn test_forged_peer_left_is_rejected() {
+        let ca = tls::testing::TestCa::new();
+        let now = chrono::Utc::now().timestamp();
+        let left = |signature: Option<NodeSignature>, signed_at: i64| MessagePayload::PeerLeft {
+            hostname: "heimdall".to_string(),
+            signed_at,
+            signature,
+        };
+        let verify = |payload: &MessagePayload| -> Result<()> {
+            let (signer, message, signed_at, signature) = signed_event(payload).unwrap();
+            ca.verify(signer, &message, check_event(signed_at, now, signature)?)
+        };
+
+        // heimdall announcing its own leave
+        let genuine = ca.sign_as("heimdall", &peer_left_bytes("heimdall", now));
+        verify(&left(Some(genuine.clone()), now)).unwrap();
+
+        // A third peer relaying a leave of heimdall, unsigned or signed with its own key
+        assert!(verify(&left(None, now)).is_err());
+        let forged = ca.sign_as("loki", &peer_left_bytes("heimdall", now));
+        assert!(verify(&left(Some(forged), now)).is_err());
+
+        // Or replaying heimdall's signature under another time
+        assert!(verify(&left(Some(genuine.clone()), now - 60)).is_err());
+        let stale = ca.sign_as("heimdall", &peer_left_bytes("heimdall", now - 2 * EVENT_MAX_AGE_SECS));
+        assert!(verify(&left(Some(stale), now - 2 * EVENT_MAX_AGE_SECS)).is_err());
+    }
+
+    #[test]
+    fn test_forged_peer_renamed_is_rejected() {
+        let ca = tls::testing::TestCa::new();
+        let now = chrono::Utc::now().timestamp();
+        let renamed = |signature: NodeSignature| MessagePayload::PeerRenamed {
+            old_hostname: "heimdall".to_string(),
+            new_hostname: "odin".to_string(),
+            signed_at: now,
+            signature: Some(signature),
+        };
+        let verify = |payload: &MessagePayload| -> Result<()> {
+            let (signer, message, signed_at, signature) = signed_event(payload).unwrap();
+            ca.verify(signer, &message, check_event(signed_at, now, signature)?)
+        };
+
+        verify(&renamed(ca.sign_as("heimdall", &peer_renamed_bytes("heimdall", "odin", now)))).unwrap();
+        // Neither a third peer nor the new name can rename heimdall
+        assert!(verify(&renamed(ca.sign_as("loki", &peer_renamed_bytes("heimdall", "odin", now)))).is_err());
+        assert!(verify(&renamed(ca.sign_as("odin", &peer_renamed_bytes("heimdall", "odin", now)))).is_err());
+        // heimdall's signature of another rename doesn't carry over
+        assert!(verify(&renamed(ca.sign_as("heimdall", &peer_renamed_bytes("heimdall", "loki", now)))).is_err());
+    }
+}
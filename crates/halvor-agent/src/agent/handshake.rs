@@ -0,0 +1,124 @@
+//! Version and capability handshake
+//!
+//! Before using a feature that not every release supports, a client asks the
+//! agent for its `Hello`: the wire protocol version, the halvor version it
+//! runs and the capabilities it has. Operations the agent can't handle are
+//! refused up front with a message naming both versions, instead of failing
+//! with a parse error half way through.
+//!
+//! Agents from before the handshake don't answer `Hello`; they're treated as
+//! protocol 0 with no optional capabilities.
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the agent request/response protocol
+///
+/// Bumped when requests change shape in a way older agents can't parse.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features an agent can have, as advertised in `Hello`
+pub mod capability {
+    /// Chunked file push/pull over mesh messages
+    pub const FILE_TRANSFER: &str = "file_transfer";
+    /// Gossip broadcasts of membership events
+    pub const MESH_GOSSIP: &str = "mesh_gossip";
+    /// Replicated mesh config (`ConfigUpdate`, `SyncConfig`)
+    pub const MESH_CONFIG: &str = "mesh_config";
+    /// Database sync that only sends what changed since a cursor
+    pub const INCREMENTAL_SYNC: &str = "incremental_sync";
+    /// Command execution with streamed output, stdin and exit codes
+    pub const EXEC_STREAM: &str = "exec_stream";
+    /// Native file stat, read, write, mkdir and readdir (`FileOperation`)
+    pub const FILE_OPS: &str = "file_ops";
+
+    /// Everything this build supports
+    pub const ALL: &[&str] = &[FILE_TRANSFER, MESH_GOSSIP, MESH_CONFIG, INCREMENTAL_SYNC, EXEC_STREAM, FILE_OPS];
+}
+
+/// What an agent says about itself at the start of a conversation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// halvor version the agent runs
+    pub version: String,
+    pub hostname: String,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// This build's hello
+    pub fn local(hostname: &str) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname: hostname.to_string(),
+            capabilities: capability::ALL.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Stand-in for an agent that predates the handshake
+    pub fn legacy(hostname: &str) -> Self {
+        Self {
+            protocol_version: 0,
+            version: "unknown".to_string(),
+            hostname: hostname.to_string(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Fail with an explanation if the agent lacks `capability`
+    pub fn require(&self, capability: &str) -> anyhow::Result<()> {
+        if self.supports(capability) {
+            return Ok(());
+        }
+        let agent = if self.protocol_version == 0 {
+            "an older halvor that predates version negotiation".to_string()
+        } else {
+            format!("halvor {} (protocol {})", self.version, self.protocol_version)
+        };
+        anyhow::bail!(
+            "Agent {} doesn't support {}: it runs {}, this host runs halvor {} (protocol {}). Run `halvor update` on {}.",
+            self.hostname,
+            capability,
+            agent,
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION,
+            self.hostname
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require_names_both_versions() {
+        let local = Hello::local("frigg");
+        assert!(local.require(capability::FILE_TRANSFER).is_ok());
+
+        let legacy = Hello::legacy("baulder");
+        let error = legacy.require(capability::FILE_TRANSFER).unwrap_err().to_string();
+        assert!(error.contains("file_transfer"), "{}", error);
+        assert!(error.contains("predates version negotiation"), "{}", error);
+
+        let mut older = Hello::local("odin");
+        older.version = "0.0.1".to_string();
+        older.capabilities.retain(|c| c != capability::MESH_CONFIG);
+        let error = older.require(capability::MESH_CONFIG).unwrap_err().to_string();
+        assert!(error.contains("halvor 0.0.1 (protocol 1)"), "{}", error);
+    }
+
+    #[test]
+    fn test_unknown_capabilities_are_kept() {
+        // A newer agent may list capabilities this build has never heard of
+        let json = r#"{"protocol_version":2,"version":"9.9.9","hostname":"loki","capabilities":["file_transfer","teleport"]}"#;
+        let hello: Hello = serde_json::from_str(json).unwrap();
+        assert!(hello.supports("teleport"));
+        assert!(hello.supports(capability::FILE_TRANSFER));
+    }
+}
@@ -0,0 +1,193 @@
+//! Heartbeats between mesh peers
+//!
+//! The agent pings every peer it shares a secret with on an interval. A peer
+//! that answers (or sends us anything encrypted) is marked active and its
+//! `last_seen_at` updated; one that stays silent becomes suspect and then
+//! inactive. Peers announced by gossip that we haven't paired with yet can't be
+//! pinged and are left alone.
+
+use crate::agent::api::AgentClient;
+use crate::agent::mesh::{self, MeshPeer, PeerStatus};
+use crate::agent::mesh_protocol::{MeshMessage, MessagePayload};
+use anyhow::Result;
+use halvor_core::utils::hostname::normalize_hostname;
+use std::time::Duration;
+
+/// How often peers are pinged
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Silence after which a peer becomes suspect
+pub const DEFAULT_SUSPECT_AFTER: Duration = Duration::from_secs(90);
+
+/// Silence after which a peer becomes inactive
+pub const DEFAULT_INACTIVE_AFTER: Duration = Duration::from_secs(300);
+
+/// Longest a single ping may take
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Heartbeat interval and liveness thresholds
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub suspect_after: Duration,
+    pub inactive_after: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_HEARTBEAT_INTERVAL,
+            suspect_after: DEFAULT_SUSPECT_AFTER,
+            inactive_after: DEFAULT_INACTIVE_AFTER,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn new(interval: Duration, suspect_after: Duration, inactive_after: Duration) -> Result<Self> {
+        anyhow::ensure!(!interval.is_zero(), "Heartbeat interval must be greater than zero");
+        anyhow::ensure!(
+            suspect_after < inactive_after,
+            "Peers must become suspect ({}s) before they become inactive ({}s)",
+            suspect_after.as_secs(),
+            inactive_after.as_secs()
+        );
+        Ok(Self {
+            interval,
+            suspect_after,
+            inactive_after,
+        })
+    }
+
+    /// Status of a peer in `current` state after a heartbeat
+    ///
+    /// `silent_for` is the time since the peer was last heard from.
+    pub fn next_status(&self, current: PeerStatus, answered: bool, silent_for: Duration) -> PeerStatus {
+        if answered {
+            PeerStatus::Active
+        } else if silent_for >= self.inactive_after || current == PeerStatus::Inactive {
+            PeerStatus::Inactive
+        } else if silent_for >= self.suspect_after {
+            PeerStatus::Suspect
+        } else {
+            current
+        }
+    }
+}
+
+/// Ping peers every `config.interval`, forever
+pub async fn run(config: HeartbeatConfig) {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let round = config.clone();
+        match tokio::task::spawn_blocking(move || beat(&round)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("[MESH] Heartbeat failed: {}", e),
+            Err(e) => eprintln!("[MESH] Heartbeat task failed: {}", e),
+        }
+    }
+}
+
+/// Ping every paired peer once, in parallel, and update their status
+pub fn beat(config: &HeartbeatConfig) -> Result<()> {
+    let local_hostname = mesh::local_mesh_hostname();
+    let mut peers: Vec<MeshPeer> = Vec::new();
+    for peer in mesh::list_peers()? {
+        if normalize_hostname(&peer.hostname) != normalize_hostname(&local_hostname)
+            && mesh::get_peer_shared_secret(&peer.hostname)?.is_some()
+        {
+            peers.push(peer);
+        }
+    }
+
+    let timeout = config.interval.min(PING_TIMEOUT);
+    let answers: Vec<bool> = std::thread::scope(|scope| {
+        let pings: Vec<_> = peers
+            .iter()
+            .map(|peer| scope.spawn(|| ping(&local_hostname, &peer.hostname, timeout)))
+            .collect();
+        pings.into_iter().map(|ping| ping.join().unwrap_or(false)).collect()
+    });
+
+    let now = chrono::Utc::now().timestamp();
+    for (peer, answered) in peers.iter().zip(answers) {
+        if answered {
+            if let Some(previous) = mesh::mark_peer_seen(&peer.hostname)? {
+                println!("[MESH] {} is active again (was {})", peer.hostname, previous);
+            }
+            continue;
+        }
+
+        let last_heard = peer.last_seen_at.unwrap_or(peer.joined_at);
+        let silent_for = Duration::from_secs(now.saturating_sub(last_heard).max(0) as u64);
+        let status = config.next_status(peer.status, false, silent_for);
+        if status != peer.status {
+            mesh::set_peer_status(&peer.hostname, status)?;
+            println!(
+                "[MESH] {} is now {} (not heard from for {}s)",
+                peer.hostname,
+                status,
+                silent_for.as_secs()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `peer` answered an encrypted mesh ping
+fn ping(local_hostname: &str, peer: &str, timeout: Duration) -> bool {
+    let reply = mesh::peer_address(peer).and_then(|address| {
+        AgentClient::new(&address, mesh::DEFAULT_AGENT_PORT)
+            .with_connect_timeout(timeout)
+            .with_read_timeout(timeout)
+            .with_peer(peer)?
+            .send_mesh(MeshMessage::ping(local_hostname.to_string(), peer.to_string()))
+    });
+    matches!(
+        reply,
+        Ok(Some(MeshMessage {
+            payload: MessagePayload::Pong,
+            ..
+        }))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HeartbeatConfig {
+        HeartbeatConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(30),
+            Duration::from_secs(120),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_status_transitions() {
+        let config = config();
+        let secs = Duration::from_secs;
+
+        assert_eq!(config.next_status(PeerStatus::Active, false, secs(20)), PeerStatus::Active);
+        assert_eq!(config.next_status(PeerStatus::Active, false, secs(30)), PeerStatus::Suspect);
+        assert_eq!(config.next_status(PeerStatus::Suspect, false, secs(60)), PeerStatus::Suspect);
+        assert_eq!(config.next_status(PeerStatus::Suspect, false, secs(120)), PeerStatus::Inactive);
+        // A peer that was down stays down until it answers
+        assert_eq!(config.next_status(PeerStatus::Inactive, false, secs(5)), PeerStatus::Inactive);
+        assert_eq!(config.next_status(PeerStatus::Inactive, true, secs(600)), PeerStatus::Active);
+        assert_eq!(config.next_status(PeerStatus::Suspect, true, secs(60)), PeerStatus::Active);
+    }
+
+    #[test]
+    fn test_thresholds_must_be_ordered() {
+        let secs = Duration::from_secs;
+        assert!(HeartbeatConfig::new(secs(10), secs(120), secs(30)).is_err());
+        assert!(HeartbeatConfig::new(secs(10), secs(60), secs(60)).is_err());
+        assert!(HeartbeatConfig::new(Duration::ZERO, secs(30), secs(60)).is_err());
+    }
+}
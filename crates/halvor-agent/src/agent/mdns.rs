@@ -0,0 +1,214 @@
+//! mDNS / DNS-SD advertisement of halvor agents
+//!
+//! Each agent advertises a `_halvor._tcp.local` service named after its mesh
+//! hostname, with TXT records for the hostname, halvor version, agent port and
+//! the fingerprint of its node public key (once it has one). On LANs without
+//! Tailscale this lets agents find each other without scanning subnets.
+//!
+//! Loopback is left out by default, like any other mDNS responder; tests and
+//! single-machine setups can run on loopback only instead.
+
+use crate::agent::{mesh, tls};
+use anyhow::{Context, Result};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// DNS-SD service type agents advertise
+pub const SERVICE_TYPE: &str = "_halvor._tcp.local.";
+
+/// How long to listen for advertisements by default
+pub const DEFAULT_BROWSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What an agent advertises about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentAdvertisement {
+    pub hostname: String,
+    pub version: String,
+    pub port: u16,
+    /// SHA-256 fingerprint of the agent's node public key
+    pub fingerprint: Option<String>,
+}
+
+impl AgentAdvertisement {
+    /// This agent's advertisement
+    pub fn local(port: u16) -> Result<Self> {
+        Ok(Self {
+            hostname: mesh::local_mesh_hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            port,
+            fingerprint: tls::node_key_fingerprint()?,
+        })
+    }
+
+    fn txt_properties(&self) -> Vec<(&'static str, String)> {
+        let mut properties = vec![
+            ("hostname", self.hostname.clone()),
+            ("version", self.version.clone()),
+            ("port", self.port.to_string()),
+        ];
+        if let Some(fingerprint) = &self.fingerprint {
+            properties.push(("fingerprint", fingerprint.clone()));
+        }
+        properties
+    }
+
+    /// Read an advertisement back from a resolved service
+    fn from_service(info: &ServiceInfo) -> Self {
+        let instance = info
+            .get_fullname()
+            .strip_suffix(SERVICE_TYPE)
+            .unwrap_or(info.get_fullname())
+            .trim_end_matches('.');
+        Self {
+            hostname: info
+                .get_property_val_str("hostname")
+                .unwrap_or(instance)
+                .to_string(),
+            version: info.get_property_val_str("version").unwrap_or_default().to_string(),
+            port: info
+                .get_property_val_str("port")
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(info.get_port()),
+            fingerprint: info.get_property_val_str("fingerprint").map(str::to_string),
+        }
+    }
+}
+
+/// An agent found by browsing
+#[derive(Debug, Clone)]
+pub struct FoundAgent {
+    pub advertisement: AgentAdvertisement,
+    pub addresses: Vec<Ipv4Addr>,
+}
+
+/// Advertises this agent until dropped
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvertiser {
+    /// Start advertising on the network interfaces (or only on loopback)
+    pub fn start(advertisement: &AgentAdvertisement, loopback_only: bool) -> Result<Self> {
+        let daemon = new_daemon(loopback_only)?;
+        let host_name = format!("{}.local.", advertisement.hostname);
+        let properties = advertisement.txt_properties();
+        let properties: Vec<(&str, &str)> = properties
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect();
+
+        let service = if loopback_only {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &advertisement.hostname,
+                &host_name,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                advertisement.port,
+                &properties[..],
+            )
+        } else {
+            // Addresses follow the interfaces as they come and go
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &advertisement.hostname,
+                &host_name,
+                "",
+                advertisement.port,
+                &properties[..],
+            )
+            .map(ServiceInfo::enable_addr_auto)
+        }
+        .context("Invalid mDNS service")?;
+
+        let fullname = service.get_fullname().to_string();
+        daemon
+            .register(service)
+            .context("Failed to register mDNS service")?;
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for MdnsAdvertiser {
+    fn drop(&mut self) {
+        // Say goodbye so browsers drop us right away rather than when the TTL runs out
+        if let Ok(done) = self.daemon.unregister(&self.fullname) {
+            let _ = done.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Listen for agent advertisements for `timeout`
+pub fn browse(timeout: Duration, loopback_only: bool) -> Result<Vec<FoundAgent>> {
+    let daemon = new_daemon(loopback_only)?;
+    let events = daemon
+        .browse(SERVICE_TYPE)
+        .context("Failed to browse for mDNS services")?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found: HashMap<String, FoundAgent> = HashMap::new();
+    while let Ok(event) = events.recv_deadline(deadline) {
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                let mut addresses: Vec<Ipv4Addr> =
+                    info.get_addresses_v4().into_iter().copied().collect();
+                addresses.sort();
+                found.insert(
+                    info.get_fullname().to_string(),
+                    FoundAgent {
+                        advertisement: AgentAdvertisement::from_service(&info),
+                        addresses,
+                    },
+                );
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                found.remove(&fullname);
+            }
+            _ => {}
+        }
+    }
+    let _ = daemon.shutdown();
+
+    let mut found: Vec<FoundAgent> = found.into_values().collect();
+    found.sort_by(|a, b| a.advertisement.hostname.cmp(&b.advertisement.hostname));
+    Ok(found)
+}
+
+fn new_daemon(loopback_only: bool) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new().context("Failed to start mDNS daemon")?;
+    if loopback_only {
+        daemon.disable_interface(IfKind::All)?;
+        daemon.enable_interface(IfKind::LoopbackV4)?;
+    } else {
+        // Agents are reached over IPv4 (see `HostDiscovery`)
+        daemon.disable_interface(IfKind::IPv6)?;
+    }
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advertise_and_browse_on_loopback() {
+        let advertisement = AgentAdvertisement {
+            hostname: "mdns-test-frigg".to_string(),
+            version: "1.2.3".to_string(),
+            port: 13599,
+            fingerprint: Some("ab".repeat(32)),
+        };
+        let _advertiser = MdnsAdvertiser::start(&advertisement, true).unwrap();
+
+        let found = browse(Duration::from_secs(3), true).unwrap();
+        let agent = found
+            .iter()
+            .find(|agent| agent.advertisement.hostname == advertisement.hostname)
+            .expect("advertised agent was not found");
+        assert_eq!(agent.advertisement, advertisement);
+        assert_eq!(agent.addresses, vec![Ipv4Addr::LOCALHOST]);
+    }
+}
@@ -1,17 +1,80 @@
 //! Agent mesh security - join tokens and peer key management
 
 use halvor_db as db;
-use halvor_db::generated::{agent_peers, join_tokens, peer_keys};
-use halvor_db::generated::{AgentPeersRowData, JoinTokensRowData, PeerKeysRowData};
+use halvor_db::generated::{AgentPeersRow, AgentPeersRowData, JoinTokensRow, JoinTokensRowData};
+use halvor_db::generated::{PeerKeysRow, PeerKeysRowData};
+use crate::agent::revocation::{self, PeerRevocation};
+use crate::agent::tls;
 use halvor_core::utils::crypto;
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Default lifetime of a join token
 pub const TOKEN_EXPIRY_HOURS: i64 = 24;
 
+/// Port agents listen on unless started with `--port`
+pub const DEFAULT_AGENT_PORT: u16 = 13500;
+
+/// File in the config directory holding the name set by `halvor agent hostname`
+const MESH_HOSTNAME_FILE: &str = "mesh-hostname";
+
+/// Liveness of a mesh peer, as tracked by heartbeats
+///
+/// Suspect and inactive peers are still members of the mesh: they keep their
+/// shared secret and certificates and become active again as soon as they answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerStatus {
+    /// Answered recently
+    Active,
+    /// Missed heartbeats, may just be restarting
+    Suspect,
+    /// Silent for long enough to be considered down
+    Inactive,
+}
+
+impl PeerStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeerStatus::Active => "active",
+            PeerStatus::Suspect => "suspect",
+            PeerStatus::Inactive => "inactive",
+        }
+    }
+
+    /// Parse a stored status; unknown values are treated as active
+    pub fn parse(status: &str) -> Self {
+        match status {
+            "suspect" => PeerStatus::Suspect,
+            "inactive" => PeerStatus::Inactive,
+            _ => PeerStatus::Active,
+        }
+    }
+}
+
+impl std::fmt::Display for PeerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// A mesh peer as shown by `halvor agent peers` and the web API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshPeer {
+    pub hostname: String,
+    pub tailscale_ip: Option<String>,
+    pub tailscale_hostname: Option<String>,
+    pub status: PeerStatus,
+    pub last_seen_at: Option<i64>,
+    pub joined_at: i64,
+}
+
 /// Join token structure (encoded in base64)
+///
+/// Only the issuer accepts a token, against the hash of it stored when it was
+/// issued, which also counts its uses.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JoinToken {
     pub token_id: String,
@@ -21,6 +84,20 @@ pub struct JoinToken {
     pub expires_at: i64,
     /// Encrypted shared secret for initial handshake
     pub handshake_key: String,
+    /// Fingerprint of the CA that signed the issuer's TLS certificate, so the
+    /// joiner can check it is talking to the issuer (absent in older tokens)
+    #[serde(default)]
+    pub ca_fingerprint: Option<String>,
+    /// How many nodes may join with the token, 0 for no limit (older tokens are single-use)
+    #[serde(default = "single_use")]
+    pub max_uses: u32,
+    /// Hostnames allowed to join with the token; any host if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_hostnames: Vec<String>,
+}
+
+fn single_use() -> u32 {
+    1
 }
 
 impl JoinToken {
@@ -45,21 +122,70 @@ impl JoinToken {
         let now = chrono::Utc::now().timestamp();
         now > self.expires_at
     }
+
+    /// Whether `hostname` may join with this token
+    pub fn allows(&self, hostname: &str) -> bool {
+        use halvor_core::utils::hostname::normalize_hostname;
+
+        self.allowed_hostnames.is_empty()
+            || self
+                .allowed_hostnames
+                .iter()
+                .any(|allowed| normalize_hostname(allowed) == normalize_hostname(hostname))
+    }
+}
+
+/// Lifetime and scope of a new join token
+#[derive(Debug, Clone)]
+pub struct TokenOptions {
+    pub ttl: std::time::Duration,
+    /// How many nodes may join with the token, 0 for no limit
+    pub max_uses: u32,
+    /// Hostnames allowed to join; any host if empty
+    pub allowed_hostnames: Vec<String>,
+}
+
+impl Default for TokenOptions {
+    fn default() -> Self {
+        Self {
+            ttl: std::time::Duration::from_secs(TOKEN_EXPIRY_HOURS as u64 * 3600),
+            max_uses: 1,
+            allowed_hostnames: Vec::new(),
+        }
+    }
+}
+
+/// SHA-256 of an encoded join token, as lowercase hex; tokens are stored by this
+pub fn hash_token(encoded_token: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(encoded_token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
 }
 
 /// Generate a join token for a new agent to join the mesh
+///
+/// The issuer becomes a mesh CA (if it isn't one already) so it can sign a
+/// certificate for the joiner.
 pub fn generate_join_token(
     issuer_hostname: &str,
     issuer_ip: &str,
     issuer_port: u16,
+    options: &TokenOptions,
 ) -> Result<(String, JoinToken)> {
     let token_id = Uuid::new_v4().to_string();
-    let expires_at = chrono::Utc::now().timestamp() + (TOKEN_EXPIRY_HOURS * 3600);
+    let expires_at = chrono::Utc::now().timestamp() + options.ttl.as_secs() as i64;
 
     // Generate a random handshake key (32 bytes for AES-256)
     let handshake_key = crypto::generate_random_key()?;
     let handshake_key_b64 = general_purpose::STANDARD.encode(&handshake_key);
 
+    let ca = tls::MeshCa::load_or_create(issuer_hostname)?;
+    tls::ensure_node_certificate(&ca, issuer_hostname)?;
+    let ca_fingerprint = tls::node_certificate()?.map(|info| info.ca_fingerprint);
+
     let token = JoinToken {
         token_id: token_id.clone(),
         issuer_hostname: issuer_hostname.to_string(),
@@ -67,96 +193,138 @@ pub fn generate_join_token(
         issuer_port,
         expires_at,
         handshake_key: handshake_key_b64,
+        ca_fingerprint,
+        max_uses: options.max_uses,
+        allowed_hostnames: options.allowed_hostnames.clone(),
     };
 
     let encoded = token.encode()?;
 
-    eprintln!("[DEBUG] Generating token - token_id: {}", token_id);
-    eprintln!("[DEBUG] Database path: {:?}", db::get_db_path()?);
-
-    // Store token in database
+    // Store only the token's hash
     let data = JoinTokensRowData {
-        token: encoded.clone(),
+        token_id,
+        token_hash: hash_token(&encoded),
         issuer_hostname: issuer_hostname.to_string(),
         expires_at,
-        used: 0,
+        max_uses: options.max_uses as i64,
+        uses: 0,
+        allowed_hostnames: join_hostnames(&options.allowed_hostnames),
         used_by_hostname: None,
         used_at: None,
+        revoked_at: None,
     };
-
-    let result = join_tokens::insert_one(data)?;
-    eprintln!("[DEBUG] Token inserted into database with ID: {}", result);
-
-    // Verify it was stored
-    let verify = join_tokens::select_many(
-        "token = ?1",
-        &[&encoded as &dyn rusqlite::types::ToSql],
-    )?;
-    eprintln!("[DEBUG] Verification: Found {} tokens matching this token immediately after insert", verify.len());
+    JoinTokensRow::insert_one(data)?;
 
     Ok((encoded, token))
 }
 
-/// Validate a join token
-pub fn validate_join_token(encoded_token: &str) -> Result<JoinToken> {
+/// Validate a join token without using it up
+///
+/// Only the issuer can vouch for a token: its use count lives in the issuer's
+/// database, so a token presented to any other node is refused.
+pub fn validate_join_token(encoded_token: &str, joiner_hostname: Option<&str>) -> Result<JoinToken> {
+    use halvor_core::utils::hostname::normalize_hostname;
+
     let token = JoinToken::decode(encoded_token)?;
 
     if token.is_expired() {
         anyhow::bail!("Join token has expired");
     }
+    if let Some(hostname) = joiner_hostname
+        && !token.allows(hostname)
+    {
+        anyhow::bail!("Join token is not valid for host '{}'", hostname);
+    }
+    if normalize_hostname(&token.issuer_hostname) != normalize_hostname(&local_mesh_hostname()) {
+        anyhow::bail!(
+            "Join token was issued by '{}', join through that node",
+            token.issuer_hostname
+        );
+    }
 
-    // Check if token exists in database and hasn't been used
-    eprintln!("[DEBUG] Validating token_id: {}", token.token_id);
-    eprintln!("[DEBUG] Database path: {:?}", db::get_db_path()?);
-    eprintln!("[DEBUG] Searching for encoded token in database (first 50 chars): {}", &encoded_token[..50.min(encoded_token.len())]);
+    match find_join_token(encoded_token)? {
+        Some(row) => check_token_row(&row)?,
+        None => anyhow::bail!("Invalid or already used join token"),
+    }
 
-    let rows = join_tokens::select_many(
-        "token = ?1 AND used = 0",
-        &[&encoded_token as &dyn rusqlite::types::ToSql],
-    )?;
+    Ok(token)
+}
 
-    eprintln!("[DEBUG] Found {} matching tokens", rows.len());
+/// Validate a join token for `joiner_hostname` and count the use
+///
+/// The use is claimed atomically, so a token can't be used more often than
+/// allowed by concurrent joins.
+pub fn redeem_join_token(encoded_token: &str, joiner_hostname: &str) -> Result<JoinToken> {
+    let token = validate_join_token(encoded_token, Some(joiner_hostname))?;
+    let now = chrono::Utc::now().timestamp();
 
-    // Also check if token exists but was already used
-    let all_rows = join_tokens::select_many(
-        "token = ?1",
-        &[&encoded_token as &dyn rusqlite::types::ToSql],
+    let conn = db::get_connection()?;
+    let claimed = conn.execute(
+        "UPDATE join_tokens SET
+            uses = uses + 1,
+            used_by_hostname = ?1,
+            used_at = ?2,
+            updated_at = ?2
+         WHERE token_hash = ?3
+            AND revoked_at IS NULL
+            AND (max_uses = 0 OR uses < max_uses)",
+        rusqlite::params![joiner_hostname, now, hash_token(encoded_token)],
     )?;
-
-    eprintln!("[DEBUG] Found {} total tokens (including used)", all_rows.len());
-    if !all_rows.is_empty() {
-        eprintln!("[DEBUG] Token exists - used={}", all_rows[0].used);
-    }
-
-    // List all tokens for debugging
-    let all_tokens = join_tokens::select_many("1=1", &[])?;
-    eprintln!("[DEBUG] Total tokens in database: {}", all_tokens.len());
-    for (i, t) in all_tokens.iter().enumerate() {
-        eprintln!("[DEBUG]   Token {}: issuer={}, used={}, token_preview={}",
-            i, t.issuer_hostname, t.used, &t.token[..50.min(t.token.len())]);
-    }
-
-    if rows.is_empty() {
+    if claimed == 0 {
         anyhow::bail!("Invalid or already used join token");
     }
 
     Ok(token)
 }
 
-/// Mark a join token as used
-pub fn mark_token_used(encoded_token: &str, joined_hostname: &str) -> Result<()> {
-    let conn = db::get_connection()?;
-    let now = chrono::Utc::now().timestamp();
+/// Join tokens this node issued, newest first
+pub fn list_join_tokens() -> Result<Vec<JoinTokensRow>> {
+    JoinTokensRow::query().order_by_created_at_desc().all()
+}
 
-    conn.execute(
-        "UPDATE join_tokens SET used = 1, used_by_hostname = ?1, used_at = ?2 WHERE token = ?3",
-        rusqlite::params![joined_hostname, now, encoded_token],
-    )?;
+/// Revoke the join token whose ID starts with `token_id`, returning it
+pub fn revoke_join_token(token_id: &str) -> Result<JoinTokensRow> {
+    let mut rows = JoinTokensRow::query().token_id().starts_with(token_id).all()?;
+    let mut row = match rows.len() {
+        0 => anyhow::bail!("No join token with ID '{}'", token_id),
+        1 => rows.remove(0),
+        n => anyhow::bail!("'{}' matches {} join tokens, give more of the ID", token_id, n),
+    };
+    if row.revoked_at.is_none() {
+        let now = chrono::Utc::now().timestamp();
+        let conn = db::get_connection()?;
+        conn.execute(
+            "UPDATE join_tokens SET revoked_at = ?1, updated_at = ?1 WHERE id = ?2",
+            rusqlite::params![now, row.id],
+        )?;
+        row.revoked_at = Some(now);
+    }
+    Ok(row)
+}
 
+fn find_join_token(encoded_token: &str) -> Result<Option<JoinTokensRow>> {
+    let token_hash = hash_token(encoded_token);
+    JoinTokensRow::query().token_hash().eq(token_hash).first()
+}
+
+fn check_token_row(row: &JoinTokensRow) -> Result<()> {
+    if row.revoked_at.is_some() {
+        anyhow::bail!("Join token has been revoked");
+    }
+    if row.max_uses > 0 && row.uses >= row.max_uses {
+        anyhow::bail!("Invalid or already used join token");
+    }
     Ok(())
 }
 
+fn join_hostnames(hostnames: &[String]) -> Option<String> {
+    (!hostnames.is_empty()).then(|| hostnames.join(","))
+}
+
 /// Add a peer to the mesh (called after successful join handshake)
+///
+/// Fails if another peer already holds `hostname` under a different key,
+/// unless that key has been revoked.
 pub fn add_peer(
     hostname: &str,
     tailscale_ip: Option<String>,
@@ -164,59 +332,279 @@ pub fn add_peer(
     public_key: &str,
     shared_secret: &str,
 ) -> Result<()> {
+    let existing = claim_hostname(hostname, public_key)?;
+    let hostname = existing.map_or_else(|| hostname.to_string(), |peer| peer.hostname);
     let now = chrono::Utc::now().timestamp();
 
     // Add to agent_peers table
     let peer_data = AgentPeersRowData {
-        hostname: hostname.to_string(),
+        hostname: hostname.clone(),
         tailscale_ip,
         tailscale_hostname,
         public_key: public_key.to_string(),
-        status: "active".to_string(),
+        status: PeerStatus::Active.as_str().to_string(),
         last_seen_at: Some(now),
         joined_at: now,
     };
 
-    agent_peers::upsert_one(
-        "hostname = ?1",
-        &[&hostname as &dyn rusqlite::types::ToSql],
-        peer_data,
-    )?;
+    AgentPeersRow::query().hostname().eq(hostname.clone()).upsert(peer_data)?;
 
     // Store shared secret in peer_keys table
     let key_data = PeerKeysRowData {
-        peer_hostname: hostname.to_string(),
+        peer_hostname: hostname.clone(),
         shared_secret: shared_secret.to_string(),
         algorithm: "aes-256-gcm".to_string(),
     };
 
-    peer_keys::upsert_one(
-        "peer_hostname = ?1",
-        &[&hostname as &dyn rusqlite::types::ToSql],
-        key_data,
-    )?;
+    PeerKeysRow::query().peer_hostname().eq(hostname).upsert(key_data)?;
 
     Ok(())
 }
 
+/// Check that `public_key` may join as `hostname`, before anything is used up
+/// on its behalf
+pub fn check_join_claim(hostname: &str, public_key: &str) -> Result<()> {
+    claim_hostname(hostname, public_key).map(|_| ())
+}
+
+/// The peer already called `hostname`, if `public_key` may take its place
+fn claim_hostname(hostname: &str, public_key: &str) -> Result<Option<AgentPeersRow>> {
+    let existing = match find_peer(hostname)? {
+        Some(stored) => AgentPeersRow::query().hostname().eq(stored).first()?,
+        None => None,
+    };
+    check_hostname_claim(
+        hostname,
+        existing.as_ref().map(|peer| peer.public_key.as_str()),
+        public_key,
+        revocation::find(hostname)?.as_ref(),
+    )?;
+    Ok(existing)
+}
+
+/// Refuse to give `hostname` to `public_key` while a peer still holds it with
+/// another key that hasn't been revoked
+fn check_hostname_claim(
+    hostname: &str,
+    existing_key: Option<&str>,
+    public_key: &str,
+    revocation: Option<&PeerRevocation>,
+) -> Result<()> {
+    match existing_key {
+        Some(key) if key != public_key && !revocation.is_some_and(|revoked| revoked.covers(Some(key))) => {
+            anyhow::bail!(
+                "'{}' is already a mesh peer with a different key; revoke it before it joins again",
+                hostname
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Record a peer announced by another mesh member
+///
+/// Only adds the peer to `agent_peers`; there is no shared secret with it until
+/// the two nodes pair. Returns false if the peer was already known.
+pub fn record_peer(
+    hostname: &str,
+    tailscale_ip: Option<String>,
+    tailscale_hostname: Option<String>,
+    public_key: &str,
+) -> Result<bool> {
+    if let Some(existing) = find_peer(hostname)? {
+        if let Some(ip) = &tailscale_ip {
+            update_peer_tailscale_ip(&existing, ip)?;
+        }
+        if let Some(ts_hostname) = &tailscale_hostname {
+            update_peer_tailscale_hostname(&existing, ts_hostname)?;
+        }
+        return Ok(false);
+    }
+
+    let peer_data = AgentPeersRowData {
+        hostname: hostname.to_string(),
+        tailscale_ip,
+        tailscale_hostname,
+        public_key: public_key.to_string(),
+        status: PeerStatus::Active.as_str().to_string(),
+        last_seen_at: None,
+        joined_at: chrono::Utc::now().timestamp(),
+    };
+    AgentPeersRow::query().hostname().eq(hostname).upsert(peer_data)?;
+    Ok(true)
+}
+
+/// Rename a peer, keeping its shared secret
+///
+/// Returns false if no peer is called `old_hostname`.
+pub fn rename_peer(old_hostname: &str, new_hostname: &str) -> Result<bool> {
+    let Some(existing) = find_peer(old_hostname)? else {
+        return Ok(false);
+    };
+
+    db::with_transaction(|tx| {
+        tx.execute(
+            "UPDATE agent_peers SET hostname = ?1 WHERE hostname = ?2",
+            rusqlite::params![new_hostname, existing],
+        )?;
+        tx.execute(
+            "UPDATE peer_keys SET peer_hostname = ?1 WHERE peer_hostname = ?2",
+            rusqlite::params![new_hostname, existing],
+        )?;
+        Ok(())
+    })?;
+
+    Ok(true)
+}
+
+/// Address to reach a peer's agent at: its Tailscale IP or hostname if known,
+/// otherwise its mesh hostname
+pub fn peer_address(peer_hostname: &str) -> Result<String> {
+    let row = AgentPeersRow::query().hostname().eq(peer_hostname).first()?;
+    Ok(row
+        .and_then(|r| r.tailscale_ip.or(r.tailscale_hostname))
+        .unwrap_or_else(|| peer_hostname.to_string()))
+}
+
 /// Get all active peers in the mesh
 pub fn get_active_peers() -> Result<Vec<String>> {
-    let rows = agent_peers::select_many(
-        "status = ?1",
-        &[&"active" as &dyn rusqlite::types::ToSql],
-    )?;
+    let rows = AgentPeersRow::query()
+        .status()
+        .eq(PeerStatus::Active.as_str())
+        .all()?;
 
     Ok(rows.into_iter().map(|r| r.hostname).collect())
 }
 
+/// Get every peer in the mesh, whether or not it is answering heartbeats
+pub fn get_peers() -> Result<Vec<String>> {
+    Ok(list_peers()?.into_iter().map(|p| p.hostname).collect())
+}
+
+/// Get every peer in the mesh with its liveness, ordered by hostname
+pub fn list_peers() -> Result<Vec<MeshPeer>> {
+    let rows = AgentPeersRow::query().order_by_hostname_asc().all()?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| MeshPeer {
+            status: PeerStatus::parse(&r.status),
+            hostname: r.hostname,
+            tailscale_ip: r.tailscale_ip,
+            tailscale_hostname: r.tailscale_hostname,
+            last_seen_at: r.last_seen_at,
+            joined_at: r.joined_at,
+        })
+        .collect())
+}
+
+/// Set a peer's liveness status
+pub fn set_peer_status(hostname: &str, status: PeerStatus) -> Result<()> {
+    let conn = db::get_connection()?;
+
+    conn.execute(
+        "UPDATE agent_peers SET status = ?1 WHERE hostname = ?2",
+        rusqlite::params![status.as_str(), hostname],
+    )?;
+
+    Ok(())
+}
+
+/// Record that a peer was just heard from, making it active again
+///
+/// Returns the status it had if that wasn't active.
+pub fn mark_peer_seen(hostname: &str) -> Result<Option<PeerStatus>> {
+    let previous = AgentPeersRow::query()
+        .hostname()
+        .eq(hostname)
+        .first()?
+        .map(|r| PeerStatus::parse(&r.status));
+
+    let conn = db::get_connection()?;
+    let now = chrono::Utc::now().timestamp();
+    conn.execute(
+        "UPDATE agent_peers SET last_seen_at = ?1, status = ?2 WHERE hostname = ?3",
+        rusqlite::params![now, PeerStatus::Active.as_str(), hostname],
+    )?;
+
+    Ok(previous.filter(|status| *status != PeerStatus::Active))
+}
+
 /// Get shared secret for a peer
 pub fn get_peer_shared_secret(peer_hostname: &str) -> Result<Option<String>> {
-    let rows = peer_keys::select_many(
-        "peer_hostname = ?1",
-        &[&peer_hostname as &dyn rusqlite::types::ToSql],
-    )?;
+    let row = PeerKeysRow::query().peer_hostname().eq(peer_hostname).first()?;
+
+    Ok(row.map(|r| r.shared_secret))
+}
+
+/// Find the stored hostname of a peer, matching by normalized hostname
+/// (e.g. "frigg.bombay-pinecone.ts.net" finds peer "frigg")
+pub fn find_peer(hostname: &str) -> Result<Option<String>> {
+    use halvor_core::utils::hostname::normalize_hostname;
+
+    let normalized = normalize_hostname(hostname);
+    let rows = AgentPeersRow::query().all()?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| r.hostname)
+        .find(|h| h.eq_ignore_ascii_case(hostname) || normalize_hostname(h) == normalized))
+}
+
+/// Hostname this node uses in the mesh
+///
+/// The name given by `halvor agent hostname`, if the node was renamed.
+/// Otherwise the short Tailscale name (e.g. "mint" from
+/// "mint.bombay-pinecone.ts.net") if available, or the normalized system
+/// hostname (e.g. "mint.local" -> "mint").
+pub fn local_mesh_hostname() -> String {
+    if let Ok(Some(hostname)) = mesh_hostname_override() {
+        return hostname;
+    }
+    crate::apps::tailscale::get_tailscale_hostname()
+        .ok()
+        .flatten()
+        .map(|ts_hostname| {
+            ts_hostname
+                .split('.')
+                .next()
+                .unwrap_or(&ts_hostname)
+                .to_string()
+        })
+        .unwrap_or_else(|| {
+            let system_hostname = halvor_core::utils::hostname::get_current_hostname()
+                .unwrap_or_else(|_| "unknown".to_string());
+            halvor_core::utils::hostname::normalize_hostname(&system_hostname)
+        })
+}
 
-    Ok(rows.first().map(|r| r.shared_secret.clone()))
+/// The mesh name this node was renamed to, if it was
+pub fn mesh_hostname_override() -> Result<Option<String>> {
+    use halvor_core::utils::hostname::normalize_hostname;
+
+    let path = halvor_core::config::config_manager::get_config_dir()?.join(MESH_HOSTNAME_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let hostname = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let hostname = normalize_hostname(&hostname);
+    Ok((!hostname.is_empty()).then_some(hostname))
+}
+
+/// Make `hostname` this node's mesh name whatever its Tailscale and system
+/// hostnames are, or go back to those with `None`
+pub fn set_mesh_hostname_override(hostname: Option<&str>) -> Result<()> {
+    use halvor_core::utils::hostname::normalize_hostname;
+
+    let path = halvor_core::config::config_manager::get_config_dir()?.join(MESH_HOSTNAME_FILE);
+    match hostname {
+        Some(hostname) => std::fs::write(&path, format!("{}\n", normalize_hostname(hostname)))
+            .with_context(|| format!("Failed to write {}", path.display())),
+        None if path.exists() => std::fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove {}", path.display())),
+        None => Ok(()),
+    }
 }
 
 /// Update peer last seen timestamp
@@ -256,11 +644,19 @@ pub fn update_peer_tailscale_ip(hostname: &str, tailscale_ip: &str) -> Result<()
     Ok(())
 }
 
-/// Remove a peer from the mesh
-pub fn remove_peer(hostname: &str) -> Result<()> {
-    agent_peers::delete_by_hostname(hostname)?;
-    // peer_keys will be deleted automatically via CASCADE
-    Ok(())
+/// Remove a peer from the mesh, revoking the certificates this node issued to it
+///
+/// Returns how many certificates were revoked. The peer's certificates from
+/// other CAs stop being accepted here too, since it is no longer a mesh peer.
+pub fn remove_peer(hostname: &str) -> Result<usize> {
+    db::with_transaction(|tx| {
+        tx.execute("DELETE FROM agent_peers WHERE hostname = ?1", rusqlite::params![hostname])?;
+        // Foreign keys aren't enforced, so the shared secret has to go explicitly
+        tx.execute("DELETE FROM peer_keys WHERE peer_hostname = ?1", rusqlite::params![hostname])?;
+        Ok(())
+    })?;
+
+    tls::revoke_peer_certificates(hostname)
 }
 
 /// Refresh Tailscale hostnames for all peers from current Tailscale status
@@ -325,6 +721,7 @@ pub fn cleanup_expired_tokens() -> Result<usize> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::agent::tls::NodeSignature;
 
     #[test]
     fn test_token_encode_decode() {
@@ -335,6 +732,9 @@ mod tests {
             issuer_port: 13500,
             expires_at: chrono::Utc::now().timestamp() + 3600,
             handshake_key: "test-key".to_string(),
+            ca_fingerprint: Some("ab".repeat(32)),
+            max_uses: 3,
+            allowed_hostnames: vec!["baulder".to_string()],
         };
 
         let encoded = token.encode().unwrap();
@@ -342,6 +742,62 @@ mod tests {
 
         assert_eq!(token.token_id, decoded.token_id);
         assert_eq!(token.issuer_hostname, decoded.issuer_hostname);
+        assert_eq!(token.ca_fingerprint, decoded.ca_fingerprint);
+        assert_eq!(decoded.max_uses, 3);
+        assert_eq!(decoded.allowed_hostnames, vec!["baulder".to_string()]);
         assert!(!decoded.is_expired());
+        assert_eq!(hash_token(&encoded), hash_token(&token.encode().unwrap()));
+        assert_eq!(hash_token(&encoded).len(), 64);
+    }
+
+    #[test]
+    fn test_token_without_ca_fingerprint_decodes() {
+        // Tokens from agents without TLS support
+        let json = r#"{"token_id":"t","issuer_hostname":"frigg","issuer_ip":"100.66.176.17","issuer_port":13500,"expires_at":0,"handshake_key":"k"}"#;
+        let decoded = JoinToken::decode(&general_purpose::STANDARD.encode(json)).unwrap();
+        assert!(decoded.ca_fingerprint.is_none());
+        // Older tokens are single-use, for any host
+        assert_eq!(decoded.max_uses, 1);
+        assert!(decoded.allowed_hostnames.is_empty());
+    }
+
+    #[test]
+    fn test_token_hostname_scope() {
+        let json = r#"{"token_id":"t","issuer_hostname":"frigg","issuer_ip":"100.66.176.17","issuer_port":13500,"expires_at":0,"handshake_key":"k"}"#;
+        let mut token = JoinToken::decode(&general_purpose::STANDARD.encode(json)).unwrap();
+        assert!(token.allows("anyone"));
+
+        token.allowed_hostnames = vec!["baulder".to_string(), "odin".to_string()];
+        assert!(token.allows("baulder"));
+        assert!(token.allows("ODIN"));
+        assert!(!token.allows("heimdall"));
+    }
+
+    #[test]
+    fn test_hostname_taken_by_another_key_needs_revocation() {
+        let old_key = tls::encode_public_key(&tls::generate_node_key().unwrap());
+        let new_key = tls::encode_public_key(&tls::generate_node_key().unwrap());
+        let revocation = |public_key: &str| PeerRevocation {
+            hostname: "heimdall".to_string(),
+            public_key: public_key.to_string(),
+            revoked_by: "frigg".to_string(),
+            revoked_at: 1_700_000_000,
+            signature: NodeSignature {
+                certificate: String::new(),
+                signature: String::new(),
+            },
+        };
+
+        // New hosts, and known hosts joining again with the same key
+        assert!(check_hostname_claim("heimdall", None, &new_key, None).is_ok());
+        assert!(check_hostname_claim("heimdall", Some(&old_key), &old_key, None).is_ok());
+
+        // Someone else's key can't take the name over
+        assert!(check_hostname_claim("heimdall", Some(&old_key), &new_key, None).is_err());
+        let other = tls::encode_public_key(&tls::generate_node_key().unwrap());
+        assert!(check_hostname_claim("heimdall", Some(&old_key), &new_key, Some(&revocation(&other))).is_err());
+
+        // Unless the old key has been revoked
+        assert!(check_hostname_claim("heimdall", Some(&old_key), &new_key, Some(&revocation(&old_key))).is_ok());
     }
 }
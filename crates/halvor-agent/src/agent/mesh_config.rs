@@ -0,0 +1,219 @@
+//! Key/value config replicated across the mesh
+//!
+//! Every entry carries a version from a hybrid logical clock: milliseconds since
+//! the epoch shifted left 16 bits, plus a counter in the low bits. A node's
+//! clock is always ahead of both its wall clock and every version it has seen,
+//! so a write made after seeing another write always supersedes it, even if the
+//! writer's clock is behind. Concurrent writes are settled last-writer-wins on
+//! `(version, origin)`, so every node converges on the same value.
+//!
+//! Writes are gossiped as `ConfigUpdate` broadcasts; nodes that missed one
+//! catch up when they exchange snapshots with a peer (`SyncConfig`).
+
+use crate::agent::gossip::Gossip;
+use crate::agent::mesh;
+use crate::agent::mesh_protocol::{MeshMessage, MessagePayload, MessageRouter};
+use anyhow::{Context, Result};
+use halvor_db as db;
+use halvor_db::generated::MeshConfigRow;
+use serde::{Deserialize, Serialize};
+
+/// Bits of a version used by the logical counter
+const COUNTER_BITS: u32 = 16;
+
+/// A replicated config value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: serde_json::Value,
+    /// Hybrid logical clock timestamp of the write
+    pub version: u64,
+    /// Node that made the write
+    pub origin: String,
+}
+
+impl ConfigEntry {
+    /// Whether this write wins over `other` for the same key
+    pub fn supersedes(&self, other: &ConfigEntry) -> bool {
+        (self.version, self.origin.as_str()) > (other.version, other.origin.as_str())
+    }
+
+    /// Milliseconds since the epoch at which the write was made
+    pub fn timestamp_millis(&self) -> u64 {
+        self.version >> COUNTER_BITS
+    }
+}
+
+/// Next clock reading after `last`, given the wall clock `now_millis`
+pub fn next_version(last: u64, now_millis: u64) -> u64 {
+    (now_millis << COUNTER_BITS).max(last + 1)
+}
+
+/// Look up a config value
+pub fn get(key: &str) -> Result<Option<ConfigEntry>> {
+    MeshConfigRow::query().key().eq(key).first()?
+        .map(entry_from_row)
+        .transpose()
+}
+
+/// All config values, ordered by key
+pub fn list() -> Result<Vec<ConfigEntry>> {
+    MeshConfigRow::query().order_by_key_asc().all()?
+        .into_iter()
+        .map(entry_from_row)
+        .collect()
+}
+
+/// Set `key` on this node, returning the new entry
+///
+/// The write is only local; [`publish`] it to replicate it.
+pub fn set(key: &str, value: serde_json::Value) -> Result<ConfigEntry> {
+    anyhow::ensure!(!key.trim().is_empty(), "Config key cannot be empty");
+
+    let entry = ConfigEntry {
+        key: key.to_string(),
+        value,
+        version: next_version(latest_version()?, now_millis()),
+        origin: mesh::local_mesh_hostname(),
+    };
+    merge(&entry)?;
+    Ok(entry)
+}
+
+/// Apply a write from another node if it wins over the local value
+///
+/// Returns false if the local value was newer (or the same write).
+pub fn merge(entry: &ConfigEntry) -> Result<bool> {
+    let version = i64::try_from(entry.version).context("Config version out of range")?;
+    let value = serde_json::to_string(&entry.value)?;
+    let now = chrono::Utc::now().timestamp();
+
+    let conn = db::get_connection()?;
+    let changed = conn.execute(
+        "INSERT INTO mesh_config (id, key, value, version, origin, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+         ON CONFLICT(key) DO UPDATE SET
+            value = excluded.value,
+            version = excluded.version,
+            origin = excluded.origin,
+            updated_at = excluded.updated_at
+         WHERE excluded.version > mesh_config.version
+            OR (excluded.version = mesh_config.version AND excluded.origin > mesh_config.origin)",
+        rusqlite::params![
+            uuid::Uuid::new_v4().to_string(),
+            entry.key,
+            value,
+            version,
+            entry.origin,
+            now
+        ],
+    )?;
+    Ok(changed > 0)
+}
+
+/// Merge a batch of writes, returning how many changed a local value
+pub fn merge_all(entries: &[ConfigEntry]) -> Result<usize> {
+    let mut changed = 0;
+    for entry in entries {
+        if merge(entry)? {
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+/// Gossip a local write to the mesh, returning how many peers took it
+pub fn publish(entry: &ConfigEntry) -> usize {
+    Gossip::default().broadcast(MessagePayload::ConfigUpdate {
+        key: entry.key.clone(),
+        value: entry.value.clone(),
+        version: entry.version,
+    })
+}
+
+/// Register the handler that applies `ConfigUpdate` broadcasts
+///
+/// The broadcast's `from` is the node that made the write.
+pub fn register(router: &mut MessageRouter) {
+    router.register("config_update".to_string(), |message: &MeshMessage| {
+        if let MessagePayload::ConfigUpdate { key, value, version } = &message.payload {
+            let entry = ConfigEntry {
+                key: key.clone(),
+                value: value.clone(),
+                version: *version,
+                origin: message.from.clone(),
+            };
+            if merge(&entry)? {
+                println!("[MESH] Config {} updated by {}", key, message.from);
+            }
+        }
+        Ok(None)
+    });
+}
+
+/// Highest version this node has written or seen
+fn latest_version() -> Result<u64> {
+    let conn = db::get_connection()?;
+    let latest: Option<i64> =
+        conn.query_row("SELECT MAX(version) FROM mesh_config", [], |row| row.get(0))?;
+    Ok(latest.unwrap_or(0).max(0) as u64)
+}
+
+fn now_millis() -> u64 {
+    chrono::Utc::now().timestamp_millis().max(0) as u64
+}
+
+fn entry_from_row(row: MeshConfigRow) -> Result<ConfigEntry> {
+    Ok(ConfigEntry {
+        value: serde_json::from_str(&row.value)
+            .with_context(|| format!("Invalid stored value for config {}", row.key))?,
+        key: row.key,
+        version: row.version.max(0) as u64,
+        origin: row.origin,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: &str, version: u64, origin: &str) -> ConfigEntry {
+        ConfigEntry {
+            key: "dns.upstream".to_string(),
+            value: serde_json::json!(value),
+            version,
+            origin: origin.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_clock_is_monotonic() {
+        let now = 1_700_000_000_000;
+        let first = next_version(0, now);
+        assert_eq!(first >> COUNTER_BITS, now);
+
+        // Same millisecond, or a clock that went backwards, still moves forward
+        let second = next_version(first, now);
+        assert!(second > first);
+        assert!(next_version(second, now - 5_000) > second);
+
+        // Having seen a write from a node with a fast clock keeps us ahead of it
+        let remote = next_version(0, now + 60_000);
+        assert!(next_version(remote, now) > remote);
+    }
+
+    #[test]
+    fn test_last_writer_wins() {
+        let older = entry("1.1.1.1", 10, "frigg");
+        let newer = entry("9.9.9.9", 11, "baulder");
+        assert!(newer.supersedes(&older));
+        assert!(!older.supersedes(&newer));
+
+        // Concurrent writes are settled by origin, the same way on every node
+        let a = entry("1.1.1.1", 10, "baulder");
+        let b = entry("9.9.9.9", 10, "frigg");
+        assert!(b.supersedes(&a));
+        assert!(!a.supersedes(&b));
+        assert!(!a.supersedes(&a));
+    }
+}
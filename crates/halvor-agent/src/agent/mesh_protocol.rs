@@ -7,15 +7,35 @@
 //! - Streaming media (audio/video)
 //! - Configuration updates
 //! - Custom JSON payloads
-
+//! - Encrypted envelopes around agent requests/responses between peers
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use anyhow::Context;
+use base64::{Engine, engine::general_purpose};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
 /// Maximum size for a single message chunk (16MB)
 pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
 
+/// Algorithm identifier for sealed messages
+pub const ENCRYPTION_ALGORITHM: &str = "aes-256-gcm";
+
+/// Length of an AES-GCM nonce in bytes
+const GCM_NONCE_LEN: usize = 12;
+
+/// Length of an AES-GCM authentication tag in bytes
+const GCM_TAG_LEN: usize = 16;
+
+/// `to` value addressing every peer
+pub const BROADCAST: &str = "broadcast";
+
 /// Mesh message envelope - wraps all messages sent between peers
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MeshMessage {
     /// Unique message ID for tracking and deduplication
     pub message_id: String,
@@ -34,10 +54,14 @@ pub struct MeshMessage {
 
     /// Message timestamp (Unix timestamp)
     pub timestamp: i64,
+
+    /// How many more times a broadcast may be relayed (see `agent::gossip`)
+    #[serde(default)]
+    pub ttl: u8,
 }
 
 /// Different types of payloads that can be sent between peers
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
 pub enum MessagePayload {
     /// Database sync - send SQLite changes
@@ -66,6 +90,36 @@ pub enum MessagePayload {
         checksum: String,
     },
 
+    /// Ask how much of a pushed file the receiver already has
+    FileTransferStatus {
+        /// Destination path on the receiver
+        path: String,
+        /// Total file size in bytes
+        total_size: u64,
+        /// Total number of chunks
+        total_chunks: u32,
+        /// SHA-256 checksum of complete file
+        checksum: String,
+    },
+
+    /// Response to `FileTransferStatus`
+    FileTransferProgress {
+        /// Destination path on the receiver
+        path: String,
+        /// First chunk the receiver has not stored yet
+        next_chunk: u32,
+    },
+
+    /// Ask for one chunk of a file on the receiver (answered with `FileTransfer`)
+    FileRequest {
+        /// Source path on the receiver
+        path: String,
+        /// Chunk number (0-indexed)
+        chunk_index: u32,
+        /// Chunk size in bytes, at most `MAX_CHUNK_SIZE`
+        chunk_size: u32,
+    },
+
     /// Media streaming (audio/video)
     MediaStream {
         /// Stream ID for tracking
@@ -119,6 +173,78 @@ pub enum MessagePayload {
         /// Error message
         message: String,
     },
+
+    /// A node joined the mesh (broadcast by the node that admitted it)
+    PeerJoined {
+        hostname: String,
+        tailscale_ip: Option<String>,
+        tailscale_hostname: Option<String>,
+        public_key: String,
+    },
+
+    /// A node left the mesh, signed by the node itself (removing another node
+    /// takes a `PeerRevoked`); see [`crate::agent::gossip::peer_left`]
+    PeerLeft {
+        hostname: String,
+        #[serde(default)]
+        signed_at: i64,
+        #[serde(default)]
+        signature: Option<crate::agent::tls::NodeSignature>,
+    },
+
+    /// A node was revoked from the mesh (see [`crate::agent::revocation`])
+    ///
+    /// `wipe` is only honoured by the revoked node itself, when the revoking
+    /// node sends the revocation to it directly.
+    PeerRevoked {
+        revocation: crate::agent::revocation::PeerRevocation,
+        #[serde(default)]
+        wipe: bool,
+    },
+
+    /// A node changed its mesh hostname, signed by the node under its old
+    /// name; see [`crate::agent::gossip::peer_renamed`]
+    PeerRenamed {
+        old_hostname: String,
+        new_hostname: String,
+        #[serde(default)]
+        signed_at: i64,
+        #[serde(default)]
+        signature: Option<crate::agent::tls::NodeSignature>,
+    },
+
+    /// AES-256-GCM ciphertext (see [`MeshMessage::seal`])
+    Encrypted {
+        /// Ciphertext without the authentication tag (base64)
+        ciphertext: String,
+        /// ID of the request this message answers, if it is a response
+        in_reply_to: Option<String>,
+    },
+}
+
+impl MessagePayload {
+    /// Name handlers are registered under in a [`MessageRouter`]
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            MessagePayload::DatabaseSync { .. } => "database_sync",
+            MessagePayload::FileTransfer { .. } => "file_transfer",
+            MessagePayload::FileTransferStatus { .. } => "file_transfer_status",
+            MessagePayload::FileTransferProgress { .. } => "file_transfer_progress",
+            MessagePayload::FileRequest { .. } => "file_request",
+            MessagePayload::MediaStream { .. } => "media_stream",
+            MessagePayload::ConfigUpdate { .. } => "config_update",
+            MessagePayload::CustomJson { .. } => "custom_json",
+            MessagePayload::Ping => "ping",
+            MessagePayload::Pong => "pong",
+            MessagePayload::Ack { .. } => "ack",
+            MessagePayload::Error { .. } => "error",
+            MessagePayload::PeerJoined { .. } => "peer_joined",
+            MessagePayload::PeerLeft { .. } => "peer_left",
+            MessagePayload::PeerRevoked { .. } => "peer_revoked",
+            MessagePayload::PeerRenamed { .. } => "peer_renamed",
+            MessagePayload::Encrypted { .. } => "encrypted",
+        }
+    }
 }
 
 /// Database operations
@@ -137,7 +263,7 @@ pub enum MediaType {
 }
 
 /// Encryption metadata
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptionMetadata {
     /// Algorithm used (e.g., "aes-256-gcm")
     pub algorithm: String,
@@ -157,6 +283,7 @@ impl MeshMessage {
             payload,
             encryption: None,
             timestamp: chrono::Utc::now().timestamp(),
+            ttl: 0,
         }
     }
 
@@ -204,6 +331,45 @@ impl MeshMessage {
         Self::new(from, to, MessagePayload::Pong)
     }
 
+    /// Create an acknowledgment of `ack_message_id`
+    pub fn ack(from: String, to: String, ack_message_id: String) -> Self {
+        Self::new(from, to, MessagePayload::Ack { ack_message_id })
+    }
+
+    /// Create an error response
+    pub fn error(from: String, to: String, code: &str, message: impl Into<String>) -> Self {
+        Self::new(
+            from,
+            to,
+            MessagePayload::Error {
+                code: code.to_string(),
+                message: message.into(),
+            },
+        )
+    }
+
+    /// Create a custom application message
+    pub fn custom_json(from: String, to: String, app_type: &str, data: serde_json::Value) -> Self {
+        Self::new(
+            from,
+            to,
+            MessagePayload::CustomJson {
+                app_type: app_type.to_string(),
+                data,
+            },
+        )
+    }
+
+    /// Hostname to answer this message from: the recipient it was addressed to,
+    /// or this host for broadcasts
+    pub fn reply_from(&self) -> String {
+        if self.to == BROADCAST {
+            crate::agent::mesh::local_mesh_hostname()
+        } else {
+            self.to.clone()
+        }
+    }
+
     /// Serialize to JSON
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
@@ -223,11 +389,130 @@ impl MeshMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(bytes)
     }
+
+    /// Encrypt `plaintext` for a peer with the secret shared with it
+    ///
+    /// The header fields (`message_id`, `from`, `to`, `timestamp`, `in_reply_to`)
+    /// are authenticated as associated data, so they can't be altered or moved
+    /// to another ciphertext.
+    pub fn seal(
+        from: String,
+        to: String,
+        plaintext: &[u8],
+        shared_secret: &str,
+        in_reply_to: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut message = Self::new(
+            from,
+            to,
+            MessagePayload::Encrypted {
+                ciphertext: String::new(),
+                in_reply_to: in_reply_to.clone(),
+            },
+        );
+
+        let cipher = Aes256Gcm::new(&derive_key(shared_secret));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = message.associated_data(in_reply_to.as_deref());
+        let mut sealed = cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+            .map_err(|e| anyhow::anyhow!("Failed to encrypt message: {}", e))?;
+
+        // aes-gcm appends the tag to the ciphertext
+        let tag = sealed.split_off(sealed.len() - GCM_TAG_LEN);
+        message.payload = MessagePayload::Encrypted {
+            ciphertext: general_purpose::STANDARD.encode(&sealed),
+            in_reply_to,
+        };
+        message.encryption = Some(EncryptionMetadata {
+            algorithm: ENCRYPTION_ALGORITHM.to_string(),
+            iv: general_purpose::STANDARD.encode(nonce),
+            tag: general_purpose::STANDARD.encode(tag),
+        });
+
+        Ok(message)
+    }
+
+    /// Decrypt a sealed message, verifying its header and ciphertext
+    pub fn open(&self, shared_secret: &str) -> anyhow::Result<Vec<u8>> {
+        let MessagePayload::Encrypted {
+            ciphertext,
+            in_reply_to,
+        } = &self.payload
+        else {
+            anyhow::bail!("Message is not encrypted");
+        };
+        let encryption = self
+            .encryption
+            .as_ref()
+            .context("Encrypted message has no encryption metadata")?;
+        if encryption.algorithm != ENCRYPTION_ALGORITHM {
+            anyhow::bail!("Unsupported encryption algorithm: {}", encryption.algorithm);
+        }
+
+        let iv = general_purpose::STANDARD
+            .decode(&encryption.iv)
+            .context("Invalid IV encoding")?;
+        if iv.len() != GCM_NONCE_LEN {
+            anyhow::bail!("Invalid IV length: {}", iv.len());
+        }
+        let mut sealed = general_purpose::STANDARD
+            .decode(ciphertext)
+            .context("Invalid ciphertext encoding")?;
+        sealed.extend(
+            general_purpose::STANDARD
+                .decode(&encryption.tag)
+                .context("Invalid tag encoding")?,
+        );
+
+        let cipher = Aes256Gcm::new(&derive_key(shared_secret));
+        let aad = self.associated_data(in_reply_to.as_deref());
+        cipher
+            .decrypt(Nonce::from_slice(&iv), Payload { msg: &sealed, aad: &aad })
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt message from {} (wrong key or tampered message)", self.from))
+    }
+
+    /// ID of the request a sealed response answers
+    pub fn in_reply_to(&self) -> Option<&str> {
+        match &self.payload {
+            MessagePayload::Encrypted { in_reply_to, .. } => in_reply_to.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn associated_data(&self, in_reply_to: Option<&str>) -> Vec<u8> {
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            self.message_id,
+            self.from,
+            self.to,
+            self.timestamp,
+            in_reply_to.unwrap_or("")
+        )
+        .into_bytes()
+    }
+}
+
+/// Derive the AES-256 key for a peer from its shared secret
+///
+/// Secrets in `peer_keys` are not guaranteed to be exactly 32 raw bytes, so the
+/// key is a SHA-256 digest of the secret.
+fn derive_key(shared_secret: &str) -> Key<Aes256Gcm> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"halvor-mesh-envelope-v1\n");
+    hasher.update(shared_secret.as_bytes());
+    let digest: [u8; 32] = hasher.finalize().into();
+    digest.into()
 }
 
 /// Message router for handling incoming messages
+///
+/// Handlers are registered per message type (see [`MessagePayload::message_type`]).
+/// `CustomJson` messages are routed by `app_type` first, so each application can
+/// register its own handler with [`MessageRouter::register_custom`].
 pub struct MessageRouter {
     handlers: HashMap<String, Box<dyn MessageHandler>>,
+    custom_handlers: HashMap<String, Box<dyn MessageHandler>>,
 }
 
 /// Trait for handling specific message types
@@ -235,38 +520,97 @@ pub trait MessageHandler: Send + Sync {
     fn handle(&self, message: &MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error>;
 }
 
+impl<F> MessageHandler for F
+where
+    F: Fn(&MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error> + Send + Sync,
+{
+    fn handle(&self, message: &MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error> {
+        self(message)
+    }
+}
+
+/// Answers `Ping` with `Pong`
+pub struct PingHandler;
+
+impl MessageHandler for PingHandler {
+    fn handle(&self, message: &MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error> {
+        Ok(Some(MeshMessage::pong(message.reply_from(), message.from.clone())))
+    }
+}
+
+/// Accepts `Pong` and `Ack` without a reply
+pub struct AckHandler;
+
+impl MessageHandler for AckHandler {
+    fn handle(&self, _message: &MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error> {
+        Ok(None)
+    }
+}
+
+/// Logs `Error` messages from peers; errors are never answered
+pub struct ErrorHandler;
+
+impl MessageHandler for ErrorHandler {
+    fn handle(&self, message: &MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error> {
+        if let MessagePayload::Error { code, message: text } = &message.payload {
+            eprintln!("[MESH] Error from {}: {} ({})", message.from, text, code);
+        }
+        Ok(None)
+    }
+}
+
 impl MessageRouter {
     pub fn new() -> Self {
         Self {
             handlers: HashMap::new(),
+            custom_handlers: HashMap::new(),
         }
     }
 
+    /// Router with handlers for `Ping`, `Pong`, `Ack` and `Error`
+    pub fn with_builtin_handlers() -> Self {
+        let mut router = Self::new();
+        router.register("ping".to_string(), PingHandler);
+        router.register("pong".to_string(), AckHandler);
+        router.register("ack".to_string(), AckHandler);
+        router.register("error".to_string(), ErrorHandler);
+        router
+    }
+
     /// Register a handler for a specific message type
     pub fn register<H: MessageHandler + 'static>(&mut self, message_type: String, handler: H) {
         self.handlers.insert(message_type, Box::new(handler));
     }
 
+    /// Register a handler for `CustomJson` messages with the given `app_type`
+    pub fn register_custom<H: MessageHandler + 'static>(&mut self, app_type: String, handler: H) {
+        self.custom_handlers.insert(app_type, Box::new(handler));
+    }
+
+    /// Whether a handler is registered for this message
+    pub fn handles(&self, message: &MeshMessage) -> bool {
+        self.handler_for(message).is_some()
+    }
+
     /// Route an incoming message to the appropriate handler
     pub fn route(&self, message: &MeshMessage) -> Result<Option<MeshMessage>, anyhow::Error> {
-        let message_type = match &message.payload {
-            MessagePayload::DatabaseSync { .. } => "database_sync",
-            MessagePayload::FileTransfer { .. } => "file_transfer",
-            MessagePayload::MediaStream { .. } => "media_stream",
-            MessagePayload::ConfigUpdate { .. } => "config_update",
-            MessagePayload::CustomJson { .. } => "custom_json",
-            MessagePayload::Ping => "ping",
-            MessagePayload::Pong => "pong",
-            MessagePayload::Ack { .. } => "ack",
-            MessagePayload::Error { .. } => "error",
-        };
-
-        if let Some(handler) = self.handlers.get(message_type) {
+        if let Some(handler) = self.handler_for(message) {
             handler.handle(message)
         } else {
             Ok(None)
         }
     }
+
+    fn handler_for(&self, message: &MeshMessage) -> Option<&dyn MessageHandler> {
+        if let MessagePayload::CustomJson { app_type, .. } = &message.payload
+            && let Some(handler) = self.custom_handlers.get(app_type)
+        {
+            return Some(handler.as_ref());
+        }
+        self.handlers
+            .get(message.payload.message_type())
+            .map(|handler| handler.as_ref())
+    }
 }
 
 impl Default for MessageRouter {
@@ -311,4 +655,121 @@ mod tests {
             panic!("Wrong payload type");
         }
     }
+
+    #[test]
+    fn test_builtin_handlers() {
+        let router = MessageRouter::with_builtin_handlers();
+
+        let ping = MeshMessage::ping("alice".to_string(), "bob".to_string());
+        let reply = router.route(&ping).unwrap().expect("ping should be answered");
+        assert!(matches!(reply.payload, MessagePayload::Pong));
+        assert_eq!(reply.from, "bob");
+        assert_eq!(reply.to, "alice");
+
+        let ack = MeshMessage::ack("alice".to_string(), "bob".to_string(), ping.message_id.clone());
+        assert!(router.route(&ack).unwrap().is_none());
+        let error = MeshMessage::error("alice".to_string(), "bob".to_string(), "test", "failed");
+        assert!(router.route(&error).unwrap().is_none());
+
+        let sync = MeshMessage::config_update(
+            "alice".to_string(),
+            "bob".to_string(),
+            "key".to_string(),
+            serde_json::json!(1),
+            1,
+        );
+        assert!(!router.handles(&sync));
+    }
+
+    #[test]
+    fn test_custom_handlers_route_by_app_type() {
+        let mut router = MessageRouter::new();
+        router.register_custom("echo".to_string(), |message: &MeshMessage| {
+            let MessagePayload::CustomJson { data, .. } = &message.payload else {
+                anyhow::bail!("expected CustomJson");
+            };
+            Ok(Some(MeshMessage::custom_json(
+                message.reply_from(),
+                message.from.clone(),
+                "echo",
+                data.clone(),
+            )))
+        });
+
+        let echo = MeshMessage::custom_json(
+            "alice".to_string(),
+            "bob".to_string(),
+            "echo",
+            serde_json::json!({"n": 1}),
+        );
+        let reply = router.route(&echo).unwrap().unwrap();
+        match reply.payload {
+            MessagePayload::CustomJson { app_type, data } => {
+                assert_eq!(app_type, "echo");
+                assert_eq!(data, serde_json::json!({"n": 1}));
+            }
+            _ => panic!("Wrong payload type"),
+        }
+
+        let other = MeshMessage::custom_json(
+            "alice".to_string(),
+            "bob".to_string(),
+            "other",
+            serde_json::Value::Null,
+        );
+        assert!(!router.handles(&other));
+
+        // A generic custom_json handler catches app types without their own handler
+        router.register("custom_json".to_string(), |_: &MeshMessage| Ok::<_, anyhow::Error>(None));
+        assert!(router.handles(&other));
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let secret = "c2hhcmVkLXNlY3JldA==";
+        let msg = MeshMessage::seal(
+            "alice".to_string(),
+            "bob".to_string(),
+            b"{\"Ping\":null}",
+            secret,
+            None,
+        )
+        .unwrap();
+
+        // Round-trips through JSON like it would on the wire
+        let received = MeshMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+        assert_eq!(received.open(secret).unwrap(), b"{\"Ping\":null}");
+        assert!(received.open("other-secret").is_err());
+    }
+
+    #[test]
+    fn test_sealed_header_is_authenticated() {
+        let secret = "c2hhcmVkLXNlY3JldA==";
+        let msg = MeshMessage::seal(
+            "alice".to_string(),
+            "bob".to_string(),
+            b"payload",
+            secret,
+            Some("request-1".to_string()),
+        )
+        .unwrap();
+
+        let mut forged = MeshMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+        forged.from = "mallory".to_string();
+        assert!(forged.open(secret).is_err());
+
+        let mut forged = MeshMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+        forged.timestamp += 1;
+        assert!(forged.open(secret).is_err());
+
+        let mut forged = MeshMessage::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+        forged.payload = MessagePayload::Encrypted {
+            ciphertext: match &msg.payload {
+                MessagePayload::Encrypted { ciphertext, .. } => ciphertext.clone(),
+                _ => unreachable!(),
+            },
+            in_reply_to: Some("request-2".to_string()),
+        };
+        assert!(forged.open(secret).is_err());
+    }
 }
@@ -1,12 +1,26 @@
 pub mod api;
+pub mod auth;
 pub mod client;
 pub mod data_sync;
 pub mod discovery;
+pub mod exec_stream;
+pub mod executor;
+pub mod file_ops;
+pub mod gossip;
+pub mod handshake;
+pub mod heartbeat;
 pub mod install;
+pub mod mdns;
 pub mod mesh;
+pub mod mesh_config;
 pub mod mesh_protocol;
+pub mod policy;
+pub mod rename;
+pub mod revocation;
 pub mod server;
 pub mod sync;
+pub mod tls;
+pub mod transfer;
 
 pub use client::HalvorClient;
 pub use discovery::HostDiscovery;
@@ -0,0 +1,479 @@
+//! Command execution policy for remote `ExecuteCommand` and `FileOperation` requests
+//!
+//! Policy is deny-by-default: a peer may only run a command if a rule in
+//! `agent_exec_policies` allows it. Rules match a peer hostname (or `*` for every
+//! peer) and a command (a program name, an absolute path, or `*` for any
+//! command). A program name matches the bare name, or the program in one of
+//! the system binary directories (`SYSTEM_BIN_DIRS`), never a copy elsewhere.
+//! Running a command through `sudo` additionally requires the matching rule to
+//! have `allow_sudo` set, and only sudo's `-n` and `--` options are accepted.
+//! Any other program named `sudo` is denied outright.
+//! Rejected requests are recorded in `agent_exec_audit`.
+//!
+//! Native file operations are checked the same way, as commands named after the
+//! operation (`file:read`, `file:write`, …; see `file_ops`) with the resolved
+//! path as their argument. Rules can limit them to paths under a list of
+//! directories, and the agent's own config and data directories (its database
+//! and keys) are off limits to them whatever the rules say.
+
+use halvor_core::utils::hostname::normalize_hostname;
+use halvor_db::generated::{AgentExecAuditRow, AgentExecAuditRowData};
+use halvor_db::generated::{AgentExecPoliciesRow, AgentExecPoliciesRowData};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Wildcard matching any peer or any command
+pub const ANY: &str = "*";
+
+/// Directories a program-name rule matches the program in
+const SYSTEM_BIN_DIRS: &[&str] = &[
+    "/bin",
+    "/sbin",
+    "/usr/bin",
+    "/usr/sbin",
+    "/usr/local/bin",
+    "/usr/local/sbin",
+];
+
+/// sudo options that take no value and can't change who runs the command or how
+const SUDO_FLAGS: &[&str] = &["-n", "--non-interactive"];
+
+/// A single allowlist entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub peer_hostname: String,
+    pub command: String,
+    pub allow_sudo: bool,
+    /// Directories file operations are limited to; any path if empty
+    pub paths: Vec<String>,
+}
+
+/// Result of evaluating a request against the policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny(String),
+}
+
+impl PolicyRule {
+    fn matches_peer(&self, peer_hostname: &str) -> bool {
+        self.peer_hostname == ANY
+            || normalize_hostname(&self.peer_hostname) == normalize_hostname(peer_hostname)
+    }
+
+    /// Rules containing '/' match the exact path; others match the program
+    /// name, run bare or from a system binary directory
+    fn matches_command(&self, command: &str) -> bool {
+        if self.command == ANY {
+            return true;
+        }
+        if self.command.contains('/') {
+            return self.command == command;
+        }
+        // `/tmp/x/systemctl` is not systemctl
+        program_name(command) == self.command && is_system_program(command)
+    }
+
+    /// Whether `command` is allowed by the rule, including the path of a file operation
+    fn allows(&self, command: &str, args: &[String]) -> bool {
+        if !self.matches_command(command) {
+            return false;
+        }
+        match file_path(command, args) {
+            Some(path) if !self.paths.is_empty() => {
+                self.paths.iter().any(|dir| Path::new(path).starts_with(dir))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Evaluate a request against a set of rules
+pub fn evaluate(rules: &[PolicyRule], peer_hostname: &str, command: &str, args: &[String]) -> Decision {
+    let peer_rules: Vec<&PolicyRule> = rules.iter().filter(|r| r.matches_peer(peer_hostname)).collect();
+
+    if program_name(command) == "sudo" {
+        // Only the system's sudo; any other program called sudo is just denied
+        if !is_system_program(command) {
+            return Decision::Deny(format!("'{}' is not the system sudo", command));
+        }
+        let (target, target_args) = match sudo_target(args) {
+            Ok(target) => target,
+            Err(reason) => return Decision::Deny(reason),
+        };
+        let matching: Vec<&&PolicyRule> = peer_rules.iter().filter(|r| r.allows(target, target_args)).collect();
+        if matching.is_empty() {
+            return Decision::Deny(format!("'{}' is not allowed for peer '{}'", target, peer_hostname));
+        }
+        if !matching.iter().any(|r| r.allow_sudo) {
+            return Decision::Deny(format!(
+                "sudo is not allowed for '{}' from peer '{}'",
+                target, peer_hostname
+            ));
+        }
+        return Decision::Allow;
+    }
+
+    if peer_rules.iter().any(|r| r.allows(command, args)) {
+        Decision::Allow
+    } else {
+        Decision::Deny(format!("'{}' is not allowed for peer '{}'", command, peer_hostname))
+    }
+}
+
+/// Check a request against the rules stored in the local database
+pub fn check(peer_hostname: &str, command: &str, args: &[String]) -> Decision {
+    if let Some(path) = requested_path(command, args) {
+        match protected_dirs() {
+            Ok(dirs) if is_protected(path, &dirs) => {
+                return Decision::Deny(format!("'{}' belongs to the agent", path));
+            }
+            Ok(_) => {}
+            Err(e) => return Decision::Deny(format!("failed to find the agent's directories: {}", e)),
+        }
+    }
+    match list_rules(None) {
+        Ok(rules) => evaluate(&rules, peer_hostname, command, args),
+        Err(e) => Decision::Deny(format!("failed to load execution policy: {}", e)),
+    }
+}
+
+/// List policy rules, optionally only those that apply to `peer_hostname`
+pub fn list_rules(peer_hostname: Option<&str>) -> Result<Vec<PolicyRule>> {
+    let rows = AgentExecPoliciesRow::query()
+        .order_by_peer_hostname_asc()
+        .order_by_command_asc()
+        .all()?;
+    let rules = rows.into_iter().map(|row| PolicyRule {
+        peer_hostname: row.peer_hostname,
+        command: row.command,
+        allow_sudo: row.allow_sudo != 0,
+        paths: row
+            .paths
+            .map(|paths| paths.split(',').map(str::to_string).collect())
+            .unwrap_or_default(),
+    });
+
+    Ok(match peer_hostname {
+        Some(peer) => rules.filter(|r| r.matches_peer(peer)).collect(),
+        None => rules.collect(),
+    })
+}
+
+/// Allow `peer_hostname` to run `command` (creates or updates the rule)
+///
+/// `paths` limits a file operation rule to those directories, which are
+/// resolved like the paths of requests.
+pub fn allow(peer_hostname: &str, command: &str, allow_sudo: bool, paths: &[String]) -> Result<()> {
+    let peer_hostname = normalize_rule_peer(peer_hostname);
+
+    if !paths.is_empty() && !is_file_command(command) {
+        anyhow::bail!("Only file operation rules (file:read etc.) can be limited to paths");
+    }
+    let mut resolved = Vec::new();
+    for path in paths {
+        let dir = crate::agent::file_ops::resolve_path(path)?;
+        let dir = dir
+            .to_str()
+            .filter(|dir| !dir.contains(','))
+            .ok_or_else(|| anyhow::anyhow!("'{}' can't be used as a rule path", dir.display()))?;
+        resolved.push(dir.to_string());
+    }
+
+    let data = AgentExecPoliciesRowData {
+        peer_hostname: peer_hostname.clone(),
+        command: command.to_string(),
+        allow_sudo: allow_sudo as i64,
+        paths: (!resolved.is_empty()).then(|| resolved.join(",")),
+    };
+
+    AgentExecPoliciesRow::query()
+        .peer_hostname()
+        .eq(peer_hostname)
+        .command()
+        .eq(command)
+        .upsert(data)?;
+
+    Ok(())
+}
+
+/// Remove the rule for `peer_hostname` and `command`, returning how many were deleted
+pub fn revoke(peer_hostname: &str, command: &str) -> Result<usize> {
+    AgentExecPoliciesRow::query()
+        .peer_hostname()
+        .eq(normalize_rule_peer(peer_hostname))
+        .command()
+        .eq(command)
+        .delete()
+}
+
+/// Record a rejected execution attempt
+pub fn record_denied(peer_hostname: &str, command: &str, args: &[String], reason: &str) -> Result<()> {
+    let data = AgentExecAuditRowData {
+        peer_hostname: peer_hostname.to_string(),
+        command: command.to_string(),
+        args: serde_json::to_string(args)?,
+        reason: reason.to_string(),
+    };
+
+    AgentExecAuditRow::insert_one(data)?;
+    Ok(())
+}
+
+/// Most recent rejected execution attempts, newest first
+pub fn recent_denials(limit: usize) -> Result<Vec<AgentExecAuditRow>> {
+    AgentExecAuditRow::query().order_by_created_at_desc().limit(limit).all()
+}
+
+fn normalize_rule_peer(peer_hostname: &str) -> String {
+    if peer_hostname == ANY {
+        ANY.to_string()
+    } else {
+        normalize_hostname(peer_hostname)
+    }
+}
+
+/// The program a sudo invocation runs, and its arguments
+///
+/// Options such as `-u`, `-g` or `-C` take a value that would otherwise be
+/// mistaken for the program, and others (`-i`, `-s`, `-E`) change what runs,
+/// so anything but the options in `SUDO_FLAGS` is refused.
+fn sudo_target(args: &[String]) -> Result<(&str, &[String]), String> {
+    let mut start = 0;
+    while let Some(arg) = args.get(start) {
+        if arg == "--" {
+            start += 1;
+            break;
+        }
+        if !arg.starts_with('-') {
+            break;
+        }
+        if !SUDO_FLAGS.contains(&arg.as_str()) {
+            return Err(format!("sudo option '{}' is not allowed", arg));
+        }
+        start += 1;
+    }
+    match args.get(start) {
+        Some(target) => Ok((target, &args[start + 1..])),
+        None => Err("sudo without a command is not allowed".to_string()),
+    }
+}
+
+fn is_file_command(command: &str) -> bool {
+    command.starts_with("file:")
+}
+
+/// The path a file operation is for
+fn file_path<'a>(command: &str, args: &'a [String]) -> Option<&'a str> {
+    is_file_command(command)
+        .then(|| args.first().map(String::as_str))
+        .flatten()
+}
+
+/// The path a file operation is for, run through sudo or not
+fn requested_path<'a>(command: &str, args: &'a [String]) -> Option<&'a str> {
+    if program_name(command) == "sudo" {
+        let (target, target_args) = sudo_target(args).ok()?;
+        return file_path(target, target_args);
+    }
+    file_path(command, args)
+}
+
+/// The agent's config directory (database, keys, certificates) and data directory
+fn protected_dirs() -> Result<Vec<PathBuf>> {
+    use halvor_core::config::config_manager;
+
+    let data_dir = config_manager::get_home_dir()?.join(".local").join("share").join("halvor");
+    [config_manager::get_config_dir()?, data_dir]
+        .into_iter()
+        .map(|dir| Ok(dir.canonicalize().unwrap_or(dir)))
+        .collect()
+}
+
+fn is_protected(path: &str, protected: &[PathBuf]) -> bool {
+    protected.iter().any(|dir| Path::new(path).starts_with(dir))
+}
+
+/// Whether `command` is run bare or from one of `SYSTEM_BIN_DIRS`
+fn is_system_program(command: &str) -> bool {
+    match Path::new(command).parent() {
+        Some(dir) if dir.as_os_str().is_empty() => true,
+        Some(dir) => SYSTEM_BIN_DIRS.iter().any(|bin| dir == Path::new(bin)),
+        None => false,
+    }
+}
+
+fn program_name(command: &str) -> &str {
+    Path::new(command)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(peer: &str, command: &str, allow_sudo: bool) -> PolicyRule {
+        PolicyRule {
+            peer_hostname: peer.to_string(),
+            command: command.to_string(),
+            allow_sudo,
+            paths: Vec::new(),
+        }
+    }
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_deny_by_default() {
+        assert!(matches!(evaluate(&[], "frigg", "ls", &[]), Decision::Deny(_)));
+    }
+
+    #[test]
+    fn test_allow_by_name_path_and_wildcard() {
+        let rules = vec![
+            rule("frigg", "uptime", false),
+            rule("frigg", "/usr/bin/docker", false),
+            rule("*", "hostname", false),
+        ];
+
+        assert_eq!(evaluate(&rules, "frigg", "uptime", &[]), Decision::Allow);
+        assert_eq!(evaluate(&rules, "frigg", "/usr/bin/uptime", &[]), Decision::Allow);
+        assert_eq!(evaluate(&rules, "frigg", "/usr/bin/docker", &[]), Decision::Allow);
+        assert!(matches!(evaluate(&rules, "frigg", "docker", &[]), Decision::Deny(_)));
+        assert_eq!(evaluate(&rules, "baulder", "hostname", &[]), Decision::Allow);
+        assert!(matches!(evaluate(&rules, "baulder", "uptime", &[]), Decision::Deny(_)));
+    }
+
+    #[test]
+    fn test_program_name_only_matches_system_directories() {
+        let rules = vec![rule("frigg", "systemctl", true)];
+
+        assert_eq!(evaluate(&rules, "frigg", "/usr/sbin/systemctl", &[]), Decision::Allow);
+        assert_eq!(evaluate(&rules, "frigg", "/bin/systemctl", &[]), Decision::Allow);
+        for command in ["/tmp/x/systemctl", "./systemctl", "bin/systemctl", "/usr/bin/../../tmp/systemctl"] {
+            assert!(matches!(evaluate(&rules, "frigg", command, &[]), Decision::Deny(_)), "{}", command);
+            assert!(
+                matches!(evaluate(&rules, "frigg", "sudo", &args(&[command])), Decision::Deny(_)),
+                "sudo {}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn test_sudo_requires_allow_sudo() {
+        let rules = vec![
+            rule("frigg", "systemctl", true),
+            rule("frigg", "apt", false),
+        ];
+
+        assert_eq!(
+            evaluate(&rules, "frigg", "sudo", &args(&["-n", "systemctl", "restart", "k3s"])),
+            Decision::Allow
+        );
+        assert!(matches!(
+            evaluate(&rules, "frigg", "sudo", &args(&["apt", "upgrade"])),
+            Decision::Deny(_)
+        ));
+        assert!(matches!(
+            evaluate(&rules, "frigg", "sudo", &args(&["rm", "-rf", "/"])),
+            Decision::Deny(_)
+        ));
+        assert!(matches!(evaluate(&rules, "frigg", "sudo", &args(&["-i"])), Decision::Deny(_)));
+        assert_eq!(
+            evaluate(&rules, "frigg", "sudo", &args(&["-n", "--", "systemctl", "status"])),
+            Decision::Allow
+        );
+    }
+
+    #[test]
+    fn test_only_system_sudo_is_sudo() {
+        let rules = vec![rule("frigg", "systemctl", true), rule("frigg", "*", false)];
+        let restart = args(&["-n", "systemctl", "restart", "k3s"]);
+
+        assert_eq!(evaluate(&rules, "frigg", "/usr/bin/sudo", &restart), Decision::Allow);
+        for command in ["/tmp/sudo", "./sudo", "/usr/bin/../../tmp/sudo"] {
+            assert!(matches!(evaluate(&rules, "frigg", command, &restart), Decision::Deny(_)), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_file_rules_limited_to_paths() {
+        let mut write = rule("frigg", "file:write", true);
+        write.paths = vec!["/srv/data".to_string()];
+        let rules = vec![write];
+
+        assert_eq!(evaluate(&rules, "frigg", "file:write", &args(&["/srv/data/app.toml"])), Decision::Allow);
+        for path in ["/srv/database", "/etc/passwd", "/srv"] {
+            assert!(
+                matches!(evaluate(&rules, "frigg", "file:write", &args(&[path])), Decision::Deny(_)),
+                "{}",
+                path
+            );
+        }
+        // Owner changes go through sudo with the same path
+        assert_eq!(
+            evaluate(&rules, "frigg", "sudo", &args(&["file:write", "/srv/data/app.toml"])),
+            Decision::Allow
+        );
+        assert!(matches!(
+            evaluate(&rules, "frigg", "sudo", &args(&["file:write", "/etc/shadow"])),
+            Decision::Deny(_)
+        ));
+    }
+
+    #[test]
+    fn test_agent_directories_are_protected() {
+        let protected = vec![PathBuf::from("/home/frigg/.config/halvor")];
+        let write = |path: &str| args(&["file:write", path]);
+
+        assert_eq!(requested_path("file:read", &args(&["/etc/motd"])), Some("/etc/motd"));
+        assert_eq!(requested_path("sudo", &write("/etc/motd")), Some("/etc/motd"));
+        assert_eq!(requested_path("ls", &args(&["/etc"])), None);
+
+        assert!(is_protected("/home/frigg/.config/halvor/halvor.db", &protected));
+        assert!(is_protected("/home/frigg/.config/halvor", &protected));
+        assert!(!is_protected("/home/frigg/.config/halvor-old/config", &protected));
+    }
+
+    #[test]
+    fn test_sudo_options_with_values_are_denied() {
+        let rules = vec![
+            rule("frigg", "docker", true),
+            rule("frigg", "systemctl", true),
+        ];
+
+        // The option's value names an allowed program, but sudo runs the shell
+        for invocation in [
+            &["-u", "docker", "/bin/sh", "-c", "id"][..],
+            &["-g", "systemctl", "/bin/sh"],
+            &["-n", "-u", "docker", "/bin/sh"],
+            &["-C", "docker", "/bin/sh"],
+            &["-udocker", "/bin/sh"],
+            &["--user=docker", "/bin/sh"],
+            &["-s", "docker"],
+            &["-E", "docker", "ps"],
+        ] {
+            assert!(
+                matches!(evaluate(&rules, "frigg", "sudo", &args(invocation)), Decision::Deny(_)),
+                "sudo {:?}",
+                invocation
+            );
+        }
+    }
+
+    #[test]
+    fn test_wildcard_command_does_not_grant_sudo() {
+        let rules = vec![rule("frigg", "*", false)];
+
+        assert_eq!(evaluate(&rules, "frigg", "ls", &[]), Decision::Allow);
+        assert!(matches!(
+            evaluate(&rules, "frigg", "sudo", &args(&["ls"])),
+            Decision::Deny(_)
+        ));
+    }
+}
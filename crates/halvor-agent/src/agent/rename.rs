@@ -0,0 +1,745 @@
+//! Renaming this node everywhere it is known by name
+//!
+//! A node's hostname is written into the `HOST_<name>_*` keys of the .env
+//! file, its `host_info` row, the mesh tables of every peer, k3s node labels
+//! and kubeconfig contexts. [`RenamePlan::new`] works out every change up
+//! front so it can be shown as a diff; [`RenamePlan::apply`] then makes them
+//! one step at a time, and undoes the steps it already made if one fails.
+//!
+//! The system hostname itself is left alone (changing it needs root). The
+//! node keeps the new name in the mesh regardless: it is stored as the mesh
+//! hostname override and the node gets a certificate for it, so it
+//! authenticates and signs as the new name from then on.
+//!
+//! Peers only accept a rename signed under the old name, and only talk to the
+//! node under the name they know it by. So the new certificate is fetched
+//! first, the rename is announced with the old one, and only then does the
+//! node switch; undoing it announces the way back before switching back.
+
+use crate::agent::gossip::{self, Gossip};
+use crate::agent::mesh;
+use crate::agent::tls;
+use anyhow::{Context, Result};
+use halvor_core::utils::exec::local;
+use halvor_core::utils::hostname::normalize_hostname;
+use std::fs;
+use std::path::PathBuf;
+
+/// Node labels kubelet sets from the system hostname; they follow it, not us
+const MANAGED_NODE_LABELS: &[&str] = &["kubernetes.io/hostname", "k3s.io/hostname"];
+
+/// One change made by a rename, and how to undo it
+#[derive(Debug, Clone)]
+pub enum RenameStep {
+    /// Replace the contents of a file, if it still holds `before`
+    EditFile {
+        title: String,
+        path: PathBuf,
+        before: String,
+        after: String,
+    },
+    /// Rename the node's `host_info` row
+    HostInfo,
+    /// Rename the node's own `agent_peers` row and keys, or create it
+    LocalPeer { create: bool },
+    /// Set k3s node labels that carry the old hostname to the new one
+    NodeLabels { labels: Vec<(String, String)> },
+    /// Get a certificate for the new name, tell every peer to rename this
+    /// node and switch to the new name; `previous` is the mesh hostname
+    /// override it replaces
+    MeshIdentity {
+        peers: usize,
+        previous: Option<String>,
+        certificate: bool,
+    },
+}
+
+/// Every change needed to rename this node from `old_hostname` to `new_hostname`
+#[derive(Debug, Clone)]
+pub struct RenamePlan {
+    pub old_hostname: String,
+    pub new_hostname: String,
+    pub steps: Vec<RenameStep>,
+    /// Things that were checked but can't or needn't be changed
+    pub notes: Vec<String>,
+}
+
+impl RenamePlan {
+    /// Work out the changes without making any
+    pub fn new(old_hostname: &str, new_hostname: &str) -> Result<Self> {
+        let old_hostname = normalize_hostname(old_hostname);
+        let new_hostname = normalize_hostname(new_hostname);
+        validate_hostname(&new_hostname)?;
+        if old_hostname == new_hostname {
+            anyhow::bail!("Hostname is already '{}'", new_hostname);
+        }
+
+        let mut plan = Self {
+            old_hostname,
+            new_hostname,
+            steps: Vec::new(),
+            notes: Vec::new(),
+        };
+        plan.plan_env_file()?;
+        plan.plan_host_info()?;
+        plan.plan_local_peer()?;
+        plan.plan_kubeconfig()?;
+        plan.plan_node_labels();
+        plan.plan_mesh_identity()?;
+        Ok(plan)
+    }
+
+    /// Lines describing every change, `-`/`+` prefixed where something is replaced
+    pub fn diff(&self) -> Vec<String> {
+        let (old, new) = (&self.old_hostname, &self.new_hostname);
+        let mut lines = Vec::new();
+        for step in &self.steps {
+            lines.push(format!("{}:", step.title()));
+            match step {
+                RenameStep::EditFile { before, after, .. } => {
+                    lines.extend(line_diff(before, after).into_iter().map(|line| format!("  {}", line)));
+                }
+                RenameStep::HostInfo => {
+                    lines.push(format!("  - hostname = {}", old));
+                    lines.push(format!("  + hostname = {}", new));
+                }
+                RenameStep::LocalPeer { create: false } => {
+                    lines.push(format!("  - agent_peers/peer_keys: {}", old));
+                    lines.push(format!("  + agent_peers/peer_keys: {}", new));
+                }
+                RenameStep::LocalPeer { create: true } => {
+                    lines.push(format!("  + agent_peers: {}", new));
+                }
+                RenameStep::NodeLabels { labels } => {
+                    for (node, key) in labels {
+                        lines.push(format!("  - {} {}={}", node, key, old));
+                        lines.push(format!("  + {} {}={}", node, key, new));
+                    }
+                }
+                RenameStep::MeshIdentity { peers, certificate, .. } => {
+                    if *certificate {
+                        lines.push(format!("  issue TLS certificate for {}", new));
+                    }
+                    if *peers > 0 {
+                        lines.push(format!("  announce {} -> {} to {} peer(s)", old, new, peers));
+                    }
+                    lines.push(format!("  - mesh hostname = {}", old));
+                    lines.push(format!("  + mesh hostname = {}", new));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Make every change in order, rolling back the ones already made if one fails
+    pub fn apply(&self) -> Result<()> {
+        for (index, step) in self.steps.iter().enumerate() {
+            match step.apply(&self.old_hostname, &self.new_hostname) {
+                Ok(()) => println!("  ✓ {}", step.title()),
+                Err(e) => {
+                    println!("  ✗ {}: {:#}", step.title(), e);
+                    let failed_rollbacks = self.rollback(&self.steps[..index]);
+                    let outcome = if failed_rollbacks > 0 {
+                        format!("{} change(s) could not be rolled back, see above", failed_rollbacks)
+                    } else {
+                        format!("{} earlier change(s) rolled back", index)
+                    };
+                    return Err(e.context(format!("Rename failed at '{}'; {}", step.title(), outcome)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo `done` in reverse order, returning how many couldn't be undone
+    fn rollback(&self, done: &[RenameStep]) -> usize {
+        let mut failed = 0;
+        for step in done.iter().rev() {
+            match step.rollback(&self.old_hostname, &self.new_hostname) {
+                Ok(()) => println!("  ↺ Rolled back: {}", step.title()),
+                Err(e) => {
+                    println!("  ⚠ Failed to roll back '{}': {:#}", step.title(), e);
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    fn plan_env_file(&mut self) -> Result<()> {
+        let Ok(halvor_dir) = halvor_core::config::find_halvor_dir() else {
+            self.notes.push("No halvor config directory, .env not checked".to_string());
+            return Ok(());
+        };
+        let path = halvor_dir.join(".env");
+        if !path.exists() {
+            return Ok(());
+        }
+        let before = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read .env file: {}", path.display()))?;
+
+        let new_prefix = format!("HOST_{}_", self.new_hostname.to_uppercase());
+        if before
+            .lines()
+            .any(|line| line.trim_start().trim_start_matches("export ").starts_with(&new_prefix))
+        {
+            anyhow::bail!(
+                "{} already has {}* keys; remove them first",
+                path.display(),
+                new_prefix
+            );
+        }
+
+        let after =
+            halvor_core::config::env_file::rename_host_keys(&before, &self.old_hostname, &self.new_hostname);
+        if after != before {
+            self.steps.push(RenameStep::EditFile {
+                title: format!(".env host keys ({})", path.display()),
+                path,
+                before,
+                after,
+            });
+        }
+        Ok(())
+    }
+
+    fn plan_host_info(&mut self) -> Result<()> {
+        let find = |hostname: &String| {
+            halvor_db::host_info::HostInfoRow::query().hostname().eq(hostname).first()
+        };
+        if find(&self.old_hostname)?.is_none() {
+            return Ok(());
+        }
+        if find(&self.new_hostname)?.is_some() {
+            anyhow::bail!(
+                "host_info already has an entry for '{}'; remove it first",
+                self.new_hostname
+            );
+        }
+        self.steps.push(RenameStep::HostInfo);
+        Ok(())
+    }
+
+    fn plan_local_peer(&mut self) -> Result<()> {
+        if mesh::find_peer(&self.new_hostname)?.is_some() {
+            anyhow::bail!("'{}' is already the name of a mesh peer", self.new_hostname);
+        }
+        let create = mesh::find_peer(&self.old_hostname)?.is_none();
+        self.steps.push(RenameStep::LocalPeer { create });
+        Ok(())
+    }
+
+    fn plan_kubeconfig(&mut self) -> Result<()> {
+        let Some(path) = kubeconfig_path() else {
+            return Ok(());
+        };
+        if !path.exists() {
+            return Ok(());
+        }
+        let before = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read kubeconfig: {}", path.display()))?;
+        let after = rename_in_kubeconfig(&before, &self.old_hostname, &self.new_hostname);
+        if after != before {
+            self.steps.push(RenameStep::EditFile {
+                title: format!("kubeconfig contexts ({})", path.display()),
+                path,
+                before,
+                after,
+            });
+        }
+        Ok(())
+    }
+
+    fn plan_node_labels(&mut self) {
+        if !local::check_command_exists("kubectl") {
+            return;
+        }
+        let nodes = local::execute("kubectl", &["get", "nodes", "-o", "json", "--request-timeout=5s"])
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| serde_json::from_slice::<serde_json::Value>(&output.stdout).ok());
+        let Some(nodes) = nodes else {
+            self.notes.push("k3s cluster not reachable, node labels not checked".to_string());
+            return;
+        };
+
+        let mut labels = Vec::new();
+        let mut managed = false;
+        for node in nodes["items"].as_array().into_iter().flatten() {
+            let name = node["metadata"]["name"].as_str().unwrap_or_default();
+            let Some(node_labels) = node["metadata"]["labels"].as_object() else {
+                continue;
+            };
+            for (key, value) in node_labels {
+                if value.as_str() != Some(self.old_hostname.as_str()) {
+                    continue;
+                }
+                if MANAGED_NODE_LABELS.contains(&key.as_str()) {
+                    managed = true;
+                } else {
+                    labels.push((name.to_string(), key.clone()));
+                }
+            }
+        }
+        if managed {
+            self.notes.push(format!(
+                "k3s keeps the node name and {} until the system hostname changes and k3s restarts",
+                MANAGED_NODE_LABELS.join("/")
+            ));
+        }
+        if !labels.is_empty() {
+            self.steps.push(RenameStep::NodeLabels { labels });
+        }
+    }
+
+    fn plan_mesh_identity(&mut self) -> Result<()> {
+        let peers = mesh::get_active_peers()?
+            .into_iter()
+            .filter(|peer| {
+                let peer = normalize_hostname(peer);
+                peer != self.old_hostname && peer != self.new_hostname
+            })
+            .count();
+        let certificate = tls::node_certificate()?.is_some();
+        if peers > 0 && !certificate {
+            anyhow::bail!(
+                "Peers only accept a rename signed by this node; it needs a mesh certificate first"
+            );
+        }
+        self.steps.push(RenameStep::MeshIdentity {
+            peers,
+            previous: mesh::mesh_hostname_override()?,
+            certificate,
+        });
+        Ok(())
+    }
+}
+
+impl RenameStep {
+    pub fn title(&self) -> String {
+        match self {
+            RenameStep::EditFile { title, .. } => title.clone(),
+            RenameStep::HostInfo => "host_info entry".to_string(),
+            RenameStep::LocalPeer { .. } => "Local mesh database".to_string(),
+            RenameStep::NodeLabels { .. } => "k3s node labels".to_string(),
+            RenameStep::MeshIdentity { .. } => "Mesh identity and peers".to_string(),
+        }
+    }
+
+    fn apply(&self, old: &str, new: &str) -> Result<()> {
+        match self {
+            RenameStep::EditFile { path, before, after, .. } => replace_file(path, before, after),
+            RenameStep::HostInfo => rename_host_info(old, new),
+            RenameStep::LocalPeer { create: false } => {
+                if !mesh::rename_peer(old, new)? {
+                    anyhow::bail!("'{}' is no longer in the mesh database", old);
+                }
+                Ok(())
+            }
+            RenameStep::LocalPeer { create: true } => create_local_peer(new),
+            RenameStep::NodeLabels { labels } => {
+                for (index, (node, key)) in labels.iter().enumerate() {
+                    if let Err(e) = set_node_label(node, key, new) {
+                        // Put back the labels already changed, so the step is all or nothing
+                        for (node, key) in &labels[..index] {
+                            let _ = set_node_label(node, key, old);
+                        }
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }
+            RenameStep::MeshIdentity { peers, previous, certificate } => {
+                switch_identity(&mut MeshSwitch::new(new, *peers, previous, *certificate), old, new)
+            }
+        }
+    }
+
+    fn rollback(&self, old: &str, new: &str) -> Result<()> {
+        match self {
+            RenameStep::EditFile { path, before, after, .. } => replace_file(path, after, before),
+            RenameStep::HostInfo => rename_host_info(new, old),
+            RenameStep::LocalPeer { create: false } => mesh::rename_peer(new, old).map(|_| ()),
+            RenameStep::LocalPeer { create: true } => {
+                halvor_db::generated::AgentPeersRow::delete_by_hostname(new)?;
+                Ok(())
+            }
+            RenameStep::NodeLabels { labels } => labels
+                .iter()
+                .try_for_each(|(node, key)| set_node_label(node, key, old)),
+            RenameStep::MeshIdentity { peers, previous, certificate } => {
+                revert_identity(&mut MeshSwitch::new(new, *peers, previous, *certificate), old, new)
+            }
+        }
+    }
+}
+
+/// Kubeconfig kubectl uses: the first `KUBECONFIG` entry, or ~/.kube/config
+fn kubeconfig_path() -> Option<PathBuf> {
+    if let Ok(paths) = std::env::var("KUBECONFIG")
+        && let Some(first) = std::env::split_paths(&paths).next()
+        && !first.as_os_str().is_empty()
+    {
+        return Some(first);
+    }
+    std::env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".kube").join("config"))
+}
+
+/// Rename clusters, contexts and users called `old`
+///
+/// Works line by line so comments and formatting survive. `server:` URLs are
+/// left alone: the system hostname (and so DNS and the API server
+/// certificate) keeps the old name.
+fn rename_in_kubeconfig(content: &str, old: &str, new: &str) -> String {
+    let mut renamed: Vec<String> = content
+        .lines()
+        .map(|line| {
+            let body = line.trim_start().trim_start_matches("- ");
+            let prefix = &line[..line.len() - body.len()];
+            let Some((key, value)) = body.split_once(':') else {
+                return line.to_string();
+            };
+            let value = value.trim();
+            let unquoted = value.trim_matches(|c| c == '"' || c == '\'');
+
+            match key {
+                "name" | "cluster" | "user" | "current-context" if unquoted == old => {
+                    format!("{}{}: {}", prefix, key, value.replace(old, new))
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect();
+    if content.ends_with('\n') {
+        renamed.push(String::new());
+    }
+    renamed.join("\n")
+}
+
+/// `-`/`+` lines for the lines that differ between two versions of a file
+///
+/// Renames never add or remove lines, so lines are compared pairwise. Values
+/// of secret-looking keys are masked.
+fn line_diff(before: &str, after: &str) -> Vec<String> {
+    before
+        .lines()
+        .zip(after.lines())
+        .filter(|(old, new)| old != new)
+        .flat_map(|(old, new)| [format!("- {}", mask_secret(old)), format!("+ {}", mask_secret(new))])
+        .collect()
+}
+
+fn mask_secret(line: &str) -> String {
+    let Some((key, _)) = line.split_once('=') else {
+        return line.to_string();
+    };
+    let upper = key.to_uppercase();
+    if ["PASS", "PASSWORD", "SECRET", "TOKEN", "KEY"]
+        .iter()
+        .any(|word| upper.ends_with(&format!("_{}", word)))
+    {
+        format!("{}=********", key)
+    } else {
+        line.to_string()
+    }
+}
+
+fn validate_hostname(hostname: &str) -> Result<()> {
+    let valid = !hostname.is_empty()
+        && hostname.len() <= 63
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        anyhow::bail!(
+            "'{}' is not a valid hostname (letters, digits and '-', at most 63 characters)",
+            hostname
+        );
+    }
+    Ok(())
+}
+
+/// Write `after` to `path`, unless it no longer holds `before`
+fn replace_file(path: &PathBuf, before: &str, after: &str) -> Result<()> {
+    let current =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if current != before {
+        anyhow::bail!("{} changed since the rename was planned", path.display());
+    }
+    fs::write(path, after).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn rename_host_info(from: &str, to: &str) -> Result<()> {
+    let conn = halvor_db::get_connection()?;
+    let updated = conn.execute(
+        "UPDATE host_info SET hostname = ?1, updated_at = ?2 WHERE hostname = ?3",
+        rusqlite::params![to, chrono::Utc::now().timestamp(), from],
+    )?;
+    if updated == 0 {
+        anyhow::bail!("host_info has no entry for '{}'", from);
+    }
+    Ok(())
+}
+
+/// Record this node in its own peer table, as `halvor agent hostname` always has
+fn create_local_peer(hostname: &str) -> Result<()> {
+    use crate::apps::tailscale;
+
+    let now = chrono::Utc::now().timestamp();
+    halvor_db::generated::AgentPeersRow::insert_one(halvor_db::generated::AgentPeersRowData {
+        hostname: hostname.to_string(),
+        tailscale_ip: tailscale::get_tailscale_ip().ok().flatten(),
+        tailscale_hostname: tailscale::get_tailscale_hostname().ok().flatten(),
+        public_key: format!("pk_{}", uuid::Uuid::new_v4()),
+        status: "active".to_string(),
+        last_seen_at: Some(now),
+        joined_at: now,
+    })?;
+    Ok(())
+}
+
+fn set_node_label(node: &str, key: &str, value: &str) -> Result<()> {
+    let label = format!("{}={}", key, value);
+    let output = local::execute("kubectl", &["label", "node", node, &label, "--overwrite"])?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "kubectl label node {} {}: {}",
+            node,
+            label,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The parts of moving this node's mesh identity between names, apart from
+/// the order they run in
+trait IdentitySwitch {
+    /// Get a certificate for `hostname` without using it yet
+    fn prepare(&mut self, hostname: &str) -> Result<()>;
+    /// Tell the mesh that `from` is now called `to`, signed as `from`
+    fn announce(&mut self, from: &str, to: &str) -> Result<()>;
+    /// Use `hostname` as this node's mesh hostname, with its certificate
+    fn switch_to(&mut self, hostname: &str) -> Result<()>;
+}
+
+/// Move from `old` to `new`, telling peers while they still accept `old`
+///
+/// Anything that can fail without the mesh noticing happens first.
+fn switch_identity(switch: &mut impl IdentitySwitch, old: &str, new: &str) -> Result<()> {
+    switch.prepare(new)?;
+    switch.announce(old, new)?;
+    switch.switch_to(new).with_context(|| {
+        format!("Peers now know this node as '{}', but it failed to switch to that name", new)
+    })
+}
+
+/// Move back from `new` to `old`, announcing it while this node still holds `new`
+fn revert_identity(switch: &mut impl IdentitySwitch, old: &str, new: &str) -> Result<()> {
+    switch.announce(new, old)?;
+    switch.switch_to(old)
+}
+
+/// [`IdentitySwitch`] for this node's real mesh identity
+struct MeshSwitch {
+    renamed_to: String,
+    peers: usize,
+    previous: Option<String>,
+    certificate: bool,
+    pending: Option<tls::PendingCertificate>,
+}
+
+impl MeshSwitch {
+    fn new(renamed_to: &str, peers: usize, previous: &Option<String>, certificate: bool) -> Self {
+        Self {
+            renamed_to: renamed_to.to_string(),
+            peers,
+            previous: previous.clone(),
+            certificate,
+            pending: None,
+        }
+    }
+}
+
+impl IdentitySwitch for MeshSwitch {
+    fn prepare(&mut self, hostname: &str) -> Result<()> {
+        if self.certificate {
+            self.pending = Some(tls::certificate_for_rename(hostname)?);
+        }
+        Ok(())
+    }
+
+    fn announce(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.peers == 0 {
+            return Ok(());
+        }
+        announce_rename(from, to, self.peers)
+    }
+
+    fn switch_to(&mut self, hostname: &str) -> Result<()> {
+        let renamed = normalize_hostname(hostname) == normalize_hostname(&self.renamed_to);
+        mesh::set_mesh_hostname_override(if renamed { Some(hostname) } else { self.previous.as_deref() })?;
+        match self.pending.take() {
+            Some(pending) => pending.install().map(|_| ()),
+            // Switching back: the CA knows this node by its old name again
+            None if self.certificate => tls::renew_node_certificate(None).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Send the rename to every peer; they relay it to nodes this one doesn't know
+///
+/// Fails if no peer took it, including when they all refused it.
+fn announce_rename(from: &str, to: &str, peers: usize) -> Result<()> {
+    let notified = Gossip::new(peers).broadcast(gossip::peer_renamed(from, to)?);
+    if notified == 0 {
+        anyhow::bail!("None of the {} peer(s) accepted the rename", peers);
+    }
+    if notified < peers {
+        println!(
+            "  ⚠ Only {} of {} peer(s) reached; the rest hear of it through them",
+            notified, peers
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_env_keys_keeps_everything_else() {
+        let env = "# frigg\nHOST_FRIGG_IP=10.0.0.1\nexport HOST_FRIGG_SUDO_PASS=hunter2\nHOST_FRIGGA_IP=10.0.0.2\nTAILNET_BASE=ts.net\n";
+        let renamed = halvor_core::config::env_file::rename_host_keys(env, "frigg", "odin");
+        assert_eq!(
+            renamed,
+            "# frigg\nHOST_ODIN_IP=10.0.0.1\nexport HOST_ODIN_SUDO_PASS=hunter2\nHOST_FRIGGA_IP=10.0.0.2\nTAILNET_BASE=ts.net\n"
+        );
+        assert_eq!(
+            line_diff(env, &renamed),
+            vec![
+                "- HOST_FRIGG_IP=10.0.0.1",
+                "+ HOST_ODIN_IP=10.0.0.1",
+                "- export HOST_FRIGG_SUDO_PASS=********",
+                "+ export HOST_ODIN_SUDO_PASS=********",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_in_kubeconfig() {
+        let config = "\
+clusters:
+- cluster:
+    server: https://frigg.tail1234.ts.net:6443
+  name: frigg
+- cluster:
+    server: https://friggs-backup:6443
+  name: default
+contexts:
+- context:
+    cluster: frigg
+    user: \"frigg\"
+  name: frigg
+current-context: frigg
+";
+        let renamed = rename_in_kubeconfig(config, "frigg", "odin");
+        assert_eq!(
+            renamed,
+            "\
+clusters:
+- cluster:
+    server: https://frigg.tail1234.ts.net:6443
+  name: odin
+- cluster:
+    server: https://friggs-backup:6443
+  name: default
+contexts:
+- context:
+    cluster: odin
+    user: \"odin\"
+  name: odin
+current-context: odin
+"
+        );
+        // Nothing to rename leaves the file as it was
+        assert_eq!(rename_in_kubeconfig(config, "baulder", "odin"), config);
+    }
+
+    #[test]
+    fn test_hostname_checks() {
+        assert!(validate_hostname("odin-2").is_ok());
+        assert!(validate_hostname("").is_err());
+        assert!(validate_hostname("-odin").is_err());
+        assert!(validate_hostname("odin_2").is_err());
+    }
+
+    /// Records the calls made to it, failing the ones named in `fail`
+    #[derive(Default)]
+    struct FakeSwitch {
+        calls: Vec<String>,
+        fail: Vec<&'static str>,
+    }
+
+    impl FakeSwitch {
+        fn record(&mut self, call: String, kind: &str) -> Result<()> {
+            self.calls.push(call);
+            if self.fail.contains(&kind) {
+                anyhow::bail!("{} failed", kind);
+            }
+            Ok(())
+        }
+    }
+
+    impl IdentitySwitch for FakeSwitch {
+        fn prepare(&mut self, hostname: &str) -> Result<()> {
+            self.record(format!("prepare {}", hostname), "prepare")
+        }
+
+        fn announce(&mut self, from: &str, to: &str) -> Result<()> {
+            self.record(format!("announce {} -> {}", from, to), "announce")
+        }
+
+        fn switch_to(&mut self, hostname: &str) -> Result<()> {
+            self.record(format!("switch to {}", hostname), "switch")
+        }
+    }
+
+    #[test]
+    fn test_identity_switch_order_and_rollback() {
+        let mut switch = FakeSwitch::default();
+        switch_identity(&mut switch, "frigg", "odin").unwrap();
+        revert_identity(&mut switch, "frigg", "odin").unwrap();
+        assert_eq!(
+            switch.calls,
+            vec![
+                "prepare odin",
+                "announce frigg -> odin",
+                "switch to odin",
+                // The way back is announced while still holding the new name
+                "announce odin -> frigg",
+                "switch to frigg",
+            ]
+        );
+
+        // Failing to get a certificate for the new name leaves peers untouched
+        let mut switch = FakeSwitch {
+            fail: vec!["prepare"],
+            ..Default::default()
+        };
+        assert!(switch_identity(&mut switch, "frigg", "odin").is_err());
+        assert_eq!(switch.calls, vec!["prepare odin"]);
+
+        // Peers refusing the way back fail the rollback, and the node keeps its new name
+        let mut switch = FakeSwitch {
+            fail: vec!["announce"],
+            ..Default::default()
+        };
+        assert!(revert_identity(&mut switch, "frigg", "odin").is_err());
+        assert_eq!(switch.calls, vec!["announce odin -> frigg"]);
+    }
+}
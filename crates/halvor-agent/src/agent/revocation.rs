@@ -0,0 +1,261 @@
+//! Revoking a peer's mesh membership
+//!
+//! `halvor agent remove` signs a [`PeerRevocation`] with the removing
+//! node's certificate key and broadcasts it as `PeerRevoked`. Every node that
+//! accepts the signature drops the peer and its shared secret, revokes the
+//! certificates it issued to it and records the revocation. Recorded
+//! revocations are passed on during database sync, so syncing with a node
+//! that missed the broadcast doesn't bring the peer back.
+//!
+//! A revocation names the key the peer held: the same host can be admitted
+//! again with a new join token, which gives it a new key.
+
+use crate::agent::api::AgentClient;
+use crate::agent::mesh;
+use crate::agent::mesh_protocol::MessagePayload;
+use crate::agent::tls::{self, NodePublicKey, NodeSignature};
+use anyhow::{Context, Result};
+use halvor_core::utils::hostname::normalize_hostname;
+use halvor_db::generated::RevokedPeersRow;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A signed statement that a peer is no longer part of the mesh
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerRevocation {
+    /// Mesh hostname of the revoked peer
+    pub hostname: String,
+    /// Public key the peer held when it was revoked
+    pub public_key: String,
+    /// Node that revoked the peer, and signed this revocation
+    pub revoked_by: String,
+    pub revoked_at: i64,
+    pub signature: NodeSignature,
+}
+
+impl PeerRevocation {
+    /// Revoke `hostname`, a peer of this node, signed with this node's key
+    pub fn sign(hostname: &str) -> Result<Self> {
+        let peer = halvor_db::generated::AgentPeersRow::query()
+            .hostname()
+            .eq(hostname)
+            .first()?
+            .with_context(|| format!("'{}' is not a mesh peer of this host", hostname))?;
+
+        let mut revocation = Self {
+            hostname: normalize_hostname(&peer.hostname),
+            public_key: peer.public_key,
+            revoked_by: mesh::local_mesh_hostname(),
+            revoked_at: chrono::Utc::now().timestamp(),
+            signature: NodeSignature {
+                certificate: String::new(),
+                signature: String::new(),
+            },
+        };
+        revocation.signature = tls::sign_with_node_key(&revocation.signed_bytes())?.context(
+            "This host has no mesh certificate to sign the revocation with (see `halvor agent cert rotate`)",
+        )?;
+        Ok(revocation)
+    }
+
+    /// Check the signature against the revoking node's certificate
+    pub fn verify(&self) -> Result<()> {
+        tls::verify_node_signature(&self.revoked_by, &self.signed_bytes(), &self.signature)
+            .with_context(|| format!("Invalid revocation of {} by {}", self.hostname, self.revoked_by))
+    }
+
+    /// Whether this revocation applies to the peer while it holds `public_key`
+    ///
+    /// Only a real node key other than the revoked one (the host joined again)
+    /// lifts it; placeholder keys from agents without TLS don't.
+    pub fn covers(&self, public_key: Option<&str>) -> bool {
+        match public_key {
+            Some(key) if key != self.public_key => NodePublicKey::decode(key).is_none(),
+            _ => true,
+        }
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "halvor peer revocation\n{}\n{}\n{}\n{}",
+            normalize_hostname(&self.hostname),
+            self.public_key,
+            normalize_hostname(&self.revoked_by),
+            self.revoked_at
+        )
+        .into_bytes()
+    }
+}
+
+/// Verify and apply a revocation from another node (or this one)
+///
+/// Drops the peer and its shared secret, unless it has joined again with a
+/// new key since, and revokes the certificates this node issued to it. Returns
+/// false if the revocation was already known.
+pub fn apply(revocation: &PeerRevocation) -> Result<bool> {
+    let hostname = normalize_hostname(&revocation.hostname);
+    if hostname == normalize_hostname(&mesh::local_mesh_hostname()) {
+        anyhow::bail!("Refusing to record a revocation of this host");
+    }
+    if let Some(known) = find(&hostname)?
+        && known.revoked_at >= revocation.revoked_at
+    {
+        return Ok(false);
+    }
+    revocation.verify()?;
+
+    let peer = match mesh::find_peer(&hostname)? {
+        Some(stored) => halvor_db::generated::AgentPeersRow::query().hostname().eq(stored).first()?,
+        None => None,
+    };
+    let now = chrono::Utc::now().timestamp();
+
+    let removed = halvor_db::with_transaction(|tx| {
+        tx.execute(
+            "INSERT INTO revoked_peers
+                (id, hostname, public_key, revoked_by, revoked_at, revocation, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)
+             ON CONFLICT(hostname) DO UPDATE SET
+                public_key = excluded.public_key,
+                revoked_by = excluded.revoked_by,
+                revoked_at = excluded.revoked_at,
+                revocation = excluded.revocation,
+                updated_at = excluded.updated_at",
+            rusqlite::params![
+                Uuid::new_v4().to_string(),
+                hostname,
+                revocation.public_key,
+                revocation.revoked_by,
+                revocation.revoked_at,
+                serde_json::to_string(revocation)?,
+                now,
+            ],
+        )?;
+        // Foreign keys aren't enforced, so peer_keys rows are removed explicitly
+        let removed = peer
+            .as_ref()
+            .filter(|peer| revocation.covers(Some(&peer.public_key)))
+            .map(|peer| peer.hostname.clone());
+        if let Some(stored) = &removed {
+            tx.execute("DELETE FROM agent_peers WHERE hostname = ?1", rusqlite::params![stored])?;
+            tx.execute("DELETE FROM peer_keys WHERE peer_hostname = ?1", rusqlite::params![stored])?;
+        }
+        Ok(removed)
+    })?;
+
+    if let Some(stored) = &removed {
+        tls::revoke_peer_certificates(stored)?;
+    }
+    Ok(true)
+}
+
+/// The recorded revocation of `hostname`, if any
+pub fn find(hostname: &str) -> Result<Option<PeerRevocation>> {
+    let hostname = normalize_hostname(hostname);
+    let row = RevokedPeersRow::query().hostname().eq(hostname).first()?;
+    row.map(|row| serde_json::from_str(&row.revocation).context("Invalid stored revocation"))
+        .transpose()
+}
+
+/// Every recorded revocation, oldest first
+pub fn list() -> Result<Vec<PeerRevocation>> {
+    RevokedPeersRow::query().order_by_revoked_at_asc().all()?
+        .into_iter()
+        .map(|row| serde_json::from_str(&row.revocation).context("Invalid stored revocation"))
+        .collect()
+}
+
+/// Whether `hostname` has been revoked while holding `public_key`
+///
+/// Without a key, any revocation of the host counts.
+pub fn is_revoked(hostname: &str, public_key: Option<&str>) -> Result<bool> {
+    Ok(find(hostname)?.is_some_and(|revocation| revocation.covers(public_key)))
+}
+
+/// Send `revocation` to the revoked peer itself, asking it to wipe its mesh state
+///
+/// Must happen before the revocation is applied here, while this node still
+/// shares a secret with the peer.
+pub fn request_wipe(revocation: &PeerRevocation) -> Result<()> {
+    let peer = mesh::find_peer(&revocation.hostname)?
+        .with_context(|| format!("'{}' is not a mesh peer of this host", revocation.hostname))?;
+    let client = AgentClient::new(&mesh::peer_address(&peer)?, mesh::DEFAULT_AGENT_PORT).with_peer(&peer)?;
+    let payload = MessagePayload::PeerRevoked {
+        revocation: revocation.clone(),
+        wipe: true,
+    };
+    match client.send_to_peer(payload)?.map(|reply| reply.payload) {
+        Some(MessagePayload::Ack { .. }) => Ok(()),
+        Some(MessagePayload::Error { code, message }) => anyhow::bail!("{} ({})", message, code),
+        _ => anyhow::bail!("{} did not confirm the wipe", peer),
+    }
+}
+
+/// Forget this node's mesh membership, after being revoked with a wipe
+///
+/// Drops every peer, shared secret, join token and revocation, and this
+/// node's certificates, keys and CA. Exec policies and replicated config are
+/// left alone.
+pub fn wipe_mesh_state() -> Result<()> {
+    halvor_db::with_transaction(|tx| {
+        for table in ["agent_peers", "peer_keys", "join_tokens", "revoked_peers", "mesh_certificates"] {
+            tx.execute(&format!("DELETE FROM {}", table), [])?;
+        }
+        tx.execute("DELETE FROM settings WHERE key LIKE 'sync_cursor:%'", [])?;
+        Ok(())
+    })?;
+
+    tls::remove_mesh_identity()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revocation(public_key: &str) -> PeerRevocation {
+        PeerRevocation {
+            hostname: "heimdall".to_string(),
+            public_key: public_key.to_string(),
+            revoked_by: "frigg".to_string(),
+            revoked_at: 1_700_000_000,
+            signature: NodeSignature {
+                certificate: String::new(),
+                signature: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_revocation_covers_old_keys_only() {
+        let old_key = tls::encode_public_key(&tls::generate_node_key().unwrap());
+        let new_key = tls::encode_public_key(&tls::generate_node_key().unwrap());
+        let revoked = revocation(&old_key);
+
+        assert!(revoked.covers(None));
+        assert!(revoked.covers(Some(&old_key)));
+        // Stale copies of the peer with a placeholder key stay revoked
+        assert!(revoked.covers(Some("pk_1234")));
+        // Joining again gives the host a new key
+        assert!(!revoked.covers(Some(&new_key)));
+    }
+
+    #[test]
+    fn test_signed_bytes_cover_every_field() {
+        let revoked = revocation("pk_1234");
+        let mut other = revoked.clone();
+        other.revoked_at += 1;
+        assert_ne!(revoked.signed_bytes(), other.signed_bytes());
+
+        let mut other = revoked.clone();
+        other.hostname = "frigg".to_string();
+        assert_ne!(revoked.signed_bytes(), other.signed_bytes());
+
+        let mut other = revoked.clone();
+        other.revoked_by = "baulder".to_string();
+        assert_ne!(revoked.signed_bytes(), other.signed_bytes());
+
+        let mut other = revoked.clone();
+        other.public_key = "pk_5678".to_string();
+        assert_ne!(revoked.signed_bytes(), other.signed_bytes());
+    }
+}
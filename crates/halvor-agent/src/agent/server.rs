@@ -1,8 +1,68 @@
-use halvor_core::utils::{bytes_to_string, format_bind_address, read_json, write_json};
+use halvor_core::utils::{
+    DEFAULT_MAX_FRAME_SIZE, FrameError, bytes_to_string, format_bind_address, read_json_async,
+    write_json_async, write_legacy_json_async,
+};
+use crate::agent::auth::{self, PeerAuth, ReplayGuard};
+use crate::agent::exec_stream::{ExecFrame, ExitStatus, OutputStream};
+use crate::agent::file_ops::{FileOp, FileOpOutput};
+use crate::agent::gossip::{self, Gossip};
+use crate::agent::handshake::{self, Hello};
+use crate::agent::heartbeat::{self, HeartbeatConfig};
+use crate::agent::mdns::{self, AgentAdvertisement, MdnsAdvertiser};
+use crate::agent::mesh_config::{self, ConfigEntry};
+use crate::agent::mesh_protocol::{BROADCAST, MeshMessage, MessageHandler, MessagePayload, MessageRouter};
+use crate::agent::policy::{self, Decision};
+use crate::agent::tls::{self, IssuedCertificate, NodePublicKey, ServerTls};
+use crate::agent::transfer::FileTransferHandler;
 use anyhow::{Context, Result};
 use base64::Engine;
+use halvor_core::utils::hostname::normalize_hostname;
 use serde::{Deserialize, Serialize};
-use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, mpsc};
+use tokio::time::{Instant, sleep_until, timeout};
+use tokio_rustls::TlsAcceptor;
+
+/// Default maximum number of concurrent connections
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Default timeout for reading a request or writing a response
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default timeout for handling a single request
+pub const DEFAULT_HANDLER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long in-flight connections get to finish after a shutdown signal
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// How long database changes stay in the change log for incremental syncs;
+/// peers that haven't synced for longer get a full sync
+const CHANGE_LOG_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// How often the agent checks whether its TLS certificate needs renewing
+const CERT_RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Largest chunk of program output sent in one exec stream frame
+const EXEC_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Frames buffered between an exec stream and the program before applying backpressure
+const EXEC_CHANNEL_CAPACITY: usize = 32;
+
+/// How long to keep forwarding output after a streamed program exits; background
+/// processes it started may hold its stdout open indefinitely
+const EXEC_OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Busy replies sent at once when at the connection limit; beyond that,
+/// connections are just closed
+const MAX_BUSY_REPLIES: usize = 4;
+
+/// How long a busy reply may take before the connection is closed anyway
+const BUSY_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Halvor Agent Server
 /// Runs as a daemon on each host to enable secure remote execution and config sync
@@ -10,23 +70,56 @@ pub struct AgentServer {
     port: u16,
     #[allow(dead_code)]
     secret: Option<String>,
+    max_frame_size: usize,
+    max_connections: usize,
+    io_timeout: Duration,
+    handler_timeout: Duration,
+    replay_guard: ReplayGuard,
+    tls: ServerTls,
+    router: MessageRouter,
+    gossip: Gossip,
+    heartbeat: HeartbeatConfig,
+    mdns: bool,
+}
+
+/// A streamed exec that passed authentication and the exec policy
+struct ExecStream {
+    command: String,
+    args: Vec<String>,
+    /// Peer running the command
+    peer: String,
+    /// This node, as the peer addressed it
+    local: String,
+    secret: String,
+    /// ID of the opening request; frames in both directions are bound to it
+    request_id: String,
+}
+
+/// How a connection reached the server
+enum Transport {
+    /// TLS is not enabled on this agent
+    Plain,
+    /// A plaintext connection to an agent that only accepts TLS
+    TlsRequired,
+    /// TLS, with the mesh peer named by the client certificate (if one was sent)
+    Tls { peer: Option<String> },
+    /// TLS with a client certificate that is not acceptable
+    Rejected(String),
 }
 
 impl Default for AgentServer {
     fn default() -> Self {
-        Self {
-            port: 13500,
-            secret: None,
-        }
+        Self::new(13500, None)
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum AgentRequest {
+    /// Run a program; must be signed by a mesh peer and allowed by this host's policy
     ExecuteCommand {
         command: String,
         args: Vec<String>,
-        token: String,
+        auth: PeerAuth,
     },
     GetHostInfo,
     SyncConfig {
@@ -35,7 +128,8 @@ pub enum AgentRequest {
     SyncDatabase {
         /// Hostname of the requesting agent
         from_hostname: String,
-        /// Timestamp of last sync (to avoid unnecessary transfers)
+        /// `sync_cursor` from this agent's previous reply; only rows changed
+        /// since are sent (None, or a cursor the agent can't serve, gets everything)
         last_sync: Option<i64>,
     },
     Ping,
@@ -45,10 +139,78 @@ pub enum AgentRequest {
         joiner_hostname: String,
         joiner_public_key: String,
     },
+    /// Version and capability handshake, carrying the client's own `Hello`
+    Hello(Hello),
     /// Validate a join token (check if it's valid before attempting join)
     ValidateToken {
         join_token: String,
     },
+    /// Another request, encrypted by a mesh peer with their shared secret
+    Secure(MeshMessage),
+    /// Ask this node's mesh CA for a new certificate (sent encrypted)
+    RenewCertificate {
+        /// Base64 P-256 public key of the requesting node
+        public_key: String,
+        /// Name to issue the certificate for, when the node is about to be
+        /// renamed; its current name otherwise
+        #[serde(default)]
+        hostname: Option<String>,
+    },
+    /// A mesh protocol message, dispatched through the server's `MessageRouter`
+    /// (sent encrypted)
+    Mesh(MeshMessage),
+    /// Run a program and stream its output: a sealed `ExecuteCommand`, after
+    /// which the connection carries `ExecFrame`s both ways (see `exec_stream`)
+    ExecuteStream(MeshMessage),
+    /// Stat, read, write or list files; signed like `ExecuteCommand` and checked
+    /// against the same policy (see `file_ops`)
+    FileOperation { op: FileOp, auth: PeerAuth },
+}
+
+impl AgentRequest {
+    /// Requests that carry secrets or run commands are only accepted encrypted
+    pub fn requires_encryption(&self) -> bool {
+        matches!(
+            self,
+            AgentRequest::ExecuteCommand { .. }
+                | AgentRequest::FileOperation { .. }
+                | AgentRequest::SyncConfig { .. }
+                | AgentRequest::SyncDatabase { .. }
+                | AgentRequest::RenewCertificate { .. }
+                | AgentRequest::Mesh(_)
+        )
+    }
+
+    /// Requests accepted over TLS without a client certificate, so nodes can join
+    /// (and nodes that joined before TLS can get a certificate)
+    fn allowed_without_certificate(&self) -> bool {
+        matches!(
+            self,
+            AgentRequest::Ping
+                | AgentRequest::Hello(_)
+                | AgentRequest::ValidateToken { .. }
+                | AgentRequest::JoinRequest { .. }
+                | AgentRequest::Secure(_)
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            AgentRequest::ExecuteCommand { .. } => "ExecuteCommand",
+            AgentRequest::GetHostInfo => "GetHostInfo",
+            AgentRequest::SyncConfig { .. } => "SyncConfig",
+            AgentRequest::SyncDatabase { .. } => "SyncDatabase",
+            AgentRequest::Ping => "Ping",
+            AgentRequest::JoinRequest { .. } => "JoinRequest",
+            AgentRequest::Hello(_) => "Hello",
+            AgentRequest::ValidateToken { .. } => "ValidateToken",
+            AgentRequest::Secure(_) => "Secure",
+            AgentRequest::RenewCertificate { .. } => "RenewCertificate",
+            AgentRequest::Mesh(_) => "Mesh",
+            AgentRequest::ExecuteStream(_) => "ExecuteStream",
+            AgentRequest::FileOperation { .. } => "FileOperation",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,20 +219,43 @@ pub enum AgentResponse {
     Error { message: String },
     HostInfo { info: HostInfo },
     Pong,
+    Hello(Hello),
     /// Response to join request with shared secret
     JoinAccepted {
         shared_secret: String,
         mesh_peers: Vec<String>,
+        /// Certificate for the joiner, if its public key could be certified
+        #[serde(default)]
+        certificate: Option<IssuedCertificate>,
+        /// CA certificates trusted by this node
+        #[serde(default)]
+        ca_certificates: Vec<String>,
     },
     /// Response to token validation
     TokenValid {
         issuer_hostname: String,
     },
+    /// Response to a `Secure` request, encrypted for the requesting peer
+    Secure(MeshMessage),
+    /// Response to `RenewCertificate`
+    CertificateIssued {
+        certificate: IssuedCertificate,
+        ca_certificates: Vec<String>,
+    },
+    /// This agent only accepts TLS connections
+    TlsRequired,
+    /// Response to a `Mesh` message: the handler's reply, if it sent one
+    Mesh { reply: Option<MeshMessage> },
+    /// Response to `FileOperation`
+    File(FileOpOutput),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HostInfo {
     pub hostname: String,
+    /// halvor version the agent runs (missing from agents before the handshake)
+    #[serde(default)]
+    pub version: Option<String>,
     pub local_ip: Option<String>,
     pub tailscale_ip: Option<String>,
     pub tailscale_hostname: Option<String>,
@@ -81,46 +266,515 @@ pub struct HostInfo {
 
 impl AgentServer {
     pub fn new(port: u16, secret: Option<String>) -> Self {
-        Self { port, secret }
+        Self {
+            port,
+            secret,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            io_timeout: DEFAULT_IO_TIMEOUT,
+            handler_timeout: DEFAULT_HANDLER_TIMEOUT,
+            replay_guard: ReplayGuard::default(),
+            tls: ServerTls::default(),
+            router: Self::default_router(),
+            gossip: Gossip::default(),
+            heartbeat: HeartbeatConfig::default(),
+            mdns: true,
+        }
+    }
+
+    fn default_router() -> MessageRouter {
+        let mut router = MessageRouter::with_builtin_handlers();
+        gossip::register_membership_handlers(&mut router);
+        mesh_config::register(&mut router);
+        router
+    }
+
+    /// Set the maximum accepted request frame size in bytes
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Set the maximum number of connections handled at once
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Set the timeout for reading a request and writing its response
+    pub fn with_io_timeout(mut self, io_timeout: Duration) -> Self {
+        self.io_timeout = io_timeout;
+        self
+    }
+
+    /// Set the timeout for handling a single request
+    pub fn with_handler_timeout(mut self, handler_timeout: Duration) -> Self {
+        self.handler_timeout = handler_timeout;
+        self
+    }
+
+    /// Handle mesh messages of `message_type` (see `MessagePayload::message_type`),
+    /// replacing any built-in handler for it
+    pub fn with_message_handler<H: MessageHandler + 'static>(mut self, message_type: &str, handler: H) -> Self {
+        self.router.register(message_type.to_string(), handler);
+        self
+    }
+
+    /// Accept file pushes into, and serve pulls from, `transfer_dir`
+    ///
+    /// Peers can't read or write anything outside it.
+    pub fn with_transfer_dir(mut self, transfer_dir: PathBuf) -> Self {
+        FileTransferHandler::new(transfer_dir).register(&mut self.router);
+        self
+    }
+
+    /// Set how often peers are pinged and when silent ones become suspect or inactive
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Advertise this agent over mDNS (on by default)
+    pub fn with_mdns(mut self, mdns: bool) -> Self {
+        self.mdns = mdns;
+        self
+    }
+
+    /// Handle `CustomJson` mesh messages for `app_type`
+    pub fn with_custom_handler<H: MessageHandler + 'static>(mut self, app_type: &str, handler: H) -> Self {
+        self.router.register_custom(app_type.to_string(), handler);
+        self
     }
 
     /// Start the agent server
-    pub fn start(&self) -> Result<()> {
+    ///
+    /// Connections are handled concurrently up to the connection limit; extra
+    /// connections are rejected with an error response. Returns after SIGTERM
+    /// or Ctrl-C once in-flight connections finish (or the grace period ends).
+    ///
+    /// Once this node has a mesh certificate only TLS connections are accepted;
+    /// the certificate is picked up (and renewed) without a restart.
+    pub async fn start(self) -> Result<()> {
         let addr = format_bind_address(self.port);
-        let listener =
-            TcpListener::bind(&addr).with_context(|| format!("Failed to bind to {}", addr))?;
+        let listener = TcpListener::bind(&addr)
+            .await
+            .with_context(|| format!("Failed to bind to {}", addr))?;
 
         println!("Halvor agent listening on port {}", self.port);
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.handle_connection(stream) {
-                        eprintln!("Error handling connection: {}", e);
-                    }
+        tokio::spawn(renew_certificate_periodically());
+        tokio::spawn(heartbeat::run(self.heartbeat.clone()));
+
+        // Advertised until the server stops
+        let _advertiser = if self.mdns {
+            match AgentAdvertisement::local(self.port)
+                .and_then(|advertisement| MdnsAdvertiser::start(&advertisement, false))
+            {
+                Ok(advertiser) => {
+                    println!("Advertising on mDNS as {}", mdns::SERVICE_TYPE);
+                    Some(advertiser)
                 }
                 Err(e) => {
-                    eprintln!("Error accepting connection: {}", e);
+                    eprintln!("[AGENT SERVER] mDNS advertisement failed: {}", e);
+                    None
                 }
             }
+        } else {
+            None
+        };
+
+        let server = Arc::new(self);
+        let permits = Arc::new(Semaphore::new(server.max_connections));
+        let busy_replies = Arc::new(Semaphore::new(MAX_BUSY_REPLIES));
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            eprintln!("Error accepting connection: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let server = Arc::clone(&server);
+                    match Arc::clone(&permits).try_acquire_owned() {
+                        Ok(permit) => {
+                            tokio::spawn(async move {
+                                if let Err(e) = server.handle_connection(stream).await {
+                                    eprintln!("Error handling connection from {}: {}", peer, e);
+                                }
+                                drop(permit);
+                            });
+                        }
+                        Err(_) => {
+                            eprintln!("Connection limit reached, rejecting {}", peer);
+                            // A flood of connections mustn't pile up tasks of its own
+                            if let Ok(permit) = Arc::clone(&busy_replies).try_acquire_owned() {
+                                tokio::spawn(async move {
+                                    server.reject_busy(stream).await;
+                                    drop(permit);
+                                });
+                            }
+                        }
+                    }
+                }
+                _ = &mut shutdown => {
+                    println!("Shutdown signal received, no longer accepting connections");
+                    break;
+                }
+            }
+        }
+
+        // Wait for in-flight connections by reclaiming every permit
+        let all = u32::try_from(server.max_connections).unwrap_or(u32::MAX);
+        if timeout(SHUTDOWN_GRACE_PERIOD, permits.acquire_many(all))
+            .await
+            .is_err()
+        {
+            eprintln!(
+                "Shutdown grace period elapsed with {} connection(s) still open",
+                server.max_connections - permits.available_permits()
+            );
+        }
+
+        println!("Halvor agent stopped");
+        Ok(())
+    }
+
+    async fn handle_connection(self: Arc<Self>, stream: TcpStream) -> Result<()> {
+        // Framed plaintext starts with "HLV", a TLS handshake with 0x16
+        let mut first = [0u8; 1];
+        let peeked = timeout(self.io_timeout, stream.peek(&mut first))
+            .await
+            .context("Timed out waiting for request")??;
+        let is_tls = peeked == 1 && first[0] == tls::TLS_HANDSHAKE_BYTE;
+
+        let Some(config) = self.tls.config() else {
+            if is_tls {
+                return Self::refuse_tls(stream, self.io_timeout).await;
+            }
+            return self.serve(stream, Transport::Plain).await;
+        };
+
+        if !is_tls {
+            return self.serve(stream, Transport::TlsRequired).await;
         }
 
+        let stream = timeout(self.io_timeout, TlsAcceptor::from(config).accept(stream))
+            .await
+            .context("Timed out during TLS handshake")?
+            .context("TLS handshake failed")?;
+
+        let certificates: Vec<_> = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|c| c.clone().into_owned()).collect())
+            .unwrap_or_default();
+        let transport = if certificates.is_empty() {
+            Transport::Tls { peer: None }
+        } else {
+            // Looks the certificate up in the database
+            match tokio::task::spawn_blocking(move || tls::peer_identity(&certificates)).await? {
+                Ok(peer) => Transport::Tls { peer: Some(peer) },
+                Err(e) => Transport::Rejected(format!("Unauthorized: client certificate {}", e)),
+            }
+        };
+
+        self.serve(stream, transport).await
+    }
+
+    /// Answer a TLS handshake with a plaintext frame so the client's TLS stack
+    /// fails with a decode error and it can retry without TLS
+    async fn refuse_tls(mut stream: TcpStream, io_timeout: Duration) -> Result<()> {
+        // Consume the ClientHello first; closing with unread data resets the connection
+        let mut hello = vec![0u8; 8192];
+        let _ = timeout(Duration::from_millis(200), stream.read(&mut hello)).await;
+
+        let response = AgentResponse::Error {
+            message: "TLS is not enabled on this agent (no mesh certificate yet)".to_string(),
+        };
+        let _ = timeout(io_timeout, write_json_async(&mut stream, &response)).await;
+        let _ = stream.shutdown().await;
         Ok(())
     }
 
-    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+    async fn serve<S>(self: Arc<Self>, mut stream: S, transport: Transport) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         // Read request
-        let request: AgentRequest = read_json(&mut stream, 4096)?;
+        let read = timeout(
+            self.io_timeout,
+            read_json_async::<_, AgentRequest>(&mut stream, self.max_frame_size),
+        )
+        .await
+        .context("Timed out waiting for request")?;
+
+        let request = match read {
+            Ok(request) => request,
+            Err(e) => {
+                // Pre-framing clients can't decode frames - answer in their format
+                if let Some(FrameError::LegacyPeer) = e.downcast_ref::<FrameError>() {
+                    // Consume the rest of the legacy request first; closing with unread
+                    // data makes the kernel reset the connection and drop our reply
+                    let mut rest = vec![0u8; 8192];
+                    let _ = timeout(Duration::from_millis(200), stream.read(&mut rest)).await;
+
+                    let response = AgentResponse::Error {
+                        message: e.to_string(),
+                    };
+                    let _ = timeout(
+                        self.io_timeout,
+                        write_legacy_json_async(&mut stream, &response),
+                    )
+                    .await;
+                    let _ = stream.shutdown().await;
+                } else if e.downcast_ref::<serde_json::Error>().is_some() {
+                    // Usually a request added in a newer release; say so rather than
+                    // leaving the client with a closed connection
+                    let response = AgentResponse::Error {
+                        message: format!(
+                            "Unsupported request: this agent runs halvor {} (protocol {}); update halvor on both hosts",
+                            env!("CARGO_PKG_VERSION"),
+                            handshake::PROTOCOL_VERSION
+                        ),
+                    };
+                    let _ = timeout(self.io_timeout, write_json_async(&mut stream, &response)).await;
+                }
+                return Err(e);
+            }
+        };
+
+        // Streamed execs keep the connection for themselves until the program exits
+        if matches!(request, AgentRequest::ExecuteStream(_)) {
+            return self.serve_exec_stream(stream, request, transport).await;
+        }
+
+        // Handlers shell out and hit the database, so run them off the async workers.
+        // A timed-out handler keeps running in the background; the client just stops waiting.
+        let server = Arc::clone(&self);
+        let handler = tokio::task::spawn_blocking(move || server.handle_request(request, &transport));
+        let response = match timeout(self.handler_timeout, handler).await {
+            Ok(Ok(Ok(response))) => response,
+            Ok(Ok(Err(e))) => AgentResponse::Error {
+                message: e.to_string(),
+            },
+            Ok(Err(e)) => AgentResponse::Error {
+                message: format!("Request handler failed: {}", e),
+            },
+            Err(_) => AgentResponse::Error {
+                message: format!(
+                    "Request timed out after {}s",
+                    self.handler_timeout.as_secs()
+                ),
+            },
+        };
+
+        // Send response
+        timeout(self.io_timeout, write_json_async(&mut stream, &response))
+            .await
+            .context("Timed out writing response")??;
+        // Sends close_notify on TLS connections
+        let _ = timeout(self.io_timeout, stream.shutdown()).await;
+
+        Ok(())
+    }
+
+    /// Tell a client the server is at its connection limit
+    async fn reject_busy(&self, mut stream: TcpStream) {
+        let response = AgentResponse::Error {
+            message: format!(
+                "Agent busy: connection limit ({}) reached, try again shortly",
+                self.max_connections
+            ),
+        };
+        let _ = timeout(BUSY_REPLY_TIMEOUT, write_json_async(&mut stream, &response)).await;
+    }
+
+    fn handle_request(&self, request: AgentRequest, transport: &Transport) -> Result<AgentResponse> {
+        let peer = match Self::authorize_transport(&request, transport) {
+            Ok(peer) => peer,
+            Err(response) => return Ok(*response),
+        };
+
+        match request {
+            AgentRequest::Secure(message) => self.handle_secure(message, peer),
+            request if request.requires_encryption() => Ok(AgentResponse::Error {
+                message: format!(
+                    "{} must be encrypted: only mesh peers can send it (join the mesh first)",
+                    request.name()
+                ),
+            }),
+            request => self.dispatch(request),
+        }
+    }
+
+    /// Check `request` may be made over `transport`
+    ///
+    /// Returns the peer named by the client certificate on TLS connections
+    /// (`Some(None)` without one), or the response refusing the request.
+    fn authorize_transport<'t>(
+        request: &AgentRequest,
+        transport: &'t Transport,
+    ) -> Result<Option<Option<&'t str>>, Box<AgentResponse>> {
+        match transport {
+            Transport::TlsRequired => Err(Box::new(AgentResponse::TlsRequired)),
+            Transport::Rejected(message) => {
+                eprintln!("[AGENT SERVER] Rejected {}: {}", request.name(), message);
+                Err(Box::new(AgentResponse::Error {
+                    message: message.clone(),
+                }))
+            }
+            Transport::Tls { peer: None } if !request.allowed_without_certificate() => {
+                Err(Box::new(AgentResponse::Error {
+                    message: format!(
+                        "Unauthorized: {} requires a mesh client certificate",
+                        request.name()
+                    ),
+                }))
+            }
+            Transport::Tls { peer } => Ok(Some(peer.as_deref())),
+            Transport::Plain => Ok(None),
+        }
+    }
+
+    /// Decrypt a request from a mesh peer, handle it and encrypt the response
+    ///
+    /// `peer` is `Some` for TLS connections, holding the peer named by the client
+    /// certificate; it must be the peer that encrypted the request.
+    fn handle_secure(&self, message: MeshMessage, peer: Option<Option<&str>>) -> Result<AgentResponse> {
+        let (request, secret) = match self.open_secure(&message, peer) {
+            Ok(opened) => opened,
+            Err(response) => return Ok(*response),
+        };
+        let response = match request {
+            AgentRequest::Secure(_) | AgentRequest::ExecuteStream(_) => AgentResponse::Error {
+                message: "Nested encrypted requests are not supported".to_string(),
+            },
+            AgentRequest::ExecuteCommand { ref auth, .. } | AgentRequest::FileOperation { ref auth, .. }
+                if auth.from_hostname != message.from =>
+            {
+                AgentResponse::Error {
+                    message: "Unauthorized: request was signed by a different peer".to_string(),
+                }
+            }
+            // Anonymous TLS connections may only fetch a certificate
+            ref request
+                if peer == Some(None) && !matches!(request, AgentRequest::RenewCertificate { .. }) =>
+            {
+                AgentResponse::Error {
+                    message: format!(
+                        "Unauthorized: {} requires a mesh client certificate",
+                        request.name()
+                    ),
+                }
+            }
+            AgentRequest::RenewCertificate { public_key, hostname } => self
+                .renew_certificate(&message.from, &public_key, hostname.as_deref())
+                .unwrap_or_else(|e| AgentResponse::Error {
+                    message: e.to_string(),
+                }),
+            AgentRequest::Mesh(mesh_message) => self.route_mesh(&message.from, mesh_message),
+            request => self.dispatch(request).unwrap_or_else(|e| AgentResponse::Error {
+                message: e.to_string(),
+            }),
+        };
+
+        let sealed = MeshMessage::seal(
+            message.to.clone(),
+            message.from.clone(),
+            &serde_json::to_vec(&response)?,
+            &secret,
+            Some(message.message_id.clone()),
+        )?;
+        Ok(AgentResponse::Secure(sealed))
+    }
+
+    /// Check and decrypt a request sealed by a mesh peer, returning it with
+    /// the peer's shared secret
+    ///
+    /// Failures are answered in plaintext, the peer may not have a key we can
+    /// encrypt for.
+    fn open_secure(
+        &self,
+        message: &MeshMessage,
+        peer: Option<Option<&str>>,
+    ) -> Result<(AgentRequest, String), Box<AgentResponse>> {
+        use crate::agent::mesh;
+
+        if let Some(Some(peer)) = peer
+            && normalize_hostname(peer) != normalize_hostname(&message.from)
+        {
+            return Err(Box::new(AgentResponse::Error {
+                message: format!(
+                    "Unauthorized: client certificate belongs to '{}', not '{}'",
+                    peer, message.from
+                ),
+            }));
+        }
+
+        let secret = match mesh::get_peer_shared_secret(&message.from) {
+            Ok(Some(secret)) => secret,
+            Ok(None) => {
+                return Err(Box::new(AgentResponse::Error {
+                    message: format!("Unauthorized: '{}' is not a known mesh peer", message.from),
+                }));
+            }
+            Err(e) => {
+                return Err(Box::new(AgentResponse::Error {
+                    message: e.to_string(),
+                }));
+            }
+        };
+        let plaintext = match self.open_sealed(message, &secret) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                eprintln!("[AGENT SERVER] Rejected encrypted request from {}: {}", message.from, e);
+                return Err(Box::new(AgentResponse::Error {
+                    message: format!("Unauthorized: {}", e),
+                }));
+            }
+        };
+        // Anything the peer managed to encrypt proves it is alive
+        match mesh::mark_peer_seen(&message.from) {
+            Ok(Some(previous)) => println!("[MESH] {} is active again (was {})", message.from, previous),
+            Ok(None) => {}
+            Err(e) => eprintln!("[AGENT SERVER] Failed to record {} as seen: {}", message.from, e),
+        }
 
-        // Handle request
+        match serde_json::from_slice(&plaintext) {
+            Ok(request) => Ok((request, secret)),
+            Err(_) => Err(Box::new(AgentResponse::Error {
+                message: "Invalid encrypted request".to_string(),
+            })),
+        }
+    }
+
+    /// Decrypt a sealed request, rejecting stale or replayed messages
+    fn open_sealed(&self, message: &MeshMessage, secret: &str) -> Result<Vec<u8>> {
+        let plaintext = message.open(secret)?;
+        auth::check_timestamp(message.timestamp)?;
+        self.replay_guard
+            .check(&message.from, &message.message_id, message.timestamp)?;
+        Ok(plaintext)
+    }
+
+    fn dispatch(&self, request: AgentRequest) -> Result<AgentResponse> {
         let response = match request {
             AgentRequest::Ping => AgentResponse::Pong,
+            AgentRequest::Hello(client) => self.hello(&client),
             AgentRequest::GetHostInfo => self.get_host_info()?,
             AgentRequest::ExecuteCommand {
                 command,
                 args,
-                token,
-            } => self.execute_command(&command, &args, &token)?,
+                auth,
+            } => self.execute_command(&command, &args, &auth)?,
+            AgentRequest::FileOperation { op, auth } => self.file_operation(&op, &auth),
             AgentRequest::SyncConfig { data } => self.sync_config(data)?,
             AgentRequest::SyncDatabase {
                 from_hostname,
@@ -132,12 +786,100 @@ impl AgentServer {
                 joiner_public_key,
             } => self.handle_join_request(&join_token, &joiner_hostname, &joiner_public_key)?,
             AgentRequest::ValidateToken { join_token } => self.validate_token(&join_token)?,
+            AgentRequest::Secure(_) | AgentRequest::RenewCertificate { .. } | AgentRequest::Mesh(_) => {
+                anyhow::bail!("{} must be handled by handle_secure", request.name())
+            }
+            AgentRequest::ExecuteStream(_) => {
+                anyhow::bail!("ExecuteStream must be the first request on its own connection")
+            }
         };
 
-        // Send response
-        write_json(&mut stream, &response)?;
+        Ok(response)
+    }
 
-        Ok(())
+    /// Dispatch a mesh message from an authenticated peer through the router
+    ///
+    /// Handler failures and unhandled message types are answered with an
+    /// `Error` mesh message rather than failing the request. Broadcasts may come
+    /// from any node, relayed by `sender`; they are relayed on, handled once,
+    /// and never answered.
+    fn route_mesh(&self, sender: &str, message: MeshMessage) -> AgentResponse {
+        if message.to == BROADCAST {
+            if self.gossip.accept(&message, sender)
+                && self.router.handles(&message)
+                && let Err(e) = self.router.route(&message)
+            {
+                eprintln!(
+                    "[AGENT SERVER] Mesh {} handler failed for broadcast from {}: {}",
+                    message.payload.message_type(),
+                    message.from,
+                    e
+                );
+                // Tell the origin, if it handed us the broadcast itself
+                if normalize_hostname(&message.from) == normalize_hostname(sender) {
+                    let reply = MeshMessage::error(
+                        message.reply_from(),
+                        message.from.clone(),
+                        "handler_failed",
+                        format!("{:#}", e),
+                    );
+                    return AgentResponse::Mesh { reply: Some(reply) };
+                }
+            }
+            return AgentResponse::Mesh { reply: None };
+        }
+
+        if normalize_hostname(&message.from) != normalize_hostname(sender) {
+            return AgentResponse::Error {
+                message: format!(
+                    "Unauthorized: mesh message claims to be from '{}' but was sent by '{}'",
+                    message.from, sender
+                ),
+            };
+        }
+
+        let message_type = message.payload.message_type();
+        if !self.router.handles(&message) {
+            let reply = MeshMessage::error(
+                message.reply_from(),
+                message.from.clone(),
+                "unsupported",
+                format!("No handler for {} messages", message_type),
+            );
+            return AgentResponse::Mesh { reply: Some(reply) };
+        }
+
+        let reply = match self.router.route(&message) {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!(
+                    "[AGENT SERVER] Mesh {} handler failed for message from {}: {}",
+                    message_type, message.from, e
+                );
+                Some(MeshMessage::error(
+                    message.reply_from(),
+                    message.from.clone(),
+                    "handler_failed",
+                    e.to_string(),
+                ))
+            }
+        };
+        AgentResponse::Mesh { reply }
+    }
+
+    fn hello(&self, client: &Hello) -> AgentResponse {
+        let hello = Hello::local(&crate::agent::mesh::local_mesh_hostname());
+        if client.protocol_version != hello.protocol_version {
+            println!(
+                "[AGENT SERVER] {} runs halvor {} (protocol {}), this agent runs {} (protocol {})",
+                client.hostname,
+                client.version,
+                client.protocol_version,
+                hello.version,
+                hello.protocol_version
+            );
+        }
+        AgentResponse::Hello(hello)
     }
 
     fn get_host_info(&self) -> Result<AgentResponse> {
@@ -183,6 +925,7 @@ impl AgentServer {
         Ok(AgentResponse::HostInfo {
             info: HostInfo {
                 hostname,
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
                 local_ip,
                 tailscale_ip,
                 tailscale_hostname,
@@ -197,11 +940,11 @@ impl AgentServer {
         &self,
         command: &str,
         args: &[String],
-        _token: &str,
+        auth: &PeerAuth,
     ) -> Result<AgentResponse> {
-        // TODO: Validate token
-        // TODO: Check permissions
-        // TODO: Execute command safely
+        if let Err(response) = self.authorize_exec(command, args, auth) {
+            return Ok(*response);
+        }
 
         use std::process::Command;
         let output = Command::new(command)
@@ -223,17 +966,326 @@ impl AgentServer {
         }
     }
 
-    fn sync_config(&self, _data: Vec<u8>) -> Result<AgentResponse> {
-        // TODO: Decrypt and apply config sync
-        // TODO: Handle conflicts
+    fn file_operation(&self, op: &FileOp, auth: &PeerAuth) -> AgentResponse {
+        let payload = match op.payload() {
+            Ok(payload) => payload,
+            Err(e) => {
+                return AgentResponse::Error {
+                    message: e.to_string(),
+                };
+            }
+        };
+        // Policy and the operation itself see where the path really leads
+        let op = match op.resolved() {
+            Ok(op) => op,
+            Err(e) => {
+                return AgentResponse::Error {
+                    message: format!("{} failed: {:#}", op.name(), e),
+                };
+            }
+        };
+        let (command, args) = op.policy_command();
+        if let Err(response) = self.authorize("FileOperation", &payload, &command, &args, auth) {
+            return *response;
+        }
+
+        match op.apply() {
+            Ok(output) => AgentResponse::File(output),
+            Err(e) => AgentResponse::Error {
+                message: format!("{} failed: {:#}", op.name(), e),
+            },
+        }
+    }
+
+    /// Check a command was signed by a mesh peer and is allowed by this host's policy
+    fn authorize_exec(&self, command: &str, args: &[String], auth: &PeerAuth) -> Result<(), Box<AgentResponse>> {
+        let payload = auth::exec_payload(command, args).map_err(|e| AgentResponse::Error {
+            message: e.to_string(),
+        })?;
+        self.authorize("ExecuteCommand", &payload, command, args, auth)
+    }
+
+    /// Check `operation` was signed by a mesh peer, and `command` is allowed for
+    /// it by this host's policy
+    fn authorize(
+        &self,
+        operation: &str,
+        payload: &[u8],
+        command: &str,
+        args: &[String],
+        auth: &PeerAuth,
+    ) -> Result<(), Box<AgentResponse>> {
+        if let Err(e) = self.authenticate(auth, operation, payload) {
+            let reason = format!("authentication failed: {}", e);
+            self.record_denied(&auth.from_hostname, command, args, &reason);
+            return Err(Box::new(AgentResponse::Error {
+                message: format!("Unauthorized: {}", reason),
+            }));
+        }
+
+        if let Decision::Deny(reason) = policy::check(&auth.from_hostname, command, args) {
+            self.record_denied(&auth.from_hostname, command, args, &reason);
+            return Err(Box::new(AgentResponse::Error {
+                message: format!("Forbidden: {}", reason),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Run a command for a mesh peer, streaming its output back as it's produced
+    ///
+    /// There's no handler timeout: the program runs until it exits, the client
+    /// cancels it or the client hangs up.
+    async fn serve_exec_stream<S>(
+        self: Arc<Self>,
+        stream: S,
+        request: AgentRequest,
+        transport: Transport,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let server = Arc::clone(&self);
+        let opened =
+            tokio::task::spawn_blocking(move || server.open_exec_stream(request, &transport)).await?;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let exec = match opened {
+            Ok(exec) => exec,
+            Err(response) => {
+                timeout(self.io_timeout, write_json_async(&mut writer, &*response))
+                    .await
+                    .context("Timed out writing response")??;
+                let _ = timeout(self.io_timeout, writer.shutdown()).await;
+                return Ok(());
+            }
+        };
+
+        println!(
+            "[AGENT SERVER] Running '{}' for {} (streamed)",
+            exec.command, exec.peer
+        );
+        let spawned = tokio::process::Command::new(&exec.command)
+            .args(&exec.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                let frame = ExecFrame::Error {
+                    message: format!("Failed to execute command: {}: {}", exec.command, e),
+                };
+                self.send_exec_frame(&mut writer, &exec, &frame).await?;
+                let _ = timeout(self.io_timeout, writer.shutdown()).await;
+                return Ok(());
+            }
+        };
+
+        let (output_tx, mut output_rx) = mpsc::channel(EXEC_CHANNEL_CAPACITY);
+        forward_output(child.stdout.take(), OutputStream::Stdout, output_tx.clone());
+        forward_output(child.stderr.take(), OutputStream::Stderr, output_tx);
+
+        // Written from its own task: a program that isn't reading its input
+        // mustn't stop us forwarding its output
+        let (stdin_tx, mut stdin_rx) = mpsc::channel::<Vec<u8>>(EXEC_CHANNEL_CAPACITY);
+        let mut stdin_tx = Some(stdin_tx);
+        if let Some(mut stdin) = child.stdin.take() {
+            tokio::spawn(async move {
+                while let Some(data) = stdin_rx.recv().await {
+                    if stdin.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let (client_tx, mut client_rx) = mpsc::channel(EXEC_CHANNEL_CAPACITY);
+        let max_frame_size = self.max_frame_size;
+        let client_reader = tokio::spawn(async move {
+            loop {
+                let request = read_json_async::<_, AgentRequest>(&mut reader, max_frame_size).await;
+                let failed = request.is_err();
+                if client_tx.send(request).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut status = None;
+        let mut output_open = true;
+        let mut drain_deadline = Instant::now();
+        while status.is_none() || output_open {
+            tokio::select! {
+                chunk = output_rx.recv(), if output_open => match chunk {
+                    Some((stream, data)) => {
+                        let frame = ExecFrame::output(stream, &data);
+                        self.send_exec_frame(&mut writer, &exec, &frame).await?;
+                    }
+                    None => output_open = false,
+                },
+                request = client_rx.recv() => {
+                    let frame = match request {
+                        Some(Ok(request)) => self.open_exec_frame(&exec, request),
+                        Some(Err(e)) => Err(e),
+                        None => Err(anyhow::anyhow!("connection closed")),
+                    };
+                    match frame {
+                        Ok(ExecFrame::Stdin { data }) => {
+                            if let Some(stdin_tx) = &stdin_tx {
+                                let _ = stdin_tx.send(ExecFrame::decode(&data)?).await;
+                            }
+                        }
+                        Ok(ExecFrame::StdinEof) => stdin_tx = None,
+                        Ok(ExecFrame::Cancel) => {
+                            println!("[AGENT SERVER] {} cancelled '{}'", exec.peer, exec.command);
+                            let _ = child.start_kill();
+                        }
+                        Ok(frame) => {
+                            eprintln!("[AGENT SERVER] Ignoring unexpected exec stream frame from {}: {:?}", exec.peer, frame);
+                        }
+                        Err(e) => {
+                            // Nobody is left to report to; don't leave the program running
+                            eprintln!(
+                                "[AGENT SERVER] Lost exec stream from {} ({}), stopping '{}'",
+                                exec.peer, e, exec.command
+                            );
+                            let _ = child.kill().await;
+                            return Ok(());
+                        }
+                    }
+                }
+                exited = child.wait(), if status.is_none() => {
+                    status = Some(ExitStatus::from(exited?));
+                    drain_deadline = Instant::now() + EXEC_OUTPUT_DRAIN_TIMEOUT;
+                }
+                _ = sleep_until(drain_deadline), if status.is_some() && output_open => {
+                    output_open = false;
+                }
+            }
+        }
+        client_reader.abort();
+
+        let status = status.expect("loop ends once the program exits");
+        println!(
+            "[AGENT SERVER] '{}' for {} finished: {}",
+            exec.command, exec.peer, status
+        );
+        self.send_exec_frame(&mut writer, &exec, &ExecFrame::Exit(status)).await?;
+        let _ = timeout(self.io_timeout, writer.shutdown()).await;
+        Ok(())
+    }
+
+    /// Check the opening request of a streamed exec like any sealed `ExecuteCommand`
+    fn open_exec_stream(&self, request: AgentRequest, transport: &Transport) -> Result<ExecStream, Box<AgentResponse>> {
+        let peer = Self::authorize_transport(&request, transport)?;
+        let AgentRequest::ExecuteStream(message) = request else {
+            return Err(Box::new(AgentResponse::Error {
+                message: format!("Expected ExecuteStream, got {}", request.name()),
+            }));
+        };
+        let (request, secret) = self.open_secure(&message, peer)?;
+        let AgentRequest::ExecuteCommand { command, args, auth } = request else {
+            return Err(Box::new(AgentResponse::Error {
+                message: format!("ExecuteStream must carry ExecuteCommand, not {}", request.name()),
+            }));
+        };
+        if auth.from_hostname != message.from {
+            return Err(Box::new(AgentResponse::Error {
+                message: "Unauthorized: command was signed by a different peer".to_string(),
+            }));
+        }
+        self.authorize_exec(&command, &args, &auth)?;
+
+        Ok(ExecStream {
+            command,
+            args,
+            peer: message.from,
+            local: message.to,
+            secret,
+            request_id: message.message_id,
+        })
+    }
+
+    /// Decrypt a frame the client sent on an exec stream
+    fn open_exec_frame(&self, exec: &ExecStream, request: AgentRequest) -> Result<ExecFrame> {
+        let AgentRequest::Secure(message) = request else {
+            anyhow::bail!("expected an encrypted frame, got {}", request.name());
+        };
+        if message.from != exec.peer || message.in_reply_to() != Some(exec.request_id.as_str()) {
+            anyhow::bail!("frame does not belong to this stream");
+        }
+        let plaintext = self.open_sealed(&message, &exec.secret)?;
+        serde_json::from_slice(&plaintext).context("Invalid exec stream frame")
+    }
+
+    async fn send_exec_frame<W>(&self, writer: &mut W, exec: &ExecStream, frame: &ExecFrame) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let sealed = MeshMessage::seal(
+            exec.local.clone(),
+            exec.peer.clone(),
+            &serde_json::to_vec(frame)?,
+            &exec.secret,
+            Some(exec.request_id.clone()),
+        )?;
+        timeout(self.io_timeout, write_json_async(writer, &AgentResponse::Secure(sealed)))
+            .await
+            .context("Timed out writing exec stream frame")?
+    }
+
+    /// Verify a request was signed by a known mesh peer and is not a replay
+    fn authenticate(&self, auth: &PeerAuth, operation: &str, payload: &[u8]) -> Result<()> {
+        use crate::agent::mesh;
+
+        let secret = mesh::get_peer_shared_secret(&auth.from_hostname)?
+            .with_context(|| format!("'{}' is not a known mesh peer", auth.from_hostname))?;
+        auth::verify(auth, &secret, operation, payload)?;
+        self.replay_guard
+            .check(&auth.from_hostname, &auth.nonce, auth.timestamp)
+    }
+
+    fn record_denied(&self, peer_hostname: &str, command: &str, args: &[String], reason: &str) {
+        eprintln!(
+            "[AGENT SERVER] Denied '{}' from {}: {}",
+            command, peer_hostname, reason
+        );
+        if let Err(e) = policy::record_denied(peer_hostname, command, args, reason) {
+            eprintln!("[AGENT SERVER] ⚠ Failed to record denied command: {}", e);
+        }
+    }
+
+    /// Merge a peer's replicated config into ours and answer with ours
+    ///
+    /// `data` is the peer's entries as JSON; the reply is all of this node's
+    /// entries after merging, so both sides end up with the winning values.
+    fn sync_config(&self, data: Vec<u8>) -> Result<AgentResponse> {
+        let entries: Vec<ConfigEntry> =
+            serde_json::from_slice(&data).context("Invalid config sync data")?;
+        let changed = mesh_config::merge_all(&entries)?;
+        if changed > 0 {
+            println!("[AGENT SERVER] Config sync updated {} key(s)", changed);
+        }
+
         Ok(AgentResponse::Success {
-            output: "Config synced".to_string(),
+            output: serde_json::to_string(&mesh_config::list()?)?,
         })
     }
 
-    fn sync_database(&self, from_hostname: &str, _last_sync: Option<i64>) -> Result<AgentResponse> {
+    /// Share this node's mesh state with a peer
+    ///
+    /// With a `last_sync` cursor the change log covers, only peers changed since
+    /// are sent; deleted ones aren't, since removals spread only as signed
+    /// revocations (sent every time). Otherwise
+    /// everything is sent, including host configs from the .env file (which
+    /// isn't change-tracked). Either way the reply carries the cursor to send
+    /// next time.
+    fn sync_database(&self, from_hostname: &str, last_sync: Option<i64>) -> Result<AgentResponse> {
         use halvor_core::services::host;
-        use halvor_db::generated::agent_peers;
+        use halvor_db::change_log::{self, ChangeOp};
+        use halvor_db::generated::AgentPeersRow;
 
         // Export host configs and settings for this host
         let local_hostname = std::env::var("HOSTNAME")
@@ -242,12 +1294,24 @@ impl AgentServer {
             .trim()
             .to_string();
 
+        let conn = halvor_db::get_connection()?;
+        let retention_start = chrono::Utc::now().timestamp() - CHANGE_LOG_RETENTION.as_secs() as i64;
+        change_log::prune(&conn, retention_start)?;
+
+        // Taken before reading rows: anything changed meanwhile is sent again next time
+        let sync_cursor = change_log::latest_seq(&conn)?;
+        let since = match last_sync {
+            Some(since) if change_log::covers(&conn, since)? => Some(since),
+            _ => None,
+        };
+
         // Get all hosts from .env config
-        let hosts = host::list_hosts().unwrap_or_default();
         let mut host_configs = std::collections::HashMap::new();
-        for hostname in &hosts {
-            if let Ok(Some(config)) = host::get_host_config(hostname) {
-                host_configs.insert(hostname.clone(), config);
+        if since.is_none() {
+            for hostname in &host::list_hosts().unwrap_or_default() {
+                if let Ok(Some(config)) = host::get_host_config(hostname) {
+                    host_configs.insert(hostname.clone(), config);
+                }
             }
         }
 
@@ -256,11 +1320,22 @@ impl AgentServer {
             std::collections::HashMap::new();
         // Settings are now in environment variables loaded via direnv from .envrc
 
-        // Get ALL mesh peers from database to share with requesting node
-        let peer_rows = agent_peers::select_many(
-            "status = ?1",
-            &[&"active" as &dyn rusqlite::types::ToSql],
-        ).unwrap_or_default();
+        // Mesh peers changed since the cursor (all of them for a full sync)
+        let peer_rows = match since {
+            Some(since) => {
+                let mut rows = Vec::new();
+                for change in change_log::changes_since(&conn, "agent_peers", since)? {
+                    if change.operation == ChangeOp::Delete {
+                        continue;
+                    }
+                    if let Some(row) = AgentPeersRow::query().hostname().eq(&change.row_key).first()? {
+                        rows.push(row);
+                    }
+                }
+                rows
+            }
+            None => AgentPeersRow::query().all()?,
+        };
 
         let mut mesh_peers = Vec::new();
         for peer in &peer_rows {
@@ -275,13 +1350,16 @@ impl AgentServer {
             }));
         }
 
-        // Serialize sync data - includes ALL known peers for self-healing
         let sync_data = serde_json::json!({
             "from_hostname": from_hostname,
             "local_hostname": local_hostname,
+            "full_sync": since.is_none(),
+            "sync_cursor": sync_cursor,
             "hosts": host_configs,
             "settings": db_settings,
-            "mesh_peers": mesh_peers, // Share all known peers
+            "mesh_peers": mesh_peers,
+            "revocations": crate::agent::revocation::list().unwrap_or_default(),
+            "ca_certificates": tls::trusted_cas().unwrap_or_default(),
         });
 
         let data_str = serde_json::to_string(&sync_data)?;
@@ -301,12 +1379,21 @@ impl AgentServer {
 
         eprintln!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
         eprintln!("[AGENT SERVER] Received join request from: {}", joiner_hostname);
-        eprintln!("[AGENT SERVER] Token preview (first 50 chars): {}", &join_token[..50.min(join_token.len())]);
         eprintln!("[AGENT SERVER] Public key: {}", joiner_public_key);
 
-        // Validate the join token
+        // Refuse a hostname held by another node before the token is used up
+        if let Err(e) = mesh::check_join_claim(joiner_hostname, joiner_public_key) {
+            eprintln!("[AGENT SERVER] ✗ Refusing join: {}", e);
+            eprintln!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            return Ok(AgentResponse::Error {
+                message: format!("Failed to add peer: {}", e),
+            });
+        }
+
+        // Validate the join token and claim one of its uses, so concurrent
+        // joins can't use it more often than allowed
         eprintln!("[AGENT SERVER] Starting token validation...");
-        let _token = match mesh::validate_join_token(join_token) {
+        let token = match mesh::redeem_join_token(join_token, joiner_hostname) {
             Ok(t) => {
                 eprintln!("[AGENT SERVER] ✓ Token validation successful");
                 t
@@ -342,22 +1429,48 @@ impl AgentServer {
         }
         eprintln!("[AGENT SERVER] ✓ Peer added to mesh");
 
-        // Mark token as used
-        eprintln!("[AGENT SERVER] Marking token as used...");
-        if let Err(e) = mesh::mark_token_used(join_token, joiner_hostname) {
-            eprintln!("[AGENT SERVER] ⚠ Warning: Failed to mark token as used: {}", e);
-        } else {
-            eprintln!("[AGENT SERVER] ✓ Token marked as used");
-        }
+        eprintln!(
+            "[AGENT SERVER] ✓ Token {} used ({} of {})",
+            token.token_id,
+            joiner_hostname,
+            if token.max_uses == 0 { "unlimited".to_string() } else { token.max_uses.to_string() }
+        );
+
+        // Sign a certificate for the joiner with this node's mesh CA
+        let certificate = match NodePublicKey::decode(joiner_public_key) {
+            Some(public_key) => match self.issue_certificate(joiner_hostname, &public_key) {
+                Ok(issued) => {
+                    eprintln!(
+                        "[AGENT SERVER] ✓ Issued certificate {} (expires {})",
+                        issued.fingerprint,
+                        format_timestamp(issued.expires_at)
+                    );
+                    Some(issued)
+                }
+                Err(e) => {
+                    eprintln!("[AGENT SERVER] ⚠ Warning: Failed to issue certificate: {}", e);
+                    None
+                }
+            },
+            None => {
+                eprintln!("[AGENT SERVER] Joiner sent no certificate key (older agent), skipping certificate");
+                None
+            }
+        };
 
         // Get current mesh peers
         let peers = mesh::get_active_peers().unwrap_or_default();
         eprintln!("[AGENT SERVER] Current mesh has {} peer(s)", peers.len());
 
-        // Broadcast new peer to all existing peers in the mesh
+        // Gossip the new peer to the rest of the mesh (it already knows about us)
         eprintln!("[AGENT SERVER] Broadcasting new peer to existing mesh members...");
-        let broadcast_count = self.broadcast_new_peer_to_mesh(joiner_hostname, &peers);
-        eprintln!("[AGENT SERVER] Notified {} existing peer(s)", broadcast_count);
+        let broadcast_count = self.gossip.broadcast(MessagePayload::PeerJoined {
+            hostname: joiner_hostname.to_string(),
+            tailscale_ip: None,
+            tailscale_hostname: None,
+            public_key: joiner_public_key.to_string(),
+        });
+        eprintln!("[AGENT SERVER] Handed the announcement to {} peer(s)", broadcast_count);
 
         eprintln!("[AGENT SERVER] ✓ Join accepted! Mesh now has {} peer(s)", peers.len() + 1);
         eprintln!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
@@ -365,62 +1478,89 @@ impl AgentServer {
         Ok(AgentResponse::JoinAccepted {
             shared_secret,
             mesh_peers: peers,
+            certificate,
+            ca_certificates: tls::trusted_cas().unwrap_or_default(),
         })
     }
 
-    /// Broadcast new peer information to all existing peers in the mesh
-    fn broadcast_new_peer_to_mesh(&self, new_peer_hostname: &str, existing_peers: &[String]) -> usize {
-        use crate::agent::api::AgentClient;
-        use crate::agent::discovery::HostDiscovery;
-
-        let discovery = HostDiscovery::default();
-        let Ok(hosts) = discovery.discover_all() else {
-            eprintln!("[AGENT SERVER] Failed to discover hosts for broadcast");
-            return 0;
-        };
-
-        let mut notified = 0;
+    /// Sign a certificate with this node's mesh CA, creating the CA (and this
+    /// node's own certificate) on first use
+    fn issue_certificate(&self, hostname: &str, public_key: &NodePublicKey) -> Result<IssuedCertificate> {
+        use crate::agent::mesh;
 
-        for peer_hostname in existing_peers {
-            // Find the host info for this peer
-            let host_info = hosts.iter().find(|h| &h.hostname == peer_hostname);
+        let local_hostname = mesh::local_mesh_hostname();
+        let ca = tls::MeshCa::load_or_create(&local_hostname)?;
+        if tls::ensure_node_certificate(&ca, &local_hostname)? {
+            eprintln!("[AGENT SERVER] ✓ Issued this node's own certificate, TLS is now required");
+        }
+        ca.issue(hostname, public_key)
+    }
 
-            if let Some(host) = host_info {
-                if !host.reachable {
-                    eprintln!("[AGENT SERVER]   {} (unreachable, skipping)", peer_hostname);
-                    continue;
-                }
+    /// Issue a new certificate to a mesh peer, revoking the ones it had before
+    ///
+    /// A peer about to rename itself can ask for a certificate for its new
+    /// name, as long as no other node holds that name. Its current
+    /// certificates stay valid until it has switched over.
+    fn renew_certificate(
+        &self,
+        peer_hostname: &str,
+        public_key: &str,
+        hostname: Option<&str>,
+    ) -> Result<AgentResponse> {
+        use crate::agent::{mesh, revocation};
 
-                let ip = host.tailscale_ip.as_ref().or(host.local_ip.as_ref());
-                if let Some(ip) = ip {
-                    let client = AgentClient::new(ip, host.agent_port);
+        if tls::MeshCa::load()?.is_none() {
+            return Ok(AgentResponse::Error {
+                message: "This node is not a mesh CA".to_string(),
+            });
+        }
+        let Some(public_key) = NodePublicKey::decode(public_key) else {
+            return Ok(AgentResponse::Error {
+                message: "Invalid public key: expected a base64 P-256 point".to_string(),
+            });
+        };
 
-                    // Notify peer about the new node via sync
-                    match client.sync_database(new_peer_hostname, None) {
-                        Ok(_) => {
-                            eprintln!("[AGENT SERVER]   ✓ Notified {}", peer_hostname);
-                            notified += 1;
-                        }
-                        Err(e) => {
-                            eprintln!("[AGENT SERVER]   ✗ Failed to notify {}: {}", peer_hostname, e);
-                        }
-                    }
-                } else {
-                    eprintln!("[AGENT SERVER]   {} (no IP, skipping)", peer_hostname);
-                }
-            } else {
-                eprintln!("[AGENT SERVER]   {} (not found, skipping)", peer_hostname);
+        if let Some(hostname) = hostname
+            && normalize_hostname(hostname) != normalize_hostname(peer_hostname)
+        {
+            if normalize_hostname(hostname) == normalize_hostname(&mesh::local_mesh_hostname())
+                || mesh::find_peer(hostname)?.is_some()
+                || revocation::find(hostname)?.is_some()
+            {
+                return Ok(AgentResponse::Error {
+                    message: format!("'{}' is already the name of a mesh node", hostname),
+                });
             }
+            let certificate = self.issue_certificate(hostname, &public_key)?;
+            eprintln!(
+                "[AGENT SERVER] Issued certificate for {}, to be renamed {}",
+                peer_hostname, hostname
+            );
+            return Ok(AgentResponse::CertificateIssued {
+                certificate,
+                ca_certificates: tls::trusted_cas()?,
+            });
         }
 
-        notified
+        let certificate = self.issue_certificate(peer_hostname, &public_key)?;
+        let revoked = tls::revoke_superseded_certificates(peer_hostname, &certificate.fingerprint)?;
+        eprintln!(
+            "[AGENT SERVER] Renewed certificate for {} ({} previous revoked)",
+            peer_hostname, revoked
+        );
+
+        Ok(AgentResponse::CertificateIssued {
+            certificate,
+            ca_certificates: tls::trusted_cas()?,
+        })
     }
 
     /// Validate a join token without consuming it
     fn validate_token(&self, join_token: &str) -> Result<AgentResponse> {
         use crate::agent::mesh;
 
-        match mesh::validate_join_token(join_token) {
+        // The joiner's hostname isn't known yet; the allowlist is checked on join
+        match mesh::validate_join_token(join_token, None) {
             Ok(token) => Ok(AgentResponse::TokenValid {
                 issuer_hostname: token.issuer_hostname,
             }),
@@ -430,3 +1570,74 @@ impl AgentServer {
         }
     }
 }
+
+/// Renew this node's certificate when it gets close to expiring
+async fn renew_certificate_periodically() {
+    let mut interval = tokio::time::interval(CERT_RENEWAL_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        match tokio::task::spawn_blocking(tls::renew_if_needed).await {
+            Ok(Ok(Some(info))) => println!(
+                "Renewed TLS certificate (expires {})",
+                format_timestamp(info.expires_at)
+            ),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => eprintln!("Failed to renew TLS certificate: {}", e),
+            Err(e) => eprintln!("Certificate renewal task failed: {}", e),
+        }
+    }
+}
+
+/// Forward a streamed program's output pipe to the exec stream in chunks
+fn forward_output<R>(pipe: Option<R>, stream: OutputStream, output: mpsc::Sender<(OutputStream, Vec<u8>)>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; EXEC_CHUNK_SIZE];
+        loop {
+            match pipe.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if output.send((stream, buf[..n].to_vec())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| dt.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// Resolve when the process receives SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -1,9 +1,23 @@
 use crate::agent::api::AgentClient;
 use crate::agent::discovery::DiscoveredHost;
+use crate::agent::revocation::{self, PeerRevocation};
+use crate::agent::{mesh, tls};
 use halvor_core::services::host;
 use anyhow::Result;
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Settings key prefix for where the last database sync with each peer left off
+const SYNC_CURSOR_PREFIX: &str = "sync_cursor:";
+
+fn sync_cursor_key(peer_hostname: &str) -> String {
+    format!(
+        "{}{}",
+        SYNC_CURSOR_PREFIX,
+        halvor_core::utils::hostname::normalize_hostname(peer_hostname)
+    )
+}
+
 /// Sync configuration between halvor agents
 pub struct ConfigSync {
     local_hostname: String,
@@ -53,8 +67,6 @@ impl ConfigSync {
 
     /// Sync encrypted environment data (from .env file)
     pub fn sync_encrypted_data(&self, hosts: &[DiscoveredHost]) -> Result<()> {
-        use crate::agent::mesh;
-
         for host in hosts {
             if !host.reachable {
                 continue;
@@ -72,11 +84,19 @@ impl ConfigSync {
                     .ok_or_else(|| anyhow::anyhow!("No IP for host {}", host.hostname))?,
                 host.agent_port,
             );
+            // Sync data contains secrets, so it is only exchanged encrypted with peers
+            let client = match client.with_peer(&host.hostname) {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
 
-            // Sync host configs and mesh peers from remote
-            if let Ok(sync_data_str) = client.sync_database(&self.local_hostname, None) {
+            // Sync host configs and mesh peers from remote, picking up where
+            // the last sync with this peer left off
+            let cursor_key = sync_cursor_key(&host.hostname);
+            let last_sync = halvor_db::get_setting(&cursor_key)?.and_then(|c| c.parse().ok());
+            if let Ok(sync_data_str) = client.sync_database(&self.local_hostname, last_sync) {
                 if let Ok(sync_data) = serde_json::from_str::<serde_json::Value>(&sync_data_str) {
-                    // Sync host configs (write to .env)
+                    // Sync host configs (write to .env); only sent on a full sync
                     if let Some(hosts_json) = sync_data.get("hosts") {
                         if let Some(hosts_map) = hosts_json.as_object() {
                             for (hostname, config_json) in hosts_map {
@@ -96,62 +116,39 @@ impl ConfigSync {
                         }
                     }
 
-                    // Sync mesh peers - self-healing: add any peers we don't know about
-                    if let Some(peers_json) = sync_data.get("mesh_peers") {
-                        if let Some(peers_array) = peers_json.as_array() {
-                            for peer_json in peers_array {
-                                let peer_hostname = match peer_json.get("hostname")
-                                    .and_then(|v| v.as_str())
-                                {
-                                    Some(name) => name,
-                                    None => continue, // Skip peers without hostname
-                                };
-
-                                // Check if we already have this peer
-                                let existing_peers = mesh::get_active_peers().unwrap_or_default();
-                                let normalized_peer = halvor_core::utils::hostname::normalize_hostname(peer_hostname);
-                                let normalized_local = halvor_core::utils::hostname::normalize_hostname(&self.local_hostname);
-                                
-                                // Skip self
-                                if normalized_peer == normalized_local {
-                                    continue;
-                                }
+                    // Trust the mesh CAs the peer trusts, so certificates issued by
+                    // other token-issuing nodes are accepted here too
+                    if let Some(cas_json) = sync_data.get("ca_certificates")
+                        && let Ok(ca_certificates) =
+                            serde_json::from_value::<Vec<String>>(cas_json.clone())
+                    {
+                        match tls::add_trusted_cas(&ca_certificates) {
+                            Ok(0) => {}
+                            Ok(added) => eprintln!("  ✓ Trusted {} new mesh CA certificate(s)", added),
+                            Err(e) => eprintln!("  Warning: Failed to update mesh CAs: {}", e),
+                        }
+                    }
 
-                                // Check if peer already exists
-                                let peer_exists = existing_peers.iter().any(|p| {
-                                    halvor_core::utils::hostname::normalize_hostname(p) == normalized_peer
-                                });
-
-                                if !peer_exists {
-                                    // Add missing peer to local database (self-healing)
-                                    let tailscale_ip = peer_json.get("tailscale_ip")
-                                        .and_then(|v| v.as_str())
-                                        .map(|s| s.to_string());
-                                    let tailscale_hostname = peer_json.get("tailscale_hostname")
-                                        .and_then(|v| v.as_str())
-                                        .map(|s| s.to_string());
-                                    let public_key = peer_json.get("public_key")
-                                        .and_then(|v| v.as_str())
-                                        .map(|s| s.to_string())
-                                        .unwrap_or_else(|| format!("pk_{}", Uuid::new_v4()));
-                                    
-                                    // Generate a temporary shared secret (will be updated on next sync)
-                                    let shared_secret = format!("sync_secret_{}", Uuid::new_v4());
-
-                                    if let Err(e) = mesh::add_peer(
-                                        &normalized_peer,
-                                        tailscale_ip,
-                                        tailscale_hostname,
-                                        &public_key,
-                                        &shared_secret,
-                                    ) {
-                                        eprintln!("  Warning: Failed to add peer {}: {}", normalized_peer, e);
-                                    } else {
-                                        eprintln!("  ✓ Added missing peer: {} (self-healed)", normalized_peer);
-                                    }
-                                }
-                            }
+                    // Revocations this node missed, so revoked peers aren't re-added
+                    // below. Signed revocations are the only way peers are removed
+                    // by sync; a peer's own deletions aren't trusted.
+                    if let Some(revocations_json) = sync_data.get("revocations")
+                        && let Ok(revocations) =
+                            serde_json::from_value::<Vec<PeerRevocation>>(revocations_json.clone())
+                    {
+                        self.apply_revocations(&host.hostname, &revocations);
+                    }
+
+                    // Sync mesh peers - self-healing: add peers we don't know about
+                    match self.apply_peer_changes(&host.hostname, &sync_data) {
+                        Ok(0) => {}
+                        Ok(changed) => {
+                            eprintln!("  ✓ Applied {} peer change(s) from {}", changed, host.hostname)
                         }
+                        Err(e) => eprintln!(
+                            "  Warning: Failed to apply peer changes from {}: {}",
+                            host.hostname, e
+                        ),
                     }
                 }
             }
@@ -159,4 +156,146 @@ impl ConfigSync {
 
         Ok(())
     }
+
+    /// Forget where previous syncs left off, so the next sync with each peer is a full one
+    pub fn reset_sync_cursors(&self) -> Result<()> {
+        let conn = halvor_db::get_connection()?;
+        conn.execute(
+            "DELETE FROM settings WHERE key LIKE ?1",
+            rusqlite::params![format!("{}%", SYNC_CURSOR_PREFIX)],
+        )?;
+        Ok(())
+    }
+
+    /// Apply signed peer revocations from a `SyncDatabase` reply
+    ///
+    /// Each is checked on its own; invalid ones are skipped with a warning.
+    fn apply_revocations(&self, remote: &str, revocations: &[PeerRevocation]) {
+        use halvor_core::utils::hostname::normalize_hostname;
+
+        for revocation in revocations {
+            if normalize_hostname(&revocation.hostname) == normalize_hostname(&self.local_hostname) {
+                continue;
+            }
+            match revocation::apply(revocation) {
+                Ok(false) => {}
+                Ok(true) => eprintln!(
+                    "  ✓ Peer {} was revoked by {} (learned from {})",
+                    revocation.hostname, revocation.revoked_by, remote
+                ),
+                Err(e) => eprintln!("  Warning: Ignoring revocation from {}: {:#}", remote, e),
+            }
+        }
+    }
+
+    /// Apply the peers from a `SyncDatabase` reply in one transaction
+    ///
+    /// Revoked peers are never re-added, and peers are never removed: that
+    /// takes a signed revocation (see `apply_revocations`), not the word of
+    /// whichever peer this node syncs with. The sync cursor for `remote` is stored
+    /// in the same transaction, so an interrupted sync is simply repeated.
+    /// Returns how many peers changed.
+    fn apply_peer_changes(&self, remote: &str, sync_data: &serde_json::Value) -> Result<usize> {
+        use halvor_core::utils::hostname::normalize_hostname;
+
+        let local = normalize_hostname(&self.local_hostname);
+        let revoked: HashMap<String, PeerRevocation> = revocation::list()?
+            .into_iter()
+            .map(|revocation| (normalize_hostname(&revocation.hostname), revocation))
+            .collect();
+        let now = chrono::Utc::now().timestamp();
+        let string_field = |json: &serde_json::Value, field: &str| {
+            json.get(field).and_then(|v| v.as_str()).map(|s| s.to_string())
+        };
+
+        halvor_db::with_transaction(|tx| {
+            let mut changed = 0;
+
+            let peers = sync_data.get("mesh_peers").and_then(|v| v.as_array());
+            for peer_json in peers.into_iter().flatten() {
+                let Some(hostname) = string_field(peer_json, "hostname") else {
+                    continue; // Skip peers without hostname
+                };
+                let hostname = normalize_hostname(&hostname);
+                if hostname == local {
+                    continue;
+                }
+                let public_key = string_field(peer_json, "public_key");
+                if revoked
+                    .get(&hostname)
+                    .is_some_and(|revocation| revocation.covers(public_key.as_deref()))
+                {
+                    continue;
+                }
+                // A known peer may be stored under a longer name than the normalized one
+                let hostname = mesh::find_peer(&hostname)?.unwrap_or(hostname);
+
+                // New peers are recorded without a shared secret until the two nodes
+                // pair; known ones only pick up Tailscale details. Unchanged rows
+                // aren't touched, so they don't echo back through the change log.
+                changed += tx.execute(
+                    "INSERT INTO agent_peers
+                        (id, hostname, tailscale_ip, tailscale_hostname, public_key, status,
+                         last_seen_at, joined_at, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, 'active', NULL, ?6, ?7, ?7)
+                     ON CONFLICT(hostname) DO UPDATE SET
+                        tailscale_ip = COALESCE(excluded.tailscale_ip, agent_peers.tailscale_ip),
+                        tailscale_hostname = COALESCE(excluded.tailscale_hostname, agent_peers.tailscale_hostname),
+                        updated_at = excluded.updated_at
+                     WHERE COALESCE(excluded.tailscale_ip, agent_peers.tailscale_ip) IS NOT agent_peers.tailscale_ip
+                        OR COALESCE(excluded.tailscale_hostname, agent_peers.tailscale_hostname) IS NOT agent_peers.tailscale_hostname",
+                    rusqlite::params![
+                        Uuid::new_v4().to_string(),
+                        hostname,
+                        string_field(peer_json, "tailscale_ip"),
+                        string_field(peer_json, "tailscale_hostname"),
+                        public_key.unwrap_or_else(|| format!("pk_{}", Uuid::new_v4())),
+                        peer_json.get("joined_at").and_then(|v| v.as_i64()).unwrap_or(now),
+                        now,
+                    ],
+                )?;
+            }
+
+            if let Some(cursor) = sync_data.get("sync_cursor").and_then(|v| v.as_i64()) {
+                tx.execute(
+                    "INSERT INTO settings (id, key, value, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?4)
+                     ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+                    rusqlite::params![Uuid::new_v4().to_string(), sync_cursor_key(remote), cursor.to_string(), now],
+                )?;
+            }
+            Ok(changed)
+        })
+    }
+
+    /// Exchange replicated config with every paired peer
+    ///
+    /// Catches up on (and passes on) writes whose gossip broadcast was missed,
+    /// e.g. while a node was offline. Returns how many local keys changed.
+    pub fn sync_mesh_config(&self) -> Result<usize> {
+        use crate::agent::{mesh, mesh_config};
+        use halvor_core::utils::hostname::normalize_hostname;
+
+        let mut changed = 0;
+        for peer in mesh::get_peers()? {
+            if normalize_hostname(&peer) == normalize_hostname(&self.local_hostname)
+                || mesh::get_peer_shared_secret(&peer)?.is_none()
+            {
+                continue;
+            }
+
+            let client = match AgentClient::new(&mesh::peer_address(&peer)?, mesh::DEFAULT_AGENT_PORT)
+                .with_peer(&peer)
+            {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            match client.sync_config(&mesh_config::list()?) {
+                Ok(remote) => changed += mesh_config::merge_all(&remote)?,
+                Err(e) => eprintln!("  Warning: Failed to sync config with {}: {}", peer, e),
+            }
+        }
+
+        Ok(changed)
+    }
 }
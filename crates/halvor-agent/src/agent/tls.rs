@@ -0,0 +1,1274 @@
+//! Mutual TLS for agent connections, backed by a mesh-local certificate authority
+//!
+//! A node that issues join tokens acts as a small mesh CA: during `JoinRequest`
+//! it signs a certificate for the joiner's key, and it gives itself a
+//! certificate from the same CA. Once a node holds a certificate its agent
+//! listener only accepts TLS, and only handshakes with a client certificate
+//! from a trusted mesh CA may send anything beyond the join bootstrap
+//! (`Ping`, `ValidateToken`, `JoinRequest` and certificate renewal).
+//!
+//! Several nodes can issue tokens, so each node trusts a bundle of mesh CA
+//! certificates. Joiners receive the issuer's bundle and peers merge each
+//! other's bundles during sync.
+//!
+//! Files live in `<config dir>/tls/`:
+//! - `ca.key`, `ca.crt`, `ca.json`: this node's CA (token-issuing nodes only)
+//! - `node.key`, `node.crt`, `node.json`: this node's key, certificate chain and metadata
+//! - `trusted-cas.pem`: CA certificates this node trusts
+
+use crate::agent::api::AgentClient;
+use crate::agent::mesh;
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose};
+use halvor_core::config::config_manager;
+use halvor_core::utils::hostname::normalize_hostname;
+use halvor_db::generated::{MeshCertificatesRow, MeshCertificatesRowData};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose,
+    IsCa, Issuer, KeyPair, KeyUsagePurpose, PublicKeyData, SerialNumber, SignatureAlgorithm,
+};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ParsedCertificate, WebPkiClientVerifier};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme, StreamOwned,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Validity of a mesh CA certificate
+pub const CA_VALIDITY_DAYS: i64 = 3650;
+
+/// Validity of node certificates issued by a mesh CA
+pub const CERT_VALIDITY_DAYS: i64 = 90;
+
+/// Node certificates are renewed once they have less than this left
+pub const RENEW_BEFORE_DAYS: i64 = 30;
+
+/// First byte of a TLS handshake record, used to tell TLS from framed plaintext
+pub const TLS_HANDSHAKE_BYTE: u8 = 0x16;
+
+const TLS_DIR_NAME: &str = "tls";
+const CA_KEY_FILE: &str = "ca.key";
+const CA_CERT_FILE: &str = "ca.crt";
+const CA_INFO_FILE: &str = "ca.json";
+const NODE_KEY_FILE: &str = "node.key";
+const NODE_CERT_FILE: &str = "node.crt";
+const NODE_INFO_FILE: &str = "node.json";
+const TRUSTED_CAS_FILE: &str = "trusted-cas.pem";
+
+/// Server name used for the handshake when connecting by an address that isn't a valid name
+const FALLBACK_SERVER_NAME: &str = "halvor-agent";
+
+const DAY_SECS: i64 = 24 * 3600;
+
+/// A certificate signed by a mesh CA, as sent to the node it was issued for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedCertificate {
+    /// PEM chain: the node certificate followed by the issuing CA certificate
+    pub certificate: String,
+    /// SHA-256 fingerprint of the node certificate
+    pub fingerprint: String,
+    pub serial: String,
+    pub expires_at: i64,
+}
+
+/// Metadata for this node's certificate (`node.json`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeCertificateInfo {
+    /// Mesh hostname the certificate was issued for
+    pub hostname: String,
+    /// Hostname of the node whose CA signed the certificate
+    pub issuer_hostname: String,
+    /// SHA-256 fingerprint of the signing CA certificate
+    pub ca_fingerprint: String,
+    pub fingerprint: String,
+    pub serial: String,
+    pub expires_at: i64,
+}
+
+impl NodeCertificateInfo {
+    /// Whether the certificate expires within [`RENEW_BEFORE_DAYS`]
+    pub fn needs_renewal(&self) -> bool {
+        self.expires_at - chrono::Utc::now().timestamp() < RENEW_BEFORE_DAYS * DAY_SECS
+    }
+}
+
+/// Metadata for this node's CA (`ca.json`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CaInfo {
+    common_name: String,
+    fingerprint: String,
+    expires_at: i64,
+}
+
+/// This node's certificate authority
+pub struct MeshCa {
+    key: KeyPair,
+    info: CaInfo,
+    certificate_pem: String,
+}
+
+impl MeshCa {
+    /// Load this node's CA, if it has one
+    pub fn load() -> Result<Option<Self>> {
+        let dir = tls_dir()?;
+        let key_path = dir.join(CA_KEY_FILE);
+        if !key_path.exists() {
+            return Ok(None);
+        }
+
+        let key = KeyPair::from_pem(&read_string(&key_path)?)
+            .with_context(|| format!("Invalid CA key: {}", key_path.display()))?;
+        let info: CaInfo = serde_json::from_str(&read_string(&dir.join(CA_INFO_FILE))?)
+            .context("Invalid CA metadata")?;
+        let certificate_pem = read_string(&dir.join(CA_CERT_FILE))?;
+
+        Ok(Some(Self {
+            key,
+            info,
+            certificate_pem,
+        }))
+    }
+
+    /// Load this node's CA, creating it on first use
+    pub fn load_or_create(hostname: &str) -> Result<Self> {
+        if let Some(ca) = Self::load()? {
+            return Ok(ca);
+        }
+
+        let common_name = format!("halvor mesh CA ({})", hostname);
+        let key = KeyPair::generate().context("Failed to generate CA key")?;
+        let mut params = ca_params(&common_name);
+        let now = chrono::Utc::now().timestamp();
+        let expires_at = now + CA_VALIDITY_DAYS * DAY_SECS;
+        params.not_before = to_datetime(now - 3600)?;
+        params.not_after = to_datetime(expires_at)?;
+        params.serial_number = Some(random_serial().0);
+
+        let certificate = params
+            .self_signed(&key)
+            .context("Failed to create CA certificate")?;
+        let info = CaInfo {
+            common_name,
+            fingerprint: fingerprint(certificate.der()),
+            expires_at,
+        };
+
+        let dir = tls_dir()?;
+        write_private(&dir.join(CA_KEY_FILE), &key.serialize_pem())?;
+        write_file(&dir.join(CA_CERT_FILE), &certificate.pem())?;
+        write_file(&dir.join(CA_INFO_FILE), &serde_json::to_string_pretty(&info)?)?;
+        add_trusted_cas(&[certificate.pem()])?;
+
+        Ok(Self {
+            key,
+            info,
+            certificate_pem: certificate.pem(),
+        })
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.info.fingerprint
+    }
+
+    pub fn certificate_pem(&self) -> &str {
+        &self.certificate_pem
+    }
+
+    pub fn expires_at(&self) -> i64 {
+        self.info.expires_at
+    }
+
+    /// Sign a certificate for `hostname` and record it in `mesh_certificates`
+    pub fn issue(&self, hostname: &str, public_key: &impl PublicKeyData) -> Result<IssuedCertificate> {
+        let (issued, certificate_pem) = self.sign(hostname, public_key)?;
+
+        MeshCertificatesRow::insert_one(MeshCertificatesRowData {
+            peer_hostname: hostname.to_string(),
+            serial: issued.serial.clone(),
+            fingerprint: issued.fingerprint.clone(),
+            certificate: certificate_pem,
+            expires_at: issued.expires_at,
+            revoked_at: None,
+        })?;
+
+        Ok(issued)
+    }
+
+    /// Sign a certificate for `hostname`, returning it with the node certificate's own PEM
+    fn sign(&self, hostname: &str, public_key: &impl PublicKeyData) -> Result<(IssuedCertificate, String)> {
+        let mut params = CertificateParams::new(vec![hostname.to_string()])
+            .with_context(|| format!("'{}' can't be used as a certificate name", hostname))?;
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, hostname);
+        params.distinguished_name = distinguished_name;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        params.use_authority_key_identifier_extension = true;
+
+        let now = chrono::Utc::now().timestamp();
+        let expires_at = now + CERT_VALIDITY_DAYS * DAY_SECS;
+        params.not_before = to_datetime(now - 3600)?;
+        params.not_after = to_datetime(expires_at)?;
+        let (serial_number, serial) = random_serial();
+        params.serial_number = Some(serial_number);
+
+        // The CA certificate's own params are only needed for its name and key id
+        let issuer = Issuer::new(ca_params(&self.info.common_name), &self.key);
+        let certificate = params
+            .signed_by(public_key, &issuer)
+            .context("Failed to sign node certificate")?;
+
+        let issued = IssuedCertificate {
+            certificate: format!("{}{}", certificate.pem(), self.certificate_pem),
+            fingerprint: fingerprint(certificate.der()),
+            serial,
+            expires_at,
+        };
+        Ok((issued, certificate.pem()))
+    }
+}
+
+fn ca_params(common_name: &str) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, common_name);
+    params.distinguished_name = distinguished_name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params
+}
+
+/// A P-256 public key sent by another node (uncompressed point, base64)
+pub struct NodePublicKey(Vec<u8>);
+
+impl NodePublicKey {
+    /// Decode a key from `JoinRequest`/`RenewCertificate`; `None` for keys that
+    /// aren't P-256 points (e.g. placeholders sent by older agents)
+    pub fn decode(encoded: &str) -> Option<Self> {
+        let bytes = general_purpose::STANDARD.decode(encoded).ok()?;
+        (bytes.len() == 65 && bytes[0] == 0x04).then_some(Self(bytes))
+    }
+}
+
+impl PublicKeyData for NodePublicKey {
+    fn der_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        &rcgen::PKCS_ECDSA_P256_SHA256
+    }
+}
+
+/// Generate a new node key (not stored until a certificate is installed for it)
+pub fn generate_node_key() -> Result<KeyPair> {
+    KeyPair::generate().context("Failed to generate node key")
+}
+
+/// Encode a node key's public half for `JoinRequest`/`RenewCertificate`
+pub fn encode_public_key(key: &KeyPair) -> String {
+    general_purpose::STANDARD.encode(key.public_key_raw())
+}
+
+/// Metadata of this node's certificate, if it has one
+pub fn node_certificate() -> Result<Option<NodeCertificateInfo>> {
+    let path = tls_dir()?.join(NODE_INFO_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let info = serde_json::from_str(&read_string(&path)?).context("Invalid node certificate metadata")?;
+    Ok(Some(info))
+}
+
+/// SHA-256 fingerprint of this node's public key, if it has a key yet
+pub fn node_key_fingerprint() -> Result<Option<String>> {
+    let key_path = tls_dir()?.join(NODE_KEY_FILE);
+    if !key_path.exists() {
+        return Ok(None);
+    }
+    let key = KeyPair::from_pem(&read_string(&key_path)?)
+        .with_context(|| format!("Invalid node key: {}", key_path.display()))?;
+    Ok(Some(fingerprint(key.public_key_raw())))
+}
+
+/// Store a certificate issued for `key` as this node's identity and trust the given CAs
+pub fn install_node_certificate(
+    key: &KeyPair,
+    issued: &IssuedCertificate,
+    ca_certificates: &[String],
+    hostname: &str,
+    issuer_hostname: &str,
+) -> Result<NodeCertificateInfo> {
+    let chain = parse_certificates(&issued.certificate)?;
+    let ca = chain
+        .get(1)
+        .context("Issued certificate chain does not include the CA certificate")?;
+
+    let info = NodeCertificateInfo {
+        hostname: hostname.to_string(),
+        issuer_hostname: issuer_hostname.to_string(),
+        ca_fingerprint: fingerprint(ca),
+        fingerprint: issued.fingerprint.clone(),
+        serial: issued.serial.clone(),
+        expires_at: issued.expires_at,
+    };
+
+    // The issuing CA is always trusted, even if the issuer left it out of the bundle
+    let mut cas = split_pem(&issued.certificate).split_off(1);
+    cas.extend(ca_certificates.iter().cloned());
+    add_trusted_cas(&cas)?;
+
+    let dir = tls_dir()?;
+    write_private(&dir.join(NODE_KEY_FILE), &key.serialize_pem())?;
+    write_file(&dir.join(NODE_CERT_FILE), &issued.certificate)?;
+    write_file(&dir.join(NODE_INFO_FILE), &serde_json::to_string_pretty(&info)?)?;
+
+    Ok(info)
+}
+
+/// Give a CA node a certificate from its own CA if it has none, or renew an
+/// expiring self-issued one. Returns whether a certificate was issued.
+pub fn ensure_node_certificate(ca: &MeshCa, hostname: &str) -> Result<bool> {
+    if let Some(current) = node_certificate()? {
+        // Certificates from another node's CA are renewed through that node
+        if !current.needs_renewal() || current.ca_fingerprint != ca.fingerprint() {
+            return Ok(false);
+        }
+    }
+
+    let key = generate_node_key()?;
+    let issued = ca.issue(hostname, &key)?;
+    install_node_certificate(&key, &issued, &[], hostname, hostname)?;
+    Ok(true)
+}
+
+/// Replace this node's certificate with a new one for a fresh key
+///
+/// The certificate is issued for this node's current mesh hostname.
+/// Self-issued certificates are signed locally; others are requested from the
+/// issuing node (or `from`, e.g. for nodes that joined before TLS existed).
+pub fn renew_node_certificate(from: Option<&str>) -> Result<NodeCertificateInfo> {
+    request_certificate(&mesh::local_mesh_hostname(), from)?.install()
+}
+
+/// Get a certificate for `hostname`, the name this node is about to be renamed to
+///
+/// It comes from the CA that issued the current certificate, which has to be
+/// reachable under the current name; it isn't used until it is installed.
+pub fn certificate_for_rename(hostname: &str) -> Result<PendingCertificate> {
+    request_certificate(hostname, None)
+}
+
+/// A certificate issued for this node that it doesn't use yet
+pub struct PendingCertificate {
+    key: KeyPair,
+    issued: IssuedCertificate,
+    ca_certificates: Vec<String>,
+    hostname: String,
+    issuer_hostname: String,
+}
+
+impl PendingCertificate {
+    /// Start using the certificate as this node's own
+    pub fn install(&self) -> Result<NodeCertificateInfo> {
+        install_node_certificate(
+            &self.key,
+            &self.issued,
+            &self.ca_certificates,
+            &self.hostname,
+            &self.issuer_hostname,
+        )
+    }
+}
+
+fn request_certificate(hostname: &str, from: Option<&str>) -> Result<PendingCertificate> {
+    let local_hostname = mesh::local_mesh_hostname();
+    let current = node_certificate()?;
+    let issuer = match (from, &current) {
+        (Some(from), _) => from.to_string(),
+        (None, Some(current)) => current.issuer_hostname.clone(),
+        (None, None) => anyhow::bail!(
+            "This node has no certificate yet; name the peer whose CA should issue one"
+        ),
+    };
+    // Matched by CA rather than name, which is an old one after a rename
+    let own_ca = MeshCa::load()?;
+    let self_issued = match (from, &current, &own_ca) {
+        (None, Some(current), Some(ca)) => current.ca_fingerprint == ca.fingerprint(),
+        _ => normalize_hostname(&issuer) == normalize_hostname(&local_hostname),
+    };
+
+    let key = generate_node_key()?;
+    if self_issued {
+        let ca = own_ca.context("This node is not a mesh CA")?;
+        let issued = ca.issue(hostname, &key)?;
+        return Ok(PendingCertificate {
+            key,
+            issued,
+            ca_certificates: Vec::new(),
+            hostname: hostname.to_string(),
+            issuer_hostname: hostname.to_string(),
+        });
+    }
+
+    let peer = mesh::find_peer(&issuer)?
+        .with_context(|| format!("'{}' is not a mesh peer of this host", issuer))?;
+    let mut client = AgentClient::new(&mesh::peer_address(&peer)?, mesh::DEFAULT_AGENT_PORT).with_peer(&peer)?;
+    if trusted_cas()?.is_empty() {
+        // Nodes that joined before TLS have no CA to check the server against.
+        // The renewal is still sealed and authenticated with the peer's shared secret.
+        client = client.with_server_trust(ServerTrust::Bootstrap);
+    }
+    let renamed = normalize_hostname(hostname) != normalize_hostname(&local_hostname);
+    let (issued, ca_certificates) =
+        client.renew_certificate(&encode_public_key(&key), renamed.then_some(hostname))?;
+    // Agents from before renames ignore the name and issue for the current one
+    if renamed && !is_issued_for(&issued, hostname)? {
+        anyhow::bail!("{} can't issue certificates for a new name; update it first", peer);
+    }
+    Ok(PendingCertificate {
+        key,
+        issued,
+        ca_certificates,
+        hostname: hostname.to_string(),
+        issuer_hostname: peer,
+    })
+}
+
+/// Whether `issued` names `hostname`
+fn is_issued_for(issued: &IssuedCertificate, hostname: &str) -> Result<bool> {
+    let chain = parse_certificates(&issued.certificate)?;
+    let leaf = chain.first().context("Issued certificate chain is empty")?;
+    let parsed = ParsedCertificate::try_from(leaf).map_err(|e| anyhow::anyhow!("Invalid issued certificate: {}", e))?;
+    let name = ServerName::try_from(hostname).with_context(|| format!("Invalid hostname: {}", hostname))?;
+    Ok(rustls::client::verify_server_name(&parsed, &name).is_ok())
+}
+
+/// Renew this node's certificate if it is close to expiring
+pub fn renew_if_needed() -> Result<Option<NodeCertificateInfo>> {
+    match node_certificate()? {
+        Some(current) if current.needs_renewal() => renew_node_certificate(None).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Delete this node's CA, certificate, key and trusted CAs, leaving it outside any mesh
+pub fn remove_mesh_identity() -> Result<()> {
+    let dir = tls_dir()?;
+    for name in [
+        CA_KEY_FILE,
+        CA_CERT_FILE,
+        CA_INFO_FILE,
+        NODE_KEY_FILE,
+        NODE_CERT_FILE,
+        NODE_INFO_FILE,
+        TRUSTED_CAS_FILE,
+    ] {
+        let path = dir.join(name);
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// CA certificates this node trusts, as PEM
+pub fn trusted_cas() -> Result<Vec<String>> {
+    let path = tls_dir()?.join(TRUSTED_CAS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(split_pem(&read_string(&path)?))
+}
+
+/// Add CA certificates to the trust bundle, returning how many were new
+pub fn add_trusted_cas(certificates: &[String]) -> Result<usize> {
+    let mut trusted = trusted_cas()?;
+    let mut known: Vec<String> = trusted
+        .iter()
+        .filter_map(|pem| parse_certificates(pem).ok()?.first().map(|der| fingerprint(der)))
+        .collect();
+
+    let mut added = 0;
+    for pem in certificates.iter().flat_map(|pem| split_pem(pem)) {
+        let der = parse_certificates(&pem)?
+            .into_iter()
+            .next()
+            .context("Empty CA certificate")?;
+        let fingerprint = fingerprint(&der);
+        if !known.contains(&fingerprint) {
+            known.push(fingerprint);
+            trusted.push(pem);
+            added += 1;
+        }
+    }
+
+    if added > 0 {
+        write_file(&tls_dir()?.join(TRUSTED_CAS_FILE), &trusted.concat())?;
+    }
+    Ok(added)
+}
+
+/// Mark every certificate issued to `hostname` as revoked, returning how many were
+pub fn revoke_peer_certificates(hostname: &str) -> Result<usize> {
+    let conn = halvor_db::get_connection()?;
+    let now = chrono::Utc::now().timestamp();
+
+    let revoked = conn.execute(
+        "UPDATE mesh_certificates SET revoked_at = ?1, updated_at = ?1
+         WHERE peer_hostname = ?2 AND revoked_at IS NULL",
+        rusqlite::params![now, hostname],
+    )?;
+
+    Ok(revoked)
+}
+
+/// Revoke the certificates issued to `hostname` other than `current_fingerprint`
+pub fn revoke_superseded_certificates(hostname: &str, current_fingerprint: &str) -> Result<usize> {
+    let conn = halvor_db::get_connection()?;
+    let now = chrono::Utc::now().timestamp();
+
+    let revoked = conn.execute(
+        "UPDATE mesh_certificates SET revoked_at = ?1, updated_at = ?1
+         WHERE peer_hostname = ?2 AND fingerprint != ?3 AND revoked_at IS NULL",
+        rusqlite::params![now, hostname, current_fingerprint],
+    )?;
+
+    Ok(revoked)
+}
+
+/// Whether a certificate with this fingerprint has been revoked
+pub fn is_revoked(fingerprint: &str) -> Result<bool> {
+    MeshCertificatesRow::query()
+        .fingerprint()
+        .eq(fingerprint)
+        .revoked_at()
+        .is_not_null()
+        .exists()
+}
+
+/// Certificates issued by this node's CA, newest first
+pub fn issued_certificates() -> Result<Vec<MeshCertificatesRow>> {
+    MeshCertificatesRow::query().order_by_created_at_desc().all()
+}
+
+/// Work out which mesh peer a verified client certificate belongs to
+///
+/// The chain has already been checked against the trusted CAs; this rejects
+/// revoked certificates and ones not issued for a mesh peer (or this host).
+/// Suspect and inactive peers are accepted, so they can come back.
+pub fn peer_identity(certificates: &[CertificateDer<'_>]) -> Result<String> {
+    let leaf = certificates.first().context("no certificate presented")?;
+    if is_revoked(&fingerprint(leaf))? {
+        anyhow::bail!("has been revoked");
+    }
+
+    let parsed = ParsedCertificate::try_from(leaf).map_err(|e| anyhow::anyhow!("is invalid: {}", e))?;
+    let mut candidates = mesh::get_peers()?;
+    candidates.push(mesh::local_mesh_hostname());
+
+    candidates
+        .into_iter()
+        .find(|name| {
+            ServerName::try_from(name.as_str())
+                .map(|server_name| rustls::client::verify_server_name(&parsed, &server_name).is_ok())
+                .unwrap_or(false)
+        })
+        .context("does not belong to a mesh peer")
+}
+
+/// A signature by a mesh node, which any node trusting its CA can check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSignature {
+    /// The signer's certificate chain (PEM)
+    pub certificate: String,
+    /// ECDSA P-256 SHA-256 signature (ASN.1 DER, base64)
+    pub signature: String,
+}
+
+/// Sign `message` with this node's key, if it has a certificate
+pub fn sign_with_node_key(message: &[u8]) -> Result<Option<NodeSignature>> {
+    let dir = tls_dir()?;
+    let cert_path = dir.join(NODE_CERT_FILE);
+    let key_path = dir.join(NODE_KEY_FILE);
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+
+    let key = KeyPair::from_pem(&read_string(&key_path)?)
+        .with_context(|| format!("Invalid node key: {}", key_path.display()))?;
+    sign_with(&key, &read_string(&cert_path)?, message).map(Some)
+}
+
+/// Check that `signed` is `signer`'s signature of `message`
+///
+/// The signing certificate must chain to a trusted mesh CA, be issued for
+/// `signer` and not be revoked here.
+pub fn verify_node_signature(signer: &str, message: &[u8], signed: &NodeSignature) -> Result<()> {
+    let chain = parse_certificates(&signed.certificate)?;
+    let leaf = chain.first().context("Signature has no certificate")?;
+    if is_revoked(&fingerprint(leaf))? {
+        anyhow::bail!("Signing certificate has been revoked");
+    }
+
+    let mut cas = Vec::new();
+    for pem in trusted_cas()? {
+        cas.extend(parse_certificates(&pem)?);
+    }
+    verify_with(&cas, signer, message, signed)
+}
+
+fn sign_with(key: &KeyPair, certificate: &str, message: &[u8]) -> Result<NodeSignature> {
+    use rcgen::SigningKey;
+
+    let signature = key.sign(message).context("Failed to sign with node key")?;
+    Ok(NodeSignature {
+        certificate: certificate.to_string(),
+        signature: general_purpose::STANDARD.encode(signature),
+    })
+}
+
+fn verify_with(
+    cas: &[CertificateDer<'_>],
+    signer: &str,
+    message: &[u8],
+    signed: &NodeSignature,
+) -> Result<()> {
+    let chain = parse_certificates(&signed.certificate)?;
+    let (leaf, intermediates) = chain.split_first().context("Signature has no certificate")?;
+    let anchors = cas
+        .iter()
+        .map(webpki::anchor_from_trusted_cert)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!("Invalid trusted CA certificate: {}", e))?;
+
+    let cert = webpki::EndEntityCert::try_from(leaf)
+        .map_err(|e| anyhow::anyhow!("Invalid signing certificate: {}", e))?;
+    cert.verify_for_usage(
+        provider().signature_verification_algorithms.all,
+        &anchors,
+        intermediates,
+        UnixTime::now(),
+        webpki::KeyUsage::client_auth(),
+        None,
+        None,
+    )
+    .map_err(|e| anyhow::anyhow!("Signing certificate is not from a trusted mesh CA: {}", e))?;
+
+    let name = ServerName::try_from(signer).with_context(|| format!("Invalid signer name: {}", signer))?;
+    cert.verify_is_valid_for_subject_name(&name)
+        .map_err(|_| anyhow::anyhow!("Signing certificate was not issued to {}", signer))?;
+
+    let signature = general_purpose::STANDARD
+        .decode(&signed.signature)
+        .context("Invalid signature encoding")?;
+    cert.verify_signature(webpki::ring::ECDSA_P256_SHA256, message, &signature)
+        .map_err(|_| anyhow::anyhow!("Signature does not match"))
+}
+
+/// SHA-256 fingerprint of a DER certificate, as lowercase hex
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn root_store() -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for pem in trusted_cas()? {
+        for der in parse_certificates(&pem)? {
+            roots.add(der).context("Invalid trusted CA certificate")?;
+        }
+    }
+    Ok(roots)
+}
+
+/// A certificate chain and its private key
+type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// This node's certificate chain and key, if it has a certificate
+fn node_identity() -> Result<Option<Identity>> {
+    let dir = tls_dir()?;
+    let cert_path = dir.join(NODE_CERT_FILE);
+    let key_path = dir.join(NODE_KEY_FILE);
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+
+    let chain = parse_certificates(&read_string(&cert_path)?)?;
+    let key = PrivateKeyDer::from_pem_slice(read_string(&key_path)?.as_bytes())
+        .with_context(|| format!("Invalid node key: {}", key_path.display()))?;
+    Ok(Some((chain, key)))
+}
+
+/// The identity to present to agents: this node's certificate, unless it was
+/// issued for a name the node no longer uses
+///
+/// Between a rename and the new certificate, peers only know the new name and
+/// would refuse the old certificate. Without one the connection is anonymous,
+/// which is enough to renew it.
+fn client_identity() -> Result<Option<Identity>> {
+    match node_certificate()? {
+        Some(info) if normalize_hostname(&info.hostname) == normalize_hostname(&mesh::local_mesh_hostname()) => {
+            node_identity()
+        }
+        _ => Ok(None),
+    }
+}
+
+/// TLS configuration for the agent listener; `None` until this node has a certificate
+pub fn server_config() -> Result<Option<Arc<ServerConfig>>> {
+    let Some((chain, key)) = node_identity()? else {
+        return Ok(None);
+    };
+
+    // Client certificates are optional at the handshake so nodes can still join;
+    // the server restricts what anonymous connections may do
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store()?), provider())
+        .allow_unauthenticated()
+        .build()
+        .context("Failed to build client certificate verifier")?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(chain, key)
+        .context("Invalid node certificate")?;
+
+    Ok(Some(Arc::new(config)))
+}
+
+/// Listener TLS configuration, reloaded when the certificate or trust bundle changes
+///
+/// This lets a node switch to TLS right after joining and pick up rotated
+/// certificates without restarting the agent.
+#[derive(Default)]
+pub struct ServerTls {
+    /// Modification times of the TLS files and the configuration built from them
+    cached: Mutex<Option<(FileStamp, Option<Arc<ServerConfig>>)>>,
+}
+
+type FileStamp = Vec<Option<SystemTime>>;
+
+impl ServerTls {
+    /// Current configuration, or `None` if TLS is not enabled yet
+    pub fn config(&self) -> Option<Arc<ServerConfig>> {
+        let stamp = file_stamp();
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((cached_stamp, config)) = cached.as_ref()
+            && *cached_stamp == stamp
+        {
+            return config.clone();
+        }
+
+        let config = match server_config() {
+            Ok(config) => {
+                if config.is_some() {
+                    println!("TLS enabled: only TLS connections are accepted");
+                }
+                config
+            }
+            Err(e) => {
+                eprintln!("Failed to load TLS configuration: {}", e);
+                // Keep serving with the previous configuration
+                cached.as_ref().and_then(|(_, config)| config.clone())
+            }
+        };
+        *cached = Some((stamp, config.clone()));
+        config
+    }
+}
+
+fn file_stamp() -> FileStamp {
+    let Ok(dir) = tls_dir() else {
+        return Vec::new();
+    };
+    [NODE_CERT_FILE, NODE_KEY_FILE, TRUSTED_CAS_FILE]
+        .iter()
+        .map(|name| fs::metadata(dir.join(name)).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// How a client checks the agent's server certificate
+#[derive(Debug, Clone)]
+pub enum ServerTrust {
+    /// Chain to a trusted mesh CA; use TLS only if this node has trusted CAs
+    Mesh,
+    /// Chain to the CA with this fingerprint, sent by the server (used when joining)
+    Pinned {
+        ca_fingerprint: String,
+        server_name: String,
+    },
+    /// Accept any certificate. Only for requests whose payload is sealed with a
+    /// peer's shared secret, when this node has no CA to check against yet.
+    Bootstrap,
+}
+
+/// TLS configuration for connecting to an agent
+///
+/// Returns `None` for [`ServerTrust::Mesh`] when this node trusts no CAs yet;
+/// the connection is then made in plaintext. `expected_name` is the mesh
+/// hostname the server certificate must be issued for, if known.
+pub fn client_config(trust: &ServerTrust, expected_name: Option<&str>) -> Result<Option<Arc<ClientConfig>>> {
+    let anchors = match trust {
+        ServerTrust::Mesh => {
+            let roots = root_store()?;
+            if roots.is_empty() {
+                return Ok(None);
+            }
+            TrustAnchors::Roots(Arc::new(roots))
+        }
+        ServerTrust::Pinned { ca_fingerprint, .. } => TrustAnchors::Pinned(ca_fingerprint.clone()),
+        ServerTrust::Bootstrap => TrustAnchors::Any,
+    };
+    let expected_name = match trust {
+        ServerTrust::Pinned { server_name, .. } => Some(server_name.clone()),
+        _ => expected_name.map(|name| name.to_string()),
+    };
+
+    let provider = provider();
+    let verifier = MeshServerVerifier {
+        anchors,
+        expected_name,
+        provider: Arc::clone(&provider),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match client_identity()? {
+        Some((chain, key)) => builder
+            .with_client_auth_cert(chain, key)
+            .context("Invalid node certificate")?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Some(Arc::new(config)))
+}
+
+#[derive(Debug)]
+enum TrustAnchors {
+    Roots(Arc<RootCertStore>),
+    Pinned(String),
+    Any,
+}
+
+/// Checks agent server certificates against the mesh CAs
+///
+/// Agents are usually reached by IP, so the certificate is matched against the
+/// peer's mesh hostname when the caller knows it rather than against the address.
+#[derive(Debug)]
+struct MeshServerVerifier {
+    anchors: TrustAnchors,
+    expected_name: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for MeshServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let roots = match &self.anchors {
+            TrustAnchors::Any => return Ok(ServerCertVerified::assertion()),
+            TrustAnchors::Roots(roots) => Arc::clone(roots),
+            TrustAnchors::Pinned(ca_fingerprint) => {
+                let ca = intermediates
+                    .iter()
+                    .find(|der| fingerprint(der) == *ca_fingerprint)
+                    .ok_or_else(|| {
+                        rustls::Error::General("agent did not present the expected mesh CA".to_string())
+                    })?;
+                let mut roots = RootCertStore::empty();
+                roots.add(ca.clone().into_owned())?;
+                Arc::new(roots)
+            }
+        };
+
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        rustls::client::verify_server_cert_signed_by_trust_anchor(
+            &cert,
+            &roots,
+            intermediates,
+            now,
+            self.provider.signature_verification_algorithms.all,
+        )?;
+
+        if let Some(name) = &self.expected_name {
+            let server_name = ServerName::try_from(name.as_str())
+                .map_err(|e| rustls::Error::General(format!("invalid peer name '{}': {}", name, e)))?;
+            rustls::client::verify_server_name(&cert, &server_name)?;
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A client connection to an agent, with or without TLS
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl ClientStream {
+    /// Complete a TLS handshake over `stream`
+    pub fn connect_tls(mut stream: TcpStream, config: Arc<ClientConfig>, host: &str) -> io::Result<Self> {
+        let server_name = ServerName::try_from(host.to_string())
+            .unwrap_or_else(|_| ServerName::try_from(FALLBACK_SERVER_NAME).expect("valid server name"));
+        let mut connection = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+
+        // Finish the handshake up front so certificate errors surface here
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(ClientStream::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    /// Set the read timeout of the underlying socket
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.set_read_timeout(timeout),
+            ClientStream::Tls(stream) => stream.sock.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.read(buf),
+            ClientStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.write(buf),
+            ClientStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.flush(),
+            ClientStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Whether a failed handshake means the agent doesn't speak TLS at all
+///
+/// A node that has no certificate yet answers TLS with a plaintext error frame;
+/// agents predating TLS support just drop the connection.
+pub fn is_plaintext_peer(error: &io::Error) -> bool {
+    if matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof
+    ) {
+        return true;
+    }
+    matches!(
+        error.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidMessage(_))
+    )
+}
+
+/// Directory holding the TLS files, created with owner-only permissions
+fn tls_dir() -> Result<PathBuf> {
+    let dir = config_manager::get_config_dir()?.join(TLS_DIR_NAME);
+    if !dir.exists() {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create TLS directory: {}", dir.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))
+                .context("Failed to set TLS directory permissions")?;
+        }
+    }
+    Ok(dir)
+}
+
+fn read_string(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Write a private key, readable only by the owner
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    write_file(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn parse_certificates(pem: &str) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid PEM certificate")
+}
+
+/// Split a PEM bundle into one string per certificate
+fn split_pem(bundle: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    bundle
+        .split_inclusive(END)
+        .map(|block| block.trim())
+        .filter(|block| block.ends_with(END))
+        .map(|block| format!("{}\n", block))
+        .collect()
+}
+
+fn random_serial() -> (SerialNumber, String) {
+    let bytes = uuid::Uuid::new_v4();
+    let mut serial = [0u8; 8];
+    serial.copy_from_slice(&bytes.as_bytes()[..8]);
+    // Keep the serial positive in DER
+    let serial = u64::from_be_bytes(serial) >> 1;
+    (SerialNumber::from(serial), format!("{:016x}", serial))
+}
+
+fn to_datetime(timestamp: i64) -> Result<time::OffsetDateTime> {
+    time::OffsetDateTime::from_unix_timestamp(timestamp).context("Invalid certificate validity")
+}
+
+/// A mesh CA that lives only in memory, for tests of signed messages
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    pub struct TestCa {
+        ca: MeshCa,
+        cas: Vec<CertificateDer<'static>>,
+    }
+
+    impl TestCa {
+        pub fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let common_name = "halvor mesh CA (test)".to_string();
+            let mut params = ca_params(&common_name);
+            params.not_before = to_datetime(chrono::Utc::now().timestamp() - 60).unwrap();
+            let certificate = params.self_signed(&key).unwrap();
+            let info = CaInfo {
+                common_name,
+                fingerprint: fingerprint(certificate.der()),
+                expires_at: 0,
+            };
+            Self {
+                cas: parse_certificates(&certificate.pem()).unwrap(),
+                ca: MeshCa {
+                    key,
+                    info,
+                    certificate_pem: certificate.pem(),
+                },
+            }
+        }
+
+        /// Sign `message` as `hostname`, with a certificate issued for it
+        pub fn sign_as(&self, hostname: &str, message: &[u8]) -> NodeSignature {
+            let key = generate_node_key().unwrap();
+            let (issued, _) = self.ca.sign(hostname, &key).unwrap();
+            sign_with(&key, &issued.certificate, message).unwrap()
+        }
+
+        /// [`verify_node_signature`] against this CA alone
+        pub fn verify(&self, signer: &str, message: &[u8], signed: &NodeSignature) -> Result<()> {
+            verify_with(&self.cas, signer, message, signed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ca() -> (KeyPair, String, IssuedCertificate, KeyPair) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = ca_params("halvor mesh CA (frigg)");
+        params.not_before = to_datetime(chrono::Utc::now().timestamp() - 60).unwrap();
+        let ca_cert = params.self_signed(&ca_key).unwrap();
+
+        // Sign the way MeshCa::issue does, from a public key sent over the wire
+        let node_key = KeyPair::generate().unwrap();
+        let public_key = NodePublicKey::decode(&encode_public_key(&node_key)).unwrap();
+        let mut node = CertificateParams::new(vec!["baulder".to_string()]).unwrap();
+        node.use_authority_key_identifier_extension = true;
+        node.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let issuer = Issuer::new(ca_params("halvor mesh CA (frigg)"), &ca_key);
+        let cert = node.signed_by(&public_key, &issuer).unwrap();
+
+        let issued = IssuedCertificate {
+            certificate: format!("{}{}", cert.pem(), ca_cert.pem()),
+            fingerprint: fingerprint(cert.der()),
+            serial: "01".to_string(),
+            expires_at: 0,
+        };
+        (ca_key, ca_cert.pem(), issued, node_key)
+    }
+
+    fn verifier(anchors: TrustAnchors, expected_name: Option<&str>) -> MeshServerVerifier {
+        MeshServerVerifier {
+            anchors,
+            expected_name: expected_name.map(|s| s.to_string()),
+            provider: provider(),
+        }
+    }
+
+    fn check(verifier: &MeshServerVerifier, issued: &IssuedCertificate) -> Result<(), rustls::Error> {
+        let chain = parse_certificates(&issued.certificate).unwrap();
+        let name = ServerName::try_from("100.64.0.2").unwrap();
+        verifier
+            .verify_server_cert(&chain[0], &chain[1..], &name, &[], UnixTime::now())
+            .map(|_| ())
+    }
+
+    #[test]
+    fn test_issued_certificate_chains_to_ca() {
+        let (_, ca_pem, issued, _) = test_ca();
+        let mut roots = RootCertStore::empty();
+        roots.add(parse_certificates(&ca_pem).unwrap().remove(0)).unwrap();
+        let roots = Arc::new(roots);
+
+        check(&verifier(TrustAnchors::Roots(Arc::clone(&roots)), None), &issued).unwrap();
+        check(&verifier(TrustAnchors::Roots(Arc::clone(&roots)), Some("baulder")), &issued).unwrap();
+        assert!(check(&verifier(TrustAnchors::Roots(roots), Some("frigg")), &issued).is_err());
+    }
+
+    #[test]
+    fn test_pinned_trust_requires_matching_ca() {
+        let (_, ca_pem, issued, _) = test_ca();
+        let ca_fingerprint = fingerprint(&parse_certificates(&ca_pem).unwrap()[0]);
+
+        check(&verifier(TrustAnchors::Pinned(ca_fingerprint), Some("baulder")), &issued).unwrap();
+        assert!(check(&verifier(TrustAnchors::Pinned("00".repeat(32)), None), &issued).is_err());
+
+        // A certificate from a different CA is rejected by the first CA's roots
+        let (_, other_ca_pem, _, _) = test_ca();
+        let mut roots = RootCertStore::empty();
+        roots.add(parse_certificates(&other_ca_pem).unwrap().remove(0)).unwrap();
+        assert!(check(&verifier(TrustAnchors::Roots(Arc::new(roots)), None), &issued).is_err());
+    }
+
+    #[test]
+    fn test_node_public_key_decode() {
+        let key = KeyPair::generate().unwrap();
+        assert!(NodePublicKey::decode(&encode_public_key(&key)).is_some());
+        // Placeholder keys from older agents aren't P-256 points
+        assert!(NodePublicKey::decode("pk_1234").is_none());
+        assert!(NodePublicKey::decode(&general_purpose::STANDARD.encode([4u8; 10])).is_none());
+    }
+
+    #[test]
+    fn test_node_signature() {
+        let (_, ca_pem, issued, node_key) = test_ca();
+        let cas = parse_certificates(&ca_pem).unwrap();
+        let signed = sign_with(&node_key, &issued.certificate, b"revoke heimdall").unwrap();
+
+        verify_with(&cas, "baulder", b"revoke heimdall", &signed).unwrap();
+        // Another message, another signer name or an untrusted CA are all rejected
+        assert!(verify_with(&cas, "baulder", b"revoke frigg", &signed).is_err());
+        assert!(verify_with(&cas, "frigg", b"revoke heimdall", &signed).is_err());
+        let (_, other_ca_pem, _, _) = test_ca();
+        let other_cas = parse_certificates(&other_ca_pem).unwrap();
+        assert!(verify_with(&other_cas, "baulder", b"revoke heimdall", &signed).is_err());
+
+        // A key that doesn't match the certificate can't sign for it
+        let forged = sign_with(&KeyPair::generate().unwrap(), &issued.certificate, b"revoke heimdall").unwrap();
+        assert!(verify_with(&cas, "baulder", b"revoke heimdall", &forged).is_err());
+    }
+
+    #[test]
+    fn test_signature_after_rename() {
+        let (ca_key, ca_pem, issued, node_key) = test_ca();
+        let cas = parse_certificates(&ca_pem).unwrap();
+        let ca = MeshCa {
+            key: ca_key,
+            info: CaInfo {
+                common_name: "halvor mesh CA (frigg)".to_string(),
+                fingerprint: fingerprint(&cas[0]),
+                expires_at: 0,
+            },
+            certificate_pem: ca_pem.clone(),
+        };
+        let before = sign_with(&node_key, &issued.certificate, b"exec uptime").unwrap();
+        verify_with(&cas, "baulder", b"exec uptime", &before).unwrap();
+
+        // baulder is renamed to odin and gets a certificate for its new name
+        let new_key = generate_node_key().unwrap();
+        let (renamed, _) = ca.sign("odin", &new_key).unwrap();
+        let after = sign_with(&new_key, &renamed.certificate, b"exec uptime").unwrap();
+        verify_with(&cas, "odin", b"exec uptime", &after).unwrap();
+        assert!(verify_with(&cas, "baulder", b"exec uptime", &after).is_err());
+        // Signatures with the old certificate don't pass for the new name
+        assert!(verify_with(&cas, "odin", b"exec uptime", &before).is_err());
+    }
+
+    #[test]
+    fn test_split_pem() {
+        let (_, ca_pem, issued, _) = test_ca();
+        let blocks = split_pem(&issued.certificate);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].trim(), ca_pem.trim());
+        assert!(split_pem("").is_empty());
+    }
+}
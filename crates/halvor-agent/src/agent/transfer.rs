@@ -0,0 +1,757 @@
+//! Chunked, resumable file transfer between mesh peers
+//!
+//! Files move as `FileTransfer` mesh messages, one chunk per request. The
+//! receiver writes each chunk to `<destination>.halvor-part` and records its
+//! progress in `<destination>.halvor-part.json` before acknowledging it, so an
+//! interrupted transfer continues from the first chunk that was not acked. The
+//! whole-file SHA-256 checksum is verified before the file is moved into place.
+//!
+//! - push: the sender asks how far the receiver got (`FileTransferStatus`), then
+//!   sends the remaining chunks
+//! - pull: the puller asks for chunks one at a time (`FileRequest`) and keeps the
+//!   partial file locally
+//!
+//! Agents only read and write files inside their transfer directory.
+
+use crate::agent::api::AgentClient;
+use crate::agent::handshake::capability;
+use crate::agent::mesh_protocol::{MAX_CHUNK_SIZE, MeshMessage, MessagePayload, MessageRouter};
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose};
+use halvor_core::config::config_manager;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Chunk size used by push and pull
+///
+/// Chunks are base64 encoded and then encrypted (base64 again), so they have to
+/// be well below the 16MB frame limit.
+pub const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Environment variable overriding the default transfer directory
+pub const TRANSFER_DIR_ENV: &str = "HALVOR_TRANSFER_DIR";
+
+/// Suffix of partially received files
+const PARTIAL_SUFFIX: &str = ".halvor-part";
+
+/// Suffix of the progress file next to a partially received file
+const STATE_SUFFIX: &str = ".halvor-part.json";
+
+/// How many times an interrupted transfer is resumed before giving up
+const MAX_RETRIES: u32 = 3;
+
+/// Pause before resuming an interrupted transfer
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Result of a completed push or pull
+#[derive(Debug, Clone)]
+pub struct TransferSummary {
+    /// Where the file was written (on the receiver for pushes)
+    pub path: String,
+    pub total_size: u64,
+    pub total_chunks: u32,
+    /// First chunk transferred by this call; non-zero when an earlier transfer was resumed
+    pub resumed_from: u32,
+    pub checksum: String,
+}
+
+/// Transfer directory used when none is configured: `$HALVOR_TRANSFER_DIR`,
+/// or `~/.local/share/halvor/transfers`
+pub fn default_transfer_dir() -> Result<PathBuf> {
+    if let Ok(dir) = std::env::var(TRANSFER_DIR_ENV)
+        && !dir.is_empty()
+    {
+        return Ok(PathBuf::from(dir));
+    }
+    Ok(config_manager::get_home_dir()?
+        .join(".local")
+        .join("share")
+        .join("halvor")
+        .join("transfers"))
+}
+
+/// Resolve `path` inside `base`, rejecting anything that would leave it
+///
+/// Relative paths are relative to `base`; absolute paths must already be inside
+/// it. `..` is never accepted, and symlinks are resolved before the check.
+pub fn resolve_path(base: &Path, path: &str) -> Result<PathBuf> {
+    let requested = Path::new(path);
+    if requested
+        .components()
+        .any(|component| matches!(component, Component::ParentDir))
+    {
+        anyhow::bail!("'{}' must not contain '..'", path);
+    }
+
+    let canonical_base = base
+        .canonicalize()
+        .with_context(|| format!("Transfer directory {} does not exist", base.display()))?;
+    let relative = if requested.is_absolute() {
+        requested
+            .strip_prefix(base)
+            .or_else(|_| requested.strip_prefix(&canonical_base))
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "'{}' is outside the transfer directory {}",
+                    path,
+                    base.display()
+                )
+            })?
+    } else {
+        requested
+    };
+    let relative: PathBuf = relative
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    if relative.as_os_str().is_empty() {
+        anyhow::bail!("'{}' does not name a file", path);
+    }
+
+    // A symlink anywhere along the path could still point outside
+    let resolved = canonical_base.join(relative);
+    let mut existing = resolved.as_path();
+    while existing.symlink_metadata().is_err() {
+        existing = existing.parent().unwrap_or(&canonical_base);
+    }
+    let real = existing
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", existing.display()))?;
+    if !real.starts_with(&canonical_base) {
+        anyhow::bail!("'{}' resolves outside the transfer directory", path);
+    }
+
+    Ok(resolved)
+}
+
+/// SHA-256 of a file's contents (hex)
+pub fn file_checksum(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Number of chunks for a file; an empty file is sent as one empty chunk
+pub fn chunk_count(total_size: u64, chunk_size: usize) -> u32 {
+    let chunks = total_size.div_ceil(chunk_size as u64).max(1);
+    u32::try_from(chunks).unwrap_or(u32::MAX)
+}
+
+/// Progress of an interrupted transfer, stored next to the partial file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PartialState {
+    checksum: String,
+    total_size: u64,
+    total_chunks: u32,
+    next_chunk: u32,
+    bytes_received: u64,
+}
+
+/// A file being received, kept next to its destination until it is complete
+struct PartialFile {
+    destination: PathBuf,
+    state: PartialState,
+}
+
+impl PartialFile {
+    /// Continue receiving `checksum` into `destination`
+    ///
+    /// Progress from an earlier attempt is only reused if it was for the same
+    /// content; otherwise the transfer starts over at chunk 0.
+    fn open(destination: &Path, checksum: &str, total_size: u64, total_chunks: u32) -> Self {
+        let state = Self::load(destination)
+            .filter(|state| {
+                state.checksum == checksum
+                    && state.total_size == total_size
+                    && state.total_chunks == total_chunks
+            })
+            .unwrap_or_else(|| PartialState {
+                checksum: checksum.to_string(),
+                total_size,
+                total_chunks,
+                next_chunk: 0,
+                bytes_received: 0,
+            });
+
+        Self {
+            destination: destination.to_path_buf(),
+            state,
+        }
+    }
+
+    fn load(destination: &Path) -> Option<PartialState> {
+        let content = fs::read_to_string(suffixed(destination, STATE_SUFFIX)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn next_chunk(&self) -> u32 {
+        self.state.next_chunk
+    }
+
+    /// Store chunk `chunk_index`, returning true once the file is complete
+    ///
+    /// Chunks must arrive in order. The last chunk triggers the checksum check
+    /// and the move to the destination.
+    fn write_chunk(&mut self, chunk_index: u32, data: &[u8]) -> Result<bool> {
+        if chunk_index != self.state.next_chunk {
+            anyhow::bail!(
+                "expected chunk {} of {}, got chunk {}",
+                self.state.next_chunk,
+                self.state.total_chunks,
+                chunk_index
+            );
+        }
+        if chunk_index >= self.state.total_chunks {
+            anyhow::bail!(
+                "chunk {} is past the end of the file ({} chunks)",
+                chunk_index,
+                self.state.total_chunks
+            );
+        }
+        if self.state.bytes_received + data.len() as u64 > self.state.total_size {
+            anyhow::bail!("chunk {} is larger than the rest of the file", chunk_index);
+        }
+
+        let data_path = suffixed(&self.destination, PARTIAL_SUFFIX);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&data_path)
+            .with_context(|| format!("Failed to open {}", data_path.display()))?;
+        // Drops anything written after the last recorded chunk
+        file.set_len(self.state.bytes_received)?;
+        file.seek(SeekFrom::Start(self.state.bytes_received))?;
+        file.write_all(data)
+            .with_context(|| format!("Failed to write {}", data_path.display()))?;
+        file.sync_data()?;
+
+        self.state.next_chunk += 1;
+        self.state.bytes_received += data.len() as u64;
+        if self.state.next_chunk < self.state.total_chunks {
+            self.save()?;
+            return Ok(false);
+        }
+
+        self.finish(&data_path)?;
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<()> {
+        let state_path = suffixed(&self.destination, STATE_SUFFIX);
+        fs::write(&state_path, serde_json::to_vec(&self.state)?)
+            .with_context(|| format!("Failed to write {}", state_path.display()))
+    }
+
+    /// Verify the complete file and move it to the destination
+    fn finish(&self, data_path: &Path) -> Result<()> {
+        let checksum = file_checksum(data_path)?;
+        if self.state.bytes_received != self.state.total_size || checksum != self.state.checksum {
+            self.discard();
+            anyhow::bail!(
+                "checksum mismatch for {} (expected {}, got {}); the transfer will start over",
+                self.destination.display(),
+                self.state.checksum,
+                checksum
+            );
+        }
+
+        fs::rename(data_path, &self.destination).with_context(|| {
+            format!("Failed to move file into place at {}", self.destination.display())
+        })?;
+        let _ = fs::remove_file(suffixed(&self.destination, STATE_SUFFIX));
+        Ok(())
+    }
+
+    fn discard(&self) {
+        let _ = fs::remove_file(suffixed(&self.destination, PARTIAL_SUFFIX));
+        let _ = fs::remove_file(suffixed(&self.destination, STATE_SUFFIX));
+    }
+}
+
+/// Whether `destination` already holds exactly this content
+fn is_complete(destination: &Path, checksum: &str, total_size: u64) -> bool {
+    fs::metadata(destination).is_ok_and(|meta| meta.is_file() && meta.len() == total_size)
+        && file_checksum(destination).is_ok_and(|existing| existing == checksum)
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Serves pushes into, and pulls out of, an agent's transfer directory
+pub struct FileTransferHandler {
+    base_dir: PathBuf,
+    /// Checksums of files being pulled, reused while their size and mtime don't change
+    checksums: Mutex<HashMap<PathBuf, CachedChecksum>>,
+}
+
+struct CachedChecksum {
+    size: u64,
+    modified: Option<SystemTime>,
+    checksum: String,
+}
+
+impl FileTransferHandler {
+    pub fn new(base_dir: PathBuf) -> Self {
+        Self {
+            base_dir,
+            checksums: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register handlers for every file transfer message type on `router`
+    pub fn register(self, router: &mut MessageRouter) {
+        let handler = Arc::new(self);
+        for message_type in ["file_transfer", "file_transfer_status", "file_request"] {
+            let handler = Arc::clone(&handler);
+            router.register(message_type.to_string(), move |message: &MeshMessage| {
+                handler.handle(message).map(Some)
+            });
+        }
+    }
+
+    fn handle(&self, message: &MeshMessage) -> Result<MeshMessage> {
+        fs::create_dir_all(&self.base_dir).with_context(|| {
+            format!("Failed to create transfer directory {}", self.base_dir.display())
+        })?;
+
+        let payload = match &message.payload {
+            MessagePayload::FileTransfer {
+                path,
+                total_size,
+                chunk_index,
+                total_chunks,
+                chunk_data,
+                checksum,
+            } => {
+                self.receive_chunk(path, *total_size, *chunk_index, *total_chunks, chunk_data, checksum)?;
+                MessagePayload::Ack {
+                    ack_message_id: message.message_id.clone(),
+                }
+            }
+            MessagePayload::FileTransferStatus {
+                path,
+                total_size,
+                total_chunks,
+                checksum,
+            } => {
+                let destination = resolve_path(&self.base_dir, path)?;
+                let next_chunk = if is_complete(&destination, checksum, *total_size) {
+                    *total_chunks
+                } else {
+                    PartialFile::open(&destination, checksum, *total_size, *total_chunks).next_chunk()
+                };
+                MessagePayload::FileTransferProgress {
+                    path: path.clone(),
+                    next_chunk,
+                }
+            }
+            MessagePayload::FileRequest {
+                path,
+                chunk_index,
+                chunk_size,
+            } => self.read_chunk(path, *chunk_index, *chunk_size)?,
+            _ => anyhow::bail!("Not a file transfer message"),
+        };
+
+        Ok(MeshMessage::new(message.reply_from(), message.from.clone(), payload))
+    }
+
+    fn receive_chunk(
+        &self,
+        path: &str,
+        total_size: u64,
+        chunk_index: u32,
+        total_chunks: u32,
+        chunk_data: &str,
+        checksum: &str,
+    ) -> Result<()> {
+        let destination = resolve_path(&self.base_dir, path)?;
+        let data = general_purpose::STANDARD
+            .decode(chunk_data)
+            .context("Invalid chunk encoding")?;
+        if data.len() > MAX_CHUNK_SIZE {
+            anyhow::bail!("chunk is larger than {} bytes", MAX_CHUNK_SIZE);
+        }
+
+        let mut partial = PartialFile::open(&destination, checksum, total_size, total_chunks);
+        // The ack for a chunk can be lost after it was stored; accept the resend
+        if chunk_index < partial.next_chunk() {
+            return Ok(());
+        }
+        if partial.next_chunk() == 0
+            && chunk_index + 1 == total_chunks
+            && chunk_index > 0
+            && is_complete(&destination, checksum, total_size)
+        {
+            return Ok(());
+        }
+
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        if partial.write_chunk(chunk_index, &data)? {
+            println!(
+                "[AGENT SERVER] Received {} ({} bytes)",
+                destination.display(),
+                total_size
+            );
+        }
+        Ok(())
+    }
+
+    fn read_chunk(&self, path: &str, chunk_index: u32, chunk_size: u32) -> Result<MessagePayload> {
+        let chunk_size = chunk_size as usize;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            anyhow::bail!("chunk size must be between 1 and {} bytes", MAX_CHUNK_SIZE);
+        }
+
+        let source = resolve_path(&self.base_dir, path)?;
+        let meta = fs::metadata(&source).with_context(|| format!("'{}' does not exist", path))?;
+        if !meta.is_file() {
+            anyhow::bail!("'{}' is not a file", path);
+        }
+
+        let total_size = meta.len();
+        let total_chunks = chunk_count(total_size, chunk_size);
+        if chunk_index >= total_chunks {
+            anyhow::bail!(
+                "chunk {} is past the end of the file ({} chunks)",
+                chunk_index,
+                total_chunks
+            );
+        }
+        let checksum = self.checksum(&source, &meta)?;
+
+        let mut file =
+            File::open(&source).with_context(|| format!("Failed to open {}", source.display()))?;
+        file.seek(SeekFrom::Start(chunk_index as u64 * chunk_size as u64))?;
+        let mut data = Vec::with_capacity(chunk_size);
+        file.take(chunk_size as u64).read_to_end(&mut data)?;
+
+        Ok(MessagePayload::FileTransfer {
+            path: path.to_string(),
+            total_size,
+            chunk_index,
+            total_chunks,
+            chunk_data: general_purpose::STANDARD.encode(&data),
+            checksum,
+        })
+    }
+
+    fn checksum(&self, source: &Path, meta: &fs::Metadata) -> Result<String> {
+        let modified = meta.modified().ok();
+        let mut checksums = self
+            .checksums
+            .lock()
+            .map_err(|_| anyhow::anyhow!("checksum cache lock poisoned"))?;
+        if let Some(cached) = checksums.get(source)
+            && cached.size == meta.len()
+            && cached.modified == modified
+        {
+            return Ok(cached.checksum.clone());
+        }
+
+        let checksum = file_checksum(source)?;
+        checksums.insert(
+            source.to_path_buf(),
+            CachedChecksum {
+                size: meta.len(),
+                modified,
+                checksum: checksum.clone(),
+            },
+        );
+        Ok(checksum)
+    }
+}
+
+/// Send `local` to `remote` (inside the peer's transfer directory)
+///
+/// Continues an earlier interrupted push of the same file. `progress` is called
+/// with the bytes the peer has and the file size after every chunk.
+pub fn push_file(
+    client: &AgentClient,
+    local: &Path,
+    remote: &str,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<TransferSummary> {
+    client.require(capability::FILE_TRANSFER)?;
+    let total_size = fs::metadata(local)
+        .with_context(|| format!("Failed to read {}", local.display()))?
+        .len();
+    let checksum = file_checksum(local)?;
+    let total_chunks = chunk_count(total_size, DEFAULT_CHUNK_SIZE);
+
+    let mut file = File::open(local).with_context(|| format!("Failed to open {}", local.display()))?;
+    let mut resumed_from = None;
+    retry_interrupted(|| {
+        let next_chunk = match request(
+            client,
+            MessagePayload::FileTransferStatus {
+                path: remote.to_string(),
+                total_size,
+                total_chunks,
+                checksum: checksum.clone(),
+            },
+        )? {
+            MessagePayload::FileTransferProgress { next_chunk, .. } => next_chunk,
+            _ => anyhow::bail!("Unexpected reply to file transfer status"),
+        };
+        resumed_from.get_or_insert(next_chunk);
+
+        for chunk_index in next_chunk..total_chunks {
+            let offset = chunk_index as u64 * DEFAULT_CHUNK_SIZE as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::with_capacity(DEFAULT_CHUNK_SIZE);
+            (&mut file)
+                .take(DEFAULT_CHUNK_SIZE as u64)
+                .read_to_end(&mut data)?;
+
+            let sent = data.len() as u64;
+            match request(
+                client,
+                MessagePayload::FileTransfer {
+                    path: remote.to_string(),
+                    total_size,
+                    chunk_index,
+                    total_chunks,
+                    chunk_data: general_purpose::STANDARD.encode(&data),
+                    checksum: checksum.clone(),
+                },
+            )? {
+                MessagePayload::Ack { .. } => progress(offset + sent, total_size),
+                _ => anyhow::bail!("Unexpected reply to chunk {}", chunk_index),
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(TransferSummary {
+        path: remote.to_string(),
+        total_size,
+        total_chunks,
+        resumed_from: resumed_from.unwrap_or(0),
+        checksum,
+    })
+}
+
+/// Fetch `remote` (inside the peer's transfer directory) into `local`
+///
+/// Partial data is kept in `<local>.halvor-part`, so running the same pull again
+/// continues where it stopped, unless the remote file changed in the meantime.
+pub fn pull_file(
+    client: &AgentClient,
+    remote: &str,
+    local: &Path,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<TransferSummary> {
+    client.require(capability::FILE_TRANSFER)?;
+    let mut resumed_from = None;
+    let mut summary = None;
+    retry_interrupted(|| {
+        let mut partial: Option<PartialFile> = None;
+        let mut chunk_index = PartialFile::load(local).map_or(0, |state| state.next_chunk);
+        resumed_from.get_or_insert(chunk_index);
+
+        loop {
+            let (total_size, total_chunks, checksum, data) = match request(
+                client,
+                MessagePayload::FileRequest {
+                    path: remote.to_string(),
+                    chunk_index,
+                    chunk_size: DEFAULT_CHUNK_SIZE as u32,
+                },
+            )? {
+                MessagePayload::FileTransfer {
+                    total_size,
+                    total_chunks,
+                    checksum,
+                    chunk_data,
+                    ..
+                } => (
+                    total_size,
+                    total_chunks,
+                    checksum,
+                    general_purpose::STANDARD
+                        .decode(chunk_data)
+                        .context("Invalid chunk encoding")?,
+                ),
+                _ => anyhow::bail!("Unexpected reply to chunk request {}", chunk_index),
+            };
+
+            // Start over if there is no usable partial file, or the source changed
+            let current = match partial.take() {
+                Some(current) if current.state.checksum == checksum => current,
+                _ => PartialFile::open(local, &checksum, total_size, total_chunks),
+            };
+            if current.next_chunk() != chunk_index {
+                chunk_index = current.next_chunk();
+                resumed_from = Some(chunk_index);
+                partial = Some(current);
+                continue;
+            }
+
+            let mut current = current;
+            let complete = current.write_chunk(chunk_index, &data)?;
+            progress(current.state.bytes_received, total_size);
+            if complete {
+                summary = Some(TransferSummary {
+                    path: local.display().to_string(),
+                    total_size,
+                    total_chunks,
+                    resumed_from: resumed_from.unwrap_or(0),
+                    checksum,
+                });
+                return Ok(());
+            }
+            chunk_index += 1;
+            partial = Some(current);
+        }
+    })?;
+
+    summary.context("Pull finished without receiving the file")
+}
+
+/// Run `attempt`, resuming it after connection failures
+///
+/// Errors reported by the agent itself are returned immediately.
+fn retry_interrupted(mut attempt: impl FnMut() -> Result<()>) -> Result<()> {
+    let mut retries = 0;
+    loop {
+        match attempt() {
+            Ok(()) => return Ok(()),
+            Err(e) if retries < MAX_RETRIES && is_connection_error(&e) => {
+                retries += 1;
+                eprintln!(
+                    "  Transfer interrupted ({}), resuming ({}/{})...",
+                    e, retries, MAX_RETRIES
+                );
+                std::thread::sleep(RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_connection_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.downcast_ref::<std::io::Error>().is_some())
+}
+
+/// Send a transfer message to the peer and unwrap its reply
+fn request(client: &AgentClient, payload: MessagePayload) -> Result<MessagePayload> {
+    match client.send_to_peer(payload)?.map(|reply| reply.payload) {
+        Some(MessagePayload::Error { code, message }) => {
+            anyhow::bail!("{} ({})", message, code)
+        }
+        Some(payload) => Ok(payload),
+        None => anyhow::bail!("Agent did not reply to the transfer request"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("halvor-transfer-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resolve_path_stays_inside_base() {
+        let base = scratch_dir("resolve");
+        let canonical = base.canonicalize().unwrap();
+
+        assert_eq!(resolve_path(&base, "a/b.txt").unwrap(), canonical.join("a/b.txt"));
+        let inside = base.join("c.txt");
+        assert_eq!(
+            resolve_path(&base, inside.to_str().unwrap()).unwrap(),
+            canonical.join("c.txt")
+        );
+
+        assert!(resolve_path(&base, "../escape.txt").is_err());
+        assert!(resolve_path(&base, "a/../../escape.txt").is_err());
+        assert!(resolve_path(&base, "/etc/passwd").is_err());
+        assert!(resolve_path(&base, "/").is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc", base.join("link")).unwrap();
+            assert!(resolve_path(&base, "link/passwd").is_err());
+            std::os::unix::fs::symlink("/nonexistent-target", base.join("dangling")).unwrap();
+            assert!(resolve_path(&base, "dangling").is_err());
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_partial_file_resumes_and_verifies() {
+        let dir = scratch_dir("partial");
+        let destination = dir.join("file.bin");
+        let content = b"hello, mesh transfer".to_vec();
+        let chunks: Vec<&[u8]> = content.chunks(8).collect();
+        let checksum = {
+            let source = dir.join("source.bin");
+            fs::write(&source, &content).unwrap();
+            file_checksum(&source).unwrap()
+        };
+        let total = content.len() as u64;
+
+        let mut partial = PartialFile::open(&destination, &checksum, total, chunks.len() as u32);
+        assert!(!partial.write_chunk(0, chunks[0]).unwrap());
+        assert!(partial.write_chunk(2, chunks[2]).is_err());
+
+        // A new attempt picks up after the last stored chunk
+        let mut resumed = PartialFile::open(&destination, &checksum, total, chunks.len() as u32);
+        assert_eq!(resumed.next_chunk(), 1);
+        assert!(!resumed.write_chunk(1, chunks[1]).unwrap());
+        assert!(resumed.write_chunk(2, chunks[2]).unwrap());
+        assert_eq!(fs::read(&destination).unwrap(), content);
+        assert!(!suffixed(&destination, PARTIAL_SUFFIX).exists());
+        assert!(!suffixed(&destination, STATE_SUFFIX).exists());
+
+        // Different content never reuses the old progress
+        let mut other = PartialFile::open(&destination, "other", total, chunks.len() as u32);
+        assert_eq!(other.next_chunk(), 0);
+        other.write_chunk(0, chunks[0]).unwrap();
+        other.write_chunk(1, chunks[1]).unwrap();
+        let err = other.write_chunk(2, chunks[2]).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+        assert!(!suffixed(&destination, PARTIAL_SUFFIX).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_count() {
+        assert_eq!(chunk_count(0, 4), 1);
+        assert_eq!(chunk_count(4, 4), 1);
+        assert_eq!(chunk_count(5, 4), 2);
+        assert_eq!(chunk_count(8, 4), 2);
+    }
+}
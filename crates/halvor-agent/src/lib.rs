@@ -18,6 +18,7 @@ use anyhow::Result;
 /// * `port` - Agent API port (default: 13500)
 /// * `_web_port` - Optional web UI port (deprecated - web UI now uses same port)
 pub async fn start(port: u16, _web_port: Option<u16>) -> Result<()> {
-    let server = AgentServer::new(port, None);
-    server.start()
+    let server = AgentServer::new(port, None)
+        .with_transfer_dir(agent::transfer::default_transfer_dir()?);
+    server.start().await
 }
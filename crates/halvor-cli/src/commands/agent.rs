@@ -1,4 +1,8 @@
 use halvor_agent::{HostDiscovery, AgentServer, agent::sync::ConfigSync};
+use halvor_agent::agent::discovery::{DEFAULT_PROBE_TIMEOUT, DEFAULT_SCAN_CONCURRENCY, Ipv4Cidr};
+use halvor_agent::agent::handshake::{Hello, capability};
+use halvor_agent::agent::heartbeat::{self, HeartbeatConfig};
+use halvor_agent::agent::mdns;
 use halvor_core::utils::hostname::get_current_hostname;
 use anyhow::{Context, Result};
 use clap::Subcommand;
@@ -19,6 +23,22 @@ pub enum AgentCommands {
         /// Run as daemon in background
         #[arg(long)]
         daemon: bool,
+        /// Directory mesh peers may push files to and pull files from
+        /// (default: $HALVOR_TRANSFER_DIR or ~/.local/share/halvor/transfers)
+        #[arg(long, value_name = "DIR")]
+        transfer_dir: Option<PathBuf>,
+        /// Seconds between heartbeat pings to mesh peers
+        #[arg(long, value_name = "SECS", default_value_t = heartbeat::DEFAULT_HEARTBEAT_INTERVAL.as_secs())]
+        heartbeat_interval: u64,
+        /// Seconds without hearing from a peer before it is marked suspect
+        #[arg(long, value_name = "SECS", default_value_t = heartbeat::DEFAULT_SUSPECT_AFTER.as_secs())]
+        suspect_after: u64,
+        /// Seconds without hearing from a peer before it is marked inactive
+        #[arg(long, value_name = "SECS", default_value_t = heartbeat::DEFAULT_INACTIVE_AFTER.as_secs())]
+        inactive_after: u64,
+        /// Don't advertise this agent over mDNS
+        #[arg(long)]
+        no_mdns: bool,
     },
     /// Stop the halvor agent daemon
     Stop,
@@ -29,6 +49,21 @@ pub enum AgentCommands {
         /// Show verbose output
         #[arg(long)]
         verbose: bool,
+        /// Subnets to scan, e.g. 192.168.1.0/24 (default: the local networks)
+        #[arg(long = "subnet", value_name = "CIDR", value_delimiter = ',')]
+        subnets: Vec<Ipv4Cidr>,
+        /// Addresses or subnets to skip
+        #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+        exclude: Vec<Ipv4Cidr>,
+        /// Number of addresses to probe at once
+        #[arg(long, default_value_t = DEFAULT_SCAN_CONCURRENCY)]
+        concurrency: usize,
+        /// Milliseconds to wait for each address to answer
+        #[arg(long, default_value_t = DEFAULT_PROBE_TIMEOUT.as_millis() as u64)]
+        timeout_ms: u64,
+        /// Milliseconds to listen for mDNS advertisements (0 to skip mDNS)
+        #[arg(long, default_value_t = mdns::DEFAULT_BROWSE_TIMEOUT.as_millis() as u64)]
+        mdns_timeout_ms: u64,
     },
     /// Sync configuration with discovered agents
     Sync {
@@ -43,7 +78,22 @@ pub enum AgentCommands {
         follow: bool,
     },
     /// Generate a join token for other agents to join this mesh
-    Token,
+    Token {
+        /// How long the token is valid, e.g. "30m", "12h" or "7d"
+        #[arg(long, default_value = "24h", value_parser = parse_ttl)]
+        ttl: Duration,
+        /// How many agents may join with the token (0 for no limit)
+        #[arg(long, default_value = "1")]
+        max_uses: u32,
+        /// Only allow this hostname to join with the token (repeatable)
+        #[arg(long = "allow-host", value_name = "HOSTNAME")]
+        hostnames: Vec<String>,
+    },
+    /// List or revoke join tokens issued by this host
+    Tokens {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
     /// Join an existing agent mesh
     Join {
         /// Join token from another agent (if not provided, will discover and prompt)
@@ -55,31 +105,140 @@ pub enum AgentCommands {
     },
     /// List peers in the mesh
     Peers,
-    /// Remove a peer from the mesh
+    /// Remove a peer from the mesh, revoking it on every peer
     Remove {
         /// Hostname of the peer to remove (if not provided, will show interactive selection)
         #[arg(value_name = "HOSTNAME")]
         hostname: Option<String>,
+        /// Also tell the removed peer to wipe its own mesh state (peers, keys, certificates)
+        #[arg(long)]
+        wipe: bool,
     },
     /// Verify mesh connectivity and communication
     Verify,
-    /// Update hostname and sync across mesh
+    /// Rename this host in .env, the database, kubeconfig, k3s labels and on every peer
+    ///
+    /// Shows every planned change first; if one fails, the changes already
+    /// made are rolled back.
     Hostname {
         /// New hostname to use
         #[arg(value_name = "HOSTNAME")]
         new_hostname: String,
+        /// Only show the planned changes
+        #[arg(long)]
+        dry_run: bool,
+        /// Don't ask for confirmation
+        #[arg(long, short = 'y')]
+        yes: bool,
     },
-    /// Execute a command on a remote agent
+    /// Execute a command on a remote agent, streaming its output
+    ///
+    /// Piped input is forwarded to the command, Ctrl-C stops it, and halvor
+    /// exits with the command's exit code.
+    #[command(alias = "exec")]
     Execute {
         /// Hostname of the agent to execute command on
         hostname: String,
         /// Command to execute
         command: String,
         /// Arguments for the command
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         args: Vec<String>,
     },
+    /// Copy a local file to a peer (resumes an interrupted push)
+    Push {
+        /// Hostname of the agent to send the file to
+        hostname: String,
+        /// Local file to send
+        local: PathBuf,
+        /// Destination inside the peer's transfer directory (a trailing '/' keeps the file name)
+        remote: String,
+    },
+    /// Copy a file from a peer (resumes an interrupted pull)
+    Pull {
+        /// Hostname of the agent to fetch the file from
+        hostname: String,
+        /// File inside the peer's transfer directory
+        remote: String,
+        /// Local destination (an existing directory keeps the file name)
+        local: PathBuf,
+    },
     /// Set up SSH keys for all mesh peers
     SetupSsh,
+    /// Manage which commands mesh peers may execute on this host
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommands,
+    },
+    /// Manage this host's mesh TLS certificate
+    Cert {
+        #[command(subcommand)]
+        command: CertCommands,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum TokenCommands {
+    /// List join tokens issued by this host or used to join it
+    List,
+    /// Revoke a join token so no more agents can join with it
+    Revoke {
+        /// Token ID, or enough of its start to be unique
+        #[arg(value_name = "TOKEN_ID")]
+        token_id: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum CertCommands {
+    /// Show this host's certificate, trusted CAs and certificates issued by its CA
+    Status,
+    /// Replace this host's certificate with a new one for a fresh key
+    Rotate {
+        /// Peer whose CA should issue the certificate (default: the current issuer)
+        #[arg(long, value_name = "PEER")]
+        from: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum PolicyCommands {
+    /// List command execution rules
+    List {
+        /// Only show rules that apply to this peer
+        #[arg(value_name = "PEER")]
+        peer: Option<String>,
+    },
+    /// Allow a peer to execute a command on this host
+    Allow {
+        /// Peer hostname ('*' for every peer)
+        #[arg(value_name = "PEER")]
+        peer: String,
+        /// Program name or absolute path ('*' for any command, 'file:read' etc. for file operations)
+        #[arg(value_name = "COMMAND")]
+        command: String,
+        /// Also allow running the command through sudo
+        #[arg(long)]
+        sudo: bool,
+        /// Limit a file operation rule to paths under this directory (repeatable)
+        #[arg(long = "path", value_name = "DIR")]
+        paths: Vec<String>,
+    },
+    /// Remove a command execution rule
+    Revoke {
+        /// Peer hostname ('*' for the wildcard rule)
+        #[arg(value_name = "PEER")]
+        peer: String,
+        /// Program name or absolute path, as given to 'allow'
+        #[arg(value_name = "COMMAND")]
+        command: String,
+    },
+    /// Show recently denied command execution attempts
+    Audit {
+        /// Number of entries to show
+        #[arg(long, short = 'n', default_value = "20")]
+        limit: usize,
+    },
 }
 
 /// Handle agent commands
@@ -89,8 +248,18 @@ pub async fn handle_agent(command: AgentCommands) -> Result<()> {
             port,
             ui,
             daemon,
+            transfer_dir,
+            heartbeat_interval,
+            suspect_after,
+            inactive_after,
+            no_mdns,
         } => {
-            start_agent(port, ui, daemon).await?;
+            let heartbeat = HeartbeatConfig::new(
+                Duration::from_secs(heartbeat_interval),
+                Duration::from_secs(suspect_after),
+                Duration::from_secs(inactive_after),
+            )?;
+            start_agent(port, ui, daemon, transfer_dir, heartbeat, !no_mdns).await?;
         }
         AgentCommands::Stop => {
             stop_agent()?;
@@ -98,8 +267,25 @@ pub async fn handle_agent(command: AgentCommands) -> Result<()> {
         AgentCommands::Status => {
             show_agent_status()?;
         }
-        AgentCommands::Discover { verbose } => {
-            discover_agents(verbose)?;
+        AgentCommands::Discover {
+            verbose,
+            subnets,
+            exclude,
+            concurrency,
+            timeout_ms,
+            mdns_timeout_ms,
+        } => {
+            let mut discovery = HostDiscovery::default()
+                .with_concurrency(concurrency)
+                .with_probe_timeout(Duration::from_millis(timeout_ms))
+                .with_mdns_timeout(Duration::from_millis(mdns_timeout_ms));
+            if !subnets.is_empty() {
+                discovery = discovery.with_subnets(subnets);
+            }
+            if !exclude.is_empty() {
+                discovery = discovery.with_exclusions(exclude);
+            }
+            discover_agents(&discovery, verbose)?;
         }
         AgentCommands::Sync { force } => {
             sync_with_agents(force)?;
@@ -107,8 +293,15 @@ pub async fn handle_agent(command: AgentCommands) -> Result<()> {
         AgentCommands::Logs { follow } => {
             show_agent_logs(follow)?;
         }
-        AgentCommands::Token => {
-            generate_join_token()?;
+        AgentCommands::Token {
+            ttl,
+            max_uses,
+            hostnames,
+        } => {
+            generate_join_token(ttl, max_uses, hostnames)?;
+        }
+        AgentCommands::Tokens { command } => {
+            handle_tokens(command)?;
         }
         AgentCommands::Join { token, host } => {
             join_mesh(token, host)?;
@@ -116,14 +309,18 @@ pub async fn handle_agent(command: AgentCommands) -> Result<()> {
         AgentCommands::Peers => {
             list_peers()?;
         }
-        AgentCommands::Remove { hostname } => {
-            remove_peer(hostname.as_deref())?;
+        AgentCommands::Remove { hostname, wipe } => {
+            remove_peer(hostname.as_deref(), wipe)?;
         }
         AgentCommands::Verify => {
             verify_mesh_connectivity()?;
         }
-        AgentCommands::Hostname { new_hostname } => {
-            update_hostname(&new_hostname)?;
+        AgentCommands::Hostname {
+            new_hostname,
+            dry_run,
+            yes,
+        } => {
+            update_hostname(&new_hostname, dry_run, yes)?;
         }
         AgentCommands::Execute {
             hostname,
@@ -132,9 +329,29 @@ pub async fn handle_agent(command: AgentCommands) -> Result<()> {
         } => {
             execute_on_agent(&hostname, &command, &args)?;
         }
+        AgentCommands::Push {
+            hostname,
+            local,
+            remote,
+        } => {
+            push_to_agent(&hostname, &local, &remote)?;
+        }
+        AgentCommands::Pull {
+            hostname,
+            remote,
+            local,
+        } => {
+            pull_from_agent(&hostname, &remote, &local)?;
+        }
         AgentCommands::SetupSsh => {
             setup_ssh_keys_for_mesh_peers()?;
         }
+        AgentCommands::Policy { command } => {
+            handle_policy(command)?;
+        }
+        AgentCommands::Cert { command } => {
+            handle_cert(command)?;
+        }
     }
     Ok(())
 }
@@ -143,7 +360,7 @@ pub async fn handle_agent(command: AgentCommands) -> Result<()> {
 /// This ensures all peers in the mesh can SSH to each other without passwords
 fn setup_ssh_keys_for_mesh_peers() -> Result<()> {
     use halvor_agent::agent::mesh;
-    use halvor_db::generated::agent_peers;
+    use halvor_db::generated::AgentPeersRow;
     use halvor_core::utils::ssh::copy_ssh_key;
     use halvor_core::utils::hostname::normalize_hostname;
     
@@ -176,10 +393,7 @@ fn setup_ssh_keys_for_mesh_peers() -> Result<()> {
         }
         
         // Get peer details from database
-        let peer_row = match agent_peers::select_one(
-            "hostname = ?1",
-            &[&peer_hostname as &dyn rusqlite::types::ToSql],
-        ) {
+        let peer_row = match AgentPeersRow::query().hostname().eq(peer_hostname).first() {
             Ok(Some(row)) => row,
             Ok(None) => {
                 eprintln!("  ⚠️  Peer {} not found in database, skipping", peer_hostname);
@@ -252,7 +466,10 @@ fn setup_ssh_keys_for_mesh_peers() -> Result<()> {
 }
 
 /// Execute a command on a remote agent
-fn execute_on_agent(hostname: &str, command: &str, args: &[String]) -> Result<()> {
+/// Find a mesh peer's agent and a client that encrypts and signs requests for it
+///
+/// Returns the client and the "ip:port" it connects to.
+fn connect_to_peer(hostname: &str) -> Result<(halvor_agent::agent::api::AgentClient, String)> {
     use halvor_agent::agent::api::AgentClient;
     
     // Discover the agent
@@ -292,26 +509,406 @@ fn execute_on_agent(hostname: &str, command: &str, args: &[String]) -> Result<()
         }
     };
     
-    println!("Executing '{}' on {}:{}...", command, ip, port);
+    // Requests are signed with the secret shared with that peer
+    let peer_hostname = match host {
+        Some(host) => host.hostname.clone(),
+        None => AgentClient::new(&ip, port).get_host_info()?.hostname,
+    };
+    let client = AgentClient::new(&ip, port).with_peer(&peer_hostname)?;
+
+    Ok((client, format!("{}:{}", ip, port)))
+}
+
+/// Send a file to a peer's transfer directory
+fn push_to_agent(hostname: &str, local: &std::path::Path, remote: &str) -> Result<()> {
+    use halvor_agent::agent::transfer;
+
+    if !local.is_file() {
+        anyhow::bail!("{} is not a file", local.display());
+    }
+    // "dir/" keeps the local file name
+    let remote = if remote.ends_with('/') {
+        let name = local
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid file name: {}", local.display()))?;
+        format!("{}{}", remote, name)
+    } else {
+        remote.to_string()
+    };
+
+    let (client, address) = connect_to_peer(hostname)?;
+    println!("Pushing {} to {} ({})...", local.display(), remote, address);
+
+    let summary = transfer::push_file(&client, local, &remote, print_transfer_progress)?;
     println!();
-    
-    let client = AgentClient::new(&ip, port);
+    print_transfer_summary(&summary);
+    Ok(())
+}
+
+/// Fetch a file from a peer's transfer directory
+fn pull_from_agent(hostname: &str, remote: &str, local: &std::path::Path) -> Result<()> {
+    use halvor_agent::agent::transfer;
+
+    let local = if local.is_dir() {
+        let name = std::path::Path::new(remote)
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("'{}' does not name a file", remote))?;
+        local.join(name)
+    } else {
+        local.to_path_buf()
+    };
+
+    let (client, address) = connect_to_peer(hostname)?;
+    println!("Pulling {} from {} to {}...", remote, address, local.display());
+
+    let summary = transfer::pull_file(&client, remote, &local, print_transfer_progress)?;
+    println!();
+    print_transfer_summary(&summary);
+    Ok(())
+}
+
+fn print_transfer_progress(done: u64, total: u64) {
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    print!("\r  {} / {} bytes ({}%)", done, total, percent);
+    let _ = io::stdout().flush();
+}
+
+fn print_transfer_summary(summary: &halvor_agent::agent::transfer::TransferSummary) {
+    if summary.resumed_from > 0 {
+        println!(
+            "  Resumed at chunk {} of {}",
+            summary.resumed_from + 1,
+            summary.total_chunks
+        );
+    }
+    println!("✓ {} ({} bytes)", summary.path, summary.total_size);
+    println!("  SHA-256: {}", summary.checksum);
+}
+
+fn execute_on_agent(hostname: &str, command: &str, args: &[String]) -> Result<()> {
+    use halvor_agent::agent::exec_stream::{CancelHandle, OutputStream};
+    use std::io::IsTerminal;
+
+    let (client, address) = connect_to_peer(hostname)?;
+
+    // Keep stdout for the command's own output
+    eprintln!("Executing '{}' on {}...", command, address);
+    eprintln!();
+
     let args_refs: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
-    
-    match client.execute_command(command, &args_refs) {
-        Ok(output) => {
-            print!("{}", output);
-            Ok(())
+
+    if !client.hello()?.supports(capability::EXEC_STREAM) {
+        eprintln!("⚠ {} runs an older halvor; output will appear when the command finishes", hostname);
+        match client.execute_command(command, &args_refs) {
+            Ok(output) => {
+                print!("{}", output);
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("Error executing command: {}", e);
+                return Err(e);
+            }
         }
-        Err(e) => {
-            eprintln!("Error executing command: {}", e);
-            Err(e.into())
+    }
+
+    // Forward piped input; a terminal stays with this process
+    let stdin: Option<Box<dyn io::Read + Send>> = if io::stdin().is_terminal() {
+        None
+    } else {
+        Some(Box::new(io::stdin()))
+    };
+
+    // First Ctrl-C asks the agent to stop the command, a second one gives up waiting
+    let cancel = CancelHandle::new();
+    let on_interrupt = cancel.clone();
+    ctrlc::set_handler(move || {
+        if on_interrupt.is_cancelled() {
+            std::process::exit(130);
         }
+        on_interrupt.cancel();
+    })
+    .context("Failed to install Ctrl-C handler")?;
+
+    let status = client
+        .execute_streaming(command, &args_refs, stdin, &cancel, |stream, data| match stream {
+            OutputStream::Stdout => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(data);
+                let _ = stdout.flush();
+            }
+            OutputStream::Stderr => {
+                let _ = io::stderr().write_all(data);
+            }
+        })
+        .inspect_err(|e| eprintln!("Error executing command: {}", e))?;
+
+    if cancel.is_cancelled() {
+        eprintln!();
+        eprintln!("Cancelled '{}' on {} ({})", command, hostname, status);
+    }
+    if !status.success() {
+        std::process::exit(status.exit_code());
     }
+    Ok(())
+}
+
+/// Handle `halvor agent policy` subcommands
+fn handle_policy(command: PolicyCommands) -> Result<()> {
+    use halvor_agent::agent::policy;
+
+    match command {
+        PolicyCommands::List { peer } => {
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!("Command Execution Policy");
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!();
+
+            let rules = policy::list_rules(peer.as_deref())?;
+            if rules.is_empty() {
+                println!("No rules - mesh peers cannot execute any commands on this host.");
+                println!();
+                println!("To allow a command:");
+                println!("  halvor agent policy allow <peer> <command> [--sudo]");
+            } else {
+                println!("{:<24} {:<32} {:<5} PATHS", "PEER", "COMMAND", "SUDO");
+                for rule in rules {
+                    println!(
+                        "{:<24} {:<32} {:<5} {}",
+                        rule.peer_hostname,
+                        rule.command,
+                        if rule.allow_sudo { "yes" } else { "no" },
+                        if rule.paths.is_empty() { "any".to_string() } else { rule.paths.join(", ") }
+                    );
+                }
+            }
+        }
+        PolicyCommands::Allow { peer, command, sudo, paths } => {
+            policy::allow(&peer, &command, sudo, &paths)?;
+            println!(
+                "✓ Allowed '{}' for peer '{}'{}",
+                command,
+                peer,
+                if sudo { " (including sudo)" } else { "" }
+            );
+            if !paths.is_empty() {
+                println!("  Limited to paths under: {}", paths.join(", "));
+            }
+        }
+        PolicyCommands::Revoke { peer, command } => {
+            if policy::revoke(&peer, &command)? == 0 {
+                anyhow::bail!("No rule found for peer '{}' and command '{}'", peer, command);
+            }
+            println!("✓ Revoked '{}' for peer '{}'", command, peer);
+        }
+        PolicyCommands::Audit { limit } => {
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!("Denied Command Executions");
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!();
+
+            let entries = policy::recent_denials(limit)?;
+            if entries.is_empty() {
+                println!("No denied attempts recorded.");
+            }
+            for entry in entries {
+                let when = chrono::DateTime::from_timestamp(entry.created_at, 0)
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|| entry.created_at.to_string());
+                let args: Vec<String> = serde_json::from_str(&entry.args).unwrap_or_default();
+                println!("{}  {}  {} {}", when, entry.peer_hostname, entry.command, args.join(" "));
+                println!("    {}", entry.reason);
+            }
+        }
+    }
+
+    Ok(())
 }
 
 /// Start the agent daemon
-async fn start_agent(port: u16, ui: bool, daemon: bool) -> Result<()> {
+/// Handle mesh certificate commands
+fn handle_cert(command: CertCommands) -> Result<()> {
+    use halvor_agent::agent::tls;
+
+    match command {
+        CertCommands::Status => {
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!("Mesh TLS Certificates");
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!();
+
+            match tls::node_certificate()? {
+                Some(info) => {
+                    println!("Node certificate:");
+                    println!("  Hostname:    {}", info.hostname);
+                    println!("  Issued by:   {}", info.issuer_hostname);
+                    println!("  Fingerprint: {}", info.fingerprint);
+                    println!(
+                        "  Expires:     {}{}",
+                        format_date(info.expires_at),
+                        if info.needs_renewal() { " (renewal due)" } else { "" }
+                    );
+                }
+                None => {
+                    println!("Node certificate: none (agent accepts plaintext connections)");
+                    println!("  Join a mesh to get one: halvor agent join <token>");
+                }
+            }
+            println!();
+            println!("Trusted mesh CAs: {}", tls::trusted_cas()?.len());
+
+            if let Some(ca) = tls::MeshCa::load()? {
+                println!();
+                println!("This host is a mesh CA:");
+                println!("  Fingerprint: {}", ca.fingerprint());
+                println!("  Expires:     {}", format_date(ca.expires_at()));
+
+                let issued = tls::issued_certificates()?;
+                if !issued.is_empty() {
+                    println!();
+                    println!("Issued certificates ({}):", issued.len());
+                    for cert in issued {
+                        let status = match cert.revoked_at {
+                            Some(revoked_at) => format!("revoked {}", format_date(revoked_at)),
+                            None => format!("expires {}", format_date(cert.expires_at)),
+                        };
+                        println!("  {:<20} {}  {}", cert.peer_hostname, &cert.fingerprint[..16], status);
+                    }
+                }
+            }
+        }
+        CertCommands::Rotate { from } => {
+            println!("Requesting a new certificate...");
+            let info = tls::renew_node_certificate(from.as_deref())?;
+            println!(
+                "✓ New certificate from {} (expires {})",
+                info.issuer_hostname,
+                format_date(info.expires_at)
+            );
+            println!("  Fingerprint: {}", info.fingerprint);
+            println!("  The running agent picks it up for new connections.");
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle `halvor agent tokens` subcommands
+fn handle_tokens(command: TokenCommands) -> Result<()> {
+    use halvor_agent::agent::mesh;
+
+    match command {
+        TokenCommands::List => {
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!("Join Tokens");
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!();
+
+            let tokens = mesh::list_join_tokens()?;
+            if tokens.is_empty() {
+                println!("No join tokens.");
+                println!();
+                println!("To create one:");
+                println!("  halvor agent token [--ttl 24h] [--max-uses 1] [--allow-host <host>]");
+                return Ok(());
+            }
+
+            let now = chrono::Utc::now().timestamp();
+            println!("{:<10} {:<12} {:<9} {:<20} {:<16} STATUS", "ID", "ISSUER", "USES", "HOSTS", "EXPIRES");
+            for token in tokens {
+                let status = if token.revoked_at.is_some() {
+                    "revoked".to_string()
+                } else if token.expires_at < now {
+                    "expired".to_string()
+                } else if token.max_uses > 0 && token.uses >= token.max_uses {
+                    "used up".to_string()
+                } else {
+                    "active".to_string()
+                };
+                let status = match &token.used_by_hostname {
+                    Some(host) => format!("{} (last used by {})", status, host),
+                    None => status,
+                };
+                println!(
+                    "{:<10} {:<12} {:<9} {:<20} {:<16} {}",
+                    &token.token_id[..8.min(token.token_id.len())],
+                    token.issuer_hostname,
+                    format!("{}/{}", token.uses, format_max_uses(token.max_uses as u32)),
+                    token.allowed_hostnames.as_deref().unwrap_or("any"),
+                    format_time(token.expires_at),
+                    status
+                );
+            }
+        }
+        TokenCommands::Revoke { token_id } => {
+            let token = mesh::revoke_join_token(&token_id)?;
+            println!("✓ Revoked join token {}", token.token_id);
+        }
+    }
+
+    Ok(())
+}
+
+fn format_max_uses(max_uses: u32) -> String {
+    if max_uses == 0 {
+        "unlimited".to_string()
+    } else {
+        max_uses.to_string()
+    }
+}
+
+/// Parse a token lifetime like "90s", "30m", "12h" or "7d"
+fn parse_ttl(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration like 30m, 12h or 7d", s))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("'{}' needs a unit: s, m, h or d", s)),
+    };
+    if amount == 0 {
+        return Err("A token lifetime must be more than zero".to_string());
+    }
+    Ok(Duration::from_secs(amount * seconds))
+}
+
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn format_date(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| dt.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// How long ago `timestamp` was, e.g. "42s ago" or "3h ago"
+fn format_last_seen(timestamp: i64) -> String {
+    let age = (chrono::Utc::now().timestamp() - timestamp).max(0);
+    match age {
+        0..60 => format!("{}s ago", age),
+        60..3600 => format!("{}m ago", age / 60),
+        3600..86400 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    }
+}
+
+async fn start_agent(
+    port: u16,
+    ui: bool,
+    daemon: bool,
+    transfer_dir: Option<PathBuf>,
+    heartbeat: HeartbeatConfig,
+    mdns: bool,
+) -> Result<()> {
     use std::fs;
     use std::path::PathBuf;
 
@@ -361,6 +958,18 @@ async fn start_agent(port: u16, ui: bool, daemon: bool) -> Result<()> {
             if ui {
                 cmd.arg("--ui");
             }
+            if let Some(transfer_dir) = &transfer_dir {
+                cmd.arg("--transfer-dir").arg(transfer_dir);
+            }
+            cmd.arg("--heartbeat-interval")
+                .arg(heartbeat.interval.as_secs().to_string())
+                .arg("--suspect-after")
+                .arg(heartbeat.suspect_after.as_secs().to_string())
+                .arg("--inactive-after")
+                .arg(heartbeat.inactive_after.as_secs().to_string());
+            if !mdns {
+                cmd.arg("--no-mdns");
+            }
             // Don't pass --daemon flag to spawned process - it runs in foreground
             // but we spawn it in background, so it becomes a daemon
             let child = cmd
@@ -441,10 +1050,17 @@ async fn start_agent(port: u16, ui: bool, daemon: bool) -> Result<()> {
         halvor_web::start_server(addr, static_dir, Some(port)).await?;
         Ok(())
     } else {
-        // Just start agent server (blocking, so run in spawn_blocking)
-        let server = AgentServer::new(port, None);
-        tokio::task::spawn_blocking(move || server.start()).await??;
-        Ok(())
+        // Just start agent server (runs until SIGTERM / Ctrl-C)
+        let transfer_dir = match transfer_dir {
+            Some(transfer_dir) => transfer_dir,
+            None => halvor_agent::agent::transfer::default_transfer_dir()?,
+        };
+        println!("Peers can push and pull files in {}", transfer_dir.display());
+        let server = AgentServer::new(port, None)
+            .with_transfer_dir(transfer_dir)
+            .with_heartbeat(heartbeat)
+            .with_mdns(mdns);
+        server.start().await
     }
 }
 
@@ -495,13 +1111,12 @@ fn show_agent_status() -> Result<()> {
 }
 
 /// Discover agents on the network
-fn discover_agents(verbose: bool) -> Result<()> {
+fn discover_agents(discovery: &HostDiscovery, verbose: bool) -> Result<()> {
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("Discovering Halvor Agents");
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!();
 
-    let discovery = HostDiscovery::default();
     let hosts = discovery.discover_all()?;
 
     if hosts.is_empty() {
@@ -510,6 +1125,7 @@ fn discover_agents(verbose: bool) -> Result<()> {
         println!("Make sure:");
         println!("  - Agents are running on other hosts (halvor agent start)");
         println!("  - Tailscale is configured and devices are connected");
+        println!("  - Multicast (mDNS, UDP port 5353) isn't blocked on the LAN");
         println!("  - Firewall allows connections on port 13500");
     } else {
         println!("Discovered {} agent(s):", hosts.len());
@@ -555,9 +1171,9 @@ fn sync_with_agents(force: bool) -> Result<()> {
     sync_with_agents_internal(&sync, force)
 }
 
-fn sync_with_agents_internal(sync: &ConfigSync, _force: bool) -> Result<()> {
+fn sync_with_agents_internal(sync: &ConfigSync, force: bool) -> Result<()> {
     use halvor_agent::agent::mesh;
-    use halvor_db::generated::agent_peers;
+    use halvor_db::generated::AgentPeersRow;
 
     let local_hostname = get_current_hostname()?;
     let normalized_local = halvor_core::utils::hostname::normalize_hostname(&local_hostname);
@@ -596,12 +1212,9 @@ fn sync_with_agents_internal(sync: &ConfigSync, _force: bool) -> Result<()> {
         }
 
         // Get peer info from database
-        let peer_rows = agent_peers::select_many(
-            "hostname = ?1",
-            &[&normalized_peer as &dyn rusqlite::types::ToSql],
-        )?;
+        let peer_row = AgentPeersRow::query().hostname().eq(&normalized_peer).first()?;
 
-        if let Some(peer_row) = peer_rows.first() {
+        if let Some(peer_row) = &peer_row {
             // If peer has a Tailscale IP, try to add it to sync list
             if let Some(ref ts_ip) = peer_row.tailscale_ip {
                 // Create a DiscoveredHost-like entry for database peer
@@ -613,6 +1226,7 @@ fn sync_with_agents_internal(sync: &ConfigSync, _force: bool) -> Result<()> {
                     local_ip: None,
                     agent_port: 13500,
                     reachable: false, // Will be tested during sync
+                    version: None,
                 });
                 synced_peers.insert(normalized_peer);
             }
@@ -638,16 +1252,28 @@ fn sync_with_agents_internal(sync: &ConfigSync, _force: bool) -> Result<()> {
     println!();
 
     // Sync host information (only with reachable discovered hosts)
-    println!("[1/3] Syncing host information...");
+    println!("[1/4] Syncing host information...");
     sync.sync_host_info(&discovered_hosts)?;
 
-    // Sync encrypted data and mesh peers (with all hosts, including database peers)
-    println!("[2/3] Syncing encrypted data and mesh peers...");
+    // Sync encrypted data and mesh peers (with all hosts, including database peers);
+    // after the first sync only changes are exchanged, unless forced
+    if force {
+        sync.reset_sync_cursors()?;
+    }
+    println!("[2/4] Syncing encrypted data and mesh peers...");
     sync.sync_encrypted_data(&hosts_to_sync)?;
 
+    // Catch up on replicated config writes whose broadcast we missed
+    println!("[3/4] Syncing mesh config...");
+    match sync.sync_mesh_config() {
+        Ok(0) => {}
+        Ok(changed) => println!("  ✓ Updated {} config key(s)", changed),
+        Err(e) => eprintln!("  Warning: Failed to sync mesh config: {}", e),
+    }
+
     // Sync mesh peer information - ensure all hosts know about each other
     // This is now handled in sync_encrypted_data which extracts peer info from sync responses
-    println!("[3/3] Updating peer information...");
+    println!("[4/4] Updating peer information...");
     
     // Add discovered hosts to local database (if not already present)
     for host in &discovered_hosts {
@@ -795,8 +1421,8 @@ fn show_agent_logs(follow: bool) -> Result<()> {
 }
 
 /// Generate a join token for other agents to join this mesh
-fn generate_join_token() -> Result<()> {
-    use halvor_agent::agent::mesh;
+fn generate_join_token(ttl: Duration, max_uses: u32, hostnames: Vec<String>) -> Result<()> {
+    use halvor_agent::agent::mesh::{self, TokenOptions};
     use halvor_agent::apps::tailscale;
 
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
@@ -804,24 +1430,8 @@ fn generate_join_token() -> Result<()> {
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!();
 
-    // Try to get Tailscale hostname first (e.g., "mint" or "mint.bombay-pinecone.ts.net")
-    // If not available, normalize system hostname (e.g., "mint.local" -> "mint")
-    let hostname = halvor_agent::apps::tailscale::get_tailscale_hostname()
-        .ok()
-        .flatten()
-        .map(|ts_hostname| {
-            // Extract short hostname from Tailscale FQDN (e.g., "mint.bombay-pinecone.ts.net" -> "mint")
-            ts_hostname
-                .split('.')
-                .next()
-                .unwrap_or(&ts_hostname)
-                .to_string()
-        })
-        .unwrap_or_else(|| {
-            // Fallback: normalize system hostname
-            let system_hostname = get_current_hostname().unwrap_or_else(|_| "unknown".to_string());
-            halvor_core::utils::hostname::normalize_hostname(&system_hostname)
-        });
+    // Short Tailscale name if available, otherwise the normalized system hostname
+    let hostname = mesh::local_mesh_hostname();
 
     // Get Tailscale IP if available, otherwise use local IP
     let ip = tailscale::get_tailscale_ip()
@@ -836,12 +1446,25 @@ fn generate_join_token() -> Result<()> {
 
     let port = 13500u16; // Default agent port
 
-    let (encoded_token, _token) = mesh::generate_join_token(&hostname, &ip, port)?;
+    let options = TokenOptions {
+        ttl,
+        max_uses,
+        allowed_hostnames: hostnames,
+    };
+    let (encoded_token, token) = mesh::generate_join_token(&hostname, &ip, port, &options)?;
 
     println!("Join token generated successfully!");
     println!();
+    println!("Token ID: {}", token.token_id);
     println!("Issuer: {} ({}:{})", hostname, ip, port);
-    println!("Expires: {} hours", mesh::TOKEN_EXPIRY_HOURS);
+    println!("Expires: {}", format_time(token.expires_at));
+    println!("Uses: {}", format_max_uses(token.max_uses));
+    if !token.allowed_hostnames.is_empty() {
+        println!("Hosts: {}", token.allowed_hostnames.join(", "));
+    }
+    if let Some(ca_fingerprint) = &token.ca_fingerprint {
+        println!("Mesh CA: {}", ca_fingerprint);
+    }
     println!();
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("TOKEN (copy this to the joining machine):");
@@ -1055,10 +1678,9 @@ fn parse_host_port(s: &str) -> Result<(String, u16)> {
 
 /// Perform the actual join operation
 fn perform_join(host: &str, port: u16, token: &str) -> Result<()> {
+    use halvor_agent::agent::api::{AgentClient, JoinAccepted};
     use halvor_agent::agent::mesh::{self, JoinToken};
-    use halvor_agent::agent::server::{AgentRequest, AgentResponse};
-    use halvor_core::utils::{format_address, read_json, write_json};
-    use std::net::{TcpStream, ToSocketAddrs};
+    use halvor_agent::agent::tls::{self, ServerTrust};
 
     // Validate token format
     let decoded = JoinToken::decode(token)?;
@@ -1068,113 +1690,100 @@ fn perform_join(host: &str, port: u16, token: &str) -> Result<()> {
 
     println!("Connecting to {}:{}...", host, port);
 
-    // Try to get Tailscale hostname first (e.g., "mint" or "mint.bombay-pinecone.ts.net")
-    // If not available, normalize system hostname (e.g., "mint.local" -> "mint")
-    let local_hostname = halvor_agent::apps::tailscale::get_tailscale_hostname()
-        .ok()
-        .flatten()
-        .map(|ts_hostname| {
-            // Extract short hostname from Tailscale FQDN (e.g., "mint.bombay-pinecone.ts.net" -> "mint")
-            ts_hostname
-                .split('.')
-                .next()
-                .unwrap_or(&ts_hostname)
-                .to_string()
-        })
-        .unwrap_or_else(|| {
-            // Fallback: normalize system hostname
-            let system_hostname = get_current_hostname().unwrap_or_else(|_| "unknown".to_string());
-            halvor_core::utils::hostname::normalize_hostname(&system_hostname)
-        });
-
-    // Generate a public key for this node (for future encrypted communication)
-    let public_key = format!("pk_{}", uuid::Uuid::new_v4());
+    // Short Tailscale name if available, otherwise the normalized system hostname
+    let local_hostname = mesh::local_mesh_hostname();
 
-    // Send join request
-    let addr = format_address(host, port);
-    let socket_addr = addr
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Failed to resolve address: {}", addr))?;
+    // Key for this node's TLS certificate; only stored once the issuer signs it
+    let node_key = tls::generate_node_key()?;
 
-    let mut stream = TcpStream::connect_timeout(&socket_addr, Duration::from_secs(10))
-        .context("Failed to connect to agent")?;
+    // Tokens from TLS-enabled issuers pin the CA that signed the issuer's certificate
+    let mut client = AgentClient::new(host, port).with_connect_timeout(Duration::from_secs(10));
+    if let Some(ca_fingerprint) = &decoded.ca_fingerprint {
+        client = client.with_server_trust(ServerTrust::Pinned {
+            ca_fingerprint: ca_fingerprint.clone(),
+            server_name: decoded.issuer_hostname.clone(),
+        });
+    }
 
-    let request = AgentRequest::JoinRequest {
-        join_token: token.to_string(),
-        joiner_hostname: local_hostname.clone(),
-        joiner_public_key: public_key,
-    };
+    let JoinAccepted {
+        shared_secret,
+        mesh_peers,
+        certificate,
+        ca_certificates,
+    } = client.join(token, &local_hostname, &tls::encode_public_key(&node_key))?;
 
-    write_json(&mut stream, &request)?;
-    let response: AgentResponse = read_json(&mut stream, 8192)?;
+    println!();
+    println!("Successfully joined the mesh!");
+    println!();
+    println!(
+        "Mesh peers: {}",
+        if mesh_peers.is_empty() {
+            "(none yet)".to_string()
+        } else {
+            mesh_peers.join(", ")
+        }
+    );
 
-    match response {
-        AgentResponse::JoinAccepted {
-            shared_secret,
-            mesh_peers,
-        } => {
-            println!();
-            println!("Successfully joined the mesh!");
-            println!();
-            println!(
-                "Mesh peers: {}",
-                if mesh_peers.is_empty() {
-                    "(none yet)".to_string()
-                } else {
-                    mesh_peers.join(", ")
-                }
-            );
+    // Store the issuer peer relationship locally
+    mesh::add_peer(
+        &decoded.issuer_hostname,
+        Some(decoded.issuer_ip.clone()),
+        None,
+        "issuer",
+        &shared_secret,
+    )?;
 
-            // Store the issuer peer relationship locally
-            mesh::add_peer(
-                &decoded.issuer_hostname,
-                Some(decoded.issuer_ip.clone()),
+    // Add all other mesh peers to local database
+    println!();
+    if !mesh_peers.is_empty() {
+        println!("Adding {} mesh peer(s) to local database...", mesh_peers.len());
+        for peer_hostname in &mesh_peers {
+            // Generate a placeholder shared secret for now
+            // TODO: In a production system, this should be exchanged securely
+            let peer_secret = format!("temp_secret_{}", uuid::Uuid::new_v4());
+
+            if let Err(e) = mesh::add_peer(
+                peer_hostname,
+                None, // Will be discovered via Tailscale
                 None,
-                "issuer",
-                &shared_secret,
-            )?;
-
-            // Add all other mesh peers to local database
-            println!();
-            if !mesh_peers.is_empty() {
-                println!("Adding {} mesh peer(s) to local database...", mesh_peers.len());
-                for peer_hostname in &mesh_peers {
-                    // Generate a placeholder shared secret for now
-                    // TODO: In a production system, this should be exchanged securely
-                    let peer_secret = format!("temp_secret_{}", uuid::Uuid::new_v4());
-
-                    if let Err(e) = mesh::add_peer(
-                        peer_hostname,
-                        None, // Will be discovered via Tailscale
-                        None,
-                        &format!("pk_{}", uuid::Uuid::new_v4()),
-                        &peer_secret,
-                    ) {
-                        eprintln!("  Warning: Failed to add peer {}: {}", peer_hostname, e);
-                    } else {
-                        println!("  ✓ Added peer: {}", peer_hostname);
-                    }
-                }
+                &format!("pk_{}", uuid::Uuid::new_v4()),
+                &peer_secret,
+            ) {
+                eprintln!("  Warning: Failed to add peer {}: {}", peer_hostname, e);
+            } else {
+                println!("  ✓ Added peer: {}", peer_hostname);
             }
-
-            println!();
-            println!("You can now sync with this mesh using: halvor agent sync");
         }
-        AgentResponse::Error { message } => {
-            anyhow::bail!("Join failed: {}", message);
+    }
+
+    // Store the certificate the issuer's mesh CA signed for us
+    println!();
+    match certificate {
+        Some(issued) => {
+            let info = tls::install_node_certificate(
+                &node_key,
+                &issued,
+                &ca_certificates,
+                &local_hostname,
+                &decoded.issuer_hostname,
+            )?;
+            println!("✓ Received mesh certificate (expires {})", format_date(info.expires_at));
+            println!("  The agent on this host now only accepts TLS connections.");
         }
-        _ => {
-            anyhow::bail!("Unexpected response from agent");
+        None => {
+            println!("Issuer did not send a mesh certificate; agent traffic stays plaintext.");
         }
     }
 
+    println!();
+    println!("You can now sync with this mesh using: halvor agent sync");
+
     Ok(())
 }
 
-/// List peers in the mesh
+/// List peers in the mesh with their heartbeat status
 fn list_peers() -> Result<()> {
-    use halvor_agent::agent::mesh;
+    use halvor_agent::agent::mesh::{self, PeerStatus};
 
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("Mesh Peers");
@@ -1185,7 +1794,7 @@ fn list_peers() -> Result<()> {
     // This ensures we show the latest information even if database is stale
     let _ = mesh::refresh_peer_tailscale_hostnames();
 
-    let peers = mesh::get_active_peers()?;
+    let peers = mesh::list_peers()?;
 
     if peers.is_empty() {
         println!("No peers in mesh.");
@@ -1194,10 +1803,20 @@ fn list_peers() -> Result<()> {
         println!("  1. Generate a token: halvor agent token");
         println!("  2. On another machine: halvor agent join <token>");
     } else {
-        println!("Active peers ({}):", peers.len());
+        let active = peers.iter().filter(|p| p.status == PeerStatus::Active).count();
+        println!("Peers ({}, {} active):", peers.len(), active);
         println!();
-        for peer in peers {
-            println!("  - {}", peer);
+        println!("  {:<24} {:<10} LAST SEEN", "HOSTNAME", "STATUS");
+        for peer in &peers {
+            let last_seen = peer
+                .last_seen_at
+                .map(format_last_seen)
+                .unwrap_or_else(|| "never".to_string());
+            println!("  {:<24} {:<10} {}", peer.hostname, peer.status, last_seen);
+        }
+        if active < peers.len() {
+            println!();
+            println!("Suspect and inactive peers have missed heartbeats; they become active again once they answer.");
         }
     }
 
@@ -1205,12 +1824,19 @@ fn list_peers() -> Result<()> {
 }
 
 /// Remove a peer from the mesh
-fn remove_peer(hostname: Option<&str>) -> Result<()> {
+///
+/// The removal is signed and broadcast as a revocation, so every peer drops
+/// the removed node's secret and sync doesn't bring it back. Hosts without a
+/// mesh certificate can't sign one, so they can't remove peers.
+fn remove_peer(hostname: Option<&str>, wipe: bool) -> Result<()> {
+    use halvor_agent::agent::gossip::Gossip;
     use halvor_agent::agent::mesh;
+    use halvor_agent::agent::mesh_protocol::MessagePayload;
+    use halvor_agent::agent::revocation::{self, PeerRevocation};
     use halvor_core::utils::hostname::normalize_hostname;
-    use halvor_db::generated::agent_peers;
+    use halvor_db::generated::AgentPeersRow;
 
-    let peers = mesh::get_active_peers()?;
+    let peers = mesh::get_peers()?;
 
     if peers.is_empty() {
         println!("No peers in mesh to remove.");
@@ -1254,12 +1880,7 @@ fn remove_peer(hostname: Option<&str>) -> Result<()> {
 
         let mut peer_list: Vec<(usize, String, Option<String>, Option<String>)> = Vec::new();
         for (idx, peer_hostname) in peers.iter().enumerate() {
-            let peer_row = agent_peers::select_one(
-                "hostname = ?1",
-                &[&peer_hostname as &dyn rusqlite::types::ToSql],
-            )
-            .ok()
-            .flatten();
+            let peer_row = AgentPeersRow::query().hostname().eq(peer_hostname).first().ok().flatten();
 
             let ip = peer_row.as_ref().and_then(|r| r.tailscale_ip.clone());
             let ts_hostname = peer_row.as_ref().and_then(|r| r.tailscale_hostname.clone());
@@ -1310,16 +1931,37 @@ fn remove_peer(hostname: Option<&str>) -> Result<()> {
         return Ok(());
     }
 
-    // Remove the peer
-    match mesh::remove_peer(&hostname_to_remove) {
-        Ok(()) => {
-            println!("✓ Removed peer '{}' from mesh.", hostname_to_remove);
-        }
-        Err(e) => {
-            return Err(anyhow::anyhow!("Failed to remove peer: {}", e));
+    // Revoke the peer; unsigned removals aren't accepted by the rest of the mesh
+    let revocation = PeerRevocation::sign(&hostname_to_remove).with_context(|| {
+        format!(
+            "Can't remove '{}' without a mesh certificate to sign the revocation",
+            hostname_to_remove
+        )
+    })?;
+
+    // Sent first: afterwards this host no longer shares a secret with the peer
+    if wipe {
+        match revocation::request_wipe(&revocation) {
+            Ok(()) => println!("✓ '{}' wiped its mesh state.", hostname_to_remove),
+            Err(e) => println!(
+                "⚠ Could not have '{}' wipe its mesh state: {:#}",
+                hostname_to_remove, e
+            ),
         }
     }
 
+    revocation::apply(&revocation).context("Failed to revoke peer")?;
+    println!(
+        "✓ Revoked peer '{}' and the certificates issued to it.",
+        hostname_to_remove
+    );
+
+    let notified = Gossip::default().broadcast(MessagePayload::PeerRevoked {
+        revocation,
+        wipe: false,
+    });
+    println!("✓ Announced revocation to {} peer(s), who relay it to the rest of the mesh.", notified);
+
     Ok(())
 }
 
@@ -1361,7 +2003,7 @@ fn verify_mesh_connectivity() -> Result<()> {
     println!();
 
     // Build peer info map
-    use halvor_db::generated::agent_peers;
+    use halvor_db::generated::AgentPeersRow;
     let mut peer_info: Vec<(String, Option<String>, Option<String>, bool, bool, bool)> = Vec::new();
 
     for peer_hostname in &db_peers {
@@ -1373,13 +2015,10 @@ fn verify_mesh_connectivity() -> Result<()> {
         }
 
         // Get peer info from database
-        let peer_rows = agent_peers::select_many(
-            "hostname = ?1",
-            &[&normalized_peer as &dyn rusqlite::types::ToSql],
-        )?;
-        
-        let tailscale_ip = peer_rows.first().and_then(|r| r.tailscale_ip.clone());
-        let tailscale_hostname = peer_rows.first().and_then(|r| r.tailscale_hostname.clone());
+        let peer_row = AgentPeersRow::query().hostname().eq(&normalized_peer).first()?;
+
+        let tailscale_ip = peer_row.as_ref().and_then(|r| r.tailscale_ip.clone());
+        let tailscale_hostname = peer_row.as_ref().and_then(|r| r.tailscale_hostname.clone());
 
         // Find in discovered hosts
         let discovered = discovered_hosts.iter().find(|h| {
@@ -1418,6 +2057,7 @@ fn verify_mesh_connectivity() -> Result<()> {
     let mut ping_failed = 0;
     let mut sync_ok = 0;
     let mut sync_failed = 0;
+    let mut versions: Vec<(String, Option<Hello>)> = Vec::new();
 
     // Test each peer
     for (i, (hostname, ip, ts_hostname, discovered, _reachable, _ping_ok)) in peer_info.iter().enumerate() {
@@ -1451,15 +2091,30 @@ fn verify_mesh_connectivity() -> Result<()> {
             Ok(false) | Err(_) => {
                 println!("✗");
                 ping_failed += 1;
+                versions.push((hostname.clone(), None));
                 println!();
                 continue;
             }
         }
 
-        // Test database sync
+        // Handshake: which halvor the peer runs and what it supports
+        print!("  Checking version... ");
+        io::stdout().flush()?;
+        match client.hello() {
+            Ok(hello) => {
+                println!("✓ halvor {} (protocol {})", hello.version, hello.protocol_version);
+                versions.push((hostname.clone(), Some(hello.clone())));
+            }
+            Err(e) => {
+                println!("✗ ({})", e);
+                versions.push((hostname.clone(), None));
+            }
+        }
+
+        // Test database sync (encrypted with the peer's shared secret)
         print!("  Testing database sync... ");
         io::stdout().flush()?;
-        match client.sync_database(&local_hostname, None) {
+        match client.with_peer(hostname).and_then(|c| c.sync_database(&local_hostname, None)) {
             Ok(_) => {
                 println!("✓");
                 sync_ok += 1;
@@ -1494,6 +2149,19 @@ fn verify_mesh_connectivity() -> Result<()> {
     println!("  ✗ Failed: {}", sync_failed);
     println!();
 
+    let local_hello = Hello::local(&normalized_local);
+    print_version_matrix(&local_hello, &versions);
+    let mismatched = versions
+        .iter()
+        .filter_map(|(_, hello)| hello.as_ref())
+        .any(|hello| hello.version != local_hello.version);
+
+    if mismatched {
+        println!("⚠️  Peers run different halvor versions; features missing on a peer");
+        println!("   are refused when used with it. Update them: halvor update");
+        println!();
+    }
+
     if ping_failed > 0 || sync_failed > 0 {
         println!("⚠️  Some peers have connectivity issues.");
         println!();
@@ -1510,22 +2178,48 @@ fn verify_mesh_connectivity() -> Result<()> {
     Ok(())
 }
 
-/// Update hostname and sync across mesh
-fn update_hostname(new_hostname: &str) -> Result<()> {
-    use halvor_agent::agent::api::AgentClient;
-    use halvor_agent::agent::discovery::HostDiscovery;
-    use halvor_agent::apps::tailscale;
+/// Print each peer's halvor version, protocol and capabilities next to ours
+fn print_version_matrix(local: &Hello, peers: &[(String, Option<Hello>)]) {
+    println!("Version Matrix:");
+    print!("  {:<20} {:<10} {:<9}", "HOSTNAME", "VERSION", "PROTOCOL");
+    for capability in capability::ALL {
+        print!(" {:<w$}", capability, w = capability.len());
+    }
+    println!();
+
+    let rows = std::iter::once((format!("{} (this host)", local.hostname), Some(local)))
+        .chain(peers.iter().map(|(hostname, hello)| (hostname.clone(), hello.as_ref())));
+    for (hostname, hello) in rows {
+        match hello {
+            Some(hello) => {
+                print!(
+                    "  {:<20} {:<10} {:<9}",
+                    hostname, hello.version, hello.protocol_version
+                );
+                for capability in capability::ALL {
+                    let mark = if hello.supports(capability) { "✓" } else { "✗" };
+                    print!(" {:<w$}", mark, w = capability.len());
+                }
+                println!();
+            }
+            None => println!("  {:<20} {:<10} {:<9}", hostname, "?", "?"),
+        }
+    }
+    println!();
+}
+
+/// Rename this host everywhere it is known by name (see `halvor_agent::agent::rename`)
+fn update_hostname(new_hostname: &str, dry_run: bool, yes: bool) -> Result<()> {
+    use halvor_agent::agent::mesh;
+    use halvor_agent::agent::rename::RenamePlan;
 
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("Update Hostname");
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!();
 
-    // Get current hostname
-    let current_hostname = get_current_hostname()?;
-    let normalized_current = halvor_core::utils::hostname::normalize_hostname(&current_hostname);
-
-    // Normalize new hostname
+    // The name the mesh knows this node by, which a rename overrides
+    let normalized_current = mesh::local_mesh_hostname();
     let normalized_new = halvor_core::utils::hostname::normalize_hostname(new_hostname);
 
     if normalized_current == normalized_new {
@@ -1537,124 +2231,41 @@ fn update_hostname(new_hostname: &str) -> Result<()> {
     println!("New hostname: {}", normalized_new);
     println!();
 
-    // Get Tailscale IP and hostname for the update
-    let tailscale_ip = tailscale::get_tailscale_ip().ok().flatten();
-    let tailscale_hostname = tailscale::get_tailscale_hostname().ok().flatten();
-
-    // Update local database entry
-    println!("[1/3] Updating local hostname in database...");
-
-    // Get current peer entry
-    use halvor_db::generated::agent_peers;
-    let current_peer = agent_peers::select_one(
-        "hostname = ?1",
-        &[&normalized_current as &dyn rusqlite::types::ToSql],
-    )?;
-
-    if let Some(peer) = current_peer {
-        // Update existing peer entry with new hostname
-        // We need to delete the old entry and create a new one since hostname is unique
-        let peer_data = halvor_db::generated::AgentPeersRowData {
-            hostname: normalized_new.clone(),
-            tailscale_ip: tailscale_ip.clone(),
-            tailscale_hostname: tailscale_hostname.clone(),
-            public_key: peer.public_key.clone(),
-            status: peer.status.clone(),
-            last_seen_at: peer.last_seen_at,
-            joined_at: peer.joined_at,
-        };
-
-        // Delete old entry
-        agent_peers::delete_by_hostname(&normalized_current)?;
-
-        // Create new entry
-        agent_peers::upsert_one(
-            "hostname = ?1",
-            &[&normalized_new as &dyn rusqlite::types::ToSql],
-            peer_data,
-        )?;
-
-        // Update peer_keys table
-        let conn = halvor_db::get_connection()?;
-        conn.execute(
-            "UPDATE peer_keys SET peer_hostname = ?1 WHERE peer_hostname = ?2",
-            rusqlite::params![&normalized_new, &normalized_current],
-        )?;
-
-        println!("  ✓ Local database updated");
-    } else {
-        // No existing peer entry, create new one
-        let now = chrono::Utc::now().timestamp();
-        let peer_data = halvor_db::generated::AgentPeersRowData {
-            hostname: normalized_new.clone(),
-            tailscale_ip: tailscale_ip.clone(),
-            tailscale_hostname: tailscale_hostname.clone(),
-            public_key: format!("pk_{}", uuid::Uuid::new_v4()),
-            status: "active".to_string(),
-            last_seen_at: Some(now),
-            joined_at: now,
-        };
-
-        agent_peers::upsert_one(
-            "hostname = ?1",
-            &[&normalized_new as &dyn rusqlite::types::ToSql],
-            peer_data,
-        )?;
-
-        println!("  ✓ Local database updated (new entry)");
+    // Work out every change before making any
+    let plan = RenamePlan::new(&normalized_current, &normalized_new)?;
+    println!("Planned changes:");
+    for line in plan.diff() {
+        println!("  {}", line);
+    }
+    for note in &plan.notes {
+        println!("  Note: {}", note);
     }
     println!();
 
-    // Discover and notify all peers
-    println!("[2/3] Notifying peers in mesh...");
-    let discovery = HostDiscovery::default();
-    let hosts = discovery.discover_all()?;
-
-    let mut notified = 0;
-    let mut failed = 0;
-
-    for host in &hosts {
-        if !host.reachable {
-            continue;
-        }
-
-        // Skip self
-        let normalized_peer = halvor_core::utils::hostname::normalize_hostname(&host.hostname);
-        if normalized_peer == normalized_current || normalized_peer == normalized_new {
-            continue;
-        }
-
-        let agent_host = host
-            .tailscale_ip
-            .as_ref()
-            .or(host.local_ip.as_ref())
-            .ok_or_else(|| anyhow::anyhow!("No IP for host {}", host.hostname))?;
-
-        let client = AgentClient::new(agent_host, host.agent_port);
+    if dry_run {
+        println!("Dry run - nothing was changed.");
+        return Ok(());
+    }
+    if !yes {
+        print!("Rename '{}' to '{}'? (y/N): ", normalized_current, normalized_new);
+        io::stdout().flush()?;
 
-        // Use sync_database to notify peer about hostname change
-        // The peer will receive the updated hostname in the sync data
-        match client.sync_database(&normalized_new, None) {
-            Ok(_) => {
-                println!("  ✓ Notified {}", host.hostname);
-                notified += 1;
-            }
-            Err(e) => {
-                println!("  ⚠️  Failed to notify {}: {}", host.hostname, e);
-                failed += 1;
-            }
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm)?;
+        let confirm = confirm.trim();
+        if !confirm.eq_ignore_ascii_case("y") && !confirm.eq_ignore_ascii_case("yes") {
+            println!("Cancelled.");
+            return Ok(());
         }
     }
 
-    if notified == 0 && failed == 0 {
-        println!("  (No other peers in mesh)");
-    } else {
-        println!("  Notified {} peer(s), {} failed", notified, failed);
-    }
+    println!("Applying changes...");
+    plan.apply()?;
     println!();
 
-    // Optionally update system hostname (requires sudo)
-    println!("[3/3] System hostname update (optional)...");
+    // Changing the system hostname needs root, so it is left to the user. The
+    // mesh already uses the new name either way.
+    println!("System hostname update (optional)...");
     println!("  To update system hostname, run:");
     println!("    sudo hostnamectl set-hostname {}", normalized_new);
     println!(
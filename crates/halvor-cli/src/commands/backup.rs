@@ -40,8 +40,10 @@ pub fn handle_backup_db(path: Option<&str>) -> Result<()> {
     println!("  Source: {}", db_path.display());
     println!("  Destination: {}", backup_path.display());
 
-    fs::copy(&db_path, &backup_path)
-        .with_context(|| format!("Failed to copy database to {}", backup_path.display()))?;
+    // Copying the file would miss writes still in the WAL
+    let conn = rusqlite::Connection::open(&db_path)
+        .with_context(|| format!("Failed to open database: {}", db_path.display()))?;
+    db::backup_to(&conn, &backup_path)?;
 
     println!("✓ Database backup created: {}", backup_path.display());
     Ok(())
@@ -82,6 +82,24 @@ pub enum ConfigCommands {
         #[arg(long, short = 'y')]
         yes: bool,
     },
+    /// Set a config value (JSON values such as 3 or {"a": 1} are stored as JSON)
+    Set {
+        /// Config key
+        key: String,
+        /// Config value
+        value: String,
+        /// Replicate the value to every node in the agent mesh
+        #[arg(long)]
+        mesh: bool,
+    },
+    /// Get a config value (all values if no key is given)
+    Get {
+        /// Config key
+        key: Option<String>,
+        /// Read the mesh-replicated value instead of this machine's
+        #[arg(long)]
+        mesh: bool,
+    },
 }
 
 #[derive(clap::Subcommand, Clone)]
@@ -108,12 +126,18 @@ pub enum DbCommands {
         #[arg(long)]
         path: Option<String>,
     },
-    /// Generate Rust structs from database schema
-    Generate,
+    /// Check the row structs against the database schema
+    Check,
     /// Manage database migrations (defaults to running all pending migrations)
+    ///
+    /// The database is backed up to ~/.config/halvor/backups before pending
+    /// migrations run, and each migration runs in its own transaction.
     Migrate {
         #[command(subcommand)]
         command: Option<MigrateCommands>,
+        /// Print the SQL each pending migration would execute, without running them
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Sync environment file to database (load env values into DB, delete DB values not in env)
     Sync,
@@ -220,6 +244,22 @@ pub fn handle_config(
         Some(ConfigCommands::Regenerate { hostname, yes }) => {
             anyhow::bail!("Regenerate command not yet fully implemented (hostname: {:?}, yes: {})", hostname, yes)
         }
+        Some(ConfigCommands::Set { key, value, mesh }) => {
+            if *mesh {
+                set_mesh_config(key, value)
+            } else {
+                db::settings::set_setting(key, value)?;
+                println!("✓ {} = {}", key, value);
+                Ok(())
+            }
+        }
+        Some(ConfigCommands::Get { key, mesh }) => {
+            if *mesh {
+                get_mesh_config(key.as_deref())
+            } else {
+                get_local_setting(key.as_deref())
+            }
+        }
         None => {
             // Show config summary
             let hal_config = config_manager::load_config()?;
@@ -235,6 +275,72 @@ pub fn handle_config(
     }
 }
 
+/// Set a config value on this node and gossip it to the mesh
+fn set_mesh_config(key: &str, value: &str) -> Result<()> {
+    use halvor_agent::agent::mesh_config;
+
+    // Plain words are stored as strings, anything that parses as JSON as JSON
+    let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+    let entry = mesh_config::set(key, value)?;
+    println!("✓ {} = {} (version {})", entry.key, entry.value, entry.version);
+
+    let notified = mesh_config::publish(&entry);
+    if notified == 0 {
+        println!("  No reachable peers; they pick it up on their next sync");
+    } else {
+        println!("  Announced to {} peer(s), who relay it to the rest of the mesh", notified);
+    }
+    Ok(())
+}
+
+/// Show one mesh-replicated config value, or all of them
+fn get_mesh_config(key: Option<&str>) -> Result<()> {
+    use halvor_agent::agent::mesh_config;
+
+    match key {
+        Some(key) => match mesh_config::get(key)? {
+            Some(entry) => {
+                println!("{}", entry.value);
+                let written_at = chrono::DateTime::from_timestamp_millis(entry.timestamp_millis() as i64)
+                    .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default();
+                eprintln!("  (set by {} at {}, version {})", entry.origin, written_at, entry.version);
+                Ok(())
+            }
+            None => anyhow::bail!("Mesh config key '{}' is not set", key),
+        },
+        None => {
+            let entries = mesh_config::list()?;
+            if entries.is_empty() {
+                println!("No mesh config set. Use: halvor config set <KEY> <VALUE> --mesh");
+            }
+            for entry in entries {
+                println!("{} = {}  ({})", entry.key, entry.value, entry.origin);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Show one of this machine's settings, or all of them
+fn get_local_setting(key: Option<&str>) -> Result<()> {
+    match key {
+        Some(key) => match db::settings::get_setting(key)? {
+            Some(value) => {
+                println!("{}", value);
+                Ok(())
+            }
+            None => anyhow::bail!("Config key '{}' is not set", key),
+        },
+        None => {
+            for row in db::settings::SettingsRow::query().order_by_key_asc().all()? {
+                println!("{} = {}", row.key, row.value);
+            }
+            Ok(())
+        }
+    }
+}
+
 /// Handle db subcommands
 pub fn handle_db_command(command: DbCommands) -> Result<()> {
     match command {
@@ -250,17 +356,29 @@ pub fn handle_db_command(command: DbCommands) -> Result<()> {
                 let timestamp = Utc::now().format("%Y%m%d-%H%M%S");
                 std::env::current_dir()?.join(format!("halvor-backup-{}.db", timestamp))
             };
-            std::fs::copy(&db_path, &backup_path)
-                .with_context(|| format!("Failed to copy database to {}", backup_path.display()))?;
+            // Opened directly, so the backup is taken before any pending migrations
+            let conn = rusqlite::Connection::open(&db_path)
+                .with_context(|| format!("Failed to open database: {}", db_path.display()))?;
+            db::backup_to(&conn, &backup_path)?;
             println!("✓ Database backup created: {}", backup_path.display());
             Ok(())
         }
-        DbCommands::Generate => {
-            anyhow::bail!("Generate command not yet fully implemented")
-        }
-        DbCommands::Migrate { command: migrate_cmd } => {
-            anyhow::bail!("Migrate command not yet fully implemented (command: {:?})", migrate_cmd)
-        }
+        DbCommands::Check => db::migrate::check_schema(),
+        DbCommands::Migrate {
+            command: migrate_cmd,
+            dry_run,
+        } => match migrate_cmd {
+            None if dry_run => db::migrate::migrate_dry_run(),
+            None => db::migrate::migrate_all(),
+            Some(_) if dry_run => anyhow::bail!("--dry-run only applies to running all pending migrations"),
+            Some(MigrateCommands::Up) => db::migrate::migrate_up(),
+            Some(MigrateCommands::Down) => db::migrate::migrate_down(),
+            Some(MigrateCommands::List) => db::migrate::migrate_list(),
+            Some(MigrateCommands::Generate { description })
+            | Some(MigrateCommands::GenerateShort { description }) => {
+                db::migrate::generate_migration(description)
+            }
+        },
         DbCommands::Sync => {
             anyhow::bail!("Sync command not yet fully implemented")
         }
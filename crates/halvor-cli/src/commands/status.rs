@@ -324,9 +324,9 @@ fn show_mesh_status(hostname: &str, config: &config::EnvConfig) -> Result<()> {
         let _ = mesh::refresh_peer_tailscale_hostnames();
 
         // Get mesh peers from database
-        use halvor_db::generated::agent_peers;
+        use halvor_db::generated::AgentPeersRow;
 
-        match mesh::get_active_peers() {
+        match mesh::get_peers() {
             Ok(peers) => {
                 if peers.is_empty() {
                     println!("  No peers in mesh.");
@@ -341,10 +341,7 @@ fn show_mesh_status(hostname: &str, config: &config::EnvConfig) -> Result<()> {
                     // Get detailed peer information
                     let mut peer_details = Vec::new();
                     for peer_hostname in &peers {
-                        if let Ok(Some(mut peer_row)) = agent_peers::select_one(
-                            "hostname = ?1",
-                            &[&peer_hostname as &dyn rusqlite::types::ToSql],
-                        ) {
+                        if let Ok(Some(mut peer_row)) = AgentPeersRow::query().hostname().eq(peer_hostname).first() {
                             // Try to update missing Tailscale information from devices
                             let short_name = peer_hostname.split('.').next().unwrap_or(peer_hostname);
                             if let Some(device) = tailscale_devices.iter().find(|d| {
@@ -374,7 +371,7 @@ fn show_mesh_status(hostname: &str, config: &config::EnvConfig) -> Result<()> {
                                 peer_hostname.clone(),
                                 peer_row.tailscale_ip.clone(),
                                 peer_row.tailscale_hostname.clone(),
-                                peer_row.last_seen_at,
+                                Some(mesh::PeerStatus::parse(&peer_row.status)),
                             ));
                         } else {
                             peer_details.push((peer_hostname.clone(), None, None, None));
@@ -391,20 +388,15 @@ fn show_mesh_status(hostname: &str, config: &config::EnvConfig) -> Result<()> {
                     );
                     println!("  {}", "-".repeat(80));
 
-                    for (hostname, ip, ts_hostname, last_seen) in &peer_details {
+                    for (hostname, ip, ts_hostname, peer_status) in &peer_details {
                         let ip_str = ip.as_deref().unwrap_or("N/A");
                         let ts_str = ts_hostname.as_deref().unwrap_or("N/A");
-                        let status = if let Some(seen) = last_seen {
-                            let age = chrono::Utc::now().timestamp() - seen;
-                            if age < 300 {
-                                "✓"
-                            } else if age < 3600 {
-                                "⚠"
-                            } else {
-                                "✗"
-                            }
-                        } else {
-                            "?"
+                        // Kept up to date by the agent's heartbeats
+                        let status = match peer_status {
+                            Some(mesh::PeerStatus::Active) => "✓",
+                            Some(mesh::PeerStatus::Suspect) => "⚠",
+                            Some(mesh::PeerStatus::Inactive) => "✗",
+                            None => "?",
                         };
                         println!(
                             "  {:<30} {:<20} {:<30} {}",
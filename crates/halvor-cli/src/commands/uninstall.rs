@@ -298,6 +298,12 @@ fn handle_local_guided_uninstall() -> Result<()> {
                 if let Err(e) = std::fs::remove_file(&db_path) {
                     eprintln!("  ⚠ Warning: Failed to remove database: {}", e);
                 } else {
+                    // The WAL and shared-memory files go with it
+                    for suffix in ["-wal", "-shm"] {
+                        let mut path = db_path.clone().into_os_string();
+                        path.push(suffix);
+                        let _ = std::fs::remove_file(path);
+                    }
                     println!("  ✓ Removed database");
                 }
             } else {
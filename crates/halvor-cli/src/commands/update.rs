@@ -76,6 +76,7 @@ fn update_with_node_selection(
         local_ip: Some("127.0.0.1".to_string()),
         agent_port: 13500,
         reachable: true,
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
     };
 
     let mut available_nodes: Vec<(&halvor_agent::agent::discovery::DiscoveredHost, bool)> = Vec::new();
@@ -29,6 +29,9 @@ fn main() -> Result<()> {
     // Check for updates (non-blocking, only in production mode)
     commands::utils::check_for_updates();
 
+    // Let remote commands go through halvor agents where hosts run one
+    halvor_agent::agent::executor::register();
+
     let cli = Cli::parse();
     commands::handle_command(cli.hostname, cli.command)?;
 
@@ -1,4 +1,4 @@
-use crate::config::HostConfig;
+use crate::config::{HostConfig, HostTransport};
 use anyhow::{Context, Result};
 use std::fs;
 use std::path::PathBuf;
@@ -39,6 +39,9 @@ pub fn write_host_to_env_file(
             hostname_upper, backup_path
         ));
     }
+    if config.transport != HostTransport::default() {
+        lines.push(format!("HOST_{}_TRANSPORT={}", hostname_upper, config.transport));
+    }
 
     // Write back to file
     fs::write(env_path, lines.join("\n") + "\n")
@@ -71,3 +74,32 @@ pub fn remove_host_from_env_file(env_path: &PathBuf, hostname: &str) -> Result<(
 
     Ok(())
 }
+
+/// Rename a host's `HOST_<name>_*` keys in .env file content
+///
+/// Unlike removing the host and writing it again, this keeps every key
+/// (including `SUDO_PASS`/`SUDO_USER`), comments and the order of lines.
+pub fn rename_host_keys(content: &str, old_hostname: &str, new_hostname: &str) -> String {
+    let old_prefix = format!("HOST_{}_", old_hostname.to_uppercase());
+    let new_prefix = format!("HOST_{}_", new_hostname.to_uppercase());
+
+    let mut renamed: Vec<String> = content
+        .lines()
+        .map(|line| {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let trimmed = line.trim_start();
+            let (export, key) = match trimmed.strip_prefix("export ") {
+                Some(rest) => ("export ", rest),
+                None => ("", trimmed),
+            };
+            match key.strip_prefix(&old_prefix) {
+                Some(rest) => format!("{}{}{}{}", indent, export, new_prefix, rest),
+                None => line.to_string(),
+            }
+        })
+        .collect();
+    if content.ends_with('\n') {
+        renamed.push(String::new());
+    }
+    renamed.join("\n")
+}
@@ -2,7 +2,9 @@ use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 pub mod config_manager;
 pub mod env_file;
@@ -15,6 +17,47 @@ pub struct HostConfig {
     pub backup_path: Option<String>,
     pub sudo_password: Option<String>, // Sudo password from environment (HOST_<name>_SUDO_PASS)
     pub sudo_user: Option<String>,     // Sudo user from environment (HOST_<name>_SUDO_USER)
+    #[serde(default)]
+    pub transport: HostTransport,      // How to reach the host (HOST_<name>_TRANSPORT)
+}
+
+/// How commands reach a remote host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostTransport {
+    /// Through the host's halvor agent if it answers, otherwise SSH
+    ///
+    /// The agent still refuses commands its policy doesn't allow for this
+    /// host, so only choose this for hosts with policy rules set up.
+    Auto,
+    /// Only through the host's halvor agent
+    Agent,
+    /// Only over SSH
+    #[default]
+    Ssh,
+}
+
+impl FromStr for HostTransport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(HostTransport::Auto),
+            "agent" => Ok(HostTransport::Agent),
+            "ssh" => Ok(HostTransport::Ssh),
+            other => anyhow::bail!("Unknown transport '{}' (expected agent, ssh or auto)", other),
+        }
+    }
+}
+
+impl fmt::Display for HostTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostTransport::Auto => write!(f, "auto"),
+            HostTransport::Agent => write!(f, "agent"),
+            HostTransport::Ssh => write!(f, "ssh"),
+        }
+    }
 }
 
 pub struct SmbServerConfig {
@@ -121,6 +164,7 @@ pub fn load_env_config(_halvor_dir: &Path) -> Result<EnvConfig> {
                     backup_path: None,
                     sudo_password: None,
                     sudo_user: None,
+                    transport: HostTransport::default(),
                 });
                 // Only set IP if not already set by HOST_<name>_IP
                 if config.ip.is_none() {
@@ -134,6 +178,7 @@ pub fn load_env_config(_halvor_dir: &Path) -> Result<EnvConfig> {
                     backup_path: None,
                     sudo_password: None,
                     sudo_user: None,
+                    transport: HostTransport::default(),
                 });
                 config.ip = Some(value);
             } else if let Some(rest) = hostname.strip_suffix("_HOSTNAME") {
@@ -144,6 +189,7 @@ pub fn load_env_config(_halvor_dir: &Path) -> Result<EnvConfig> {
                     backup_path: None,
                     sudo_password: None,
                     sudo_user: None,
+                    transport: HostTransport::default(),
                 });
                 config.hostname = Some(value);
             } else if let Some(rest) = hostname.strip_suffix("_BACKUP_PATH") {
@@ -154,6 +200,7 @@ pub fn load_env_config(_halvor_dir: &Path) -> Result<EnvConfig> {
                     backup_path: None,
                     sudo_password: None,
                     sudo_user: None,
+                    transport: HostTransport::default(),
                 });
                 config.backup_path = Some(value);
             } else if let Some(rest) = hostname.strip_suffix("_SUDO_PASS") {
@@ -164,6 +211,7 @@ pub fn load_env_config(_halvor_dir: &Path) -> Result<EnvConfig> {
                     backup_path: None,
                     sudo_password: None,
                     sudo_user: None,
+                    transport: HostTransport::default(),
                 });
                 config.sudo_password = Some(value);
             } else if let Some(rest) = hostname.strip_suffix("_SUDO_USER") {
@@ -174,8 +222,23 @@ pub fn load_env_config(_halvor_dir: &Path) -> Result<EnvConfig> {
                     backup_path: None,
                     sudo_password: None,
                     sudo_user: None,
+                    transport: HostTransport::default(),
                 });
                 config.sudo_user = Some(value);
+            } else if let Some(rest) = hostname.strip_suffix("_TRANSPORT") {
+                let hostname_lower = rest.to_lowercase();
+                let transport = value
+                    .parse()
+                    .with_context(|| format!("Invalid {}", key))?;
+                let config = hosts.entry(hostname_lower).or_insert_with(|| HostConfig {
+                    ip: None,
+                    hostname: None,
+                    backup_path: None,
+                    sudo_password: None,
+                    sudo_user: None,
+                    transport: HostTransport::default(),
+                });
+                config.transport = transport;
             }
         } else if let Some(server_name) = key.strip_prefix("SMB_") {
             // Parse SMB server configuration
@@ -3,10 +3,28 @@ use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
 use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
 
+use crate::config::{EnvConfig, HostTransport};
 // Import SshConnection from ssh module
 use crate::utils::ssh::SshConnection;
-// Agent-based execution is handled in halvor-agent crate
+
+/// Connects to a host's halvor agent, for [`Executor::Agent`]
+///
+/// The agent client lives in halvor-agent, which depends on this crate, so it
+/// is plugged in at startup with [`set_agent_connector`].
+pub type AgentConnector =
+    fn(hostname: &str, config: &EnvConfig) -> Result<Box<dyn CommandExecutor + Send + Sync>>;
+
+static AGENT_CONNECTOR: OnceLock<AgentConnector> = OnceLock::new();
+
+/// Hosts already warned about falling back to SSH
+static SSH_FALLBACKS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Let [`Executor::new`] reach hosts through their halvor agents
+pub fn set_agent_connector(connector: AgentConnector) {
+    let _ = AGENT_CONNECTOR.set(connector);
+}
 
 /// Local command execution helpers
 pub mod local {
@@ -408,12 +426,14 @@ fn prompt_ssh_username(host: &str) -> Result<String> {
     }
 }
 
-/// Executor that can be either local or remote via SSH
+/// Executor that can be local, remote via SSH, or remote via the host's halvor agent
 /// Automatically determines execution context based on hostname and config
-/// Note: Agent-based execution is handled in halvor-cli where agent module is available
+/// (`HOST_<name>_TRANSPORT` picks between the agent and SSH)
 pub enum Executor {
     Local,
     Remote(SshConnection),
+    /// Commands run by the host's halvor agent (see [`set_agent_connector`])
+    Agent(Box<dyn CommandExecutor + Send + Sync>),
 }
 
 impl Executor {
@@ -487,7 +507,7 @@ impl Executor {
             ip.clone()
         } else {
             // If no IP configured, assume remote
-            return Ok(Executor::Remote({
+            return Self::connect_remote(&actual_hostname, config, host_config.transport, || {
                 let hostname_val = host_config.hostname.as_ref().ok_or_else(|| {
                     anyhow::anyhow!("No IP or Tailscale hostname configured for {}", hostname)
                 })?;
@@ -506,8 +526,8 @@ impl Executor {
                 // Get sudo password and user from host config
                 let sudo_password = host_config.sudo_password.clone();
                 let sudo_user = host_config.sudo_user.clone();
-                SshConnection::new_with_sudo_password(&host_with_user, sudo_password, sudo_user)?
-            }));
+                SshConnection::new_with_sudo_password(&host_with_user, sudo_password, sudo_user)
+            });
         };
 
         // Get local IP addresses (both regular and Tailscale)
@@ -552,29 +572,70 @@ impl Executor {
                 )
             })?;
 
-            // Determine which host to connect to (prefer Tailscale hostname, fallback to IP)
-            let target_host = if let Some(hostname_val) = &host_config.hostname {
-                hostname_val.clone()
-            } else if let Some(ip) = &host_config.ip {
-                ip.clone()
-            } else {
-                anyhow::bail!("No IP or Tailscale hostname configured for {}", hostname);
-            };
-
-            // Get sudo password and user from host config
-            let sudo_password = host_config.sudo_password.clone();
-            let sudo_user = host_config.sudo_user.clone();
-
-            // Create SSH connection
-            let username = crate::config::get_default_username();
-            let host_with_user = format!("{}@{}", username, target_host);
-            let conn = SshConnection::new_with_sudo_password(
-                &host_with_user,
-                sudo_password,
-                sudo_user,
-            )?;
-
-            Ok(Executor::Remote(conn))
+            Self::connect_remote(&actual_hostname, config, host_config.transport, || {
+                // Determine which host to connect to (prefer Tailscale hostname, fallback to IP)
+                let target_host = if let Some(hostname_val) = &host_config.hostname {
+                    hostname_val.clone()
+                } else if let Some(ip) = &host_config.ip {
+                    ip.clone()
+                } else {
+                    anyhow::bail!("No IP or Tailscale hostname configured for {}", hostname);
+                };
+
+                // Get sudo password and user from host config
+                let sudo_password = host_config.sudo_password.clone();
+                let sudo_user = host_config.sudo_user.clone();
+
+                // Create SSH connection
+                let username = crate::config::get_default_username();
+                let host_with_user = format!("{}@{}", username, target_host);
+                SshConnection::new_with_sudo_password(&host_with_user, sudo_password, sudo_user)
+            })
+        }
+    }
+
+    /// Reach a remote host over the transport its config selects
+    ///
+    /// SSH unless the host opts into its agent. `Auto` tries the host's agent
+    /// first and falls back to SSH when it isn't reachable (or this host isn't
+    /// one of its mesh peers), warning once per host. The SSH connection is
+    /// only opened if needed.
+    fn connect_remote(
+        hostname: &str,
+        config: &EnvConfig,
+        transport: HostTransport,
+        ssh: impl FnOnce() -> Result<SshConnection>,
+    ) -> Result<Self> {
+        match (transport, AGENT_CONNECTOR.get()) {
+            (HostTransport::Ssh, _) | (HostTransport::Auto, None) => Ok(Executor::Remote(ssh()?)),
+            (HostTransport::Agent, None) => anyhow::bail!(
+                "HOST_{}_TRANSPORT is 'agent' but agent support is not available in this program",
+                hostname.to_uppercase()
+            ),
+            (HostTransport::Agent, Some(connect)) => connect(hostname, config)
+                .map(Executor::Agent)
+                .with_context(|| {
+                    format!(
+                        "Failed to reach the halvor agent on {} (HOST_{}_TRANSPORT=agent)",
+                        hostname,
+                        hostname.to_uppercase()
+                    )
+                }),
+            (HostTransport::Auto, Some(connect)) => match connect(hostname, config) {
+                Ok(agent) => Ok(Executor::Agent(agent)),
+                Err(e) => {
+                    let mut warned = SSH_FALLBACKS.lock().unwrap_or_else(|e| e.into_inner());
+                    if !warned.iter().any(|host| host == hostname) {
+                        eprintln!(
+                            "⚠️  Halvor agent on {} not available ({}), using SSH",
+                            hostname, e
+                        );
+                        warned.push(hostname.to_string());
+                    }
+                    drop(warned);
+                    Ok(Executor::Remote(ssh()?))
+                }
+            },
         }
     }
 
@@ -582,7 +643,7 @@ impl Executor {
     pub fn target_host(&self, hostname: &str, config: &crate::config::EnvConfig) -> Result<String> {
         match self {
             Executor::Local => Ok(hostname.to_string()),
-            Executor::Remote(_) => {
+            Executor::Remote(_) | Executor::Agent(_) => {
                 let host_config = config
                     .hosts
                     .get(hostname)
@@ -610,6 +671,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => local::execute_shell(command),
             Executor::Remote(exec) => exec.execute_shell(command),
+            Executor::Agent(exec) => exec.execute_shell(command),
         }
     }
 
@@ -628,6 +690,7 @@ impl CommandExecutor for Executor {
                 Ok(())
             }
             Executor::Remote(exec) => exec.execute_interactive(program, args),
+            Executor::Agent(exec) => exec.execute_interactive(program, args),
         }
     }
 
@@ -635,6 +698,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => Ok(local::check_command_exists(command)),
             Executor::Remote(exec) => exec.check_command_exists(command),
+            Executor::Agent(exec) => exec.check_command_exists(command),
         }
     }
 
@@ -642,6 +706,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => Ok(local::is_linux()),
             Executor::Remote(exec) => exec.is_linux(),
+            Executor::Agent(exec) => exec.is_linux(),
         }
     }
 
@@ -649,6 +714,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => local::read_file(path),
             Executor::Remote(exec) => exec.read_file(path),
+            Executor::Agent(exec) => exec.read_file(path),
         }
     }
 
@@ -694,6 +760,7 @@ impl CommandExecutor for Executor {
                 Ok(())
             }
             Executor::Remote(exec) => exec.write_file(path, content),
+            Executor::Agent(exec) => exec.write_file(path, content),
         }
     }
 
@@ -705,6 +772,7 @@ impl CommandExecutor for Executor {
                 Ok(())
             }
             Executor::Remote(exec) => exec.mkdir_p(path),
+            Executor::Agent(exec) => exec.mkdir_p(path),
         }
     }
 
@@ -712,6 +780,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => Ok(local::is_file(path)),
             Executor::Remote(exec) => exec.file_exists(path),
+            Executor::Agent(exec) => exec.file_exists(path),
         }
     }
 
@@ -735,6 +804,7 @@ impl CommandExecutor for Executor {
                 Ok(())
             }
             Executor::Remote(exec) => exec.execute_shell_interactive(command),
+            Executor::Agent(exec) => exec.execute_shell_interactive(command),
         }
     }
 
@@ -742,6 +812,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => Ok(whoami::username()),
             Executor::Remote(exec) => exec.get_username(),
+            Executor::Agent(exec) => exec.get_username(),
         }
     }
 
@@ -749,6 +820,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => local::list_directory(path),
             Executor::Remote(exec) => exec.list_directory(path),
+            Executor::Agent(exec) => exec.list_directory(path),
         }
     }
 
@@ -756,6 +828,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => Ok(local::is_directory(path)),
             Executor::Remote(exec) => exec.is_directory(path),
+            Executor::Agent(exec) => exec.is_directory(path),
         }
     }
 
@@ -764,6 +837,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => local::get_uid(),
             Executor::Remote(exec) => exec.get_uid(),
+            Executor::Agent(exec) => exec.get_uid(),
         }
     }
 
@@ -772,6 +846,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => local::get_gid(),
             Executor::Remote(exec) => exec.get_gid(),
+            Executor::Agent(exec) => exec.get_gid(),
         }
     }
 
@@ -779,6 +854,7 @@ impl CommandExecutor for Executor {
         match self {
             Executor::Local => local::get_home_dir(),
             Executor::Remote(exec) => exec.get_home_dir(),
+            Executor::Agent(exec) => exec.get_home_dir(),
         }
     }
 
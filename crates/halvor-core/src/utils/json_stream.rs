@@ -1,40 +1,426 @@
+//! Length-prefixed JSON framing for agent connections
+//!
+//! Every message on the wire is a frame:
+//!
+//! ```text
+//! +------------+---------+----------------------+-----------------+
+//! | magic "HLV"| version | length (u32, BE)     | payload (JSON)  |
+//! | 3 bytes    | 1 byte  | 4 bytes              | `length` bytes  |
+//! +------------+---------+----------------------+-----------------+
+//! ```
+//!
+//! Releases before framing wrote bare JSON, so a peer whose first byte is `{`
+//! or `"` is reported as [`FrameError::LegacyPeer`] instead of a JSON parse error.
+
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Magic bytes at the start of every frame
+pub const FRAME_MAGIC: &[u8; 3] = b"HLV";
+
+/// Current frame format version
+pub const FRAME_VERSION: u8 = 1;
+
+/// Size of the frame header (magic + version + length)
+pub const FRAME_HEADER_LEN: usize = 8;
+
+/// Default maximum payload size accepted by `read_frame` (16MB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Errors produced while decoding a frame header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// Peer sent unframed JSON (halvor release without framed protocol)
+    LegacyPeer,
+    /// Header did not start with the frame magic
+    BadMagic,
+    /// Peer speaks a different frame version
+    VersionMismatch { expected: u8, found: u8 },
+    /// Payload length exceeds the configured maximum
+    TooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::LegacyPeer => write!(
+                f,
+                "Protocol version mismatch: peer uses the legacy unframed protocol (expected framed protocol v{}). Update halvor on both hosts.",
+                FRAME_VERSION
+            ),
+            FrameError::BadMagic => write!(f, "Invalid frame header (not a halvor agent stream)"),
+            FrameError::VersionMismatch { expected, found } => write!(
+                f,
+                "Protocol version mismatch: expected frame version {}, peer sent {}. Update halvor on both hosts.",
+                expected, found
+            ),
+            FrameError::TooLarge { size, max } => {
+                write!(f, "Frame too large: {} bytes (max {} bytes)", size, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Encode a frame header for a payload of `len` bytes
+pub fn encode_frame_header(len: usize) -> Result<[u8; FRAME_HEADER_LEN]> {
+    let len = u32::try_from(len).context("Frame payload exceeds u32::MAX bytes")?;
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[..3].copy_from_slice(FRAME_MAGIC);
+    header[3] = FRAME_VERSION;
+    header[4..].copy_from_slice(&len.to_be_bytes());
+    Ok(header)
+}
+
+/// Decode a frame header, returning the payload length
+pub fn decode_frame_header(
+    header: &[u8; FRAME_HEADER_LEN],
+    max_frame_size: usize,
+) -> std::result::Result<usize, FrameError> {
+    if is_legacy_json_start(header[0]) {
+        return Err(FrameError::LegacyPeer);
+    }
+    if &header[..3] != FRAME_MAGIC {
+        return Err(FrameError::BadMagic);
+    }
+    if header[3] != FRAME_VERSION {
+        return Err(FrameError::VersionMismatch {
+            expected: FRAME_VERSION,
+            found: header[3],
+        });
+    }
+    let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if size > max_frame_size {
+        return Err(FrameError::TooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+    Ok(size)
+}
+
+/// Payload buffer allocated up front; larger frames grow it as they arrive
+const INITIAL_PAYLOAD_CAPACITY: usize = 64 * 1024;
+
+/// Payload bytes quoted in JSON parse errors
+const ERROR_PREVIEW_LEN: usize = 200;
+
+const HEADER_CLOSED: &str = "Connection closed before a frame header was received (peer may be running an older halvor without framed protocol)";
+
+fn check_complete(payload: &[u8], size: usize) -> Result<()> {
+    if payload.len() < size {
+        anyhow::bail!(
+            "Connection closed mid-frame (expected {} bytes, got {})",
+            size,
+            payload.len()
+        );
+    }
+    Ok(())
+}
+
+/// The start of a payload, for error messages
+fn preview(payload: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&payload[..payload.len().min(ERROR_PREVIEW_LEN)]);
+    if payload.len() > ERROR_PREVIEW_LEN {
+        format!("{}… ({} bytes)", text, payload.len())
+    } else {
+        text.into_owned()
+    }
+}
+
+/// Bare JSON messages from legacy peers start with an object or a unit-variant string
+fn is_legacy_json_start(byte: u8) -> bool {
+    byte == b'{' || byte == b'"'
+}
+
+/// Read a single frame payload from a stream
+pub fn read_frame<S: Read>(stream: &mut S, max_frame_size: usize) -> Result<Vec<u8>> {
+    // Check the first byte on its own: a legacy request such as `"Ping"` is
+    // shorter than a frame header and the peer is waiting for a reply.
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    stream.read_exact(&mut header[..1]).context(HEADER_CLOSED)?;
+    if is_legacy_json_start(header[0]) {
+        return Err(FrameError::LegacyPeer.into());
+    }
+    stream.read_exact(&mut header[1..]).context(HEADER_CLOSED)?;
+    let size = decode_frame_header(&header, max_frame_size)?;
+
+    // The buffer grows as bytes arrive, so a header alone can't make us
+    // allocate the maximum frame size
+    let mut payload = Vec::with_capacity(size.min(INITIAL_PAYLOAD_CAPACITY));
+    stream.by_ref().take(size as u64).read_to_end(&mut payload)?;
+    check_complete(&payload, size)?;
+    Ok(payload)
+}
 
-/// Read a JSON-serialized value from a TcpStream
-pub fn read_json<T>(stream: &mut TcpStream, buffer_size: usize) -> Result<T>
+/// Write a single frame to a stream
+pub fn write_frame<S: Write>(stream: &mut S, payload: &[u8]) -> Result<()> {
+    let header = encode_frame_header(payload.len())?;
+    stream.write_all(&header)?;
+    stream.write_all(payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read a framed JSON value from a stream
+pub fn read_json<S, T>(stream: &mut S, max_frame_size: usize) -> Result<T>
 where
+    S: Read,
     T: for<'de> Deserialize<'de>,
 {
-    let mut buffer = vec![0u8; buffer_size];
-    let size = stream.read(&mut buffer)?;
-    let json_str = String::from_utf8_lossy(&buffer[..size]);
-    serde_json::from_str(&json_str).with_context(|| format!("Failed to parse JSON: {}", json_str))
+    let payload = read_frame(stream, max_frame_size)?;
+    serde_json::from_slice(&payload).with_context(|| format!("Failed to parse JSON: {}", preview(&payload)))
 }
 
-/// Write a JSON-serialized value to a TcpStream
-pub fn write_json<T>(stream: &mut TcpStream, value: &T) -> Result<()>
+/// Write a JSON value as a single frame
+pub fn write_json<S, T>(stream: &mut S, value: &T) -> Result<()>
 where
+    S: Write,
     T: Serialize,
 {
-    let json_str = serde_json::to_string(value).context("Failed to serialize value to JSON")?;
-    stream.write_all(json_str.as_bytes())?;
+    let payload = serde_json::to_vec(value).context("Failed to serialize value to JSON")?;
+    write_frame(stream, &payload)
+}
+
+/// Write a bare (unframed) JSON value
+///
+/// Only used to answer peers detected as [`FrameError::LegacyPeer`], which
+/// cannot decode frames.
+pub fn write_legacy_json<S, T>(stream: &mut S, value: &T) -> Result<()>
+where
+    S: Write,
+    T: Serialize,
+{
+    let json = serde_json::to_vec(value).context("Failed to serialize value to JSON")?;
+    stream.write_all(&json)?;
     stream.flush()?;
     Ok(())
 }
 
-/// Send a JSON request and read a JSON response over a TcpStream
-pub fn send_json_request<Req, Resp>(
-    stream: &mut TcpStream,
+/// Send a framed JSON request and read a framed JSON response
+pub fn send_json_request<S, Req, Resp>(
+    stream: &mut S,
     request: &Req,
-    buffer_size: usize,
+    max_frame_size: usize,
 ) -> Result<Resp>
 where
+    S: Read + Write,
     Req: Serialize,
     Resp: for<'de> Deserialize<'de>,
 {
     write_json(stream, request)?;
-    read_json(stream, buffer_size)
+    read_json(stream, max_frame_size)
+}
+
+/// Collects bytes read from a stream and splits off complete frame payloads
+///
+/// For readers that can't block in [`read_frame`], such as a socket polled
+/// with a read timeout so the same thread can also write: a timeout part way
+/// through a frame would lose the bytes `read_exact` already consumed.
+#[derive(Debug)]
+pub struct FrameBuffer {
+    buf: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl FrameBuffer {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Append bytes read from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Take the next complete frame payload, if one has arrived
+    pub fn next_frame(&mut self) -> std::result::Result<Option<Vec<u8>>, FrameError> {
+        if self.buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let header: [u8; FRAME_HEADER_LEN] = self.buf[..FRAME_HEADER_LEN]
+            .try_into()
+            .expect("slice has header length");
+        let size = decode_frame_header(&header, self.max_frame_size)?;
+        if self.buf.len() < FRAME_HEADER_LEN + size {
+            return Ok(None);
+        }
+        let payload = self.buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + size].to_vec();
+        self.buf.drain(..FRAME_HEADER_LEN + size);
+        Ok(Some(payload))
+    }
+
+    /// Whether part of a frame is still waiting for the rest of its bytes
+    pub fn is_partial(&self) -> bool {
+        !self.buf.is_empty()
+    }
+}
+
+/// Read a single frame payload from an async stream
+pub async fn read_frame_async<S>(stream: &mut S, max_frame_size: usize) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    stream.read_exact(&mut header[..1]).await.context(HEADER_CLOSED)?;
+    if is_legacy_json_start(header[0]) {
+        return Err(FrameError::LegacyPeer.into());
+    }
+    stream.read_exact(&mut header[1..]).await.context(HEADER_CLOSED)?;
+    let size = decode_frame_header(&header, max_frame_size)?;
+
+    let mut payload = Vec::with_capacity(size.min(INITIAL_PAYLOAD_CAPACITY));
+    (&mut *stream).take(size as u64).read_to_end(&mut payload).await?;
+    check_complete(&payload, size)?;
+    Ok(payload)
+}
+
+/// Write a single frame to an async stream
+pub async fn write_frame_async<S>(stream: &mut S, payload: &[u8]) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let header = encode_frame_header(payload.len())?;
+    stream.write_all(&header).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read a framed JSON value from an async stream
+pub async fn read_json_async<S, T>(stream: &mut S, max_frame_size: usize) -> Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    let payload = read_frame_async(stream, max_frame_size).await?;
+    serde_json::from_slice(&payload).with_context(|| format!("Failed to parse JSON: {}", preview(&payload)))
+}
+
+/// Write a JSON value as a single frame to an async stream
+pub async fn write_json_async<S, T>(stream: &mut S, value: &T) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = serde_json::to_vec(value).context("Failed to serialize value to JSON")?;
+    write_frame_async(stream, &payload).await
+}
+
+/// Write a bare (unframed) JSON value to an async stream (legacy peers only)
+pub async fn write_legacy_json_async<S, T>(stream: &mut S, value: &T) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let json = serde_json::to_vec(value).context("Failed to serialize value to JSON")?;
+    stream.write_all(&json).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_roundtrip_large_payload() {
+        let value = serde_json::json!({ "token": "x".repeat(100_000) });
+        let mut buf = Vec::new();
+        write_json(&mut buf, &value).unwrap();
+
+        let decoded: serde_json::Value =
+            read_json(&mut Cursor::new(buf), DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &[b'0'; 64]).unwrap();
+
+        let err = read_frame(&mut Cursor::new(buf), 32).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FrameError>(),
+            Some(&FrameError::TooLarge { size: 64, max: 32 })
+        );
+    }
+
+    #[test]
+    fn test_truncated_frame_and_long_parse_errors() {
+        // A header claiming the maximum size, with the connection closing after it
+        let header = encode_frame_header(DEFAULT_MAX_FRAME_SIZE).unwrap();
+        let mut truncated = header.to_vec();
+        truncated.extend_from_slice(b"{}");
+        let err = read_frame(&mut Cursor::new(truncated), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert!(err.to_string().contains("got 2"), "{}", err);
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, "x".repeat(10_000).as_bytes()).unwrap();
+        let err = read_json::<_, String>(&mut Cursor::new(buf), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert!(err.to_string().len() < 300, "{}", err);
+        assert!(err.to_string().ends_with("(10000 bytes)"), "{}", err);
+    }
+
+    #[test]
+    fn test_legacy_peer_detected() {
+        for legacy in [br#"{"GetHostInfo":null}"#.to_vec(), br#""Ping""#.to_vec()] {
+            let err = read_frame(&mut Cursor::new(legacy), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+            assert_eq!(err.downcast_ref::<FrameError>(), Some(&FrameError::LegacyPeer));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_frame_matches_sync() {
+        let mut buf = Vec::new();
+        write_json_async(&mut buf, &"hello").await.unwrap();
+
+        let decoded: String = read_json(&mut Cursor::new(&buf), DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(decoded, "hello");
+
+        let decoded: String = read_json_async(&mut buf.as_slice(), DEFAULT_MAX_FRAME_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(decoded, "hello");
+    }
+
+    #[test]
+    fn test_frame_buffer_splits_partial_reads() {
+        let mut wire = Vec::new();
+        write_json(&mut wire, &"first").unwrap();
+        write_json(&mut wire, &"second").unwrap();
+
+        let mut frames = FrameBuffer::new(DEFAULT_MAX_FRAME_SIZE);
+        let mut decoded = Vec::new();
+        // Arrives three bytes at a time, splitting headers and payloads
+        for chunk in wire.chunks(3) {
+            frames.extend(chunk);
+            while let Some(payload) = frames.next_frame().unwrap() {
+                decoded.push(serde_json::from_slice::<String>(&payload).unwrap());
+            }
+        }
+        assert_eq!(decoded, vec!["first", "second"]);
+        assert!(!frames.is_partial());
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut header = encode_frame_header(0).unwrap();
+        header[3] = FRAME_VERSION + 1;
+        assert_eq!(
+            decode_frame_header(&header, DEFAULT_MAX_FRAME_SIZE),
+            Err(FrameError::VersionMismatch {
+                expected: FRAME_VERSION,
+                found: FRAME_VERSION + 1
+            })
+        );
+    }
 }
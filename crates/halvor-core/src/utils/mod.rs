@@ -12,5 +12,8 @@ pub mod string;
 pub mod update;
 
 // Re-export commonly used utilities
-pub use json_stream::{read_json, send_json_request, write_json};
+pub use json_stream::{
+    DEFAULT_MAX_FRAME_SIZE, FrameBuffer, FrameError, read_frame, read_json, read_json_async, send_json_request,
+    write_frame, write_json, write_json_async, write_legacy_json, write_legacy_json_async,
+};
 pub use string::{bytes_to_string, bytes_to_string_strict, format_address, format_bind_address};
@@ -0,0 +1,522 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::ext::IdentExt;
+use syn::{Data, DeriveInput, Fields, GenericArgument, Ident, LitStr, PathArguments, Type, parse_macro_input};
+
+/// Derive the `Table` trait for a halvor-db row struct
+///
+/// Columns are read by name, so fields can be declared in any order. The
+/// struct needs a primary key (the `id` field unless another is marked) and
+/// `created_at`/`updated_at` timestamps; every other field is a data column.
+///
+/// Example:
+/// ```rust,ignore
+/// #[derive(Debug, Clone, Table)]
+/// #[table(name = "agent_exec_policies", unique(peer_hostname, command))]
+/// pub struct AgentExecPoliciesRow {
+///     pub id: String,
+///     pub peer_hostname: String,
+///     pub command: String,
+///     #[table(default = "0")]
+///     pub allow_sudo: i64,
+///     pub created_at: i64,
+///     pub updated_at: i64,
+/// }
+/// ```
+///
+/// Struct attributes:
+/// - `#[table(name = "...")]` - Table name (required)
+/// - `#[table(unique(a, b))]` - Unique key over several columns
+///
+/// Field attributes:
+/// - `#[table(primary_key)]` - Primary key, instead of `id`
+/// - `#[table(unique)]` - Unique column; also generates `delete_by_<field>`
+/// - `#[table(skip)]` - Not a column; set to `Default::default()` when read
+/// - `#[table(sql_type = "...")]` - Column type, when it can't be inferred from the field type
+/// - `#[table(default = "...")]` - Column default, as SQL
+///
+/// Besides the trait, this generates a `<Name>Data` struct with the data
+/// columns, `from_data`, `insert_one`, `insert_many` and `delete_by_*`
+/// associated functions, and a `query()` returning a `<Name>Query`. The query
+/// has a method per column for conditions (`.status().eq("active")`) and
+/// `order_by_<column>_asc`/`_desc`, plus `limit`, `offset`, `page`, `all`,
+/// `first`, `count`, `exists`, `delete` and `upsert`.
+#[proc_macro_derive(Table, attributes(table))]
+pub fn derive_table(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// A struct field and its `#[table(...)]` attributes
+struct Column {
+    ident: Ident,
+    name: String,
+    ty: Type,
+    primary_key: bool,
+    unique: bool,
+    skip: bool,
+    sql_type: Option<String>,
+    default: Option<String>,
+}
+
+impl Column {
+    fn is_timestamp(&self) -> bool {
+        self.name == "created_at" || self.name == "updated_at"
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let vis = &input.vis;
+    let data_ident = format_ident!("{}Data", ident);
+
+    let mut table_name = None;
+    let mut unique_keys: Vec<Vec<String>> = Vec::new();
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("table")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                table_name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("unique") {
+                let mut key = Vec::new();
+                meta.parse_nested_meta(|column| {
+                    key.push(column.path.require_ident()?.unraw().to_string());
+                    Ok(())
+                })?;
+                unique_keys.push(key);
+            } else {
+                return Err(meta.error("unsupported table attribute; expected `name` or `unique(...)`"));
+            }
+            Ok(())
+        })?;
+    }
+    let table_name = table_name
+        .ok_or_else(|| syn::Error::new_spanned(ident, "missing #[table(name = \"...\")] attribute"))?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(ident, "Table can only be derived for structs with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(ident, "Table can only be derived for structs")),
+    };
+
+    let mut columns = Vec::new();
+    for field in fields {
+        let field_ident = field.ident.clone().expect("named field");
+        let mut column = Column {
+            name: field_ident.unraw().to_string(),
+            ident: field_ident,
+            ty: field.ty.clone(),
+            primary_key: false,
+            unique: false,
+            skip: false,
+            sql_type: None,
+            default: None,
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("table")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("primary_key") {
+                    column.primary_key = true;
+                } else if meta.path.is_ident("unique") {
+                    column.unique = true;
+                } else if meta.path.is_ident("skip") {
+                    column.skip = true;
+                } else if meta.path.is_ident("sql_type") {
+                    column.sql_type = Some(meta.value()?.parse::<LitStr>()?.value().to_uppercase());
+                } else if meta.path.is_ident("default") {
+                    column.default = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else {
+                    return Err(meta.error(
+                        "unsupported table attribute; expected `primary_key`, `unique`, `skip`, `sql_type` or `default`",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+        if column.skip && (column.primary_key || column.unique) {
+            return Err(syn::Error::new_spanned(&column.ident, "a skipped field can't be a key"));
+        }
+        columns.push(column);
+    }
+
+    let stored: Vec<&Column> = columns.iter().filter(|c| !c.skip).collect();
+    let marked: Vec<&Column> = stored.iter().copied().filter(|c| c.primary_key).collect();
+    let primary_key = match marked.as_slice() {
+        [] => stored
+            .iter()
+            .copied()
+            .find(|c| c.name == "id")
+            .ok_or_else(|| syn::Error::new_spanned(ident, "no `id` field; mark the primary key with #[table(primary_key)]"))?,
+        [column] => *column,
+        [_, second, ..] => {
+            return Err(syn::Error::new_spanned(&second.ident, "only one field can be the primary key"));
+        }
+    };
+    for timestamp in ["created_at", "updated_at"] {
+        if !stored.iter().any(|c| c.name == timestamp) {
+            return Err(syn::Error::new_spanned(
+                ident,
+                format!("missing `{}: i64` field; timestamps are set on insert and update", timestamp),
+            ));
+        }
+    }
+    for key in &unique_keys {
+        for name in key {
+            if !stored.iter().any(|c| &c.name == name) {
+                return Err(syn::Error::new_spanned(ident, format!("unique key names unknown column `{}`", name)));
+            }
+        }
+    }
+
+    // Data columns: everything but the primary key and timestamps
+    let data: Vec<&Column> = stored
+        .iter()
+        .copied()
+        .filter(|c| c.name != primary_key.name && !c.is_timestamp())
+        .collect();
+
+    let pk_ident = &primary_key.ident;
+    let pk_name = &primary_key.name;
+    let data_idents: Vec<&Ident> = data.iter().map(|c| &c.ident).collect();
+    let data_names: Vec<&String> = data.iter().map(|c| &c.name).collect();
+    let data_types: Vec<&Type> = data.iter().map(|c| &c.ty).collect();
+
+    // Skipped fields, and data fields on new rows, start from their defaults
+    let read_fields = columns.iter().map(|c| {
+        let field = &c.ident;
+        let name = &c.name;
+        if c.skip {
+            quote! { #field: ::std::default::Default::default() }
+        } else {
+            quote! { #field: row.get(#name)? }
+        }
+    });
+    let new_fields = columns.iter().map(|c| {
+        let field = &c.ident;
+        if c.skip || c.name == *pk_name || c.is_timestamp() {
+            quote! { #field: ::std::default::Default::default() }
+        } else {
+            quote! { #field: data.#field }
+        }
+    });
+
+    let schema_columns = stored
+        .iter()
+        .map(|c| {
+            let name = &c.name;
+            let (sql_type, nullable) = sql_type(c)?;
+            let not_null = !nullable;
+            let is_primary_key = c.name == *pk_name;
+            let default = match &c.default {
+                Some(default) => quote! { ::std::option::Option::Some(#default.to_string()) },
+                None => quote! { ::std::option::Option::None },
+            };
+            Ok(quote! {
+                ::halvor_db::core::schema::ColumnDef {
+                    name: #name.to_string(),
+                    sql_type: #sql_type.to_string(),
+                    not_null: #not_null,
+                    default_value: #default,
+                    primary_key: #is_primary_key,
+                }
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    let schema_unique_keys = stored
+        .iter()
+        .filter(|c| c.unique)
+        .map(|c| vec![c.name.clone()])
+        .chain(unique_keys.iter().cloned())
+        .map(|key| quote! { vec![#(#key.to_string()),*] });
+
+    let delete_by = std::iter::once(primary_key)
+        .chain(stored.iter().copied().filter(|c| c.unique && c.name != *pk_name))
+        .map(|c| {
+            let fn_name = format_ident!("delete_by_{}", c.ident.unraw());
+            let name = &c.name;
+            let value_ty = key_param_type(&c.ty);
+            let doc = format!("Delete the {} record with this `{}`", ident, name);
+            quote! {
+                #[doc = #doc]
+                pub fn #fn_name(value: #value_ty) -> ::anyhow::Result<usize> {
+                    let conn = ::halvor_db::get_connection()?;
+                    ::halvor_db::core::table::DbTable::<Self>::delete_many(
+                        &conn,
+                        concat!(#name, " = ?1"),
+                        &[&value as &dyn ::rusqlite::types::ToSql],
+                    )
+                }
+            }
+        });
+
+    // A condition method per column, plus ascending and descending orderings
+    let column_fns = stored
+        .iter()
+        .map(|c| {
+            let field = &c.ident;
+            let name = &c.name;
+            if RESERVED_QUERY_METHODS.contains(&name.as_str()) {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    format!("`{}` clashes with a query method; rename the field", name),
+                ));
+            }
+            let value_ty = option_inner(&c.ty).unwrap_or(&c.ty);
+            let asc = format_ident!("order_by_{}_asc", field.unraw());
+            let desc = format_ident!("order_by_{}_desc", field.unraw());
+            let column_doc = format!("Add a condition on `{}`", name);
+            Ok(quote! {
+                #[doc = #column_doc]
+                pub fn #field(self) -> ::halvor_db::core::query::Column<Self, #value_ty> {
+                    ::halvor_db::core::query::Column::new(self, #name)
+                }
+
+                pub fn #asc(self) -> Self {
+                    Self(self.0.order_by(#name, ::halvor_db::core::query::Order::Asc))
+                }
+
+                pub fn #desc(self) -> Self {
+                    Self(self.0.order_by(#name, ::halvor_db::core::query::Order::Desc))
+                }
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let query_ident = format_ident!("{}Query", ident);
+    let query_doc = format!("Typed query over [`{}`] records; see `{}::query`", ident, ident);
+
+    let data_doc = format!(
+        "Data columns of [`{}`], without the primary key and timestamps",
+        ident
+    );
+
+    Ok(quote! {
+        impl ::halvor_db::core::table::Table for #ident {
+            fn table_name() -> &'static str {
+                #table_name
+            }
+
+            fn primary_key() -> &'static str {
+                #pk_name
+            }
+
+            fn primary_key_value(&self) -> String {
+                ::std::string::ToString::to_string(&self.#pk_ident)
+            }
+
+            fn from_row(row: &::rusqlite::Row) -> ::rusqlite::Result<Self> {
+                Ok(Self {
+                    #(#read_fields,)*
+                })
+            }
+
+            fn to_insert_params(&self) -> Vec<Box<dyn ::rusqlite::types::ToSql + Send + Sync>> {
+                vec![#(Box::new(::std::clone::Clone::clone(&self.#data_idents))),*]
+            }
+
+            fn to_update_params(&self) -> Vec<Box<dyn ::rusqlite::types::ToSql + Send + Sync>> {
+                vec![#(Box::new(::std::clone::Clone::clone(&self.#data_idents))),*]
+            }
+
+            fn insert_columns() -> &'static [&'static str] {
+                &[#(#data_names),*]
+            }
+
+            fn update_columns() -> &'static [&'static str] {
+                &[#(#data_names),*]
+            }
+
+            fn all_columns() -> &'static [&'static str] {
+                &[#pk_name, #(#data_names,)* "created_at", "updated_at"]
+            }
+
+            fn schema() -> ::halvor_db::core::schema::TableSchema {
+                ::halvor_db::core::schema::TableSchema {
+                    name: #table_name.to_string(),
+                    columns: vec![#(#schema_columns),*],
+                    unique_keys: vec![#(#schema_unique_keys),*],
+                    dependents: ::std::vec::Vec::new(),
+                }
+            }
+        }
+
+        #[doc = #data_doc]
+        #[derive(Debug, Clone)]
+        #vis struct #data_ident {
+            #(pub #data_idents: #data_types,)*
+        }
+
+        impl #ident {
+            /// Build a new row from its data columns
+            ///
+            /// The primary key and timestamps are set when the row is inserted.
+            pub fn from_data(data: #data_ident) -> Self {
+                Self {
+                    #(#new_fields,)*
+                }
+            }
+
+            /// Insert a new record
+            pub fn insert_one(data: #data_ident) -> ::anyhow::Result<String> {
+                let conn = ::halvor_db::get_connection()?;
+                ::halvor_db::core::table::DbTable::<Self>::insert(&conn, &Self::from_data(data))
+            }
+
+            /// Insert several records
+            pub fn insert_many(data: Vec<#data_ident>) -> ::anyhow::Result<Vec<String>> {
+                let conn = ::halvor_db::get_connection()?;
+                data.into_iter()
+                    .map(|data| ::halvor_db::core::table::DbTable::<Self>::insert(&conn, &Self::from_data(data)))
+                    .collect()
+            }
+
+            #(#delete_by)*
+
+            /// Start a typed query over this table
+            pub fn query() -> #query_ident {
+                #query_ident(::halvor_db::core::query::Query::new())
+            }
+        }
+
+        #[doc = #query_doc]
+        #vis struct #query_ident(::halvor_db::core::query::Query<#ident>);
+
+        impl ::halvor_db::core::query::Filter for #query_ident {
+            fn filter(
+                self,
+                condition: String,
+                params: Vec<Box<dyn ::rusqlite::types::ToSql>>,
+            ) -> Self {
+                Self(::halvor_db::core::query::Filter::filter(self.0, condition, params))
+            }
+        }
+
+        impl ::std::ops::Deref for #query_ident {
+            type Target = ::halvor_db::core::query::Query<#ident>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl #query_ident {
+            #(#column_fns)*
+
+            /// Return at most `limit` rows
+            pub fn limit(self, limit: usize) -> Self {
+                Self(self.0.limit(limit))
+            }
+
+            /// Skip the first `offset` rows
+            pub fn offset(self, offset: usize) -> Self {
+                Self(self.0.offset(offset))
+            }
+
+            /// Return page `page` (counting from 0) of `per_page` rows
+            pub fn page(self, page: usize, per_page: usize) -> Self {
+                Self(self.0.page(page, per_page))
+            }
+
+            /// Every matching record
+            pub fn all(&self) -> ::anyhow::Result<Vec<#ident>> {
+                let conn = ::halvor_db::get_connection()?;
+                self.0.all_in(&conn)
+            }
+
+            /// The first matching record
+            pub fn first(&self) -> ::anyhow::Result<Option<#ident>> {
+                let conn = ::halvor_db::get_connection()?;
+                self.0.first_in(&conn)
+            }
+
+            /// Number of matching records, ignoring ordering and paging
+            pub fn count(&self) -> ::anyhow::Result<usize> {
+                let conn = ::halvor_db::get_connection()?;
+                self.0.count_in(&conn)
+            }
+
+            /// Whether any record matches
+            pub fn exists(&self) -> ::anyhow::Result<bool> {
+                let conn = ::halvor_db::get_connection()?;
+                self.0.exists_in(&conn)
+            }
+
+            /// Delete every matching record
+            pub fn delete(&self) -> ::anyhow::Result<usize> {
+                let conn = ::halvor_db::get_connection()?;
+                self.0.delete_in(&conn)
+            }
+
+            /// Update the data columns of the first matching record, or insert it
+            pub fn upsert(&self, data: #data_ident) -> ::anyhow::Result<String> {
+                let conn = ::halvor_db::get_connection()?;
+                self.0.upsert_in(&conn, |existing| match existing {
+                    Some(existing) => #ident {
+                        #(#data_idents: data.#data_idents,)*
+                        ..::std::clone::Clone::clone(existing)
+                    },
+                    None => #ident::from_data(data),
+                })
+            }
+        }
+    })
+}
+
+/// Methods of the generated query type that a column method would shadow
+const RESERVED_QUERY_METHODS: &[&str] = &[
+    "limit", "offset", "page", "all", "first", "count", "exists", "delete", "upsert", "filter",
+];
+
+/// The column type for a field, and whether it's nullable (an `Option`)
+fn sql_type(column: &Column) -> syn::Result<(String, bool)> {
+    let (inner, nullable) = match option_inner(&column.ty) {
+        Some(inner) => (inner, true),
+        None => (&column.ty, false),
+    };
+    if let Some(sql_type) = &column.sql_type {
+        return Ok((sql_type.clone(), nullable));
+    }
+    let name = match inner {
+        Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    };
+    let sql_type = match name.as_deref() {
+        Some("String") => "TEXT",
+        Some("i64" | "i32" | "i16" | "i8" | "u32" | "u16" | "u8" | "bool") => "INTEGER",
+        Some("f64" | "f32") => "REAL",
+        Some("Vec") => "BLOB",
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &column.ty,
+                "can't infer the column type; add #[table(sql_type = \"...\")]",
+            ));
+        }
+    };
+    Ok((sql_type.to_string(), nullable))
+}
+
+/// The parameter type for looking a record up by a key column: `&str` for text
+fn key_param_type(ty: &Type) -> TokenStream2 {
+    let inner = option_inner(ty).unwrap_or(ty);
+    match inner {
+        Type::Path(path) if path.path.is_ident("String") => quote! { &str },
+        _ => quote! { &#inner },
+    }
+}
+
+/// `T` for an `Option<T>` field
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
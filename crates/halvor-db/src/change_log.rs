@@ -0,0 +1,286 @@
+//! Change tracking for incremental sync
+//!
+//! Triggers on each tracked table append a row to `change_log` for every
+//! insert, update and delete, recording the table, the row's natural key (so
+//! other nodes can find their copy of it) and the operation. Deletes stay in
+//! the log as tombstones. A row whose key changes is logged as a delete of the
+//! old key plus an update of the new one.
+//!
+//! `seq` only ever increases, so a peer that remembers the last `seq` it saw
+//! can ask for just the rows changed since. Migrations that recreate a tracked
+//! table must call [`track_table`] for it again, since dropping a table drops
+//! its triggers.
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// What happened to a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl ChangeOp {
+    fn parse(operation: &str) -> Result<Self> {
+        match operation {
+            "insert" => Ok(ChangeOp::Insert),
+            "update" => Ok(ChangeOp::Update),
+            "delete" => Ok(ChangeOp::Delete),
+            other => anyhow::bail!("Unknown change log operation '{}'", other),
+        }
+    }
+}
+
+/// The latest change to one row
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: i64,
+    pub table_name: String,
+    pub row_key: String,
+    pub operation: ChangeOp,
+}
+
+/// Create the `change_log` table
+pub fn create_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS change_log (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            table_name TEXT NOT NULL,
+            row_key TEXT NOT NULL,
+            operation TEXT NOT NULL,
+            changed_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create change_log table")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_change_log_table_seq ON change_log(table_name, seq)",
+        [],
+    )
+    .context("Failed to create change_log table index")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_change_log_changed_at ON change_log(changed_at)",
+        [],
+    )
+    .context("Failed to create change_log time index")?;
+
+    Ok(())
+}
+
+/// Log changes to `table`, identifying rows by `key_columns`
+///
+/// Updates are only logged when one of `watched_columns` changes (any column
+/// if `None`), so frequently touched bookkeeping columns don't flood the log.
+pub fn track_table(
+    conn: &Connection,
+    table: &str,
+    key_columns: &[&str],
+    watched_columns: Option<&[&str]>,
+) -> Result<()> {
+    let new_key = key_expression("NEW", key_columns);
+    let old_key = key_expression("OLD", key_columns);
+    let update_of = watched_columns
+        .map(|columns| format!(" OF {}", columns.join(", ")))
+        .unwrap_or_default();
+    let now = "CAST(strftime('%s', 'now') AS INTEGER)";
+
+    let triggers = [
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS {table}_change_log_insert AFTER INSERT ON {table}
+             BEGIN
+                INSERT INTO change_log (table_name, row_key, operation, changed_at)
+                VALUES ('{table}', {new_key}, 'insert', {now});
+             END"
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS {table}_change_log_update AFTER UPDATE{update_of} ON {table}
+             BEGIN
+                INSERT INTO change_log (table_name, row_key, operation, changed_at)
+                SELECT '{table}', {old_key}, 'delete', {now} WHERE {old_key} IS NOT {new_key};
+                INSERT INTO change_log (table_name, row_key, operation, changed_at)
+                VALUES ('{table}', {new_key}, 'update', {now});
+             END"
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS {table}_change_log_delete AFTER DELETE ON {table}
+             BEGIN
+                INSERT INTO change_log (table_name, row_key, operation, changed_at)
+                VALUES ('{table}', {old_key}, 'delete', {now});
+             END"
+        ),
+    ];
+    for trigger in &triggers {
+        conn.execute(trigger, [])
+            .with_context(|| format!("Failed to create change log trigger on {}", table))?;
+    }
+
+    Ok(())
+}
+
+/// Stop logging changes to `table`
+pub fn untrack_table(conn: &Connection, table: &str) -> Result<()> {
+    for operation in ["insert", "update", "delete"] {
+        conn.execute(
+            &format!("DROP TRIGGER IF EXISTS {}_change_log_{}", table, operation),
+            [],
+        )
+        .with_context(|| format!("Failed to drop change log trigger on {}", table))?;
+    }
+    Ok(())
+}
+
+/// The latest change to each row of `table` logged after `since`, oldest first
+pub fn changes_since(conn: &Connection, table: &str, since: i64) -> Result<Vec<Change>> {
+    // SQLite takes the bare columns from the row holding MAX(seq)
+    let mut stmt = conn.prepare(
+        "SELECT MAX(seq), table_name, row_key, operation FROM change_log
+         WHERE table_name = ?1 AND seq > ?2
+         GROUP BY row_key
+         ORDER BY MAX(seq)",
+    )?;
+    let rows = stmt.query_map(rusqlite::params![table, since], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+
+    let mut changes = Vec::new();
+    for row in rows {
+        let (seq, table_name, row_key, operation) = row?;
+        changes.push(Change {
+            seq,
+            table_name,
+            row_key,
+            operation: ChangeOp::parse(&operation)?,
+        });
+    }
+    Ok(changes)
+}
+
+/// Sequence number of the newest change (0 if nothing was ever logged)
+pub fn latest_seq(conn: &Connection) -> Result<i64> {
+    // AUTOINCREMENT keeps counting in sqlite_sequence even once the log is pruned
+    let latest: Option<i64> = conn
+        .query_row(
+            "SELECT seq FROM sqlite_sequence WHERE name = 'change_log'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(latest.unwrap_or(0))
+}
+
+/// Whether everything after `since` is still in the log
+///
+/// False once [`prune`] has removed changes a peer at `since` hasn't seen;
+/// that peer needs a full sync.
+pub fn covers(conn: &Connection, since: i64) -> Result<bool> {
+    let latest = latest_seq(conn)?;
+    // A position past the end comes from before this database was recreated
+    if since > latest {
+        return Ok(false);
+    }
+    let oldest: Option<i64> = conn.query_row("SELECT MIN(seq) FROM change_log", [], |row| row.get(0))?;
+    Ok(match oldest {
+        Some(oldest) => since >= oldest - 1,
+        // Nothing logged (or everything pruned): only a peer that's caught up is covered
+        None => since == latest,
+    })
+}
+
+/// Drop changes logged before `before` (a Unix timestamp), returning how many
+pub fn prune(conn: &Connection, before: i64) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM change_log WHERE changed_at < ?1",
+        rusqlite::params![before],
+    )
+    .context("Failed to prune change log")
+}
+
+fn key_expression(row: &str, key_columns: &[&str]) -> String {
+    key_columns
+        .iter()
+        .map(|column| format!("{}.{}", row, column))
+        .collect::<Vec<_>>()
+        .join(" || '/' || ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        conn.execute(
+            "CREATE TABLE peers (hostname TEXT NOT NULL UNIQUE, ip TEXT, last_seen INTEGER)",
+            [],
+        )
+        .unwrap();
+        track_table(&conn, "peers", &["hostname"], Some(&["hostname", "ip"])).unwrap();
+        conn
+    }
+
+    fn ops(changes: &[Change]) -> Vec<(&str, ChangeOp)> {
+        changes.iter().map(|c| (c.row_key.as_str(), c.operation)).collect()
+    }
+
+    #[test]
+    fn test_logs_inserts_updates_and_tombstones() {
+        let conn = setup();
+        conn.execute("INSERT INTO peers (hostname, ip) VALUES ('frigg', '10.0.0.1')", []).unwrap();
+        conn.execute("INSERT INTO peers (hostname, ip) VALUES ('baulder', '10.0.0.2')", []).unwrap();
+        let after_inserts = latest_seq(&conn).unwrap();
+
+        conn.execute("UPDATE peers SET ip = '10.0.0.9' WHERE hostname = 'frigg'", []).unwrap();
+        conn.execute("DELETE FROM peers WHERE hostname = 'baulder'", []).unwrap();
+
+        let all = changes_since(&conn, "peers", 0).unwrap();
+        assert_eq!(ops(&all), vec![("frigg", ChangeOp::Update), ("baulder", ChangeOp::Delete)]);
+
+        let recent = changes_since(&conn, "peers", after_inserts).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert!(changes_since(&conn, "peers", latest_seq(&conn).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unwatched_columns_are_not_logged() {
+        let conn = setup();
+        conn.execute("INSERT INTO peers (hostname, ip) VALUES ('frigg', '10.0.0.1')", []).unwrap();
+        let seq = latest_seq(&conn).unwrap();
+        conn.execute("UPDATE peers SET last_seen = 42 WHERE hostname = 'frigg'", []).unwrap();
+        assert_eq!(latest_seq(&conn).unwrap(), seq);
+    }
+
+    #[test]
+    fn test_key_change_leaves_tombstone() {
+        let conn = setup();
+        conn.execute("INSERT INTO peers (hostname) VALUES ('frigg')", []).unwrap();
+        conn.execute("UPDATE peers SET hostname = 'odin' WHERE hostname = 'frigg'", []).unwrap();
+
+        let changes = changes_since(&conn, "peers", 0).unwrap();
+        assert_eq!(ops(&changes), vec![("frigg", ChangeOp::Delete), ("odin", ChangeOp::Update)]);
+    }
+
+    #[test]
+    fn test_pruned_log_requires_full_sync() {
+        let conn = setup();
+        conn.execute("INSERT INTO peers (hostname) VALUES ('frigg')", []).unwrap();
+        conn.execute("INSERT INTO peers (hostname) VALUES ('baulder')", []).unwrap();
+        assert!(covers(&conn, 0).unwrap());
+
+        assert_eq!(prune(&conn, i64::MAX).unwrap(), 2);
+        assert!(!covers(&conn, 0).unwrap());
+        assert!(covers(&conn, latest_seq(&conn).unwrap()).unwrap());
+        assert!(!covers(&conn, latest_seq(&conn).unwrap() + 1).unwrap());
+    }
+}
@@ -1,13 +1,15 @@
 use anyhow::Result;
 use rusqlite::{Connection, Row};
 
+use crate::PooledConnection;
+
 /// Database client for executing custom SQL queries
 pub struct DbClient {
-    conn: Connection,
+    conn: PooledConnection<'static>,
 }
 
 impl DbClient {
-    pub fn new(conn: Connection) -> Self {
+    pub fn new(conn: PooledConnection<'static>) -> Self {
         Self { conn }
     }
 
@@ -1,11 +1,12 @@
 pub mod client;
 pub mod errors;
-pub mod generator;
-pub mod macros;
+pub mod query;
 pub mod schema;
 pub mod table;
 
 // Re-export for convenience
 pub use client::DbClient;
 pub use errors::{execute_with_error_handling, handle_db_error};
+pub use halvor_db_macro::Table;
+pub use query::{Column, Filter, Order, Query};
 pub use table::{DbTable, Table, create_table_sql};
@@ -0,0 +1,423 @@
+//! Typed query builder for `Table` rows
+//!
+//! `#[derive(Table)]` generates a `<Name>Query` for every row struct, with a
+//! method per column, so a misspelled column fails to compile instead of at
+//! runtime:
+//!
+//! ```rust,ignore
+//! let peers = AgentPeersRow::query()
+//!     .status().eq("active")
+//!     .order_by_last_seen_at_desc()
+//!     .limit(10)
+//!     .all()?;
+//! ```
+//!
+//! Queries only build the WHERE clause; they run through the `DbTable` methods.
+
+use crate::core::table::{DbTable, Table};
+use anyhow::Result;
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use std::marker::PhantomData;
+
+/// Sort direction for `Query::order_by`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+}
+
+/// Something conditions can be added to
+///
+/// Implemented by `Query` and the generated `<Name>Query` types, so a
+/// `Column` can hand back whichever query it came from.
+pub trait Filter: Sized {
+    /// Add a condition, ANDed with the others
+    ///
+    /// `?` placeholders in the condition bind `params` in order.
+    fn filter(self, condition: String, params: Vec<Box<dyn ToSql>>) -> Self;
+}
+
+/// Conditions, ordering and paging for a select over `T`'s table
+pub struct Query<T: Table> {
+    conditions: Vec<String>,
+    params: Vec<Box<dyn ToSql>>,
+    order_by: Vec<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Table> Default for Query<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Table> Filter for Query<T> {
+    fn filter(mut self, condition: String, params: Vec<Box<dyn ToSql>>) -> Self {
+        self.conditions.push(condition);
+        self.params.extend(params);
+        self
+    }
+}
+
+impl<T: Table> Query<T> {
+    /// A query matching every row
+    pub fn new() -> Self {
+        Self {
+            conditions: Vec::new(),
+            params: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Sort by `column`, after any earlier orderings
+    pub fn order_by(mut self, column: &str, order: Order) -> Self {
+        self.order_by.push(format!("{} {}", column, order.as_sql()));
+        self
+    }
+
+    /// Return at most `limit` rows
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skip the first `offset` rows
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Return page `page` (counting from 0) of `per_page` rows
+    pub fn page(self, page: usize, per_page: usize) -> Self {
+        self.limit(per_page).offset(page * per_page)
+    }
+
+    /// The conditions as a WHERE clause, without ordering or paging
+    pub fn where_clause(&self) -> String {
+        if self.conditions.is_empty() {
+            "1=1".to_string()
+        } else {
+            self.conditions.join(" AND ")
+        }
+    }
+
+    /// The WHERE clause with ORDER BY, LIMIT and OFFSET, as `DbTable::select_many` takes it
+    pub fn select_clause(&self) -> String {
+        self.select_clause_with_limit(self.limit)
+    }
+
+    fn select_clause_with_limit(&self, limit: Option<usize>) -> String {
+        let mut clause = self.where_clause();
+        if !self.order_by.is_empty() {
+            clause.push_str(" ORDER BY ");
+            clause.push_str(&self.order_by.join(", "));
+        }
+        // SQLite only takes OFFSET after a LIMIT; -1 means no limit
+        match (limit, self.offset) {
+            (Some(limit), Some(offset)) => clause.push_str(&format!(" LIMIT {} OFFSET {}", limit, offset)),
+            (Some(limit), None) => clause.push_str(&format!(" LIMIT {}", limit)),
+            (None, Some(offset)) => clause.push_str(&format!(" LIMIT -1 OFFSET {}", offset)),
+            (None, None) => {}
+        }
+        clause
+    }
+
+    /// Parameters for the `?` placeholders in the WHERE clause
+    pub fn params(&self) -> Vec<&dyn ToSql> {
+        self.params.iter().map(|p| p.as_ref()).collect()
+    }
+
+    /// Every matching row
+    pub fn all_in(&self, conn: &Connection) -> Result<Vec<T>> {
+        DbTable::<T>::select_many(conn, &self.select_clause(), &self.params())
+    }
+
+    /// The first matching row
+    pub fn first_in(&self, conn: &Connection) -> Result<Option<T>> {
+        let clause = self.select_clause_with_limit(Some(1));
+        Ok(DbTable::<T>::select_many(conn, &clause, &self.params())?.pop())
+    }
+
+    /// Number of matching rows, ignoring ordering and paging
+    pub fn count_in(&self, conn: &Connection) -> Result<usize> {
+        DbTable::<T>::count(conn, &self.where_clause(), &self.params())
+    }
+
+    /// Whether any row matches
+    pub fn exists_in(&self, conn: &Connection) -> Result<bool> {
+        Ok(self.first_in(conn)?.is_some())
+    }
+
+    /// Delete every matching row, returning how many were deleted
+    ///
+    /// Fails for a paged query rather than deleting more than it would select.
+    pub fn delete_in(&self, conn: &Connection) -> Result<usize> {
+        anyhow::ensure!(
+            self.limit.is_none() && self.offset.is_none(),
+            "Can't delete from {} with a limit or offset",
+            T::table_name()
+        );
+        DbTable::<T>::delete_many(conn, &self.where_clause(), &self.params())
+    }
+
+    /// Update the first matching row, or insert one; see `DbTable::upsert_by`
+    pub fn upsert_in<F>(&self, conn: &Connection, builder: F) -> Result<String>
+    where
+        F: FnOnce(Option<&T>) -> T,
+    {
+        DbTable::<T>::upsert_by(conn, &self.where_clause(), &self.params(), builder)
+    }
+}
+
+/// A column of a query's table, waiting for a condition
+///
+/// `V` is the column's value type (`T` for an `Option<T>` field). Each
+/// condition hands back the query the column came from.
+pub struct Column<Q, V> {
+    query: Q,
+    name: &'static str,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<Q: Filter, V: ToSql + 'static> Column<Q, V> {
+    pub fn new(query: Q, name: &'static str) -> Self {
+        Self {
+            query,
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    fn compare(self, op: &str, value: V) -> Q {
+        let condition = format!("{} {} ?", self.name, op);
+        self.query.filter(condition, vec![Box::new(value)])
+    }
+
+    /// `column = value`
+    pub fn eq(self, value: impl Into<V>) -> Q {
+        self.compare("=", value.into())
+    }
+
+    /// `column != value`
+    pub fn ne(self, value: impl Into<V>) -> Q {
+        self.compare("!=", value.into())
+    }
+
+    /// `column < value`
+    pub fn lt(self, value: impl Into<V>) -> Q {
+        self.compare("<", value.into())
+    }
+
+    /// `column <= value`
+    pub fn le(self, value: impl Into<V>) -> Q {
+        self.compare("<=", value.into())
+    }
+
+    /// `column > value`
+    pub fn gt(self, value: impl Into<V>) -> Q {
+        self.compare(">", value.into())
+    }
+
+    /// `column >= value`
+    pub fn ge(self, value: impl Into<V>) -> Q {
+        self.compare(">=", value.into())
+    }
+
+    /// `column IS value`, which unlike `eq` matches NULL to `None`
+    pub fn is(self, value: Option<impl Into<V>>) -> Q {
+        let condition = format!("{} IS ?", self.name);
+        let value: Option<V> = value.map(Into::into);
+        self.query.filter(condition, vec![Box::new(value)])
+    }
+
+    /// `column IS NULL`
+    pub fn is_null(self) -> Q {
+        let condition = format!("{} IS NULL", self.name);
+        self.query.filter(condition, Vec::new())
+    }
+
+    /// `column IS NOT NULL`
+    pub fn is_not_null(self) -> Q {
+        let condition = format!("{} IS NOT NULL", self.name);
+        self.query.filter(condition, Vec::new())
+    }
+
+    /// `column IN (values...)`; an empty list matches nothing
+    pub fn is_in<I>(self, values: I) -> Q
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        self.list("IN", values)
+    }
+
+    /// `column NOT IN (values...)`; an empty list matches everything
+    pub fn not_in<I>(self, values: I) -> Q
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        self.list("NOT IN", values)
+    }
+
+    fn list<I>(self, op: &str, values: I) -> Q
+    where
+        I: IntoIterator,
+        I::Item: Into<V>,
+    {
+        let params: Vec<Box<dyn ToSql>> = values
+            .into_iter()
+            .map(|v| Box::new(v.into()) as Box<dyn ToSql>)
+            .collect();
+        let placeholders = vec!["?"; params.len()].join(", ");
+        let condition = format!("{} {} ({})", self.name, op, placeholders);
+        self.query.filter(condition, params)
+    }
+}
+
+impl<Q: Filter> Column<Q, String> {
+    /// `column LIKE pattern`, with `%` and `_` as wildcards
+    pub fn like(self, pattern: impl Into<String>) -> Q {
+        self.compare("LIKE", pattern.into())
+    }
+
+    /// Text starting with `prefix`, taken literally
+    pub fn starts_with(self, prefix: &str) -> Q {
+        let pattern = format!(
+            "{}%",
+            prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+        );
+        let condition = format!("{} LIKE ? ESCAPE '\\'", self.name);
+        self.query.filter(condition, vec![Box::new(pattern)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, crate::core::Table)]
+    #[table(name = "peers")]
+    struct PeerRow {
+        id: String,
+        #[table(unique)]
+        hostname: String,
+        status: String,
+        last_seen_at: Option<i64>,
+        created_at: i64,
+        updated_at: i64,
+    }
+
+    fn peers() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE peers (
+                id TEXT PRIMARY KEY,
+                hostname TEXT NOT NULL UNIQUE,
+                status TEXT NOT NULL,
+                last_seen_at INTEGER,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        for (hostname, status, last_seen_at) in [
+            ("baulder", "active", Some(30)),
+            ("frigg", "active", Some(10)),
+            ("oak", "inactive", None),
+            ("mint", "suspect", Some(20)),
+        ] {
+            let row = PeerRow::from_data(PeerRowData {
+                hostname: hostname.to_string(),
+                status: status.to_string(),
+                last_seen_at,
+            });
+            DbTable::<PeerRow>::insert(&conn, &row).unwrap();
+        }
+        conn
+    }
+
+    fn hostnames(rows: Vec<PeerRow>) -> Vec<String> {
+        rows.into_iter().map(|r| r.hostname).collect()
+    }
+
+    #[test]
+    fn test_conditions_and_ordering() {
+        let conn = peers();
+
+        let query = PeerRow::query().status().eq("active").order_by_last_seen_at_desc();
+        assert_eq!(query.select_clause(), "status = ? ORDER BY last_seen_at DESC");
+        assert_eq!(hostnames(query.all_in(&conn).unwrap()), ["baulder", "frigg"]);
+
+        let query = PeerRow::query()
+            .status()
+            .is_in(["active", "suspect"])
+            .last_seen_at()
+            .ge(20)
+            .order_by_hostname_asc();
+        assert_eq!(hostnames(query.all_in(&conn).unwrap()), ["baulder", "mint"]);
+
+        let none: [&str; 0] = [];
+        assert_eq!(PeerRow::query().status().is_in(none).count_in(&conn).unwrap(), 0);
+        assert_eq!(PeerRow::query().last_seen_at().is(None::<i64>).count_in(&conn).unwrap(), 1);
+        assert_eq!(PeerRow::query().hostname().starts_with("f").count_in(&conn).unwrap(), 1);
+        assert_eq!(PeerRow::query().hostname().starts_with("_").count_in(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_pagination_and_count() {
+        let conn = peers();
+
+        let page = |n| {
+            let query = PeerRow::query().order_by_hostname_asc().page(n, 3);
+            hostnames(query.all_in(&conn).unwrap())
+        };
+        assert_eq!(page(0), ["baulder", "frigg", "mint"]);
+        assert_eq!(page(1), ["oak"]);
+
+        let query = PeerRow::query().status().ne("inactive").limit(1);
+        assert_eq!(query.count_in(&conn).unwrap(), 3);
+        assert_eq!(query.all_in(&conn).unwrap().len(), 1);
+        assert!(query.delete_in(&conn).is_err());
+    }
+
+    #[test]
+    fn test_first_upsert_and_delete() {
+        let conn = peers();
+
+        let frigg = PeerRow::query().hostname().eq("frigg");
+        let before = frigg.first_in(&conn).unwrap().unwrap();
+        let id = before.id.clone();
+        let updated = frigg
+            .upsert_in(&conn, |existing| PeerRow {
+                status: "suspect".to_string(),
+                ..existing.cloned().unwrap()
+            })
+            .unwrap();
+        assert_eq!(updated, id);
+        let after = frigg.first_in(&conn).unwrap().unwrap();
+        assert_eq!(after.created_at, before.created_at);
+        assert!(after.updated_at >= before.updated_at);
+        assert_eq!(PeerRow::query().status().eq("suspect").count_in(&conn).unwrap(), 2);
+
+        assert_eq!(PeerRow::query().status().eq("suspect").delete_in(&conn).unwrap(), 2);
+        assert!(!frigg.exists_in(&conn).unwrap());
+        assert_eq!(DbTable::<PeerRow>::select_all(&conn).unwrap().len(), 2);
+    }
+}
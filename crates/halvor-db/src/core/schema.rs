@@ -13,10 +13,15 @@ pub struct ColumnDef {
 }
 
 /// Table schema from database
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TableSchema {
     pub name: String,
     pub columns: Vec<ColumnDef>,
+    /// Column sets with a UNIQUE constraint or index, besides the primary key
+    pub unique_keys: Vec<Vec<String>>,
+    /// CREATE statements for the table's other indexes and its triggers,
+    /// recreated when the table is rebuilt
+    pub dependents: Vec<String>,
 }
 
 /// Get the current database schema
@@ -28,7 +33,7 @@ pub fn get_database_schema(conn: &Connection) -> Result<HashMap<String, TableSch
         "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%' AND name != 'migrations'",
     )?;
     let tables: Vec<String> = stmt
-        .query_map([], |row| Ok(row.get::<_, String>(0)?))?
+        .query_map([], |row| row.get::<_, String>(0))?
         .collect::<Result<Vec<_>, _>>()?;
 
     for table_name in tables {
@@ -40,201 +45,268 @@ pub fn get_database_schema(conn: &Connection) -> Result<HashMap<String, TableSch
 }
 
 /// Get schema for a specific table
-fn get_table_schema(conn: &Connection, table_name: &str) -> Result<TableSchema> {
-    let mut columns = Vec::new();
+///
+/// Read with PRAGMA table_info and index_list rather than by parsing the
+/// CREATE TABLE statement, so columns added later and unique indexes count.
+pub fn get_table_schema(conn: &Connection, table_name: &str) -> Result<TableSchema> {
+    let mut stmt = conn.prepare("SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?1)")?;
+    let columns = stmt
+        .query_map([table_name], |row| {
+            Ok(ColumnDef {
+                name: row.get(0)?,
+                sql_type: row.get::<_, String>(1)?.to_uppercase(),
+                not_null: row.get(2)?,
+                default_value: row.get(3)?,
+                primary_key: row.get::<_, i64>(4)? > 0,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
 
-    // Get CREATE TABLE statement
-    let create_sql: String = conn.query_row(
-        "SELECT sql FROM sqlite_master WHERE type='table' AND name = ?1",
-        [table_name],
-        |row| row.get(0),
+    // The primary key's own index (origin 'pk') isn't a unique key
+    let mut stmt = conn.prepare(
+        "SELECT name FROM pragma_index_list(?1) WHERE \"unique\" = 1 AND origin != 'pk' ORDER BY name",
     )?;
-
-    // Parse the CREATE TABLE statement to extract column definitions
-    // This is a simplified parser - SQLite's schema is complex, but we can extract basics
-    let sql_upper = create_sql.to_uppercase();
-    let start = sql_upper.find("CREATE TABLE").unwrap_or(0);
-    let paren_start = create_sql[start..].find('(').unwrap_or(0) + start;
-    let paren_end = create_sql.rfind(')').unwrap_or(create_sql.len());
-
-    let column_defs = &create_sql[paren_start + 1..paren_end];
-
-    // Split by comma, but be careful of nested parentheses
-    let mut current = String::new();
-    let mut depth = 0;
-    let mut parts = Vec::new();
-
-    for ch in column_defs.chars() {
-        match ch {
-            '(' => {
-                depth += 1;
-                current.push(ch);
-            }
-            ')' => {
-                depth -= 1;
-                current.push(ch);
-            }
-            ',' if depth == 0 => {
-                parts.push(current.trim().to_string());
-                current.clear();
-            }
-            _ => current.push(ch),
-        }
-    }
-    if !current.trim().is_empty() {
-        parts.push(current.trim().to_string());
+    let indexes = stmt
+        .query_map([table_name], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut unique_keys = Vec::new();
+    for index in indexes {
+        let mut stmt = conn.prepare("SELECT name FROM pragma_index_info(?1) ORDER BY seqno")?;
+        let key = stmt
+            .query_map([&index], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        unique_keys.push(key);
     }
 
-    for part in parts {
-        let part = part.trim();
-        if part.is_empty()
-            || part.to_uppercase().starts_with("PRIMARY KEY")
-            || part.to_uppercase().starts_with("UNIQUE")
-            || part.to_uppercase().starts_with("FOREIGN KEY")
-            || part.to_uppercase().starts_with("CHECK")
-        {
-            continue;
-        }
-
-        let mut col_def = ColumnDef {
-            name: String::new(),
-            sql_type: String::new(),
-            not_null: false,
-            default_value: None,
-            primary_key: false,
-        };
-
-        let words: Vec<&str> = part.split_whitespace().collect();
-        if words.is_empty() {
-            continue;
-        }
-
-        col_def.name = words[0].trim_matches('"').trim_matches('`').to_string();
-
-        if words.len() > 1 {
-            // Handle INTEGER PRIMARY KEY AUTOINCREMENT - treat as TEXT for our UUID-based system
-            let sql_type = words[1].to_uppercase();
-            if sql_type == "INTEGER" && part.to_uppercase().contains("PRIMARY KEY") {
-                // This is likely INTEGER PRIMARY KEY AUTOINCREMENT - we use TEXT PRIMARY KEY
-                col_def.sql_type = "TEXT".to_string();
-            } else {
-                col_def.sql_type = sql_type;
-            }
-        }
-
-        for word in &words[2..] {
-            let word_upper = word.to_uppercase();
-            if word_upper == "NOT" || word_upper == "NULL" {
-                col_def.not_null = true;
-            } else if word_upper == "PRIMARY" || word_upper == "KEY" {
-                col_def.primary_key = true;
-            } else if word_upper == "DEFAULT" {
-                // Next word is default value
-                if let Some(default_idx) = words.iter().position(|w| w.to_uppercase() == "DEFAULT")
-                {
-                    if default_idx + 1 < words.len() {
-                        col_def.default_value = Some(words[default_idx + 1].to_string());
-                    }
-                }
-            }
-        }
-
-        columns.push(col_def);
-    }
+    // Unique indexes are part of the table definition as unique keys
+    let mut stmt = conn.prepare(
+        "SELECT m.sql FROM sqlite_master m
+         LEFT JOIN pragma_index_list(?1) i ON i.name = m.name
+         WHERE m.tbl_name = ?1 AND m.sql IS NOT NULL
+           AND (m.type = 'trigger' OR (m.type = 'index' AND i.\"unique\" = 0))
+         ORDER BY m.type, m.name",
+    )?;
+    let dependents = stmt
+        .query_map([table_name], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
 
     Ok(TableSchema {
         name: table_name.to_string(),
         columns,
+        unique_keys,
+        dependents,
     })
 }
 
 /// Compare schemas and generate migration SQL
+///
+/// `expected` is usually a struct's `Table::schema()` and `actual` the live
+/// table. New columns SQLite can add in place get `ADD COLUMN`, and new unique
+/// keys a unique index. Anything else (dropped columns, a changed type,
+/// nullability or primary key, a dropped unique key, or a new column that
+/// can't be added in place) rebuilds the table; see [`rebuild_table_sql`].
+/// The down SQL undoes the up SQL, rebuilding the table when it dropped
+/// nothing but SQLite still can't undo it in place.
 pub fn diff_schemas(
     expected: &TableSchema,
     actual: Option<&TableSchema>,
 ) -> Result<(Vec<String>, Vec<String>)> {
+    let Some(actual) = actual else {
+        // Table doesn't exist - create it
+        let up_sql = vec![create_table_sql(&expected.name, expected)];
+        let down_sql = vec![format!("DROP TABLE IF EXISTS {}", expected.name)];
+        return Ok((up_sql, down_sql));
+    };
+
+    let actual_cols: HashMap<&str, &ColumnDef> = actual
+        .columns
+        .iter()
+        .map(|c| (c.name.as_str(), c))
+        .collect();
+    let added: Vec<&ColumnDef> = expected
+        .columns
+        .iter()
+        .filter(|c| !actual_cols.contains_key(c.name.as_str()))
+        .collect();
+    let dropped = actual
+        .columns
+        .iter()
+        .any(|c| !expected.columns.iter().any(|e| e.name == c.name));
+    let altered = expected.columns.iter().any(|col| {
+        actual_cols.get(col.name.as_str()).is_some_and(|existing| {
+            existing.sql_type != col.sql_type
+                // SQLite lets a non-INTEGER primary key hold NULL unless declared NOT NULL
+                || (existing.not_null != col.not_null && !col.primary_key)
+                || existing.primary_key != col.primary_key
+        })
+    });
+
+    // Unique keys, compared regardless of column order
+    let sorted = |key: &Vec<String>| {
+        let mut key = key.clone();
+        key.sort();
+        key
+    };
+    let actual_keys: Vec<Vec<String>> = actual.unique_keys.iter().map(sorted).collect();
+    let expected_keys: Vec<Vec<String>> = expected.unique_keys.iter().map(sorted).collect();
+    let added_keys: Vec<&Vec<String>> = expected_keys
+        .iter()
+        .zip(&expected.unique_keys)
+        .filter(|(key, _)| !actual_keys.contains(key))
+        .map(|(_, columns)| columns)
+        .collect();
+    let dropped_keys = actual_keys.iter().any(|key| !expected_keys.contains(key));
+
+    // ADD COLUMN can't add a primary key, or a NOT NULL column without a default
+    let addable = added
+        .iter()
+        .all(|c| !c.primary_key && (!c.not_null || c.default_value.is_some()));
+
+    if dropped || altered || dropped_keys || !addable {
+        let up_sql = rebuild_table_sql(actual, expected);
+        let down_sql = rebuild_table_sql(expected, actual);
+        return Ok((up_sql, down_sql));
+    }
+
     let mut up_sql = Vec::new();
     let mut down_sql = Vec::new();
+    for col in &added {
+        up_sql.push(format!("ALTER TABLE {} ADD COLUMN {}", actual.name, column_sql(col)));
+    }
+    for columns in added_keys {
+        let index = format!("idx_{}_{}_unique", actual.name, columns.join("_"));
+        up_sql.push(format!(
+            "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {}({})",
+            index,
+            actual.name,
+            columns.join(", ")
+        ));
+        down_sql.push(format!("DROP INDEX IF EXISTS {}", index));
+    }
+    if !added.is_empty() {
+        // Rebuilding also drops the new unique indexes
+        down_sql = rebuild_table_sql(expected, actual);
+    }
 
-    match actual {
-        None => {
-            // Table doesn't exist - create it
-            let columns_sql: Vec<String> = expected
-                .columns
-                .iter()
-                .map(|col| {
-                    let mut col_sql = format!("{} {}", col.name, col.sql_type);
-                    if col.not_null {
-                        col_sql.push_str(" NOT NULL");
-                    }
-                    if col.primary_key {
-                        col_sql.push_str(" PRIMARY KEY");
-                    }
-                    if let Some(ref default) = col.default_value {
-                        col_sql.push_str(&format!(" DEFAULT {}", default));
-                    }
-                    col_sql
-                })
-                .collect();
+    Ok((up_sql, down_sql))
+}
 
-            up_sql.push(format!(
-                "CREATE TABLE IF NOT EXISTS {} ({})",
-                expected.name,
-                columns_sql.join(", ")
-            ));
+/// SQL rebuilding table `from` as `to`, keeping its rows
+///
+/// Follows SQLite's procedure for schema changes ALTER TABLE can't make: create
+/// the new table as `<name>_new`, copy the rows across, drop the old table,
+/// rename the new one and recreate the indexes and triggers of both schemas
+/// (a struct's schema has none, so rebuilding to it keeps the live ones). Run
+/// it in a migration, which runs in a transaction with foreign keys off;
+/// with them on, dropping the old table deletes the rows that reference it.
+///
+/// Columns only in `from` are dropped and columns only in `to` take their
+/// default. Columns whose type changed are CAST, and NULLs in a column
+/// that becomes NOT NULL take its default, or fail the copy if it has none.
+pub fn rebuild_table_sql(from: &TableSchema, to: &TableSchema) -> Vec<String> {
+    let new_table = format!("{}_new", to.name);
+    let mut columns = Vec::new();
+    let mut values = Vec::new();
+    for col in &to.columns {
+        let Some(old) = from.columns.iter().find(|c| c.name == col.name) else {
+            continue;
+        };
+        let mut value = col.name.clone();
+        if old.sql_type != col.sql_type && !col.sql_type.is_empty() {
+            value = format!("CAST({} AS {})", value, col.sql_type);
+        }
+        if col.not_null && !old.not_null {
+            if let Some(default) = &col.default_value {
+                value = format!("COALESCE({}, {})", value, default);
+            }
+        }
+        columns.push(col.name.clone());
+        values.push(value);
+    }
 
-            down_sql.push(format!("DROP TABLE IF EXISTS {}", expected.name));
+    let mut sql = vec![
+        create_table_sql(&new_table, to),
+        format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {}",
+            new_table,
+            columns.join(", "),
+            values.join(", "),
+            from.name
+        ),
+        format!("DROP TABLE {}", from.name),
+        format!("ALTER TABLE {} RENAME TO {}", new_table, to.name),
+    ];
+    // Indexes and triggers on columns that are gone can't be recreated
+    let dropped: Vec<&str> = from
+        .columns
+        .iter()
+        .filter(|c| !to.columns.iter().any(|t| t.name == c.name))
+        .map(|c| c.name.as_str())
+        .collect();
+    for dependent in from.dependents.iter().chain(&to.dependents) {
+        if !sql.contains(dependent) && dropped.iter().all(|c| !mentions(dependent, c)) {
+            sql.push(dependent.clone());
         }
-        Some(actual) => {
-            // Table exists - check for column differences
-            let expected_cols: HashMap<&str, &ColumnDef> = expected
-                .columns
-                .iter()
-                .map(|c| (c.name.as_str(), c))
-                .collect();
-            let actual_cols: HashMap<&str, &ColumnDef> = actual
-                .columns
-                .iter()
-                .map(|c| (c.name.as_str(), c))
-                .collect();
+    }
+    sql
+}
 
-            // Find new columns
-            for (name, col) in &expected_cols {
-                if !actual_cols.contains_key(name) {
-                    let mut col_sql = format!(
-                        "ALTER TABLE {} ADD COLUMN {} {}",
-                        actual.name, col.name, col.sql_type
-                    );
-                    if col.not_null {
-                        col_sql.push_str(" NOT NULL");
-                    }
-                    if let Some(ref default) = col.default_value {
-                        col_sql.push_str(&format!(" DEFAULT {}", default));
-                    }
-                    up_sql.push(col_sql);
+/// CREATE TABLE statement for `schema`, named `name`
+fn create_table_sql(name: &str, schema: &TableSchema) -> String {
+    let mut columns_sql: Vec<String> = schema.columns.iter().map(column_sql).collect();
+    columns_sql.extend(
+        schema
+            .unique_keys
+            .iter()
+            .map(|key| format!("UNIQUE({})", key.join(", "))),
+    );
+    format!("CREATE TABLE IF NOT EXISTS {} ({})", name, columns_sql.join(", "))
+}
 
-                    // Down migration: drop column (SQLite doesn't support DROP COLUMN easily)
-                    // We'll need to recreate the table
-                    down_sql.push(format!(
-                        "-- Note: SQLite doesn't support DROP COLUMN. Manual intervention required for {}",
-                        col.name
-                    ));
-                }
-            }
+/// Whether `sql` mentions identifier `name` as a whole word
+fn mentions(sql: &str, name: &str) -> bool {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    sql.match_indices(name).any(|(i, _)| {
+        let before = sql[..i].chars().next_back();
+        let after = sql[i + name.len()..].chars().next();
+        !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+    })
+}
 
-            // Find removed columns (warn only, as SQLite doesn't support DROP COLUMN easily)
-            for (name, _) in &actual_cols {
-                if !expected_cols.contains_key(name) {
-                    up_sql.push(format!(
-                        "-- Warning: Column {} exists in database but not in struct. Manual removal required.",
-                        name
-                    ));
-                }
-            }
-        }
+/// Column definition as it appears in CREATE TABLE or ADD COLUMN
+fn column_sql(col: &ColumnDef) -> String {
+    let mut col_sql = format!("{} {}", col.name, col.sql_type);
+    if col.not_null {
+        col_sql.push_str(" NOT NULL");
     }
+    if col.primary_key {
+        col_sql.push_str(" PRIMARY KEY");
+    }
+    if let Some(ref default) = col.default_value {
+        col_sql.push_str(&format!(" DEFAULT {}", default));
+    }
+    col_sql
+}
 
-    Ok((up_sql, down_sql))
+/// A table name with the up and down SQL that would bring it in line
+pub type TableDiff = (String, Vec<String>, Vec<String>);
+
+/// Compare each table's expected schema with the live database
+///
+/// Returns the migration SQL for every table that differs, in the order given.
+pub fn diff_database(
+    conn: &Connection,
+    expected: &[TableSchema],
+) -> Result<Vec<TableDiff>> {
+    let current = get_database_schema(conn)?;
+    let mut diffs = Vec::new();
+    for table in expected {
+        let (up_sql, down_sql) = diff_schemas(table, current.get(&table.name))?;
+        if !up_sql.is_empty() || !down_sql.is_empty() {
+            diffs.push((table.name.clone(), up_sql, down_sql));
+        }
+    }
+    Ok(diffs)
 }
 
 /// Compare two database schemas and generate migration SQL
@@ -250,7 +322,8 @@ pub fn generate_migration_from_schema_diff(
         let current = current_schema.get(table_name);
         let (up_sql, down_sql) = diff_schemas(target, current)?;
         all_up_sql.extend(up_sql);
-        all_down_sql.extend(down_sql);
+        // Undo the tables in the reverse order
+        all_down_sql.splice(0..0, down_sql);
     }
 
     if all_up_sql.is_empty() && all_down_sql.is_empty() {
@@ -259,3 +332,17 @@ pub fn generate_migration_from_schema_diff(
         Ok(Some((all_up_sql, all_down_sql)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_structs_match_migrated_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::migrations::run_migrations(&conn).unwrap();
+
+        let diffs = diff_database(&conn, &crate::generated::table_schemas()).unwrap();
+        assert!(diffs.is_empty(), "row structs differ from the migrated schema: {:#?}", diffs);
+    }
+}
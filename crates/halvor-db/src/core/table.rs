@@ -1,3 +1,4 @@
+use crate::core::schema::TableSchema;
 use anyhow::Result;
 use rusqlite::{Connection, Row, params};
 use std::marker::PhantomData;
@@ -6,8 +7,8 @@ use uuid::Uuid;
 /// Trait for database table operations
 /// Implement this trait for your struct to enable type-safe database access
 ///
-/// For automatic implementation, use the `#[derive(Table)]` macro (when available)
-/// or implement manually with minimal boilerplate.
+/// Use `#[derive(Table)]` to implement it from the struct's fields; see
+/// `halvor_db_macro::Table` for the attributes it takes.
 pub trait Table: Sized {
     /// Table name in the database
     fn table_name() -> &'static str;
@@ -38,8 +39,8 @@ pub trait Table: Sized {
     /// Get all column names including id, created_at, updated_at
     fn all_columns() -> &'static [&'static str];
 
-    /// Get the row index for each column in SELECT * queries
-    fn column_indices() -> &'static [usize];
+    /// Columns and unique keys the struct expects the table to have
+    fn schema() -> TableSchema;
 }
 
 /// Type-safe database operations
@@ -106,6 +107,17 @@ impl<T: Table> DbTable<T> {
         Ok(results)
     }
 
+    /// Count records matching a WHERE clause
+    pub fn count(
+        conn: &Connection,
+        where_clause: &str,
+        params: &[&dyn rusqlite::types::ToSql],
+    ) -> Result<usize> {
+        let sql = format!("SELECT COUNT(*) FROM {} WHERE {}", T::table_name(), where_clause);
+        let count: i64 = conn.query_row(&sql, params, |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
     /// Insert a new record (generates UUID and timestamps automatically)
     /// The item's id, created_at, and updated_at fields are ignored and set automatically
     pub fn insert(conn: &Connection, item: &T) -> Result<String> {
@@ -350,3 +362,194 @@ pub fn create_table_sql(table_name: &str, columns: &[&str]) -> String {
         cols.join(", ")
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fields deliberately declared in a different order than the columns
+    #[derive(Debug, Clone, PartialEq, crate::core::Table)]
+    #[table(name = "widgets", unique(owner, label))]
+    struct WidgetRow {
+        id: String,
+        updated_at: i64,
+        label: String,
+        #[table(unique)]
+        serial: String,
+        #[table(default = "0")]
+        quantity: i64,
+        owner: Option<String>,
+        #[table(skip)]
+        cached: Vec<String>,
+        created_at: i64,
+    }
+
+    fn widgets() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE widgets (
+                id TEXT PRIMARY KEY,
+                owner TEXT,
+                serial TEXT NOT NULL UNIQUE,
+                quantity INTEGER NOT NULL DEFAULT 0,
+                label TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                UNIQUE(owner, label)
+            )",
+            [],
+        )
+        .unwrap();
+        conn
+    }
+
+    fn widget(serial: &str, quantity: i64) -> WidgetRow {
+        WidgetRow::from_data(WidgetRowData {
+            label: "gear".to_string(),
+            serial: serial.to_string(),
+            quantity,
+            owner: Some("frigg".to_string()),
+        })
+    }
+
+    #[test]
+    fn test_columns_are_read_by_name() {
+        let conn = widgets();
+        let id = DbTable::<WidgetRow>::insert(&conn, &widget("w-1", 3)).unwrap();
+
+        let row = DbTable::<WidgetRow>::select(&conn, &id).unwrap().unwrap();
+        assert_eq!(row.id, id);
+        assert_eq!(row.serial, "w-1");
+        assert_eq!(row.label, "gear");
+        assert_eq!(row.quantity, 3);
+        assert_eq!(row.owner.as_deref(), Some("frigg"));
+        assert!(row.cached.is_empty());
+        assert!(row.created_at > 0);
+        assert_eq!(row.created_at, row.updated_at);
+    }
+
+    #[test]
+    fn test_upsert_keeps_id_and_replaces_data() {
+        let conn = widgets();
+        let id = DbTable::<WidgetRow>::insert(&conn, &widget("w-1", 3)).unwrap();
+
+        let updated = DbTable::<WidgetRow>::upsert_by(
+            &conn,
+            "serial = ?1",
+            &[&"w-1" as &dyn rusqlite::types::ToSql],
+            |existing| WidgetRow {
+                quantity: 4,
+                ..existing.cloned().unwrap()
+            },
+        )
+        .unwrap();
+        assert_eq!(updated, id);
+        let rows = DbTable::<WidgetRow>::select_all(&conn).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].quantity, 4);
+    }
+
+    #[test]
+    fn test_schema_describes_columns_and_keys() {
+        let schema = WidgetRow::schema();
+        assert_eq!(schema.name, "widgets");
+
+        let columns: Vec<(&str, &str, bool)> = schema
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.sql_type.as_str(), c.not_null))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                ("id", "TEXT", true),
+                ("updated_at", "INTEGER", true),
+                ("label", "TEXT", true),
+                ("serial", "TEXT", true),
+                ("quantity", "INTEGER", true),
+                ("owner", "TEXT", false),
+                ("created_at", "INTEGER", true),
+            ]
+        );
+        assert!(schema.columns[0].primary_key);
+        assert_eq!(schema.columns[4].default_value.as_deref(), Some("0"));
+        assert_eq!(
+            schema.unique_keys,
+            [vec!["serial".to_string()], vec!["owner".to_string(), "label".to_string()]]
+        );
+
+        assert_eq!(WidgetRow::insert_columns(), ["label", "serial", "quantity", "owner"]);
+        assert_eq!(
+            WidgetRow::all_columns(),
+            ["id", "label", "serial", "quantity", "owner", "created_at", "updated_at"]
+        );
+
+        let live = crate::core::schema::get_table_schema(&widgets(), "widgets").unwrap();
+        let (up_sql, down_sql) = crate::core::schema::diff_schemas(&schema, Some(&live)).unwrap();
+        assert!(up_sql.is_empty() && down_sql.is_empty(), "{:?}", up_sql);
+    }
+
+    fn drifted_widgets() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE widgets (
+                id TEXT PRIMARY KEY,
+                owner TEXT NOT NULL,
+                serial INTEGER NOT NULL,
+                label TEXT NOT NULL,
+                legacy TEXT,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE INDEX idx_widgets_label ON widgets(label);
+            CREATE INDEX idx_widgets_legacy ON widgets(legacy);
+            INSERT INTO widgets VALUES ('w1', 'frigg', 7, 'gear', 'old', 1, 2);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_schema_diff_rebuilds_drifted_table() {
+        let conn = drifted_widgets();
+        let live = crate::core::schema::get_table_schema(&conn, "widgets").unwrap();
+        let (up_sql, _) = crate::core::schema::diff_schemas(&WidgetRow::schema(), Some(&live)).unwrap();
+        assert_eq!(
+            up_sql,
+            [
+                "CREATE TABLE IF NOT EXISTS widgets_new (id TEXT NOT NULL PRIMARY KEY, updated_at INTEGER NOT NULL, label TEXT NOT NULL, serial TEXT NOT NULL, quantity INTEGER NOT NULL DEFAULT 0, owner TEXT, created_at INTEGER NOT NULL, UNIQUE(serial), UNIQUE(owner, label))",
+                "INSERT INTO widgets_new (id, updated_at, label, serial, owner, created_at) SELECT id, updated_at, label, CAST(serial AS TEXT), owner, created_at FROM widgets",
+                "DROP TABLE widgets",
+                "ALTER TABLE widgets_new RENAME TO widgets",
+                "CREATE INDEX idx_widgets_label ON widgets(label)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schema_diff_round_trips_data() {
+        let conn = drifted_widgets();
+        let live = crate::core::schema::get_table_schema(&conn, "widgets").unwrap();
+        let (up_sql, down_sql) = crate::core::schema::diff_schemas(&WidgetRow::schema(), Some(&live)).unwrap();
+
+        conn.execute_batch(&up_sql.join(";\n")).unwrap();
+        let rows = DbTable::<WidgetRow>::select_all(&conn).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].serial, "7");
+        assert_eq!(rows[0].quantity, 0);
+        assert_eq!(rows[0].owner.as_deref(), Some("frigg"));
+        let migrated = crate::core::schema::get_table_schema(&conn, "widgets").unwrap();
+        let (drift, _) = crate::core::schema::diff_schemas(&WidgetRow::schema(), Some(&migrated)).unwrap();
+        assert!(drift.is_empty(), "{:?}", drift);
+
+        conn.execute_batch(&down_sql.join(";\n")).unwrap();
+        let reverted = crate::core::schema::get_table_schema(&conn, "widgets").unwrap();
+        assert_eq!(reverted, live);
+        let (serial, label): (i64, String) = conn
+            .query_row("SELECT serial, label FROM widgets WHERE id = 'w1'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((serial, label.as_str()), (7, "gear"));
+    }
+}
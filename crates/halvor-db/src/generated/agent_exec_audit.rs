@@ -0,0 +1,15 @@
+//! `agent_exec_audit` table
+
+use crate::core::Table;
+
+#[derive(Debug, Clone, Table)]
+#[table(name = "agent_exec_audit")]
+pub struct AgentExecAuditRow {
+    pub id: String,
+    pub peer_hostname: String,
+    pub command: String,
+    pub args: String,
+    pub reason: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
@@ -0,0 +1,16 @@
+//! `agent_exec_policies` table
+
+use crate::core::Table;
+
+#[derive(Debug, Clone, Table)]
+#[table(name = "agent_exec_policies", unique(peer_hostname, command))]
+pub struct AgentExecPoliciesRow {
+    pub id: String,
+    pub peer_hostname: String,
+    pub command: String,
+    #[table(default = "0")]
+    pub allow_sudo: i64,
+    pub paths: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
@@ -0,0 +1,18 @@
+//! `mesh_certificates` table
+
+use crate::core::Table;
+
+#[derive(Debug, Clone, Table)]
+#[table(name = "mesh_certificates")]
+pub struct MeshCertificatesRow {
+    pub id: String,
+    pub peer_hostname: String,
+    pub serial: String,
+    #[table(unique)]
+    pub fingerprint: String,
+    pub certificate: String,
+    pub expires_at: i64,
+    pub revoked_at: Option<i64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
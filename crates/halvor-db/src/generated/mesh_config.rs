@@ -0,0 +1,16 @@
+//! `mesh_config` table
+
+use crate::core::Table;
+
+#[derive(Debug, Clone, Table)]
+#[table(name = "mesh_config")]
+pub struct MeshConfigRow {
+    pub id: String,
+    #[table(unique)]
+    pub key: String,
+    pub value: String,
+    pub version: i64,
+    pub origin: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
@@ -1,29 +1,58 @@
-// Auto-generated module declarations
-// This file is generated - do not edit manually
-// Run `halvor db generate` to regenerate
+// Row structs, one module per table
+// Each derives `Table`; list new tables in `table_schemas` too
 
+pub mod agent_exec_audit;
+pub mod agent_exec_policies;
 pub mod agent_peers;
 pub mod encrypted_env_data;
 pub mod host_info;
 pub mod join_tokens;
+pub mod mesh_certificates;
+pub mod mesh_config;
 pub mod peer_keys;
+pub mod revoked_peers;
 pub mod settings;
 pub mod smb_servers;
 pub mod update_history;
 
 // Re-export all generated structs
+pub use agent_exec_audit::{AgentExecAuditRow, AgentExecAuditRowData};
+pub use agent_exec_policies::{AgentExecPoliciesRow, AgentExecPoliciesRowData};
 pub use agent_peers::{AgentPeersRow, AgentPeersRowData};
 pub use encrypted_env_data::{EncryptedEnvDataRow, EncryptedEnvDataRowData};
 pub use host_info::{HostInfoRow, HostInfoRowData};
 pub use join_tokens::{JoinTokensRow, JoinTokensRowData};
+pub use mesh_certificates::{MeshCertificatesRow, MeshCertificatesRowData};
+pub use mesh_config::{MeshConfigRow, MeshConfigRowData};
 pub use peer_keys::{PeerKeysRow, PeerKeysRowData};
+pub use revoked_peers::{RevokedPeersRow, RevokedPeersRowData};
 pub use settings::{SettingsRow, SettingsRowData};
 pub use smb_servers::{SmbServersRow, SmbServersRowData};
 pub use update_history::{UpdateHistoryRow, UpdateHistoryRowData};
 
+/// Schema of every table with a row struct, to compare against the database
+pub fn table_schemas() -> Vec<crate::core::schema::TableSchema> {
+    use crate::core::Table;
+    vec![
+        AgentExecAuditRow::schema(),
+        AgentExecPoliciesRow::schema(),
+        AgentPeersRow::schema(),
+        EncryptedEnvDataRow::schema(),
+        HostInfoRow::schema(),
+        JoinTokensRow::schema(),
+        MeshCertificatesRow::schema(),
+        MeshConfigRow::schema(),
+        PeerKeysRow::schema(),
+        RevokedPeersRow::schema(),
+        SettingsRow::schema(),
+        SmbServersRow::schema(),
+        UpdateHistoryRow::schema(),
+    ]
+}
+
 // Re-export wrapper functions with unique names
-// Generic CRUD functions (insert_one, select_one, etc.) are accessible via module paths:
-// e.g., db::settings::insert_one() or db::host_info::insert_one()
+// CRUD functions (insert_one, query, etc.) are associated functions of the row structs:
+// e.g., db::settings::SettingsRow::insert_one() or db::generated::HostInfoRow::query().hostname().eq(h).first()
 
 // Settings wrapper functions
 pub use settings::{get_setting, set_setting};
@@ -0,0 +1,17 @@
+//! `revoked_peers` table
+
+use crate::core::Table;
+
+#[derive(Debug, Clone, Table)]
+#[table(name = "revoked_peers")]
+pub struct RevokedPeersRow {
+    pub id: String,
+    #[table(unique)]
+    pub hostname: String,
+    pub public_key: String,
+    pub revoked_by: String,
+    pub revoked_at: i64,
+    pub revocation: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
@@ -1,12 +1,20 @@
+// Lets `#[derive(Table)]` name this crate as `::halvor_db` from inside it too
+extern crate self as halvor_db;
+
+pub mod change_log;
 pub mod core;
 pub mod generated;
 pub mod helpers;
 pub mod migrate;
 pub mod migrations;
+pub mod pool;
 
 use anyhow::{Context, Result};
-use rusqlite::Connection;
-use std::path::PathBuf;
+use rusqlite::{Connection, Transaction};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+pub use pool::{Pool, PooledConnection};
 
 const DB_FILE_NAME: &str = "halvor.db";
 
@@ -26,25 +34,47 @@ pub fn get_db_path() -> Result<PathBuf> {
     Ok(config_dir.join(DB_FILE_NAME))
 }
 
+static POOL: OnceLock<Pool> = OnceLock::new();
+
 /// Initialize the database and run migrations
 ///
-/// This function automatically runs all pending migrations when the database is first accessed.
-/// Migrations are run sequentially in order, ensuring the database schema is always up to date.
-pub fn init_db() -> Result<Connection> {
-    let db_path = get_db_path()?;
-    let conn = Connection::open(&db_path)
-        .with_context(|| format!("Failed to open database: {}", db_path.display()))?;
-
-    // Run migrations to set up/update schema
-    // This happens automatically on every database access to ensure schema is current
-    migrations::run_migrations(&conn)?;
-
-    Ok(conn)
+/// Opens the process-wide connection pool the first time it is called, which
+/// runs all pending migrations in order. Later calls return the same pool.
+pub fn init_db() -> Result<&'static Pool> {
+    static INIT: Mutex<()> = Mutex::new(());
+
+    if let Some(pool) = POOL.get() {
+        return Ok(pool);
+    }
+    // Only one thread opens the pool and runs the migrations
+    let _init = INIT.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(pool) = POOL.get() {
+        return Ok(pool);
+    }
+    let pool = Pool::open(&get_db_path()?)?;
+    Ok(POOL.get_or_init(|| pool))
+}
+
+/// Copy the database behind `conn` to `destination` with SQLite's online backup API
+///
+/// Unlike copying the file, this is consistent while other connections write.
+pub fn backup_to(conn: &Connection, destination: &Path) -> Result<()> {
+    conn.backup(rusqlite::MAIN_DB, destination, None)
+        .with_context(|| format!("Failed to back up database to {}", destination.display()))
 }
 
-/// Get a database connection
-pub fn get_connection() -> Result<Connection> {
-    init_db()
+/// Get a database connection from the shared pool
+///
+/// The connection goes back to the pool when dropped.
+pub fn get_connection() -> Result<PooledConnection<'static>> {
+    init_db()?.get()
+}
+
+/// Run `f` in a transaction on a pooled connection, committing if it returns `Ok`
+///
+/// See [`Pool::with_transaction`].
+pub fn with_transaction<T>(f: impl FnOnce(&Transaction) -> Result<T>) -> Result<T> {
+    init_db()?.with_transaction(f)
 }
 
 /// Get a database client for custom SQL queries
@@ -54,7 +84,7 @@ pub fn get_client() -> Result<core::DbClient> {
 }
 
 // Re-export generated modules directly for convenience
-// This allows calling db::settings::insert_one() instead of db::generated::settings::insert_one()
+// This allows calling db::settings::SettingsRow::insert_one() instead of db::generated::settings::SettingsRow::insert_one()
 pub mod settings {
     pub use super::generated::settings::*;
 }
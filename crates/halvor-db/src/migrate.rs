@@ -5,6 +5,8 @@
 //! - Running all pending migrations
 //! - Migrating up one step
 //! - Migrating down one step
+//! - Showing the SQL pending migrations would run (dry run)
+//! - Checking the row structs against the database schema
 //! - Listing migrations with interactive selection
 
 use anyhow::{Context, Result};
@@ -29,6 +31,75 @@ pub fn migrate_all() -> Result<()> {
     Ok(())
 }
 
+/// Print the SQL each pending migration would execute, without running them
+pub fn migrate_dry_run() -> Result<()> {
+    // Opened directly: `get_connection` would run the migrations
+    let db_path = crate::get_db_path()?;
+    let conn = Connection::open(&db_path)
+        .with_context(|| format!("Failed to open database: {}", db_path.display()))?;
+
+    println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    println!("Pending migrations (dry run)");
+    println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    println!();
+
+    let pending = crate::migrations::pending_migration_sql(&conn)?;
+    if pending.is_empty() {
+        println!("No pending migrations to run");
+        return Ok(());
+    }
+
+    for (version, name, statements) in &pending {
+        println!("-- Migration {}: {}", version, name);
+        for sql in statements {
+            println!("{};", sql.trim().trim_end_matches(';'));
+        }
+        println!();
+    }
+    println!(
+        "{} pending migration(s); nothing was changed. Run without --dry-run to apply.",
+        pending.len()
+    );
+
+    Ok(())
+}
+
+/// Compare every row struct with the migrated database schema
+///
+/// Fails if a table is missing, or a column's type, nullability or unique
+/// key differs from what its struct expects.
+pub fn check_schema() -> Result<()> {
+    let conn = crate::get_connection()?;
+
+    println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    println!("Checking row structs against the database schema");
+    println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    println!();
+
+    let tables = crate::generated::table_schemas();
+    let diffs = crate::core::schema::diff_database(&conn, &tables)?;
+    if diffs.is_empty() {
+        println!("✓ All {} tables match their row structs", tables.len());
+        return Ok(());
+    }
+
+    for (table, up_sql, _) in &diffs {
+        println!("-- {}", table);
+        for sql in up_sql {
+            if sql.starts_with("--") {
+                println!("{}", sql);
+            } else {
+                println!("{};", sql);
+            }
+        }
+        println!();
+    }
+    anyhow::bail!(
+        "{} table(s) differ from their row structs; add a migration (halvor db migrate generate <description>)",
+        diffs.len()
+    )
+}
+
 /// Migrate up one step
 pub fn migrate_up() -> Result<()> {
     let conn = crate::get_connection()?;
@@ -195,6 +266,10 @@ fn migrate_to_version(conn: &Connection, target_version: u32) -> Result<()> {
 }
 
 /// Generate a new migration file
+///
+/// The migration brings the database in line with the row structs, using the
+/// up and down SQL from the schema diff. When they already match, the file
+/// gets placeholders to fill in by hand.
 pub fn generate_migration(description: Vec<String>) -> Result<()> {
     if description.is_empty() {
         anyhow::bail!(
@@ -203,13 +278,18 @@ pub fn generate_migration(description: Vec<String>) -> Result<()> {
     }
 
     let desc = description.join("_").to_lowercase().replace(" ", "_");
-    create_migration_file(&desc, &[], &[])
+    let conn = crate::get_connection()?;
+    let diffs = crate::core::schema::diff_database(&conn, &crate::generated::table_schemas())?;
+    let up_sql: Vec<String> = diffs.iter().flat_map(|(_, up, _)| up.clone()).collect();
+    // Undo the tables in the reverse order
+    let down_sql: Vec<String> = diffs.iter().rev().flat_map(|(_, _, down)| down.clone()).collect();
+    create_migration_file(&desc, &up_sql, &down_sql)
 }
 
 /// Helper to create migration file
 fn create_migration_file(desc: &str, up_sql: &[String], down_sql: &[String]) -> Result<()> {
     // Find the highest migration number
-    let migrations_dir = PathBuf::from("src/db/migrations");
+    let migrations_dir = PathBuf::from("crates/halvor-db/src/migrations");
     let mut max_version = 0u32;
 
     if migrations_dir.exists() {
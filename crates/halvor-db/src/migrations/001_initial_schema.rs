@@ -75,3 +75,14 @@ pub fn up(conn: &Connection) -> Result<()> {
 
     Ok(())
 }
+
+/// Rollback: Drop the initial tables
+///
+/// The migrations table stays, since rolling back removes this migration's row from it.
+pub fn down(conn: &Connection) -> Result<()> {
+    for table in ["encrypted_env_data", "host_info", "update_history", "settings"] {
+        conn.execute(&format!("DROP TABLE IF EXISTS {}", table), [])
+            .with_context(|| format!("Failed to drop {} table", table))?;
+    }
+    Ok(())
+}
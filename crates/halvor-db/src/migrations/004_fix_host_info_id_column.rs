@@ -167,9 +167,11 @@ pub fn up(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-/// Rollback: This migration is not easily reversible
+/// Rollback: Nothing to undo
+///
+/// `up` only repairs host_info tables created before it had an id column, and
+/// the id column is what migration 001 creates. Removing it would corrupt
+/// the table rather than restore the previous version.
 pub fn down(_conn: &Connection) -> Result<()> {
-    // Rollback would require removing the id column, which SQLite doesn't support easily
-    // This migration is considered one-way
-    anyhow::bail!("Migration 004 does not support rollback");
+    Ok(())
 }
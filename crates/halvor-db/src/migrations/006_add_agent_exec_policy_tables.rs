@@ -0,0 +1,66 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Migration 006: Add agent command execution policy and audit tables
+pub fn up(conn: &Connection) -> Result<()> {
+    // Per-peer command allowlist (deny-by-default; '*' matches any peer or command)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS agent_exec_policies (
+            id TEXT PRIMARY KEY,
+            peer_hostname TEXT NOT NULL,
+            command TEXT NOT NULL,
+            allow_sudo INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            UNIQUE(peer_hostname, command)
+        )",
+        [],
+    )
+    .context("Failed to create agent_exec_policies table")?;
+
+    // Audit log of rejected ExecuteCommand requests
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS agent_exec_audit (
+            id TEXT PRIMARY KEY,
+            peer_hostname TEXT NOT NULL,
+            command TEXT NOT NULL,
+            args TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create agent_exec_audit table")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_agent_exec_policies_peer ON agent_exec_policies(peer_hostname)",
+        [],
+    )
+    .context("Failed to create agent_exec_policies peer index")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_agent_exec_audit_created_at ON agent_exec_audit(created_at)",
+        [],
+    )
+    .context("Failed to create agent_exec_audit created_at index")?;
+
+    Ok(())
+}
+
+/// Rollback migration 006
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute("DROP INDEX IF EXISTS idx_agent_exec_audit_created_at", [])
+        .context("Failed to drop agent_exec_audit created_at index")?;
+
+    conn.execute("DROP INDEX IF EXISTS idx_agent_exec_policies_peer", [])
+        .context("Failed to drop agent_exec_policies peer index")?;
+
+    conn.execute("DROP TABLE IF EXISTS agent_exec_audit", [])
+        .context("Failed to drop agent_exec_audit table")?;
+
+    conn.execute("DROP TABLE IF EXISTS agent_exec_policies", [])
+        .context("Failed to drop agent_exec_policies table")?;
+
+    Ok(())
+}
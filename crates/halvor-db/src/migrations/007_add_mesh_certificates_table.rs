@@ -0,0 +1,41 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Migration 007: Add mesh certificates table
+pub fn up(conn: &Connection) -> Result<()> {
+    // Certificates this node's mesh CA has issued (revoked_at is set on peer removal)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mesh_certificates (
+            id TEXT PRIMARY KEY,
+            peer_hostname TEXT NOT NULL,
+            serial TEXT NOT NULL,
+            fingerprint TEXT NOT NULL UNIQUE,
+            certificate TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            revoked_at INTEGER,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create mesh_certificates table")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_mesh_certificates_peer ON mesh_certificates(peer_hostname)",
+        [],
+    )
+    .context("Failed to create mesh_certificates peer index")?;
+
+    Ok(())
+}
+
+/// Rollback migration 007
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute("DROP INDEX IF EXISTS idx_mesh_certificates_peer", [])
+        .context("Failed to drop mesh_certificates peer index")?;
+
+    conn.execute("DROP TABLE IF EXISTS mesh_certificates", [])
+        .context("Failed to drop mesh_certificates table")?;
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Migration 008: Add mesh config table
+pub fn up(conn: &Connection) -> Result<()> {
+    // Key/value config replicated across the mesh. version is a hybrid logical
+    // clock timestamp; the highest (version, origin) wins.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS mesh_config (
+            id TEXT PRIMARY KEY,
+            key TEXT NOT NULL UNIQUE,
+            value TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            origin TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create mesh_config table")?;
+
+    Ok(())
+}
+
+/// Rollback migration 008
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute("DROP TABLE IF EXISTS mesh_config", [])
+        .context("Failed to drop mesh_config table")?;
+
+    Ok(())
+}
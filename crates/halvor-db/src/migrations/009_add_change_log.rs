@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// A table whose changes are logged
+struct TrackedTable {
+    name: &'static str,
+    /// Columns that identify a row across nodes
+    key_columns: &'static [&'static str],
+    /// For tables with bookkeeping columns, the columns worth syncing
+    watched_columns: Option<&'static [&'static str]>,
+}
+
+const fn tracked(name: &'static str, key_columns: &'static [&'static str]) -> TrackedTable {
+    TrackedTable {
+        name,
+        key_columns,
+        watched_columns: None,
+    }
+}
+
+/// Tables whose changes are logged
+///
+/// Node-local tables aren't: shared secrets and join tokens must not leave
+/// the node, and the exec audit and update history would only bloat the log.
+const TRACKED_TABLES: &[TrackedTable] = &[
+    tracked("settings", &["key"]),
+    tracked("host_info", &["hostname"]),
+    tracked("encrypted_env_data", &["hostname", "key"]),
+    tracked("smb_servers", &["server_name"]),
+    TrackedTable {
+        name: "agent_peers",
+        key_columns: &["hostname"],
+        watched_columns: Some(&["hostname", "tailscale_ip", "tailscale_hostname", "public_key"]),
+    },
+    tracked("agent_exec_policies", &["peer_hostname", "command"]),
+    tracked("mesh_certificates", &["fingerprint"]),
+    tracked("mesh_config", &["key"]),
+];
+
+/// Migration 009: Add change log and the triggers that fill it
+pub fn up(conn: &Connection) -> Result<()> {
+    crate::change_log::create_table(conn)?;
+
+    for table in TRACKED_TABLES {
+        crate::change_log::track_table(conn, table.name, table.key_columns, table.watched_columns)
+            .with_context(|| format!("Failed to track changes to {}", table.name))?;
+    }
+
+    Ok(())
+}
+
+/// Rollback migration 009
+pub fn down(conn: &Connection) -> Result<()> {
+    for table in TRACKED_TABLES {
+        crate::change_log::untrack_table(conn, table.name)?;
+    }
+
+    conn.execute("DROP TABLE IF EXISTS change_log", [])
+        .context("Failed to drop change_log table")?;
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Migration 010: Add revoked peers table
+pub fn up(conn: &Connection) -> Result<()> {
+    // Peers removed from the mesh. public_key is the key that was revoked, so
+    // the host can be admitted again with a new one; revocation is the signed
+    // revocation as JSON, passed on to peers during sync.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS revoked_peers (
+            id TEXT PRIMARY KEY,
+            hostname TEXT NOT NULL UNIQUE,
+            public_key TEXT NOT NULL,
+            revoked_by TEXT NOT NULL,
+            revoked_at INTEGER NOT NULL,
+            revocation TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create revoked_peers table")?;
+
+    Ok(())
+}
+
+/// Rollback migration 010
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute("DROP TABLE IF EXISTS revoked_peers", [])
+        .context("Failed to drop revoked_peers table")?;
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+
+/// Migration 011: Store join tokens by hash, with use limits, hostname scopes and revocation
+///
+/// Tokens used to be stored in full, so anyone who could read the database
+/// could join with them. Existing tokens keep working: they are found by the
+/// hash of the token the joiner presents.
+pub fn up(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE join_tokens_new (
+            id TEXT PRIMARY KEY,
+            token_id TEXT NOT NULL UNIQUE,
+            token_hash TEXT NOT NULL UNIQUE,
+            issuer_hostname TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            max_uses INTEGER NOT NULL DEFAULT 1,
+            uses INTEGER NOT NULL DEFAULT 0,
+            allowed_hostnames TEXT,
+            used_by_hostname TEXT,
+            used_at INTEGER,
+            revoked_at INTEGER,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create join_tokens_new table")?;
+
+    // Older tokens were single-use; the row id stands in for the token's own ID
+    let mut stmt = conn.prepare(
+        "SELECT id, token, issuer_hostname, expires_at, used, used_by_hostname, used_at,
+                created_at, updated_at
+         FROM join_tokens",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, Option<i64>>(6)?,
+                row.get::<_, i64>(7)?,
+                row.get::<_, i64>(8)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read join tokens")?;
+    drop(stmt);
+
+    for (id, token, issuer, expires_at, used, used_by, used_at, created_at, updated_at) in rows {
+        conn.execute(
+            "INSERT INTO join_tokens_new
+                (id, token_id, token_hash, issuer_hostname, expires_at, max_uses, uses,
+                 used_by_hostname, used_at, created_at, updated_at)
+             VALUES (?1, ?1, ?2, ?3, ?4, 1, ?5, ?6, ?7, ?8, ?9)",
+            rusqlite::params![
+                id,
+                hash_token(&token),
+                issuer,
+                expires_at,
+                used,
+                used_by,
+                used_at,
+                created_at,
+                updated_at
+            ],
+        )
+        .context("Failed to copy join token")?;
+    }
+
+    // Databases migrated before migration 013 still log join tokens
+    crate::change_log::untrack_table(conn, "join_tokens")?;
+    conn.execute("DROP TABLE join_tokens", [])
+        .context("Failed to drop old join_tokens table")?;
+    conn.execute("ALTER TABLE join_tokens_new RENAME TO join_tokens", [])
+        .context("Failed to rename join_tokens_new table")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_join_tokens_expires_at ON join_tokens(expires_at)",
+        [],
+    )
+    .context("Failed to create join_tokens expires_at index")?;
+
+    Ok(())
+}
+
+/// Rollback migration 011
+///
+/// Only hashes were kept, so tokens issued since can't be restored; their
+/// rows come back marked as used.
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE join_tokens_old (
+            id TEXT PRIMARY KEY,
+            token TEXT NOT NULL UNIQUE,
+            issuer_hostname TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            used INTEGER NOT NULL DEFAULT 0,
+            used_by_hostname TEXT,
+            used_at INTEGER,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context("Failed to create join_tokens_old table")?;
+    conn.execute(
+        "INSERT INTO join_tokens_old
+            (id, token, issuer_hostname, expires_at, used, used_by_hostname, used_at,
+             created_at, updated_at)
+         SELECT id, 'sha256:' || token_hash, issuer_hostname, expires_at, 1, used_by_hostname,
+                used_at, created_at, updated_at
+         FROM join_tokens",
+        [],
+    )
+    .context("Failed to copy join tokens")?;
+
+    crate::change_log::untrack_table(conn, "join_tokens")?;
+    conn.execute("DROP TABLE join_tokens", [])
+        .context("Failed to drop join_tokens table")?;
+    conn.execute("ALTER TABLE join_tokens_old RENAME TO join_tokens", [])
+        .context("Failed to rename join_tokens_old table")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_join_tokens_token ON join_tokens(token)",
+        [],
+    )
+    .context("Failed to create join_tokens token index")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_join_tokens_expires_at ON join_tokens(expires_at)",
+        [],
+    )
+    .context("Failed to create join_tokens expires_at index")?;
+
+    Ok(())
+}
+
+/// SHA-256 of an encoded join token, as lowercase hex (as `mesh::hash_token` computes it)
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Migration 012: Give encrypted_env_data UUID primary keys like every other table
+///
+/// The table was created with an INTEGER AUTOINCREMENT id, which the row
+/// struct can neither read nor insert. Existing rows get new UUIDs; rows are
+/// identified by hostname and key everywhere else, including the change log.
+pub fn up(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE encrypted_env_data_new (
+            id TEXT PRIMARY KEY,
+            hostname TEXT,
+            key TEXT NOT NULL,
+            encrypted_value TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            UNIQUE(hostname, key)
+        )",
+        [],
+    )
+    .context("Failed to create encrypted_env_data_new table")?;
+
+    let mut stmt = conn.prepare("SELECT id FROM encrypted_env_data")?;
+    let ids = stmt
+        .query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read encrypted env data")?;
+    drop(stmt);
+
+    for id in ids {
+        conn.execute(
+            "INSERT INTO encrypted_env_data_new
+                (id, hostname, key, encrypted_value, created_at, updated_at)
+             SELECT ?2, hostname, key, encrypted_value, created_at, updated_at
+             FROM encrypted_env_data WHERE id = ?1",
+            rusqlite::params![id, uuid::Uuid::new_v4().to_string()],
+        )
+        .context("Failed to copy encrypted env data")?;
+    }
+
+    replace_table(conn)
+}
+
+/// Rollback migration 012
+///
+/// SQLite numbers the rows again.
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE encrypted_env_data_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname TEXT,
+            key TEXT NOT NULL,
+            encrypted_value TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            UNIQUE(hostname, key)
+        )",
+        [],
+    )
+    .context("Failed to create encrypted_env_data_new table")?;
+    conn.execute(
+        "INSERT INTO encrypted_env_data_new
+            (hostname, key, encrypted_value, created_at, updated_at)
+         SELECT hostname, key, encrypted_value, created_at, updated_at
+         FROM encrypted_env_data ORDER BY created_at",
+        [],
+    )
+    .context("Failed to copy encrypted env data")?;
+
+    replace_table(conn)
+}
+
+/// Swap encrypted_env_data_new in for encrypted_env_data, keeping its change log triggers
+fn replace_table(conn: &Connection) -> Result<()> {
+    // The change log triggers belong to the old table
+    crate::change_log::untrack_table(conn, "encrypted_env_data")?;
+    conn.execute("DROP TABLE encrypted_env_data", [])
+        .context("Failed to drop old encrypted_env_data table")?;
+    conn.execute("ALTER TABLE encrypted_env_data_new RENAME TO encrypted_env_data", [])
+        .context("Failed to rename encrypted_env_data_new table")?;
+    crate::change_log::track_table(conn, "encrypted_env_data", &["hostname", "key"], None)?;
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Tables migration 009 used to log changes to, which stay on this node
+const NODE_LOCAL_TABLES: &[&str] = &["peer_keys", "join_tokens", "agent_exec_audit", "update_history"];
+
+/// Migration 013: Stop logging changes to node-local tables
+///
+/// Shared secrets and join tokens must never be replicated, and the exec
+/// audit and update history only filled the change log. Migration 009 no
+/// longer tracks them; this removes the triggers and log entries from
+/// databases migrated before that.
+pub fn up(conn: &Connection) -> Result<()> {
+    for table in NODE_LOCAL_TABLES {
+        crate::change_log::untrack_table(conn, table)?;
+        conn.execute("DELETE FROM change_log WHERE table_name = ?1", [table])
+            .with_context(|| format!("Failed to clear logged changes to {}", table))?;
+    }
+
+    Ok(())
+}
+
+/// Rollback: Nothing to undo
+///
+/// Migration 009 doesn't track these tables anymore, so there are no
+/// triggers to put back.
+pub fn down(_conn: &Connection) -> Result<()> {
+    Ok(())
+}
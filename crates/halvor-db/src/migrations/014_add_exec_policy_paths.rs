@@ -0,0 +1,21 @@
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+/// Migration 014: Add the directories a file operation rule is limited to
+///
+/// `paths` holds comma-separated absolute directories; rules without any
+/// (all rules migrated from before) apply to every path.
+pub fn up(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE agent_exec_policies ADD COLUMN paths TEXT", [])
+        .context("Failed to add paths to agent_exec_policies")?;
+
+    Ok(())
+}
+
+/// Rollback migration 014
+pub fn down(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE agent_exec_policies DROP COLUMN paths", [])
+        .context("Failed to drop paths from agent_exec_policies")?;
+
+    Ok(())
+}
@@ -16,6 +16,33 @@ mod migration_004_fix_host_info_id_column {
 mod migration_005_add_agent_mesh_tables {
     include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/005_add_agent_mesh_tables.rs"));
 }
+mod migration_006_add_agent_exec_policy_tables {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/006_add_agent_exec_policy_tables.rs"));
+}
+mod migration_007_add_mesh_certificates_table {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/007_add_mesh_certificates_table.rs"));
+}
+mod migration_008_add_mesh_config_table {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/008_add_mesh_config_table.rs"));
+}
+mod migration_009_add_change_log {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/009_add_change_log.rs"));
+}
+mod migration_010_add_revoked_peers_table {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/010_add_revoked_peers_table.rs"));
+}
+mod migration_011_hash_join_tokens {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/011_hash_join_tokens.rs"));
+}
+mod migration_012_text_ids_for_encrypted_env_data {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/012_text_ids_for_encrypted_env_data.rs"));
+}
+mod migration_013_untrack_node_local_tables {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/013_untrack_node_local_tables.rs"));
+}
+mod migration_014_add_exec_policy_paths {
+    include!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/migrations/014_add_exec_policy_paths.rs"));
+}
 
 
 const MIGRATIONS: &[Migration] = &[
@@ -23,7 +50,7 @@ const MIGRATIONS: &[Migration] = &[
         version: 1,
         name: "initial_schema",
         up: migration_001_initial_schema::up,
-        down: None,
+        down: Some(migration_001_initial_schema::down),
     },
     Migration {
         version: 2,
@@ -49,5 +76,59 @@ const MIGRATIONS: &[Migration] = &[
         up: migration_005_add_agent_mesh_tables::up,
         down: Some(migration_005_add_agent_mesh_tables::down),
     },
+    Migration {
+        version: 6,
+        name: "add_agent_exec_policy_tables",
+        up: migration_006_add_agent_exec_policy_tables::up,
+        down: Some(migration_006_add_agent_exec_policy_tables::down),
+    },
+    Migration {
+        version: 7,
+        name: "add_mesh_certificates_table",
+        up: migration_007_add_mesh_certificates_table::up,
+        down: Some(migration_007_add_mesh_certificates_table::down),
+    },
+    Migration {
+        version: 8,
+        name: "add_mesh_config_table",
+        up: migration_008_add_mesh_config_table::up,
+        down: Some(migration_008_add_mesh_config_table::down),
+    },
+    Migration {
+        version: 9,
+        name: "add_change_log",
+        up: migration_009_add_change_log::up,
+        down: Some(migration_009_add_change_log::down),
+    },
+    Migration {
+        version: 10,
+        name: "add_revoked_peers_table",
+        up: migration_010_add_revoked_peers_table::up,
+        down: Some(migration_010_add_revoked_peers_table::down),
+    },
+    Migration {
+        version: 11,
+        name: "hash_join_tokens",
+        up: migration_011_hash_join_tokens::up,
+        down: Some(migration_011_hash_join_tokens::down),
+    },
+    Migration {
+        version: 12,
+        name: "text_ids_for_encrypted_env_data",
+        up: migration_012_text_ids_for_encrypted_env_data::up,
+        down: Some(migration_012_text_ids_for_encrypted_env_data::down),
+    },
+    Migration {
+        version: 13,
+        name: "untrack_node_local_tables",
+        up: migration_013_untrack_node_local_tables::up,
+        down: Some(migration_013_untrack_node_local_tables::down),
+    },
+    Migration {
+        version: 14,
+        name: "add_exec_policy_paths",
+        up: migration_014_add_exec_policy_paths::up,
+        down: Some(migration_014_add_exec_policy_paths::down),
+    },
 
 ];
@@ -66,8 +66,10 @@ pub fn generate_migrations_cli() -> Result<()> {
             mod_name, file_name
         ));
 
-        // Check if this is the initial schema (version 1, name "initial_schema")
-        let has_down = version != &1 || name != "initial_schema";
+        // Migrations without a rollback just leave out `down`
+        let source = fs::read_to_string(migrations_dir.join(file_name))
+            .with_context(|| format!("Failed to read migration {}", file_name))?;
+        let has_down = source.contains("pub fn down(");
 
         migrations_array.push_str(&format!(
             "    Migration {{\n        version: {},\n        name: \"{}\",\n        up: {}::up,\n        down: {},\n    }},\n",
@@ -1,6 +1,9 @@
 use anyhow::{Context, Result};
-use rusqlite::Connection;
+use rusqlite::trace::{TraceEvent, TraceEventCodes};
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 pub mod generator;
 
@@ -119,23 +122,20 @@ fn remove_migration_record(conn: &Connection, version: u32) -> Result<()> {
 /// Run all pending migrations
 ///
 /// This function automatically runs any migrations that haven't been applied yet,
-/// in sequential order based on their version number.
+/// in sequential order based on their version number. Each migration runs in
+/// its own transaction together with its row in `migrations`, so a failure
+/// leaves the database as the previous migration left it. The database is
+/// backed up before the first pending migration runs.
 pub fn run_migrations(conn: &Connection) -> Result<()> {
     let current_version = get_current_migration_version(conn)?;
+    if !MIGRATIONS.iter().any(|m| m.version > current_version) {
+        return Ok(());
+    }
 
+    backup_before_migrating(conn, current_version)?;
     for migration in MIGRATIONS {
         if migration.version > current_version {
-            println!(
-                "Running migration {}: {}",
-                migration.version, migration.name
-            );
-            (migration.up)(conn).with_context(|| {
-                format!(
-                    "Failed to run migration {}: {}",
-                    migration.version, migration.name
-                )
-            })?;
-            record_migration(conn, migration.version, migration.name)?;
+            apply_migration(conn, migration)?;
         }
     }
 
@@ -146,27 +146,171 @@ pub fn run_migrations(conn: &Connection) -> Result<()> {
 pub fn migrate_up(conn: &Connection) -> Result<()> {
     let current_version = get_current_migration_version(conn)?;
 
-    for migration in MIGRATIONS {
-        if migration.version > current_version {
-            println!(
-                "Running migration {}: {}",
-                migration.version, migration.name
-            );
-            (migration.up)(conn).with_context(|| {
-                format!(
-                    "Failed to run migration {}: {}",
-                    migration.version, migration.name
-                )
-            })?;
-            record_migration(conn, migration.version, migration.name)?;
-            return Ok(());
-        }
+    if let Some(migration) = MIGRATIONS.iter().find(|m| m.version > current_version) {
+        backup_before_migrating(conn, current_version)?;
+        apply_migration(conn, migration)?;
+        return Ok(());
     }
 
     println!("No pending migrations to run");
     Ok(())
 }
 
+/// Run `migration` and record it in one transaction
+///
+/// The transaction takes the write lock up front, so when two processes open
+/// the database at once only one of them runs the migration. Returns false if
+/// another process already had.
+fn apply_migration(conn: &Connection, migration: &Migration) -> Result<bool> {
+    without_foreign_keys(conn, || apply_migration_tx(conn, migration))
+}
+
+fn apply_migration_tx(conn: &Connection, migration: &Migration) -> Result<bool> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+        .context("Failed to start migration transaction")?;
+    if get_current_migration_version(&tx)? >= migration.version {
+        return Ok(false);
+    }
+
+    println!(
+        "Running migration {}: {}",
+        migration.version, migration.name
+    );
+    (migration.up)(&tx).with_context(|| {
+        format!(
+            "Failed to run migration {}: {}",
+            migration.version, migration.name
+        )
+    })?;
+    record_migration(&tx, migration.version, migration.name)?;
+    check_foreign_keys(&tx).with_context(|| {
+        format!(
+            "Migration {}: {} broke foreign keys",
+            migration.version, migration.name
+        )
+    })?;
+    tx.commit().with_context(|| {
+        format!(
+            "Failed to commit migration {}: {}",
+            migration.version, migration.name
+        )
+    })?;
+
+    Ok(true)
+}
+
+/// Run `f` with foreign key enforcement off
+///
+/// Migrations rebuild tables (see [`crate::core::schema::rebuild_table_sql`]),
+/// and with enforcement on, dropping the old table deletes the rows that
+/// reference it. SQLite ignores the setting inside a transaction, so it is
+/// changed before the migration's transaction starts, and the migration
+/// checks the keys itself before committing.
+fn without_foreign_keys<T>(conn: &Connection, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let enabled: bool = conn.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let result = f();
+    conn.pragma_update(None, "foreign_keys", enabled)?;
+    result
+}
+
+/// Fail if any row references a row that doesn't exist
+fn check_foreign_keys(conn: &Connection) -> Result<()> {
+    let violations: i64 =
+        conn.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0))?;
+    if violations > 0 {
+        anyhow::bail!("{} rows reference rows that don't exist", violations);
+    }
+    Ok(())
+}
+
+/// Copy the database aside before migrating it from `current_version`
+///
+/// Backups go to `backups/` next to the database file, named after the time
+/// and the version they were taken at. New and in-memory databases have
+/// nothing worth keeping and aren't backed up.
+fn backup_before_migrating(conn: &Connection, current_version: u32) -> Result<Option<PathBuf>> {
+    if current_version == 0 {
+        return Ok(None);
+    }
+    let Some(db_path) = conn.path().filter(|path| !path.is_empty()).map(Path::new) else {
+        return Ok(None);
+    };
+
+    let backup_dir = db_path
+        .parent()
+        .map(|dir| dir.join("backups"))
+        .context("Database path has no parent directory")?;
+    std::fs::create_dir_all(&backup_dir).with_context(|| {
+        format!("Failed to create backup directory: {}", backup_dir.display())
+    })?;
+    let stem = db_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "halvor".to_string());
+    let backup_path = backup_dir.join(format!(
+        "{}-{}-v{}.db",
+        stem,
+        chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+        current_version
+    ));
+
+    crate::backup_to(conn, &backup_path)
+        .context("Failed to back up the database before migrating; no migrations were run")?;
+    println!("Backed up database to {}", backup_path.display());
+    Ok(Some(backup_path))
+}
+
+thread_local! {
+    /// Statements seen by [`trace_statement`] on this thread
+    static TRACED_SQL: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn trace_statement(event: TraceEvent<'_>) {
+    if let TraceEvent::Stmt(stmt, sql) = event {
+        // Statements run by triggers are reported as comments
+        if sql.starts_with("--") {
+            return;
+        }
+        let sql = stmt.expanded_sql().unwrap_or_else(|| sql.to_string());
+        TRACED_SQL.with(|traced| traced.borrow_mut().push(sql));
+    }
+}
+
+/// The SQL each pending migration would execute, without changing the database
+///
+/// Migrations are Rust functions, so their SQL is found by running them on an
+/// in-memory copy of the database and tracing the statements they execute
+/// (including reads, and the row recording the migration).
+pub fn pending_migration_sql(conn: &Connection) -> Result<Vec<(u32, &'static str, Vec<String>)>> {
+    let current_version = get_current_migration_version(conn)?;
+
+    let mut copy = Connection::open_in_memory()?;
+    rusqlite::backup::Backup::new(conn, &mut copy)?
+        .run_to_completion(256, std::time::Duration::ZERO, None)
+        .context("Failed to copy the database")?;
+    copy.pragma_update(None, "foreign_keys", false)?;
+    copy.trace_v2(TraceEventCodes::SQLITE_TRACE_STMT, Some(trace_statement));
+
+    let mut pending = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current_version) {
+        TRACED_SQL.with(|traced| traced.borrow_mut().clear());
+        let result = (migration.up)(&copy)
+            .and_then(|()| record_migration(&copy, migration.version, migration.name));
+        let statements = TRACED_SQL.with(|traced| traced.take());
+        result.with_context(|| {
+            format!(
+                "Migration {} ({}) failed on a copy of the database",
+                migration.version, migration.name
+            )
+        })?;
+        pending.push((migration.version, migration.name, statements));
+    }
+    copy.trace_v2(TraceEventCodes::empty(), None);
+
+    Ok(pending)
+}
+
 /// Rollback the last applied migration (migrate down one)
 pub fn migrate_down(conn: &Connection) -> Result<()> {
     let current_version = get_current_migration_version(conn)?;
@@ -184,13 +328,19 @@ pub fn migrate_down(conn: &Connection) -> Result<()> {
                     "Rolling back migration {}: {}",
                     migration.version, migration.name
                 );
-                down_fn(conn).with_context(|| {
-                    format!(
-                        "Failed to rollback migration {}: {}",
-                        migration.version, migration.name
-                    )
+                without_foreign_keys(conn, || {
+                    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+                        .context("Failed to start rollback transaction")?;
+                    down_fn(&tx).with_context(|| {
+                        format!(
+                            "Failed to rollback migration {}: {}",
+                            migration.version, migration.name
+                        )
+                    })?;
+                    remove_migration_record(&tx, migration.version)?;
+                    check_foreign_keys(&tx).context("Rollback broke foreign keys")?;
+                    tx.commit().context("Failed to commit rollback")
                 })?;
-                remove_migration_record(conn, migration.version)?;
                 println!("✓ Successfully rolled back migration {}", migration.version);
             } else {
                 anyhow::bail!(
@@ -232,3 +382,198 @@ pub fn get_migration_status(conn: &Connection) -> Result<Vec<(u32, String, bool,
 
     Ok(status)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database with every migration up to and including `version` applied
+    fn migrated_to(conn: &Connection, version: u32) {
+        for migration in MIGRATIONS.iter().filter(|m| m.version <= version) {
+            assert!(apply_migration(conn, migration).unwrap());
+        }
+    }
+
+    fn table_exists(conn: &Connection, table: &str) -> bool {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            > 0
+    }
+
+    /// Every table's schema and row count
+    type Snapshot = (
+        std::collections::BTreeMap<String, crate::core::schema::TableSchema>,
+        std::collections::BTreeMap<String, i64>,
+    );
+
+    fn snapshot(conn: &Connection) -> Snapshot {
+        let mut schemas = std::collections::BTreeMap::new();
+        let mut counts = std::collections::BTreeMap::new();
+        for (table, mut schema) in crate::core::schema::get_database_schema(conn).unwrap() {
+            // A rebuilt table may list its unique keys in another order
+            schema.unique_keys.iter_mut().for_each(|key| key.sort());
+            schema.unique_keys.sort();
+            let count = conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap();
+            counts.insert(table.clone(), count);
+            schemas.insert(table, schema);
+        }
+        (schemas, counts)
+    }
+
+    /// Insert a row into every table, made up from its column types
+    ///
+    /// Text columns all get the same value, so foreign keys between the new
+    /// rows hold once they are all in.
+    fn seed(conn: &Connection, round: u32) {
+        conn.pragma_update(None, "foreign_keys", false).unwrap();
+        for (table, schema) in crate::core::schema::get_database_schema(conn).unwrap() {
+            let (columns, values): (Vec<String>, Vec<String>) = schema
+                .columns
+                .iter()
+                .filter(|c| !(c.primary_key && c.sql_type == "INTEGER"))
+                .map(|c| {
+                    let value = match c.sql_type.as_str() {
+                        "INTEGER" | "REAL" => round.to_string(),
+                        "BLOB" => format!("x'{:02x}'", round),
+                        _ => format!("'seed-{}'", round),
+                    };
+                    (c.name.clone(), value)
+                })
+                .unzip();
+            conn.execute(
+                &format!("INSERT INTO {} ({}) VALUES ({})", table, columns.join(", "), values.join(", ")),
+                [],
+            )
+            .unwrap_or_else(|e| panic!("seeding {}: {}", table, e));
+        }
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+    }
+
+    #[test]
+    fn test_every_migration_rolls_back_and_reapplies() {
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in MIGRATIONS {
+            seed(&conn, migration.version);
+            let before = snapshot(&conn);
+
+            assert!(apply_migration(&conn, migration).unwrap());
+            let after = snapshot(&conn);
+
+            assert!(migration.down.is_some(), "migration {} has no down", migration.version);
+            migrate_down(&conn).unwrap();
+            assert_eq!(get_current_migration_version(&conn).unwrap(), migration.version - 1);
+            assert_eq!(snapshot(&conn), before, "migration {} down", migration.version);
+
+            assert!(apply_migration(&conn, migration).unwrap());
+            assert_eq!(snapshot(&conn), after, "migration {} up again", migration.version);
+        }
+    }
+
+    #[test]
+    fn test_node_local_tables_are_not_change_logged() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrated_to(&conn, 12);
+        // As migration 009 left databases migrated before it stopped tracking peer_keys
+        crate::change_log::track_table(&conn, "peer_keys", &["peer_hostname"], None).unwrap();
+        conn.execute(
+            "INSERT INTO change_log (table_name, row_key, operation, changed_at)
+             VALUES ('peer_keys', 'frigg', 'insert', 0)",
+            [],
+        )
+        .unwrap();
+        run_migrations(&conn).unwrap();
+
+        let triggers: Vec<String> = conn
+            .prepare("SELECT DISTINCT tbl_name FROM sqlite_master WHERE type = 'trigger' ORDER BY tbl_name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        for table in ["peer_keys", "join_tokens", "agent_exec_audit", "update_history"] {
+            assert!(!triggers.iter().any(|t| t == table), "{} is change logged", table);
+        }
+        assert!(triggers.iter().any(|t| t == "agent_peers"));
+        let logged: i64 = conn
+            .query_row("SELECT COUNT(*) FROM change_log WHERE table_name = 'peer_keys'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(logged, 0);
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrated_to(&conn, 1);
+
+        let failing = Migration {
+            version: 1000,
+            name: "half_done",
+            up: |conn| {
+                conn.execute("CREATE TABLE half_done (id TEXT PRIMARY KEY)", [])?;
+                anyhow::bail!("crashed halfway")
+            },
+            down: None,
+        };
+        assert!(apply_migration(&conn, &failing).is_err());
+        assert!(!table_exists(&conn, "half_done"));
+        assert_eq!(get_current_migration_version(&conn).unwrap(), 1);
+
+        // Already applied migrations are skipped
+        assert!(!apply_migration(&conn, &MIGRATIONS[0]).unwrap());
+    }
+
+    #[test]
+    fn test_dry_run_traces_sql_without_migrating() {
+        let conn = Connection::open_in_memory().unwrap();
+        let last = MIGRATIONS.last().unwrap().version;
+        migrated_to(&conn, last - 1);
+
+        let pending = pending_migration_sql(&conn).unwrap();
+        assert_eq!(pending.len(), 1);
+        let (version, _, statements) = &pending[0];
+        assert_eq!(*version, last);
+        assert!(
+            statements
+                .iter()
+                .any(|sql| sql.starts_with("INSERT INTO migrations") && sql.contains(&last.to_string()))
+        );
+        assert_eq!(get_current_migration_version(&conn).unwrap(), last - 1);
+
+        run_migrations(&conn).unwrap();
+        assert_eq!(get_current_migration_version(&conn).unwrap(), last);
+        assert!(pending_migration_sql(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backup_taken_before_pending_migrations() {
+        let dir = std::env::temp_dir().join(format!("halvor-migrations-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let conn = Connection::open(dir.join("halvor.db")).unwrap();
+
+        // A new database isn't backed up
+        migrated_to(&conn, 2);
+        assert!(!dir.join("backups").exists());
+
+        run_migrations(&conn).unwrap();
+        let backups: Vec<PathBuf> = std::fs::read_dir(dir.join("backups"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].to_string_lossy().ends_with("-v2.db"));
+        let backup = Connection::open(&backups[0]).unwrap();
+        assert_eq!(get_current_migration_version(&backup).unwrap(), 2);
+
+        // Nothing pending, no new backup
+        run_migrations(&conn).unwrap();
+        assert_eq!(std::fs::read_dir(dir.join("backups")).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Shared connections to `halvor.db`
+//!
+//! One pool per process: the first connection runs pending migrations, later
+//! ones are opened as needed and handed back to the pool when dropped. All
+//! connections use WAL journaling and a busy timeout, so the agent server,
+//! web API and CLI can use the database at the same time.
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Idle connections kept for reuse. More can be open at once; the extras
+/// are closed when dropped.
+const MAX_IDLE: usize = 8;
+
+/// How long a write waits for another connection to finish its own before
+/// failing with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open a connection to the database at `path`, without migrating it
+///
+/// In WAL mode readers don't block the writer or each other, and the busy
+/// timeout makes a second writer wait instead of failing.
+pub fn open_connection(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("Failed to open database: {}", path.display()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .context("Failed to set the database busy timeout")?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+        .context("Failed to switch the database to WAL mode")?;
+    Ok(conn)
+}
+
+/// A pool of connections to one database file
+pub struct Pool {
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Pool {
+    /// Open the database at `path` and run any pending migrations
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = open_connection(path)?;
+        crate::migrations::run_migrations(&conn)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    /// An idle connection, or a new one if all are in use
+    pub fn get(&self) -> Result<PooledConnection<'_>> {
+        let idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => open_connection(&self.path)?,
+        };
+        Ok(PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        })
+    }
+
+    /// Run `f` in a transaction, committing if it returns `Ok`
+    ///
+    /// The transaction takes the write lock up front. A deferred transaction
+    /// that reads before it writes can fail with SQLITE_BUSY when another
+    /// connection wrote in between, without waiting out the busy timeout.
+    pub fn with_transaction<T>(&self, f: impl FnOnce(&Transaction) -> Result<T>) -> Result<T> {
+        let mut conn = self.get()?;
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .context("Failed to start transaction")?;
+        let value = f(&tx)?;
+        tx.commit().context("Failed to commit transaction")?;
+        Ok(value)
+    }
+}
+
+/// A connection borrowed from a [`Pool`], returned to it when dropped
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection is only taken when dropped")
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection is only taken when dropped")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        // A connection left in a transaction isn't handed out again
+        if !conn.is_autocommit() {
+            return;
+        }
+        let mut idle = self.pool.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_pool() -> (PathBuf, Pool) {
+        let dir = std::env::temp_dir().join(format!("halvor-pool-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = Pool::open(&dir.join("halvor.db")).unwrap();
+        (dir, pool)
+    }
+
+    #[test]
+    fn test_connections_are_reused_and_migrated() {
+        let (dir, pool) = scratch_pool();
+        {
+            let conn = pool.get().unwrap();
+            let mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+            assert_eq!(mode, "wal");
+            assert!(crate::migrations::get_current_migration_version(&conn).unwrap() > 0);
+
+            // A second connection while the first is in use
+            let other = pool.get().unwrap();
+            assert_eq!(other.path(), conn.path());
+        }
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+
+        // Connections left in a transaction are closed instead
+        let conn = pool.get().unwrap();
+        conn.execute_batch("BEGIN").unwrap();
+        drop(conn);
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_transactions_wait_instead_of_failing() {
+        let (dir, pool) = scratch_pool();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let pool = &pool;
+                scope.spawn(move || {
+                    for i in 0..20 {
+                        pool.with_transaction(|tx| {
+                            let count: i64 =
+                                tx.query_row("SELECT COUNT(*) FROM settings", [], |row| row.get(0))?;
+                            tx.execute(
+                                "INSERT INTO settings (id, key, value, created_at, updated_at)
+                                 VALUES (?1, ?1, ?2, 0, 0)",
+                                rusqlite::params![format!("{}-{}", thread, i), count],
+                            )?;
+                            Ok(())
+                        })
+                        .unwrap();
+                    }
+                });
+            }
+        });
+
+        let conn = pool.get().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM settings", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 160);
+
+        // A failed transaction is rolled back
+        let result: Result<()> = pool.with_transaction(|tx| {
+            tx.execute("DELETE FROM settings", [])?;
+            anyhow::bail!("changed my mind")
+        });
+        assert!(result.is_err());
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM settings", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 160);
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
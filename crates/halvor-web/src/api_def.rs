@@ -93,6 +93,14 @@ impl ApiDefinition {
                 response_type: "String".to_string(),
                 description: "Execute a command on a remote agent".to_string(),
             },
+            ApiEndpoint {
+                path: "/api/mesh-peers".to_string(),
+                method: HttpMethod::GET,
+                handler: "list_mesh_peers".to_string(),
+                request_type: None,
+                response_type: "Vec<MeshPeer>".to_string(),
+                description: "List mesh peers with their heartbeat status".to_string(),
+            },
             ApiEndpoint {
                 path: "/api/version".to_string(),
                 method: HttpMethod::GET,
@@ -44,9 +44,11 @@ fn generate_typescript_client(endpoints: &[ApiEndpoint], output_base: &PathBuf)
     code.push_str("  tailscaleHostname?: string;\n");
     code.push_str("  agentPort: number;\n");
     code.push_str("  reachable: boolean;\n");
+    code.push_str("  version?: string;\n");
     code.push_str("}\n\n");
 
     code.push_str("export interface HostInfo {\n");
+    code.push_str("  version?: string;\n");
     code.push_str("  dockerVersion?: string;\n");
     code.push_str("  tailscaleInstalled: boolean;\n");
     code.push_str("  portainerInstalled: boolean;\n");
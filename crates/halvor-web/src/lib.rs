@@ -184,6 +184,20 @@ async fn execute_command(
     (status, Json(response))
 }
 
+/// List mesh peers with their heartbeat status
+/// GET /api/mesh-peers
+#[cfg(feature = "agent")]
+async fn list_mesh_peers(State(state): State<AppState>) -> impl IntoResponse {
+    let result = state.client.list_mesh_peers();
+    let response = result_to_response(result);
+    let status = if response.success {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    (status, Json(response))
+}
+
 /// Get the version of the Halvor client
 /// GET /api/version
 #[cfg(feature = "agent")]
@@ -242,6 +256,7 @@ pub async fn start_server(
                         "discover_agents" => get(discover_agents),
                         "discover_via_tailscale" => get(discover_via_tailscale),
                         "discover_via_local_network" => get(discover_via_local_network),
+                        "list_mesh_peers" => get(list_mesh_peers),
                         "get_version" => get(get_version),
                         _ => continue, // Unknown handler
                     }
@@ -8,14 +8,27 @@ export interface DiscoveredHost {
   tailscaleHostname?: string;
   agentPort?: number;
   reachable?: boolean;
+  version?: string;
 }
 
 export interface HostInfo {
+  version?: string;
   dockerVersion?: string;
   tailscaleInstalled?: boolean;
   portainerInstalled?: boolean;
 }
 
+export type PeerStatus = 'active' | 'suspect' | 'inactive';
+
+export interface MeshPeer {
+  hostname: string;
+  tailscale_ip?: string;
+  tailscale_hostname?: string;
+  status: PeerStatus;
+  last_seen_at?: number;
+  joined_at: number;
+}
+
 export interface ApiResponse<T> {
   success: boolean;
   data?: T;
@@ -83,6 +96,10 @@ export const api = {
     });
   },
 
+  async listMeshPeers(): Promise<MeshPeer[]> {
+    return apiCall<MeshPeer[]>('/mesh-peers');
+  },
+
   async getVersion(): Promise<string> {
     return apiCall<string>('/version');
   },